@@ -1,10 +1,35 @@
+// Each blockchain config defines its own `build_protos()`, so they are included in separate
+// modules to allow several blockchains to be compiled in at once.
 #[cfg(feature = "SOLANA")]
-include!("src/solana_config/build_proto.rs");
+mod solana {
+    include!("src/solana_config/build_proto.rs");
+
+    /// Builds the protos of the Solana config.
+    pub fn build() -> Result<(), ()> {
+        build_protos()
+    }
+}
 #[cfg(feature = "APTOS")]
-include!("src/aptos_config/build_proto.rs");
+mod aptos {
+    include!("src/aptos_config/build_proto.rs");
+
+    /// Builds the protos of the Aptos config.
+    pub fn build() -> Result<(), ()> {
+        build_protos()
+    }
+}
 
 include!("src/features.rs");
 
+/// Builds the protos of every blockchain config that is compiled in.
+fn build_protos() -> Result<(), ()> {
+    #[cfg(feature = "SOLANA")]
+    solana::build()?;
+    #[cfg(feature = "APTOS")]
+    aptos::build()?;
+    Ok(())
+}
+
 fn main() -> std::io::Result<()> {
     match build_protos() {
         Ok(_) => Ok(()),
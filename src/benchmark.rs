@@ -3,11 +3,12 @@ use std::{mem::size_of_val, thread, time};
 
 #[cfg(feature = "SOLANA")]
 use {
-    blockchain_etl_indexer::solana_config::types::block_response_types,
+    blockchain_etl_indexer::solana_config::types::{BlockHeightRequest, BlockRequest},
     solana_transaction_status::UiConfirmedBlock as Block,
 };
 
 use blockchain_etl_indexer::source::config::RequestConfig;
+use blockchain_etl_indexer::source::error::SourceError;
 
 /// Determines an estimate of the blockchain's throughput (block size * number of blocks / time).
 /// Takes some number of minutes for measurement. Returns the throughput in bytes per second.
@@ -16,20 +17,24 @@ use blockchain_etl_indexer::source::config::RequestConfig;
 /// NOTE: for a closer estimate, we could measure the size of every block in the period. But for now, this is probably close enough.
 #[allow(non_snake_case)]
 pub async fn get_blockchain_throughput(
-    request_builder: reqwest::RequestBuilder,
+    request_config: RequestConfig,
     time_in_minutes: u32,
-) -> u64 {
+) -> Result<u64, SourceError> {
     // our measurement periods are in minutes, but currently std::time::Duration doesn't have a `from_minutes` function.
     let time_in_seconds: u64 = (time_in_minutes as u64) * 60;
     let period = time::Duration::from_secs(time_in_seconds);
 
-    let request_config = RequestConfig::ReqBldr(request_builder);
-
-    let block_height_start =
-        blockchain_generic::call_getBlockHeight(request_config.try_clone().unwrap(), None).await;
+    let block_height_start = blockchain_generic::call_getBlockHeight::<BlockHeightRequest>(
+        request_config.try_clone().unwrap(),
+        None,
+    )
+    .await?;
     thread::sleep(period);
-    let block_height_end =
-        blockchain_generic::call_getBlockHeight(request_config.try_clone().unwrap(), None).await;
+    let block_height_end = blockchain_generic::call_getBlockHeight::<BlockHeightRequest>(
+        request_config.try_clone().unwrap(),
+        None,
+    )
+    .await?;
 
     // we want the size of a representative block. so if the one we check "was missing or skipped in long term storage", then we will try the next one.
     let example_block: Block = {
@@ -37,9 +42,12 @@ pub async fn get_blockchain_throughput(
         let blocks_in_period = block_height_start..block_height_end;
         // Go through all the blocks until we find one that is valid, then break.
         for i in blocks_in_period {
-            let block_in_period: block_response_types::BlockResponse =
-                blockchain_generic::call_getBlock(request_config.try_clone().unwrap(), i, None)
-                    .await;
+            let block_in_period = blockchain_generic::call_getBlock::<BlockRequest>(
+                request_config.try_clone().unwrap(),
+                i,
+                None,
+            )
+            .await?;
 
             if let Some(_parsed_block) = block_in_period.result {
                 parsed_block = Some(_parsed_block);
@@ -60,5 +68,5 @@ pub async fn get_blockchain_throughput(
     let block_size: u64 = size_of_val(serialized_block.as_bytes()) as u64;
     let num_blocks = block_height_end - block_height_start;
 
-    block_size * num_blocks / time_in_seconds
+    Ok(block_size * num_blocks / time_in_seconds)
 }
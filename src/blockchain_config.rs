@@ -0,0 +1,506 @@
+//! This module defines the `BlockchainConfig` trait, which each blockchain configuration
+//! (e.g. `aptos_config`) implements to plug its extraction, transformation and output into
+//! the generic indexer.  The CLI is generic over this trait, so several blockchains can be
+//! compiled into the same binary and selected at runtime with the `--chain` flag.
+
+use async_trait::async_trait;
+use log::{error, warn};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::checkpoint::CheckpointError;
+use crate::config;
+use crate::metrics::{self, Metrics};
+use crate::output::error::PublishError;
+use crate::output::publish::ChainInfo;
+use crate::reorg::{BlockLink, ReorgTracker};
+use crate::retry::{RetryError, Retryable};
+use crate::source::error::SourceError;
+
+/// Errors that interrupt the extraction of a block or range.
+#[derive(Debug, Error)]
+pub enum ExtractionError {
+    /// The request to the node failed, or the node returned an error.
+    #[error("request to the node failed: {0}")]
+    Request(String),
+    /// A source client failed to get a response from the node.
+    #[error("request to the node failed: {0}")]
+    Source(#[from] SourceError),
+    /// The node's response could not be transformed into records.
+    #[error("failed to transform block {height}: {reason}")]
+    Transform { height: u64, reason: String },
+    /// The records could not be published.
+    #[error("failed to publish records: {0}")]
+    Publish(#[from] PublishError),
+    /// Reading or writing local files failed.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The chain was reorganized deeper than `follow.max_reorg_depth`.
+    #[error("chain reorganized at block {height}: {reason}")]
+    Reorg { height: u64, reason: String },
+    /// The heights that were indexed could not be recorded.
+    #[error(transparent)]
+    Checkpoint(#[from] CheckpointError),
+    /// The extraction was stopped before it completed.
+    #[error("extraction interrupted: {0}")]
+    Interrupted(String),
+}
+
+/// What the indexing loop does about a block that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Try the block again.
+    Retry,
+    /// Give up on the block and continue with the next one.
+    Skip,
+    /// Stop indexing.
+    Abort,
+}
+
+/// The outcome of indexing the block at a single height.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The block was indexed.
+    Indexed,
+    /// There is no block at this height (e.g. a skipped slot).
+    Missing,
+    /// The block failed and was given up on, for the given reason.
+    Failed(String),
+}
+
+impl BlockOutcome {
+    /// Returns the name of the outcome, as written in reports.
+    pub fn status(&self) -> &'static str {
+        match self {
+            BlockOutcome::Indexed => "indexed",
+            BlockOutcome::Missing => "missing",
+            BlockOutcome::Failed(_) => "failed",
+        }
+    }
+}
+
+impl ExtractionError {
+    /// Returns what the indexing loop should do about this error.  Requests and transient
+    /// publishing failures are retried, blocks that can't be transformed or whose records are
+    /// rejected (or couldn't be published within the retry budget) are skipped, and
+    /// misconfigured outputs or local failures stop indexing.
+    pub fn action(&self) -> ErrorAction {
+        match self {
+            ExtractionError::Request(_) => ErrorAction::Retry,
+            ExtractionError::Source(SourceError::Retryable { .. })
+            | ExtractionError::Source(SourceError::Throttled { .. }) => ErrorAction::Retry,
+            ExtractionError::Source(SourceError::Fatal { .. })
+            | ExtractionError::Source(SourceError::Exhausted { .. }) => ErrorAction::Skip,
+            ExtractionError::Source(SourceError::Configuration { .. }) => ErrorAction::Abort,
+            ExtractionError::Transform { .. } => ErrorAction::Skip,
+            ExtractionError::Publish(PublishError::Retryable { .. }) => ErrorAction::Retry,
+            ExtractionError::Publish(PublishError::Fatal { .. })
+            | ExtractionError::Publish(PublishError::Exhausted { .. }) => ErrorAction::Skip,
+            ExtractionError::Publish(PublishError::Configuration { .. }) => ErrorAction::Abort,
+            ExtractionError::Io(_)
+            | ExtractionError::Reorg { .. }
+            | ExtractionError::Checkpoint(_)
+            | ExtractionError::Interrupted(_) => ErrorAction::Abort,
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for ExtractionError {
+    /// Returns the error of a worker task that was cancelled before it completed.
+    fn from(error: tokio::task::JoinError) -> ExtractionError {
+        ExtractionError::Interrupted(format!("a worker was stopped: {}", error))
+    }
+}
+
+impl Retryable for ExtractionError {
+    fn is_retryable(&self) -> bool {
+        self.action() == ErrorAction::Retry
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ExtractionError::Source(e) => e.retry_after(),
+            _ => None,
+        }
+    }
+}
+
+/// Implemented by each blockchain configuration.
+///
+/// A blockchain only needs to describe how to extract a single block (`extract_block`), how to
+/// transform it into records (`transform_block`) and how to publish those records to its
+/// tables (`publish_records`); the indexing loops of the `indexer` module are built on top of
+/// these.  Blockchains with a more custom indexing algorithm (e.g. streaming ranges from the
+/// node) can write their own loop around `index_block_or_skip`.
+#[async_trait]
+pub trait BlockchainConfig: Send + Sync + Sized + 'static {
+    /// The name used to select the blockchain with the `--chain` flag (e.g. `aptos`).
+    const NAME: &'static str;
+
+    /// A block (or batch of transactions) as it is returned by the node.
+    type RawBlock: Send + 'static;
+
+    /// The records transformed from a single raw block, for all of the tables.
+    type Records: Send + 'static;
+
+    /// The publisher(s) that the records are published to.  With `SEPARATE_PUBLISHERS` this
+    /// holds one `StreamPublisherConnection` per table, with `SINGLE_PUBLISHER` this can be
+    /// `output::publish::StreamPublisher`.
+    type StreamPublisher: Clone + Send + Sync + 'static;
+
+    /// Creates the configuration, reading whatever the blockchain needs from the environment.
+    async fn new() -> Result<Self, ExtractionError>;
+
+    /// Connects to the outputs of every table.
+    async fn connect_publisher(&self) -> Result<Self::StreamPublisher, ExtractionError>;
+
+    /// Disconnects from the outputs of every table.  Should be called before terminating the program.
+    async fn disconnect_publisher(
+        &self,
+        publisher: Self::StreamPublisher,
+    ) -> Result<(), ExtractionError>;
+
+    /// Requests the block at the given height from the node.  Returns `None` if there is no
+    /// block at this height (e.g. a skipped slot).
+    async fn extract_block(
+        &self,
+        height: u64,
+        metrics: Option<Metrics>,
+    ) -> Result<Option<Self::RawBlock>, ExtractionError>;
+
+    /// Transforms a raw block into the records of each table.
+    fn transform_block(
+        &self,
+        height: u64,
+        block: Self::RawBlock,
+    ) -> Result<Self::Records, ExtractionError>;
+
+    /// Publishes the records of a block to their tables.
+    async fn publish_records(
+        &self,
+        publisher: &Self::StreamPublisher,
+        records: Self::Records,
+    ) -> Result<(), ExtractionError>;
+
+    /// Begins publishing the block at `height`, before any of its records are published (and
+    /// again whenever the block is retried).  Outputs that publish each block atomically (e.g.
+    /// Kafka with `KAFKA_TRANSACTIONAL`, see `output::kafka_transaction`) should begin the
+    /// block's transaction here, aborting the one of a block that failed.
+    async fn begin_block(
+        &self,
+        _publisher: &Self::StreamPublisher,
+        _height: u64,
+    ) -> Result<(), ExtractionError> {
+        Ok(())
+    }
+
+    /// Finishes publishing the block at `height`, once its records were handed to the
+    /// publisher and before the block is recorded in the checkpoints.  Outputs that buffer
+    /// records (e.g. Kafka, whose deliveries are only awaited when it is flushed) should be
+    /// flushed here, usually with `Publisher::flush` on each table, so a block is only recorded
+    /// once its records were delivered, and the transaction begun by `begin_block` committed.
+    async fn finish_block(
+        &self,
+        _publisher: &Self::StreamPublisher,
+        _height: u64,
+    ) -> Result<(), ExtractionError> {
+        Ok(())
+    }
+
+    /// Publishes the records of the block at `height`, between `begin_block` and
+    /// `finish_block`.
+    async fn publish_block(
+        &self,
+        publisher: &Self::StreamPublisher,
+        height: u64,
+        records: Self::Records,
+    ) -> Result<(), ExtractionError> {
+        self.begin_block(publisher, height).await?;
+        self.publish_records(publisher, records).await?;
+        self.finish_block(publisher, height).await
+    }
+
+    /// Returns the approximate size of a raw block in bytes, counted against the byte budget
+    /// of the pipeline queues.  Defaults to 0, so only the item capacity applies.
+    fn raw_block_size(&self, _block: &Self::RawBlock) -> usize {
+        0
+    }
+
+    /// Returns the approximate size of the records of a block in bytes, counted against the
+    /// byte budget of the pipeline queues.  Defaults to 0, so only the item capacity applies.
+    fn records_size(&self, _records: &Self::Records) -> usize {
+        0
+    }
+
+    /// Requests the height of the latest block from the node, or returns `None` if the
+    /// blockchain can't tell.  Used to wait for new blocks when indexing without end.
+    async fn chain_head(&self, _metrics: Option<Metrics>) -> Result<Option<u64>, ExtractionError> {
+        Ok(None)
+    }
+
+    /// Returns the hash and parent hash of a raw block, or `None` if the blockchain doesn't
+    /// track reorganizations.  Blockchains that return links should implement
+    /// `publish_retractions`.
+    fn block_link(&self, _height: u64, _block: &Self::RawBlock) -> Option<BlockLink> {
+        None
+    }
+
+    /// Publishes a retraction of a block that was replaced by a reorganization to every table,
+    /// usually with `reorg::publish_retraction`.
+    async fn publish_retractions(
+        &self,
+        _publisher: &Self::StreamPublisher,
+        link: &BlockLink,
+    ) -> Result<(), ExtractionError> {
+        Err(ExtractionError::Reorg {
+            height: link.height,
+            reason: format!("{} doesn't publish retractions", Self::NAME),
+        })
+    }
+
+    /// Extracts, transforms and publishes the block at a single height.  Returns `false` if
+    /// there was no block at this height.  With a `ReorgTracker`, a block that doesn't chain
+    /// to the block published before it is handled as a reorganization first (see
+    /// `retract_reorged`).
+    async fn index_block(
+        &self,
+        height: u64,
+        publisher: &Self::StreamPublisher,
+        reorgs: Option<&ReorgTracker>,
+        metrics: Option<Metrics>,
+    ) -> Result<bool, ExtractionError> {
+        match self.extract_block(height, metrics.clone()).await? {
+            Some(block) => {
+                let link = reorgs.and(self.block_link(height, &block));
+                if let (Some(reorgs), Some(link)) = (reorgs, &link) {
+                    if reorgs.conflicts(link) {
+                        self.retract_reorged(height, reorgs, publisher, metrics)
+                            .await?;
+                    }
+                }
+                let records = self.transform_block(height, block)?;
+                self.publish_records(publisher, records).await?;
+                if let (Some(reorgs), Some(link)) = (reorgs, link) {
+                    reorgs.record(link);
+                }
+                Ok(true)
+            }
+            None => {
+                warn!("No block at height {}, skipping...", height);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles a reorganization found at `height`: walks back from the height before it to
+    /// the last block that is still on the chain, then retracts each block that was replaced
+    /// and indexes the block that replaced it, from the lowest height up.  Fails if the
+    /// reorganization is deeper than the tracker follows.  When the tracker holds fewer blocks
+    /// than that (e.g. after a restart), the oldest block it holds is taken as the boundary.
+    ///
+    /// NOTE: if indexing is retried after the retractions were published, they are published
+    /// again, so consumers should treat them as idempotent.
+    async fn retract_reorged(
+        &self,
+        height: u64,
+        reorgs: &ReorgTracker,
+        publisher: &Self::StreamPublisher,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError> {
+        let deepest = height.saturating_sub(reorgs.max_depth());
+        let oldest = reorgs.oldest().unwrap_or(height);
+        let mut replaced = Vec::new();
+        let mut common_ancestor = false;
+        for ancestor in (deepest.max(oldest)..height).rev() {
+            // NOTE: heights without a known block (e.g. skipped slots) are passed over.
+            let Some(known) = reorgs.get(ancestor) else {
+                continue;
+            };
+            let current = self
+                .extract_block(ancestor, metrics.clone())
+                .await?
+                .and_then(|block| self.block_link(ancestor, &block));
+            if current.is_some_and(|current| current.hash == known.hash) {
+                common_ancestor = true;
+                break;
+            }
+            replaced.push(known);
+        }
+        if !common_ancestor && oldest <= deepest {
+            return Err(ExtractionError::Reorg {
+                height,
+                reason: format!(
+                    "no common ancestor in the last {} blocks",
+                    reorgs.max_depth()
+                ),
+            });
+        }
+        if !common_ancestor {
+            // NOTE: the blocks below the oldest one tracked (e.g. published before a restart)
+            // can't be compared, so the reorganization is assumed to stop there.
+            warn!(
+                "No common ancestor among the blocks tracked since block {}, assuming the chain below it is unchanged...",
+                oldest
+            );
+        }
+        if replaced.is_empty() {
+            // The block before this one wasn't published (e.g. it failed), so its hash wasn't known.
+            return Ok(());
+        }
+        warn!(
+            "Chain reorganized at block {}, {} blocks were replaced, retracting them...",
+            height,
+            replaced.len()
+        );
+        metrics::record_reorg(replaced.len());
+        for known in replaced.into_iter().rev() {
+            self.publish_retractions(publisher, &known).await?;
+            reorgs.forget(known.height);
+            self.index_block(known.height, publisher, Some(reorgs), metrics.clone())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the block at a single height between `begin_block` and `finish_block`, deciding
+    /// what to do about failures with `ExtractionError::action()`: retryable failures are
+    /// retried according to the configured retry policy, skippable failures (and blocks that
+    /// exhaust the retry budget) are logged and the block is given up on.  Returns an error
+    /// only if indexing should stop.
+    async fn index_block_or_skip(
+        &self,
+        height: u64,
+        publisher: &Self::StreamPublisher,
+        reorgs: Option<&ReorgTracker>,
+        metrics: Option<Metrics>,
+    ) -> Result<BlockOutcome, ExtractionError> {
+        let indexed = config::get()
+            .retry
+            .run("index_block", || async {
+                self.begin_block(publisher, height).await?;
+                let indexed = self
+                    .index_block(height, publisher, reorgs, metrics.clone())
+                    .await?;
+                self.finish_block(publisher, height).await?;
+                Ok::<_, ExtractionError>(indexed)
+            })
+            .await;
+        match indexed {
+            Ok(true) => Ok(BlockOutcome::Indexed),
+            Ok(false) => Ok(BlockOutcome::Missing),
+            Err(RetryError::Permanent(err)) if err.action() == ErrorAction::Abort => Err(err),
+            Err(err) => {
+                error!("Failed to index block {}: {}, skipping...", height, err);
+                Ok(BlockOutcome::Failed(err.to_string()))
+            }
+        }
+    }
+
+    /// Returns the publisher a worker publishes with, when several workers index a range.
+    /// Publishers that can't be shared between workers (e.g. a RabbitMQ channel) should be
+    /// created here, usually with `StreamPublisherConnection::for_worker`.  `worker` is the
+    /// slot of the worker, from 0: the workers running at once have different slots, and a
+    /// restarted indexer reuses them (e.g. for the transactional ids of `KafkaTransaction`).
+    async fn worker_publisher(
+        &self,
+        publisher: &Self::StreamPublisher,
+        _worker: usize,
+    ) -> Result<Self::StreamPublisher, ExtractionError> {
+        Ok(publisher.clone())
+    }
+
+    /// Extracts the raw blocks in the range `[start, end)` and returns them in a Vec.  If
+    /// provided an `outdir`, should serialize the blocks and save them in the directory.
+    async fn extract_txs(
+        &self,
+        start: u64,
+        end: u64,
+        outdir: Option<PathBuf>,
+    ) -> Result<Vec<Self::RawBlock>, ExtractionError>;
+
+    /// Saves the raw blocks in the range `[start, end)` along with their expected
+    /// transformations into `dir`, for use as test examples.
+    async fn create_test_data(
+        &self,
+        start: u64,
+        end: u64,
+        dir: &Path,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError>;
+
+    /// Creates a pubsub subscription to receive requests for which blocks to index.
+    #[cfg(feature = "ORCHESTRATED")]
+    async fn subscribe_and_extract(
+        &self,
+        subscription: google_cloud_pubsub::subscription::Subscription,
+        publisher: Self::StreamPublisher,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError>;
+
+    /// Returns the Apache Avro schema of the table written to the given queue
+    /// (e.g. `QUEUE_NAME_BLOCKS`), used when publishing Avro messages to Pub/Sub.
+    #[cfg(feature = "APACHE_AVRO")]
+    fn avro_schema(queue_env: &str) -> Option<&'static str>;
+
+    /// Returns what the outputs need to know about this blockchain, to be passed to
+    /// `publish::connect` when connecting the publisher.
+    fn chain_info() -> ChainInfo {
+        ChainInfo {
+            name: Self::NAME,
+            #[cfg(feature = "APACHE_AVRO")]
+            avro_schema: Self::avro_schema,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockChain, MockPublisher};
+
+    /// Returns a tracker holding the links of the original chain at `heights`.
+    fn tracker(max_depth: u64, heights: std::ops::Range<u64>) -> ReorgTracker {
+        let reorgs = ReorgTracker::new(max_depth);
+        for height in heights {
+            reorgs.record(MockChain::original_link(height));
+        }
+        reorgs
+    }
+
+    #[tokio::test]
+    async fn test_retract_reorged() {
+        let chain = MockChain {
+            fork: Some(11),
+            ..MockChain::default()
+        };
+        let publisher = MockPublisher::prepared();
+
+        let reorgs = tracker(64, 8..13);
+        chain
+            .retract_reorged(13, &reorgs, &publisher, None)
+            .await
+            .unwrap();
+        assert_eq!(publisher.retracted(), [11, 12]);
+        assert_eq!(publisher.published(), [11, 12]);
+        assert_eq!(reorgs.get(12).unwrap().hash, "12b");
+        assert_eq!(reorgs.get(10).unwrap().hash, "10");
+
+        // NOTE: after a restart, the tracker only holds the blocks published since, so the
+        // oldest of them is taken as the boundary.
+        let publisher = MockPublisher::prepared();
+        let reorgs = tracker(64, 11..13);
+        chain
+            .retract_reorged(13, &reorgs, &publisher, None)
+            .await
+            .unwrap();
+        assert_eq!(publisher.retracted(), [11, 12]);
+
+        let reorgs = tracker(2, 11..13);
+        let deep = chain.retract_reorged(13, &reorgs, &publisher, None).await;
+        assert!(matches!(
+            deep,
+            Err(ExtractionError::Reorg { height: 13, .. })
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+//! This module implements the checkpoint store kept in a local JSON file, which maps each
+//! output to the ranges of heights it completed, e.g. `{"jsonl": [[0, 1200], [1500, 1510]]}`.
+//!
+//! Every update rewrites the whole file, which stays small as adjacent heights are merged into
+//! ranges.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{CheckpointError, CheckpointStore, HeightSet};
+
+/// A checkpoint store kept in a local JSON file.
+pub struct FileCheckpointStore {
+    /// The checkpoint file.
+    path: PathBuf,
+    /// The heights completed for each output, as last written to the file.
+    outputs: Mutex<BTreeMap<String, HeightSet>>,
+}
+
+impl FileCheckpointStore {
+    /// Opens the checkpoint file at `path`, creating its directory if needed.  The file is
+    /// created on the first record.
+    pub fn open(path: PathBuf) -> Result<FileCheckpointStore, CheckpointError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let outputs = match fs::read(&path) {
+            Ok(contents) => {
+                serde_json::from_slice(&contents).map_err(|e| CheckpointError::Corrupt {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                })?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(FileCheckpointStore {
+            path,
+            outputs: Mutex::new(outputs),
+        })
+    }
+
+    /// Writes the checkpoints to the file.
+    ///
+    /// NOTE: they are written to a temporary file that is synced and renamed over the file, so
+    /// a crash leaves either the previous or the new checkpoints, never a half-written file.
+    fn write(&self, outputs: &BTreeMap<String, HeightSet>) -> Result<(), CheckpointError> {
+        let contents = serde_json::to_vec(outputs).map_err(|e| CheckpointError::Corrupt {
+            path: self.path.clone(),
+            reason: e.to_string(),
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&contents)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(&self.path)
+    }
+}
+
+/// Syncs the directory of a file, so that a rename to it is durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), CheckpointError> {
+    let dir = match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be synced on this platform, the rename is left to the file system.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), CheckpointError> {
+    Ok(())
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    /// Returns the heights completed for the output.
+    fn completed(&self, output: &str) -> Result<HeightSet, CheckpointError> {
+        let outputs = self.outputs.lock().expect("checkpoint lock poisoned");
+        Ok(outputs.get(output).cloned().unwrap_or_default())
+    }
+
+    /// Records the heights `[start, end)` and rewrites the file.
+    fn record(&self, output: &str, start: u64, end: u64) -> Result<(), CheckpointError> {
+        let mut outputs = self.outputs.lock().expect("checkpoint lock poisoned");
+        let mut updated = outputs.clone();
+        updated
+            .entry(output.to_string())
+            .or_default()
+            .insert(start, end);
+        self.write(&updated)?;
+        *outputs = updated;
+        Ok(())
+    }
+
+    /// Returns the outputs that have checkpoints.
+    fn outputs(&self) -> Result<Vec<String>, CheckpointError> {
+        let outputs = self.outputs.lock().expect("checkpoint lock poisoned");
+        Ok(outputs.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoints").join("checkpoints.json");
+
+        let store = FileCheckpointStore::open(path.clone()).unwrap();
+        store.record("jsonl", 10, 11).unwrap();
+        store.record("jsonl", 12, 13).unwrap();
+        store.record("jsonl", 11, 12).unwrap();
+        store.record("apache_kafka", 0, 5).unwrap();
+
+        let reopened = FileCheckpointStore::open(path).unwrap();
+        let completed = reopened.completed("jsonl").unwrap();
+        assert_eq!(completed.ranges().collect::<Vec<_>>(), [(10, 13)]);
+        assert!(reopened.completed("json").unwrap().is_empty());
+        assert_eq!(reopened.outputs().unwrap(), ["apache_kafka", "jsonl"]);
+    }
+}
@@ -0,0 +1,206 @@
+//! This module implements the checkpoint store kept in a Kafka topic (`KAFKA_PROGRESS_TOPIC`),
+//! as progress markers: records keyed by the output, holding a range of heights it completed,
+//! as JSON.  It requires the `APACHE_KAFKA` feature.
+//!
+//! With `KAFKA_TRANSACTIONAL`, the marker of each block is published in the transaction of
+//! the block's records (see `output::kafka_transaction`), so a block is recorded if and only
+//! if its records were committed.  The markers are read back with `read_committed`, so the
+//! markers of aborted transactions are never read.
+//!
+//! NOTE: every marker is read to find the completed heights, so the progress topic should not
+//! be compacted (the markers of an output share its key), and should keep its records.
+
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{BaseConsumer, Consumer},
+    error::KafkaError,
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    Message, Offset, TopicPartitionList,
+};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use super::{CheckpointError, CheckpointStore, HeightSet};
+use crate::config::KafkaConfig;
+use crate::output::apache_kafka;
+
+/// How long a request to the brokers may take.
+const KAFKA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A checkpoint store kept in a Kafka topic.
+pub struct KafkaCheckpointStore {
+    /// The settings of the clients of the brokers.
+    client_config: ClientConfig,
+    /// The topic the markers are published to.
+    topic: String,
+    /// The idempotent producer of the markers recorded outside of transactions.
+    producer: FutureProducer,
+}
+
+/// Converts a Kafka error to a `CheckpointError`.
+fn kafka_error(err: KafkaError) -> CheckpointError {
+    CheckpointError::Database(err.to_string())
+}
+
+/// Returns the key and payload of the marker of the heights `[start, end)` of an output.
+pub fn marker(output: &str, start: u64, end: u64) -> (Vec<u8>, Vec<u8>) {
+    let payload = serde_json::to_vec(&(start, end)).expect("a range is serializable");
+    (output.as_bytes().to_vec(), payload)
+}
+
+impl KafkaCheckpointStore {
+    /// Opens the store kept in `kafka.progress_topic`, on the configured brokers.
+    pub fn open(kafka: &KafkaConfig) -> Result<KafkaCheckpointStore, CheckpointError> {
+        let topic = kafka.progress_topic.clone().ok_or_else(|| {
+            CheckpointError::Configuration(String::from(
+                "kafka.progress_topic should be configured",
+            ))
+        })?;
+        let client_config = apache_kafka::client_config(kafka)
+            .map_err(|e| CheckpointError::Configuration(e.to_string()))?;
+        KafkaCheckpointStore::new(client_config, topic)
+    }
+
+    /// Creates a store kept in `topic`, with clients of the brokers set in `client_config`.
+    pub fn new(
+        client_config: ClientConfig,
+        topic: String,
+    ) -> Result<KafkaCheckpointStore, CheckpointError> {
+        let producer = client_config
+            .clone()
+            .set("enable.idempotence", "true")
+            .create()
+            .map_err(kafka_error)?;
+        Ok(KafkaCheckpointStore {
+            client_config,
+            topic,
+            producer,
+        })
+    }
+
+    /// Reads every committed marker of the topic, calling `read` with the output and range of
+    /// each.  A topic that doesn't exist yet holds no markers.
+    fn read_markers(&self, mut read: impl FnMut(String, u64, u64)) -> Result<(), CheckpointError> {
+        let consumer: BaseConsumer = self
+            .client_config
+            .clone()
+            .set("group.id", "blockchain-etl-checkpoints")
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "true")
+            .set("isolation.level", "read_committed")
+            .create()
+            .map_err(kafka_error)?;
+        let metadata = consumer
+            .fetch_metadata(Some(&self.topic), KAFKA_TIMEOUT)
+            .map_err(kafka_error)?;
+        let mut remaining = metadata
+            .topics()
+            .iter()
+            .filter(|topic| topic.name() == self.topic && topic.error().is_none())
+            .flat_map(|topic| topic.partitions().iter().map(|partition| partition.id()))
+            .collect::<BTreeSet<i32>>();
+        if remaining.is_empty() {
+            return Ok(());
+        }
+        let mut assignment = TopicPartitionList::new();
+        for partition in &remaining {
+            assignment
+                .add_partition_offset(&self.topic, *partition, Offset::Beginning)
+                .map_err(kafka_error)?;
+        }
+        consumer.assign(&assignment).map_err(kafka_error)?;
+
+        // NOTE: with `read_committed`, the end of a partition is its last stable offset, so
+        // the markers of open transactions are neither read nor waited for.
+        while !remaining.is_empty() {
+            match consumer.poll(KAFKA_TIMEOUT) {
+                None => {
+                    return Err(CheckpointError::Database(format!(
+                        "timed out reading the markers of {}",
+                        self.topic
+                    )))
+                }
+                Some(Err(KafkaError::PartitionEOF(partition))) => {
+                    remaining.remove(&partition);
+                }
+                Some(Err(err)) => return Err(kafka_error(err)),
+                Some(Ok(message)) => {
+                    let output = String::from_utf8_lossy(message.key().unwrap_or_default());
+                    let (start, end) =
+                        serde_json::from_slice::<(u64, u64)>(message.payload().unwrap_or_default())
+                            .map_err(|e| {
+                                CheckpointError::Database(format!(
+                                    "invalid marker at offset {} of {}: {}",
+                                    message.offset(),
+                                    self.topic,
+                                    e
+                                ))
+                            })?;
+                    read(output.into_owned(), start, end);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CheckpointStore for KafkaCheckpointStore {
+    /// Returns the heights of the committed markers of the output.
+    fn completed(&self, output: &str) -> Result<HeightSet, CheckpointError> {
+        let mut completed = HeightSet::new();
+        self.read_markers(|marker_output, start, end| {
+            if marker_output == output {
+                completed.insert(start, end);
+            }
+        })?;
+        Ok(completed)
+    }
+
+    /// Publishes the marker of the heights `[start, end)` and waits until it is delivered.
+    ///
+    /// NOTE: the blocks published in a transaction were already recorded by it, so their
+    /// markers are published twice, which doesn't change the heights completed.
+    fn record(&self, output: &str, start: u64, end: u64) -> Result<(), CheckpointError> {
+        let (key, payload) = marker(output, start, end);
+        let delivery = self.producer.send(
+            FutureRecord::to(&self.topic).key(&key).payload(&payload),
+            Timeout::After(KAFKA_TIMEOUT),
+        );
+        futures::executor::block_on(delivery)
+            .map(|_| ())
+            .map_err(|(err, _)| kafka_error(err))
+    }
+
+    /// Returns the outputs that have committed markers.
+    fn outputs(&self) -> Result<Vec<String>, CheckpointError> {
+        let mut outputs = BTreeSet::new();
+        self.read_markers(|output, _, _| {
+            outputs.insert(output);
+        })?;
+        Ok(outputs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::mocking::MockCluster;
+
+    #[test]
+    fn test_record_and_read_markers() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("progress", 1, 1).unwrap();
+        let mut client_config = ClientConfig::new();
+        client_config.set("bootstrap.servers", cluster.bootstrap_servers());
+        let store = KafkaCheckpointStore::new(client_config, String::from("progress")).unwrap();
+
+        store.record("apache_kafka", 10, 12).unwrap();
+        store.record("apache_kafka", 12, 13).unwrap();
+        store.record("jsonl", 5, 6).unwrap();
+        let completed = store.completed("apache_kafka").unwrap();
+        assert_eq!(completed.ranges().collect::<Vec<_>>(), [(10, 13)]);
+        assert_eq!(store.outputs().unwrap(), ["apache_kafka", "jsonl"]);
+        assert!(store.completed("gcs").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! This module records which heights were indexed, so that restarts and concurrent workers skip
+//! the blocks that were already published, and so that the heights that were never published
+//! can be found.
+//!
+//! The completed heights are kept per output (e.g. `apache_kafka,jsonl`), as a set of ranges,
+//! by a `CheckpointStore`.  The store is selected with `checkpoint.backend`:
+//! - `file`: a JSON file, rewritten atomically after every update (see `file`),
+//! - `redb`: an embedded database, with the `CHECKPOINT_REDB` feature (see `redb`),
+//! - `kafka`: progress markers in a Kafka topic, with the `APACHE_KAFKA` feature (see `kafka`).
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::config::Config;
+
+pub mod file;
+
+#[cfg(feature = "CHECKPOINT_REDB")]
+pub mod redb;
+
+#[cfg(feature = "APACHE_KAFKA")]
+pub mod kafka;
+
+/// The names of the checkpoint stores that can be selected.
+pub const CHECKPOINT_BACKENDS: &[&str] = &["file", "redb", "kafka"];
+
+/// Errors reading or writing checkpoints.
+#[derive(Debug, Error)]
+pub enum CheckpointError {
+    /// The checkpoint file couldn't be read or written.
+    #[error("checkpoint I/O failed: {0}")]
+    Io(#[from] io::Error),
+    /// The checkpoints couldn't be parsed.
+    #[error("invalid checkpoints in {path:?}: {reason}")]
+    Corrupt { path: PathBuf, reason: String },
+    /// The checkpoint database failed.
+    #[error("checkpoint database failed: {0}")]
+    Database(String),
+    /// The checkpoint store is misconfigured.
+    #[error("invalid checkpoint configuration: {0}")]
+    Configuration(String),
+}
+
+/// A set of heights, stored as the disjoint ranges `[start, end)` it covers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "Vec<(u64, u64)>", into = "Vec<(u64, u64)>")]
+pub struct HeightSet {
+    /// The end (exclusive) of each range, by start.  Ranges never overlap or touch.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl HeightSet {
+    /// Creates an empty set.
+    pub fn new() -> HeightSet {
+        HeightSet::default()
+    }
+
+    /// Adds the heights `[start, end)`, merging them with the ranges they overlap or touch.
+    pub fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        if let Some((&previous_start, &previous_end)) = self.ranges.range(..=start).next_back() {
+            if previous_end >= start {
+                start = previous_start;
+                end = end.max(previous_end);
+            }
+        }
+        let merged = self
+            .ranges
+            .range(start..=end)
+            .map(|(&start, _)| start)
+            .collect::<Vec<u64>>();
+        for merged_start in merged {
+            if let Some(merged_end) = self.ranges.remove(&merged_start) {
+                end = end.max(merged_end);
+            }
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Adds every range of another set.
+    pub fn extend(&mut self, other: &HeightSet) {
+        for (start, end) in other.ranges() {
+            self.insert(start, end);
+        }
+    }
+
+    /// Returns whether the set contains the height.
+    pub fn contains(&self, height: u64) -> bool {
+        self.ranges
+            .range(..=height)
+            .next_back()
+            .is_some_and(|(_, &end)| height < end)
+    }
+
+    /// Returns whether the set contains every height of `[start, end)`.
+    pub fn contains_all(&self, start: u64, end: u64) -> bool {
+        start >= end
+            || self
+                .ranges
+                .range(..=start)
+                .next_back()
+                .is_some_and(|(_, &range_end)| end <= range_end)
+    }
+
+    /// Returns the ranges of `[start, end)` that aren't in the set, in order.
+    pub fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut next = start;
+        for (range_start, range_end) in self.ranges() {
+            if range_end <= next {
+                continue;
+            }
+            if range_start >= end {
+                break;
+            }
+            if range_start > next {
+                gaps.push((next, range_start));
+            }
+            next = range_end;
+        }
+        if next < end {
+            gaps.push((next, end));
+        }
+        gaps
+    }
+
+    /// Returns the lowest height of the set that is at least `height`, if any.
+    pub fn next_from(&self, height: u64) -> Option<u64> {
+        if self.contains(height) {
+            return Some(height);
+        }
+        self.ranges.range(height..).next().map(|(&start, _)| start)
+    }
+
+    /// Returns the height after the last range of the set, if it isn't empty.
+    pub fn end(&self) -> Option<u64> {
+        self.ranges.values().next_back().copied()
+    }
+
+    /// Returns the ranges `[start, end)` of the set, in order.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(&start, &end)| (start, end))
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+impl From<Vec<(u64, u64)>> for HeightSet {
+    fn from(ranges: Vec<(u64, u64)>) -> HeightSet {
+        let mut set = HeightSet::new();
+        for (start, end) in ranges {
+            set.insert(start, end);
+        }
+        set
+    }
+}
+
+impl From<HeightSet> for Vec<(u64, u64)> {
+    fn from(set: HeightSet) -> Vec<(u64, u64)> {
+        set.ranges().collect()
+    }
+}
+
+/// Records the heights indexed for each output.
+///
+/// NOTE: implementations are safe to share between the workers of a single process, but not
+/// between processes.
+pub trait CheckpointStore: Send + Sync {
+    /// Returns the heights completed for the output.
+    fn completed(&self, output: &str) -> Result<HeightSet, CheckpointError>;
+
+    /// Records the heights `[start, end)` as completed for the output.  Returns once the
+    /// record is durable.
+    fn record(&self, output: &str, start: u64, end: u64) -> Result<(), CheckpointError>;
+
+    /// Returns the outputs that have checkpoints.
+    fn outputs(&self) -> Result<Vec<String>, CheckpointError>;
+}
+
+/// The checkpoints of the output being indexed.  Cloning them is cheap, and the clones share
+/// the same store.
+#[derive(Clone)]
+pub struct Checkpoints {
+    /// The store the checkpoints are kept in.
+    store: Arc<dyn CheckpointStore>,
+    /// The name the checkpoints are recorded under.
+    output: String,
+}
+
+impl Checkpoints {
+    /// Returns the checkpoints of `output` in a store.
+    pub fn new(store: Box<dyn CheckpointStore>, output: String) -> Checkpoints {
+        Checkpoints {
+            store: Arc::from(store),
+            output,
+        }
+    }
+
+    /// Opens the configured checkpoint store, for the configured output.
+    pub fn open(config: &Config) -> Result<Checkpoints, CheckpointError> {
+        Ok(Checkpoints::new(
+            open(config)?,
+            config.checkpoint.output(&config.output),
+        ))
+    }
+
+    /// Returns the name the checkpoints are recorded under.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Returns the heights completed for the output.
+    pub fn completed(&self) -> Result<HeightSet, CheckpointError> {
+        self.store.completed(&self.output)
+    }
+
+    /// Records the heights `[start, end)` as completed for the output.
+    ///
+    /// NOTE: stores write to disk (and sync) before returning, so the record runs on the
+    /// blocking thread pool rather than holding up the async workers.
+    pub async fn record(&self, start: u64, end: u64) -> Result<(), CheckpointError> {
+        let (store, output) = (self.store.clone(), self.output.clone());
+        tokio::task::spawn_blocking(move || store.record(&output, start, end))
+            .await
+            .map_err(|e| CheckpointError::Io(io::Error::other(e)))?
+    }
+
+    /// Records a single height as completed for the output.
+    pub async fn record_height(&self, height: u64) -> Result<(), CheckpointError> {
+        self.record(height, height + 1).await
+    }
+
+    /// Returns the store the checkpoints are kept in.
+    pub fn store(&self) -> &dyn CheckpointStore {
+        self.store.as_ref()
+    }
+}
+
+/// Opens the configured checkpoint store.
+pub fn open(config: &Config) -> Result<Box<dyn CheckpointStore>, CheckpointError> {
+    let path = config.checkpoint.path();
+    match config.checkpoint.backend.as_str() {
+        "file" => Ok(Box::new(file::FileCheckpointStore::open(path)?)),
+        #[cfg(feature = "CHECKPOINT_REDB")]
+        "redb" => Ok(Box::new(redb::RedbCheckpointStore::open(path)?)),
+        #[cfg(not(feature = "CHECKPOINT_REDB"))]
+        "redb" => Err(CheckpointError::Configuration(String::from(
+            "the redb checkpoint store requires the CHECKPOINT_REDB feature",
+        ))),
+        #[cfg(feature = "APACHE_KAFKA")]
+        "kafka" => Ok(Box::new(kafka::KafkaCheckpointStore::open(&config.kafka)?)),
+        #[cfg(not(feature = "APACHE_KAFKA"))]
+        "kafka" => Err(CheckpointError::Configuration(String::from(
+            "the kafka checkpoint store requires the APACHE_KAFKA feature",
+        ))),
+        other => Err(CheckpointError::Configuration(format!(
+            "unknown checkpoint backend {}, expected one of: {}",
+            other,
+            CHECKPOINT_BACKENDS.join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_height_set() {
+        let mut set = HeightSet::new();
+        set.insert(10, 20);
+        set.insert(30, 40);
+        set.insert(20, 25);
+        assert_eq!(set.ranges().collect::<Vec<_>>(), [(10, 25), (30, 40)]);
+        set.insert(5, 35);
+        assert_eq!(set.ranges().collect::<Vec<_>>(), [(5, 40)]);
+        set.insert(41, 42);
+        assert!(set.contains(5) && set.contains(39) && set.contains(41));
+        assert!(!set.contains(4) && !set.contains(40) && !set.contains(42));
+        assert!(set.contains_all(10, 40) && !set.contains_all(10, 41));
+        assert_eq!(set.gaps(0, 50), [(0, 5), (40, 41), (42, 50)]);
+        assert_eq!(set.gaps(10, 41), [(40, 41)]);
+        assert_eq!(set.end(), Some(42));
+        assert_eq!(set.next_from(39), Some(39));
+        assert_eq!(set.next_from(40), Some(41));
+        assert_eq!(set.next_from(42), None);
+
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, "[[5,40],[41,42]]");
+        assert_eq!(serde_json::from_str::<HeightSet>(&json).unwrap(), set);
+    }
+}
@@ -0,0 +1,129 @@
+//! This module implements the checkpoint store kept in an embedded `redb` database, which maps
+//! each output to the ranges of heights it completed, as JSON.
+//!
+//! Each record is a write transaction, which is durable once committed, and which serializes
+//! concurrent records of the same output.
+
+use redb::{Database, ReadableTable, TableDefinition};
+use std::fs;
+use std::path::PathBuf;
+
+use super::{CheckpointError, CheckpointStore, HeightSet};
+
+/// The ranges completed (as JSON) by output.
+const CHECKPOINTS: TableDefinition<&str, &str> = TableDefinition::new("checkpoints");
+
+/// A checkpoint store kept in an embedded `redb` database.
+pub struct RedbCheckpointStore {
+    /// The database file.
+    path: PathBuf,
+    /// The open database.
+    database: Database,
+}
+
+/// Converts a database error to a `CheckpointError`.
+fn database_error(err: impl Into<redb::Error>) -> CheckpointError {
+    CheckpointError::Database(err.into().to_string())
+}
+
+impl RedbCheckpointStore {
+    /// Opens the database at `path`, creating it (and its directory) if needed.
+    pub fn open(path: PathBuf) -> Result<RedbCheckpointStore, CheckpointError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let database = Database::create(&path).map_err(database_error)?;
+        // NOTE: the table is created up front, so that reads never find it missing.
+        let transaction = database.begin_write().map_err(database_error)?;
+        transaction
+            .open_table(CHECKPOINTS)
+            .map_err(database_error)?;
+        transaction.commit().map_err(database_error)?;
+        Ok(RedbCheckpointStore { path, database })
+    }
+
+    /// Parses the ranges stored for an output.
+    fn parse(&self, ranges: &str) -> Result<HeightSet, CheckpointError> {
+        serde_json::from_str(ranges).map_err(|e| CheckpointError::Corrupt {
+            path: self.path.clone(),
+            reason: e.to_string(),
+        })
+    }
+}
+
+impl CheckpointStore for RedbCheckpointStore {
+    /// Returns the heights completed for the output.
+    fn completed(&self, output: &str) -> Result<HeightSet, CheckpointError> {
+        let transaction = self.database.begin_read().map_err(database_error)?;
+        let table = transaction
+            .open_table(CHECKPOINTS)
+            .map_err(database_error)?;
+        match table.get(output).map_err(database_error)? {
+            Some(ranges) => self.parse(ranges.value()),
+            None => Ok(HeightSet::new()),
+        }
+    }
+
+    /// Records the heights `[start, end)`, merging them with the stored ranges in a single
+    /// write transaction.
+    fn record(&self, output: &str, start: u64, end: u64) -> Result<(), CheckpointError> {
+        let transaction = self.database.begin_write().map_err(database_error)?;
+        {
+            let mut table = transaction
+                .open_table(CHECKPOINTS)
+                .map_err(database_error)?;
+            let mut completed = match table.get(output).map_err(database_error)? {
+                Some(ranges) => self.parse(ranges.value())?,
+                None => HeightSet::new(),
+            };
+            completed.insert(start, end);
+            let ranges =
+                serde_json::to_string(&completed).map_err(|e| CheckpointError::Corrupt {
+                    path: self.path.clone(),
+                    reason: e.to_string(),
+                })?;
+            table
+                .insert(output, ranges.as_str())
+                .map_err(database_error)?;
+        }
+        transaction.commit().map_err(database_error)
+    }
+
+    /// Returns the outputs that have checkpoints.
+    fn outputs(&self) -> Result<Vec<String>, CheckpointError> {
+        let transaction = self.database.begin_read().map_err(database_error)?;
+        let table = transaction
+            .open_table(CHECKPOINTS)
+            .map_err(database_error)?;
+        table
+            .iter()
+            .map_err(database_error)?
+            .map(|entry| {
+                entry
+                    .map(|(output, _)| output.value().to_string())
+                    .map_err(database_error)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoints.redb");
+
+        let store = RedbCheckpointStore::open(path.clone()).unwrap();
+        store.record("jsonl", 10, 12).unwrap();
+        store.record("jsonl", 12, 13).unwrap();
+        drop(store);
+
+        let reopened = RedbCheckpointStore::open(path).unwrap();
+        let completed = reopened.completed("jsonl").unwrap();
+        assert_eq!(completed.ranges().collect::<Vec<_>>(), [(10, 13)]);
+        assert_eq!(reopened.outputs().unwrap(), ["jsonl"]);
+    }
+}
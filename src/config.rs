@@ -0,0 +1,1597 @@
+//! This module defines the typed configuration of the indexer.
+//!
+//! The configuration is resolved in layers, each overriding the previous one:
+//! 1. the TOML configuration file (`config.toml`, or the file passed with `--config`),
+//! 2. environment variables, including those in the `.env` file (e.g. `KAFKA_ADDRESS`),
+//! 3. CLI flags (e.g. `--output-backend`).
+//!
+//! The resolved configuration is validated once at startup, and every problem is reported
+//! together rather than surfacing as a panic mid-run.  Afterwards it is available anywhere
+//! through `config::get()`.
+
+use clap::Args;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::checkpoint::CHECKPOINT_BACKENDS;
+use crate::output::dead_letter::DEAD_LETTER_QUEUE_ENVKEY;
+use crate::output::publish::OutputBackend;
+use crate::output::routing::OutputRoute;
+use crate::retry::RetryPolicy;
+
+/// The configuration file that is read when `--config` isn't passed, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// The directory the checkpoints are kept in when `checkpoint.path` isn't set.
+pub const DEFAULT_CHECKPOINT_DIR: &str = "./indexed_blocks/";
+
+/// Replaces secrets when the configuration is printed.
+pub const MASKED_SECRET: &str = "********";
+/// The keys that Kafka records can be partitioned by.
+pub const KAFKA_PARTITION_KEYS: &[&str] = &["none", "block_number", "transaction_hash", "account"];
+/// The partitioners that can route Kafka records to the partitions of their topic.
+pub const KAFKA_PARTITIONERS: &[&str] = &["murmur2", "round_robin"];
+/// The SASL mechanisms that can authenticate to Kafka.
+pub const KAFKA_SASL_MECHANISMS: &[&str] = &["plain", "scram-sha-256", "scram-sha-512"];
+/// The compression codecs of Kafka record batches.
+pub const KAFKA_COMPRESSIONS: &[&str] = &["none", "gzip", "lz4", "snappy", "zstd"];
+
+/// The prefix of the environment keys holding the queue name of each table.
+pub const QUEUE_NAME_ENVKEY_PREFIX: &str = "QUEUE_NAME_";
+/// The environment key holding the queue name used by the single publisher.
+pub const SINGLE_QUEUE_NAME_ENVKEY: &str = "QUEUE_NAME";
+/// The table name used by the single publisher.
+pub const SINGLE_PUBLISHER_TABLE: &str = "all";
+
+/// The prefix of the environment keys that override the output backend of a single table.
+pub const TABLE_OUTPUT_BACKEND_ENVKEY_PREFIX: &str = "OUTPUT_BACKEND_";
+/// The prefix of the environment keys that override the Kafka compression of a single table.
+pub const TABLE_KAFKA_COMPRESSION_ENVKEY_PREFIX: &str = "KAFKA_COMPRESSION_";
+
+/// The resolved configuration of the indexer.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Which output backend(s) each table is published to.
+    pub output: OutputConfig,
+    /// The queue name (topic, queue, stream, subdirectory or bucket) of each table, by table
+    /// name (e.g. `blocks` for `QUEUE_NAME_BLOCKS`, or `all` for the single publisher).
+    pub queues: BTreeMap<String, String>,
+    /// Settings for the `JSON` and `JSONL` outputs.
+    pub file: FileConfig,
+    /// Settings for the `APACHE_KAFKA` output.
+    pub kafka: KafkaConfig,
+    /// Settings for the `RABBITMQ_CLASSIC` and `RABBITMQ_STREAM` outputs.
+    pub rabbitmq: RabbitMQConfig,
+    /// Settings for the Google Cloud outputs and subscription.
+    pub gcp: GcpConfig,
+    /// Settings for the node the blocks are requested from.
+    pub source: SourceConfig,
+    /// How failed requests and publishes are retried.
+    pub retry: RetryPolicy,
+    /// Where the records that can't be published are kept.
+    pub dead_letter: DeadLetterConfig,
+    /// Where the heights that were indexed are recorded.
+    pub checkpoint: CheckpointConfig,
+    /// How new blocks are waited for when following the chain head.
+    pub follow: FollowConfig,
+    /// How many workers index ranges, and how ranges are split between them.
+    pub workers: WorkersConfig,
+    /// Settings for indexing ranges with the staged pipeline.
+    pub pipeline: PipelineConfig,
+    /// Settings for the Prometheus metrics server.
+    pub metrics: MetricsConfig,
+    /// Settings for the Kubernetes health check server.
+    pub health_checks: HealthChecksConfig,
+}
+
+/// Settings for choosing the output backends.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// The backends that every table is published to by default.  Optional if only one
+    /// backend is compiled in.  (env: `OUTPUT_BACKEND`, comma-separated)
+    pub backends: Vec<String>,
+    /// The backends of individual tables, by table name, overriding `backends`.  `["none"]`
+    /// drops the table.  (env: `OUTPUT_BACKEND_<TABLE>`)
+    pub routes: BTreeMap<String, Vec<String>>,
+    /// The backends that are skipped when slow or failing, when publishing to several backends.
+    /// (env: `OUTPUT_SKIPPABLE_BACKENDS`, comma-separated)
+    pub skippable_backends: Vec<String>,
+    /// How long a skippable backend may take before it is skipped, in milliseconds.
+    /// (env: `OUTPUT_SKIP_TIMEOUT_MS`)
+    pub skip_timeout_ms: u64,
+    /// Whether the blocks of a range are published strictly in height order, when several
+    /// workers index it.  Ranges are then indexed with the pipeline.  (env: `OUTPUT_ORDERED`)
+    pub ordered: bool,
+    /// How many heights ahead of the last block published the workers can be, when publishing
+    /// in order.  (env: `OUTPUT_ORDERED_BUFFER`)
+    pub ordered_buffer: u64,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            backends: Vec::new(),
+            routes: BTreeMap::new(),
+            skippable_backends: Vec::new(),
+            skip_timeout_ms: 5000,
+            ordered: false,
+            ordered_buffer: 256,
+        }
+    }
+}
+
+/// Settings for the file outputs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// The directory the records are written to.  (env: `OUTPUT_DIR`)
+    pub output_dir: Option<String>,
+}
+
+/// Settings for Apache Kafka.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// The bootstrap brokers, as `host:port`.  Defaults to `address:port`.
+    /// (env: `KAFKA_BROKERS`, comma-separated)
+    pub brokers: Vec<String>,
+    /// (env: `KAFKA_ADDRESS`)
+    pub address: Option<String>,
+    /// (env: `KAFKA_PORT`)
+    pub port: Option<u16>,
+    /// The SASL mechanism to authenticate with, one of `KAFKA_SASL_MECHANISMS`, if any.
+    /// (env: `KAFKA_SASL_MECHANISM`)
+    pub sasl_mechanism: Option<String>,
+    /// (env: `KAFKA_SASL_USERNAME`)
+    pub sasl_username: Option<String>,
+    /// Secret, masked when printed.  (env: `KAFKA_SASL_PASSWORD`)
+    pub sasl_password: Option<String>,
+    /// Whether the connections to the brokers use TLS.  (env: `KAFKA_TLS`)
+    pub tls: bool,
+    /// The PEM file of the certificate authorities trusted for the brokers, instead of the
+    /// certificate authorities of the system.  (env: `KAFKA_TLS_CA_CERT`)
+    pub tls_ca_cert: Option<String>,
+    /// The PEM file of the client certificate chain, for brokers that authenticate clients.
+    /// (env: `KAFKA_TLS_CLIENT_CERT`)
+    pub tls_client_cert: Option<String>,
+    /// The PEM file of the client's private key.  (env: `KAFKA_TLS_CLIENT_KEY`)
+    pub tls_client_key: Option<String>,
+    /// What the records are keyed by, one of `KAFKA_PARTITION_KEYS`.  Records with the same
+    /// key go to the same partition, in order.  (env: `KAFKA_PARTITION_KEY`)
+    pub partition_key: String,
+    /// The fields of the records holding the key, tried in order.  Defaults to the usual
+    /// fields for `partition_key`.  (env: `KAFKA_KEY_FIELDS`, comma-separated)
+    pub key_fields: Vec<String>,
+    /// How records are routed to partitions, one of `KAFKA_PARTITIONERS`.
+    /// (env: `KAFKA_PARTITIONER`)
+    pub partitioner: String,
+    /// The chain id set in the `chain_id` header of the records.  Defaults to the name of the
+    /// blockchain.  (env: `KAFKA_CHAIN_ID`)
+    pub chain_id: Option<String>,
+    /// The version of the records' schema, set in the `schema_version` header.
+    /// (env: `KAFKA_SCHEMA_VERSION`)
+    pub schema_version: String,
+    /// How long a producer waits for more records before sending a batch, in milliseconds.
+    /// (env: `KAFKA_LINGER_MS`)
+    pub linger_ms: u64,
+    /// The most bytes of records a producer sends to a partition in one batch.
+    /// (env: `KAFKA_BATCH_MAX_BYTES`)
+    pub batch_max_bytes: usize,
+    /// How record batches are compressed, one of `KAFKA_COMPRESSIONS`.
+    /// (env: `KAFKA_COMPRESSION`)
+    pub compression: String,
+    /// The compression of individual tables (i.e. topics), by table name, overriding
+    /// `compression`.  (env: `KAFKA_COMPRESSION_<TABLE>`)
+    pub table_compression: BTreeMap<String, String>,
+    /// Whether the records of each block, across every table, are published in a single
+    /// transaction along with the block's progress marker (see `output::kafka_transaction`).
+    /// Requires `progress_topic` and the `kafka` checkpoint store.  (env: `KAFKA_TRANSACTIONAL`)
+    pub transactional: bool,
+    /// The prefix of the transactional ids of the producers, followed by the slot of the
+    /// worker.  Must be unique to each indexer instance sharing the brokers.
+    /// (env: `KAFKA_TRANSACTIONAL_ID`)
+    pub transactional_id: String,
+    /// The topic the progress markers of the blocks are published to, read by the `kafka`
+    /// checkpoint store.  (env: `KAFKA_PROGRESS_TOPIC`)
+    pub progress_topic: Option<String>,
+}
+
+impl KafkaConfig {
+    /// Returns the compression of the table published with `queue_env` (e.g.
+    /// `QUEUE_NAME_BLOCKS`).
+    pub fn compression(&self, queue_env: &str) -> &str {
+        self.table_compression
+            .get(&table_name(queue_env))
+            .unwrap_or(&self.compression)
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> KafkaConfig {
+        KafkaConfig {
+            brokers: Vec::new(),
+            address: None,
+            port: None,
+            sasl_mechanism: None,
+            sasl_username: None,
+            sasl_password: None,
+            tls: false,
+            tls_ca_cert: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            partition_key: String::from("block_number"),
+            key_fields: Vec::new(),
+            partitioner: String::from("murmur2"),
+            chain_id: None,
+            schema_version: String::from("1"),
+            linger_ms: 0,
+            batch_max_bytes: 16384,
+            compression: String::from("none"),
+            table_compression: BTreeMap::new(),
+            transactional: false,
+            transactional_id: String::from("blockchain-etl"),
+            progress_topic: None,
+        }
+    }
+}
+
+/// Settings for RabbitMQ.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RabbitMQConfig {
+    /// (env: `RABBITMQ_ADDRESS`)
+    pub address: Option<String>,
+    /// (env: `RABBITMQ_PORT`)
+    pub port: Option<u16>,
+    /// (env: `RABBITMQ_USER`)
+    pub user: Option<String>,
+    /// Secret, masked when printed.  (env: `RABBITMQ_PASSWORD`)
+    pub password: Option<String>,
+}
+
+/// Settings for Google Cloud.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcpConfig {
+    /// The path to the credentials json file.  If not set, the default credentials of the
+    /// environment are used.  (env: `GOOGLE_APPLICATION_CREDENTIALS`)
+    pub credentials_json_path: Option<String>,
+}
+
+/// Settings for the records that can't be published (rejected by the output, or given up on
+/// by the retry policy).  If neither is set, those records are skipped.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    /// The directory the records are written to, one JSONL file per table.
+    /// (env: `DEAD_LETTER_DIR`)
+    pub dir: Option<String>,
+    /// An output backend the records are published to, in the `dead_letters` queue.
+    /// (env: `DEAD_LETTER_BACKEND`)
+    pub backend: Option<String>,
+}
+
+/// Settings for the checkpoints, which record the heights that were indexed for each output.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointConfig {
+    /// The checkpoint store: `file`, `redb` when compiled with `CHECKPOINT_REDB`, or `kafka`
+    /// (the markers in `kafka.progress_topic`) when compiled with `APACHE_KAFKA`.
+    /// (env: `CHECKPOINT_BACKEND`)
+    pub backend: String,
+    /// The file the checkpoints are kept in.  Defaults to `checkpoints.json` (or
+    /// `checkpoints.redb`) in `./indexed_blocks/`.  (env: `CHECKPOINT_PATH`)
+    pub path: Option<String>,
+    /// The name the checkpoints are recorded under.  Defaults to the output backends (e.g.
+    /// `apache_kafka,jsonl`), so that indexing to another output starts over.
+    /// (env: `CHECKPOINT_OUTPUT`)
+    pub output: Option<String>,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            backend: String::from("file"),
+            path: None,
+            output: None,
+        }
+    }
+}
+
+impl CheckpointConfig {
+    /// Returns the file the checkpoints are kept in.
+    pub fn path(&self) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(DEFAULT_CHECKPOINT_DIR).join(match self.backend.as_str() {
+                "redb" => "checkpoints.redb",
+                _ => "checkpoints.json",
+            }),
+        }
+    }
+
+    /// Returns the name the checkpoints are recorded under.
+    pub fn output(&self, output: &OutputConfig) -> String {
+        match &self.output {
+            Some(name) => name.clone(),
+            None if output.backends.is_empty() => String::from("default"),
+            None => output.backends.join(","),
+        }
+    }
+}
+
+/// Settings for the workers that index ranges concurrently.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkersConfig {
+    /// The number of workers.  Defaults to the number of CPU cores.
+    /// (env: `NUM_EXTRACTOR_THREADS`)
+    pub threads: Option<usize>,
+    /// The number of consecutive heights a worker takes at a time.  (env: `WORKER_UNIT_SIZE`)
+    pub unit_size: u64,
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig {
+            threads: None,
+            unit_size: 100,
+        }
+    }
+}
+
+impl WorkersConfig {
+    /// Returns the number of workers: `threads`, or the number of CPU cores if it isn't set.
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|cores| cores.get())
+                .unwrap_or(1)
+        })
+    }
+}
+
+/// Settings for the extract → transform → publish pipeline, which indexes ranges with separate
+/// stages instead of the workers when enabled.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Whether ranges are indexed with the pipeline.  (env: `PIPELINE_ENABLED`)
+    pub enabled: bool,
+    /// The number of workers extracting blocks.  (env: `PIPELINE_EXTRACT_WORKERS`)
+    pub extract_workers: usize,
+    /// The number of workers transforming blocks, each on a thread of the blocking pool.
+    /// (env: `PIPELINE_TRANSFORM_WORKERS`)
+    pub transform_workers: usize,
+    /// The number of workers publishing records.  (env: `PIPELINE_PUBLISH_WORKERS`)
+    pub publish_workers: usize,
+    /// The most items waiting between two stages.  (env: `PIPELINE_QUEUE_CAPACITY`)
+    pub queue_capacity: usize,
+    /// The most bytes waiting between two stages, 0 for no limit.  Needs the blockchain to
+    /// implement `raw_block_size` and `records_size`.  (env: `PIPELINE_QUEUE_BYTES`)
+    pub queue_bytes: u64,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            enabled: false,
+            extract_workers: 8,
+            transform_workers: 2,
+            publish_workers: 4,
+            queue_capacity: 64,
+            queue_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Settings for following the chain head (`index-follow`, and `index-range` without end).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FollowConfig {
+    /// The number of blocks on top of a block before it is indexed, so that blocks likely to
+    /// be dropped by a reorganization aren't published.  (env: `FOLLOW_CONFIRMATIONS`)
+    pub confirmations: u64,
+    /// How often the chain head is requested while waiting for new blocks, in milliseconds.
+    /// (env: `FOLLOW_POLL_INTERVAL_MS`)
+    pub poll_interval_ms: u64,
+    /// How many blocks back reorganizations are followed and retracted.  A deeper
+    /// reorganization stops indexing, 0 disables reorganization tracking.
+    /// (env: `FOLLOW_MAX_REORG_DEPTH`)
+    pub max_reorg_depth: u64,
+}
+
+impl Default for FollowConfig {
+    fn default() -> Self {
+        FollowConfig {
+            confirmations: 0,
+            poll_interval_ms: 5000,
+            max_reorg_depth: 64,
+        }
+    }
+}
+
+/// Settings for the node the blocks are requested from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    /// The address of the node's API.  Required when compiled with `RPC`.  (env: `ENDPOINT`)
+    pub endpoint: Option<String>,
+    /// The address requests fail over to when the primary endpoint is failing.  May be the
+    /// same as `endpoint`.  (env: `FALLBACK_ENDPOINT`)
+    pub fallback_endpoint: Option<String>,
+    /// How long to wait for a connection to the node, in seconds.  (env: `CONNECTION_TIMEOUT`)
+    pub connect_timeout_secs: u64,
+    /// How long to wait for the response to a request, in seconds.
+    /// (env: `RPC_METHOD_TIMEOUT`)
+    pub method_timeout_secs: u64,
+    /// The timeouts of individual methods, in seconds, by method name (e.g. `getBlock`),
+    /// overriding `method_timeout_secs`.
+    pub method_timeouts: BTreeMap<String, u64>,
+    /// The largest number of requests sent in a single JSON-RPC batch.
+    /// (env: `RPC_MAX_BATCH_SIZE`)
+    pub max_batch_size: usize,
+    /// Settings for the gRPC client of the `GRPC` feature.
+    pub grpc: GrpcConfig,
+    /// Settings for the REST client of the `REST` feature.
+    pub rest: RestConfig,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig {
+            endpoint: None,
+            fallback_endpoint: None,
+            connect_timeout_secs: 10,
+            method_timeout_secs: 30,
+            method_timeouts: BTreeMap::new(),
+            max_batch_size: 100,
+            grpc: GrpcConfig::default(),
+            rest: RestConfig::default(),
+        }
+    }
+}
+
+impl SourceConfig {
+    /// Returns the timeout of requests to the given method.
+    pub fn method_timeout(&self, method: &str) -> Duration {
+        Duration::from_secs(
+            *self
+                .method_timeouts
+                .get(method)
+                .unwrap_or(&self.method_timeout_secs),
+        )
+    }
+}
+
+/// Settings for the gRPC client.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// The addresses of the gRPC servers, tried in order until one connects.  If not set,
+    /// `source.endpoint` is used.  (env: `GRPC_ENDPOINTS`, comma-separated)
+    pub endpoints: Vec<String>,
+    /// The path to a PEM file of the CA certificates that `https` endpoints are verified
+    /// against, instead of the system's roots.  (env: `GRPC_CA_CERT`)
+    pub ca_cert_path: Option<String>,
+    /// The domain name the server certificate is verified against, if it differs from the
+    /// endpoint's host.  (env: `GRPC_TLS_DOMAIN`)
+    pub tls_domain: Option<String>,
+    /// A token sent as `authorization: Bearer <token>` with every request.  Secret, masked
+    /// when printed.  (env: `GRPC_AUTH_TOKEN`)
+    pub auth_token: Option<String>,
+    /// Metadata headers sent with every request (e.g. `x-api-key`), by header name.  Secret,
+    /// masked when printed.
+    pub metadata: BTreeMap<String, String>,
+    /// The compression of requests and responses: `gzip` or `zstd`.  (env: `GRPC_COMPRESSION`)
+    pub compression: Option<String>,
+    /// How often HTTP/2 keepalive pings are sent, in seconds.  If not set, no pings are sent.
+    /// (env: `GRPC_KEEPALIVE_INTERVAL`)
+    pub keepalive_interval_secs: Option<u64>,
+    /// How long to wait for the response to a keepalive ping, in seconds.
+    /// (env: `GRPC_KEEPALIVE_TIMEOUT`)
+    pub keepalive_timeout_secs: u64,
+    /// The size of the largest message that is sent or received, in bytes.
+    /// (env: `GRPC_MAX_MESSAGE_SIZE`)
+    pub max_message_size: usize,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            endpoints: Vec::new(),
+            ca_cert_path: None,
+            tls_domain: None,
+            auth_token: None,
+            metadata: BTreeMap::new(),
+            compression: None,
+            keepalive_interval_secs: None,
+            keepalive_timeout_secs: 20,
+            max_message_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl GrpcConfig {
+    /// Returns the addresses of the gRPC servers: `endpoints`, or `source.endpoint` if it
+    /// isn't set.
+    pub fn endpoints(&self, source: &SourceConfig) -> Vec<String> {
+        if self.endpoints.is_empty() {
+            source.endpoint.iter().cloned().collect()
+        } else {
+            self.endpoints.clone()
+        }
+    }
+}
+
+/// Settings for the REST client.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestConfig {
+    /// The base URL of the node's REST API, which request paths are relative to.  If not set,
+    /// `source.endpoint` is used.  (env: `REST_BASE_URL`)
+    pub base_url: Option<String>,
+    /// A token sent as `authorization: Bearer <token>` with every request.  Secret, masked when
+    /// printed.  (env: `REST_AUTH_TOKEN`)
+    pub auth_token: Option<String>,
+    /// Headers sent with every request (e.g. `x-api-key`), by header name.  Secret, masked
+    /// when printed.
+    pub headers: BTreeMap<String, String>,
+    /// The number of items requested per page of a paginated API.  (env: `REST_PAGE_SIZE`)
+    pub page_size: u64,
+    /// The longest `Retry-After` of a throttled request that is honoured, in seconds.  Longer
+    /// delays are shortened to it.  (env: `REST_MAX_RETRY_AFTER`)
+    pub max_retry_after_secs: u64,
+    /// The number of URLs whose last `ETag` and response are kept for conditional requests.
+    /// The least recently used are evicted first.  (env: `REST_ETAG_CACHE_SIZE`)
+    pub etag_cache_size: usize,
+}
+
+impl Default for RestConfig {
+    fn default() -> Self {
+        RestConfig {
+            base_url: None,
+            auth_token: None,
+            headers: BTreeMap::new(),
+            page_size: 100,
+            max_retry_after_secs: 300,
+            etag_cache_size: 128,
+        }
+    }
+}
+
+impl RestConfig {
+    /// Returns the base URL of the REST API: `base_url`, or `source.endpoint` if it isn't set.
+    pub fn base_url<'a>(&'a self, source: &'a SourceConfig) -> Option<&'a String> {
+        self.base_url.as_ref().or(source.endpoint.as_ref())
+    }
+}
+
+/// Settings for the metrics server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// (env: `METRICS_ADDRESS`)
+    pub address: String,
+    /// Required when compiled with `METRICS`.  (env: `METRICS_PORT`)
+    pub port: Option<u16>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            address: String::from("127.0.0.1"),
+            port: None,
+        }
+    }
+}
+
+/// Settings for the health check server.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthChecksConfig {
+    /// Required when compiled with `ORCHESTRATED`.  (env: `HEALTH_CHECKS_PORT`)
+    pub port: Option<u16>,
+}
+
+/// CLI flags that override the configuration file and environment variables.
+#[derive(Args, Clone, Debug, Default)]
+pub struct ConfigOverrides {
+    /// Path to the TOML configuration file [default: config.toml, if it exists]
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// The output backend(s) every table is published to, comma-separated
+    #[clap(long, global = true)]
+    pub output_backend: Option<String>,
+    /// The directory the JSON and JSONL outputs write to
+    #[clap(long, global = true)]
+    pub output_dir: Option<String>,
+    /// The port of the Prometheus metrics server
+    #[clap(long, global = true)]
+    pub metrics_port: Option<u16>,
+}
+
+/// A problem with the configuration.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    #[error("could not read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The configuration file is not valid TOML, or doesn't match the expected structure.
+    #[error("could not parse {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// A value has the wrong format.
+    #[error("{key}: {reason}")]
+    Invalid { key: String, reason: String },
+    /// A value required by the selected outputs or features is missing.
+    #[error("{key} is required {reason}")]
+    Missing { key: String, reason: String },
+}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug, Error)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration ({} errors):", self.0.len())?;
+        for error in self.0.iter() {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The configuration loaded at startup.
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Stores the configuration loaded at startup, so it is returned by `get()`.
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// Returns the configuration loaded at startup.
+///
+/// NOTE: if `init` wasn't called (e.g. when used as a library), the configuration is loaded
+/// from the default file and the environment, panicking if it is invalid.  The tests call
+/// `testing::init_config` first instead.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        Config::load(&ConfigOverrides::default()).unwrap_or_else(|errors| panic!("{}", errors))
+    })
+}
+
+/// Returns the table name of a queue env key, e.g. `blocks` for `QUEUE_NAME_BLOCKS`.
+pub fn table_name(queue_env: &str) -> String {
+    if queue_env == SINGLE_QUEUE_NAME_ENVKEY {
+        return String::from(SINGLE_PUBLISHER_TABLE);
+    }
+    queue_env
+        .strip_prefix(QUEUE_NAME_ENVKEY_PREFIX)
+        .unwrap_or(queue_env)
+        .to_lowercase()
+}
+
+/// Splits a comma-separated list, dropping empty items.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Overrides `target` with the environment variable `key`, if it is set.
+fn env_override<T: FromStr>(key: &str, target: &mut Option<T>, errors: &mut Vec<ConfigError>)
+where
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = std::env::var(key) {
+        match value.parse::<T>() {
+            Ok(parsed) => *target = Some(parsed),
+            Err(e) => errors.push(ConfigError::Invalid {
+                key: key.to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+}
+
+/// Like `env_override`, for settings that have a default value.
+fn env_override_value<T: FromStr>(key: &str, target: &mut T, errors: &mut Vec<ConfigError>)
+where
+    T::Err: fmt::Display,
+{
+    let mut value = None;
+    env_override(key, &mut value, errors);
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the file, the environment (including the `.env` file, if
+    /// there is one) and the CLI flags, then validates it.
+    pub fn load(overrides: &ConfigOverrides) -> Result<Config, ConfigErrors> {
+        let (config, errors) = Config::resolve(overrides);
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    /// Resolves the configuration layers and validates the result.  Returns the configuration
+    /// as far as it could be resolved, along with every problem found.
+    pub fn resolve(overrides: &ConfigOverrides) -> (Config, Vec<ConfigError>) {
+        let mut errors = Vec::new();
+
+        // 1. the configuration file
+        let mut config = match &overrides.config {
+            Some(path) => Config::from_file(path, &mut errors),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH), &mut errors)
+            }
+            None => Config::default(),
+        };
+
+        // 2. environment variables.  NOTE: variables that are already set take precedence over
+        // the ones in the .env file.
+        let _ = dotenvy::dotenv();
+        config.apply_env(&mut errors);
+
+        // 3. CLI flags
+        config.apply_overrides(overrides);
+
+        errors.extend(config.validate());
+        (config, errors)
+    }
+
+    /// Reads and parses a TOML configuration file.
+    fn from_file(path: &Path, errors: &mut Vec<ConfigError>) -> Config {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(source) => {
+                errors.push(ConfigError::Read {
+                    path: path.to_path_buf(),
+                    source,
+                });
+                return Config::default();
+            }
+        };
+        toml::from_str(&contents).unwrap_or_else(|source| {
+            errors.push(ConfigError::Parse {
+                path: path.to_path_buf(),
+                source,
+            });
+            Config::default()
+        })
+    }
+
+    /// Overrides the configuration with the environment variables that are set.
+    fn apply_env(&mut self, errors: &mut Vec<ConfigError>) {
+        for (key, value) in std::env::vars() {
+            if key == SINGLE_QUEUE_NAME_ENVKEY || key.starts_with(QUEUE_NAME_ENVKEY_PREFIX) {
+                self.queues.insert(table_name(&key), value);
+            } else if let Some(table) = key.strip_prefix(TABLE_OUTPUT_BACKEND_ENVKEY_PREFIX) {
+                self.output
+                    .routes
+                    .insert(table.to_lowercase(), split_list(&value));
+            } else if let Some(table) = key.strip_prefix(TABLE_KAFKA_COMPRESSION_ENVKEY_PREFIX) {
+                self.kafka
+                    .table_compression
+                    .insert(table.to_lowercase(), value);
+            }
+        }
+
+        if let Ok(backends) = std::env::var("OUTPUT_BACKEND") {
+            self.output.backends = split_list(&backends);
+        }
+        if let Ok(backends) = std::env::var("OUTPUT_SKIPPABLE_BACKENDS") {
+            self.output.skippable_backends = split_list(&backends);
+        }
+        let mut skip_timeout_ms = None;
+        env_override("OUTPUT_SKIP_TIMEOUT_MS", &mut skip_timeout_ms, errors);
+        if let Some(timeout) = skip_timeout_ms {
+            self.output.skip_timeout_ms = timeout;
+        }
+        env_override_value("OUTPUT_ORDERED", &mut self.output.ordered, errors);
+        env_override_value(
+            "OUTPUT_ORDERED_BUFFER",
+            &mut self.output.ordered_buffer,
+            errors,
+        );
+
+        env_override("OUTPUT_DIR", &mut self.file.output_dir, errors);
+
+        if let Ok(brokers) = std::env::var("KAFKA_BROKERS") {
+            self.kafka.brokers = split_list(&brokers);
+        }
+        env_override("KAFKA_ADDRESS", &mut self.kafka.address, errors);
+        env_override("KAFKA_PORT", &mut self.kafka.port, errors);
+        env_override(
+            "KAFKA_SASL_MECHANISM",
+            &mut self.kafka.sasl_mechanism,
+            errors,
+        );
+        env_override("KAFKA_SASL_USERNAME", &mut self.kafka.sasl_username, errors);
+        env_override("KAFKA_SASL_PASSWORD", &mut self.kafka.sasl_password, errors);
+        env_override_value("KAFKA_TLS", &mut self.kafka.tls, errors);
+        env_override("KAFKA_TLS_CA_CERT", &mut self.kafka.tls_ca_cert, errors);
+        env_override(
+            "KAFKA_TLS_CLIENT_CERT",
+            &mut self.kafka.tls_client_cert,
+            errors,
+        );
+        env_override(
+            "KAFKA_TLS_CLIENT_KEY",
+            &mut self.kafka.tls_client_key,
+            errors,
+        );
+        env_override_value("KAFKA_PARTITION_KEY", &mut self.kafka.partition_key, errors);
+        if let Ok(fields) = std::env::var("KAFKA_KEY_FIELDS") {
+            self.kafka.key_fields = split_list(&fields);
+        }
+        env_override_value("KAFKA_PARTITIONER", &mut self.kafka.partitioner, errors);
+        env_override("KAFKA_CHAIN_ID", &mut self.kafka.chain_id, errors);
+        env_override_value("KAFKA_LINGER_MS", &mut self.kafka.linger_ms, errors);
+        env_override_value(
+            "KAFKA_BATCH_MAX_BYTES",
+            &mut self.kafka.batch_max_bytes,
+            errors,
+        );
+        env_override_value("KAFKA_COMPRESSION", &mut self.kafka.compression, errors);
+        env_override_value("KAFKA_TRANSACTIONAL", &mut self.kafka.transactional, errors);
+        env_override_value(
+            "KAFKA_TRANSACTIONAL_ID",
+            &mut self.kafka.transactional_id,
+            errors,
+        );
+        env_override(
+            "KAFKA_PROGRESS_TOPIC",
+            &mut self.kafka.progress_topic,
+            errors,
+        );
+        env_override_value(
+            "KAFKA_SCHEMA_VERSION",
+            &mut self.kafka.schema_version,
+            errors,
+        );
+
+        env_override("RABBITMQ_ADDRESS", &mut self.rabbitmq.address, errors);
+        env_override("RABBITMQ_PORT", &mut self.rabbitmq.port, errors);
+        env_override("RABBITMQ_USER", &mut self.rabbitmq.user, errors);
+        env_override("RABBITMQ_PASSWORD", &mut self.rabbitmq.password, errors);
+
+        env_override(
+            "GOOGLE_APPLICATION_CREDENTIALS",
+            &mut self.gcp.credentials_json_path,
+            errors,
+        );
+
+        env_override("ENDPOINT", &mut self.source.endpoint, errors);
+        env_override(
+            "FALLBACK_ENDPOINT",
+            &mut self.source.fallback_endpoint,
+            errors,
+        );
+        env_override_value(
+            "CONNECTION_TIMEOUT",
+            &mut self.source.connect_timeout_secs,
+            errors,
+        );
+        env_override_value(
+            "RPC_METHOD_TIMEOUT",
+            &mut self.source.method_timeout_secs,
+            errors,
+        );
+        env_override_value(
+            "RPC_MAX_BATCH_SIZE",
+            &mut self.source.max_batch_size,
+            errors,
+        );
+
+        let grpc = &mut self.source.grpc;
+        if let Ok(endpoints) = std::env::var("GRPC_ENDPOINTS") {
+            // NOTE: not `split_list`, since the paths of the addresses are case-sensitive.
+            grpc.endpoints = endpoints
+                .split(',')
+                .map(|endpoint| endpoint.trim().to_string())
+                .filter(|endpoint| !endpoint.is_empty())
+                .collect();
+        }
+        env_override("GRPC_CA_CERT", &mut grpc.ca_cert_path, errors);
+        env_override("GRPC_TLS_DOMAIN", &mut grpc.tls_domain, errors);
+        env_override("GRPC_AUTH_TOKEN", &mut grpc.auth_token, errors);
+        env_override("GRPC_COMPRESSION", &mut grpc.compression, errors);
+        env_override(
+            "GRPC_KEEPALIVE_INTERVAL",
+            &mut grpc.keepalive_interval_secs,
+            errors,
+        );
+        env_override_value(
+            "GRPC_KEEPALIVE_TIMEOUT",
+            &mut grpc.keepalive_timeout_secs,
+            errors,
+        );
+        env_override_value("GRPC_MAX_MESSAGE_SIZE", &mut grpc.max_message_size, errors);
+
+        let rest = &mut self.source.rest;
+        env_override("REST_BASE_URL", &mut rest.base_url, errors);
+        env_override("REST_AUTH_TOKEN", &mut rest.auth_token, errors);
+        env_override_value("REST_PAGE_SIZE", &mut rest.page_size, errors);
+        env_override_value(
+            "REST_MAX_RETRY_AFTER",
+            &mut rest.max_retry_after_secs,
+            errors,
+        );
+        env_override_value("REST_ETAG_CACHE_SIZE", &mut rest.etag_cache_size, errors);
+
+        env_override("DEAD_LETTER_DIR", &mut self.dead_letter.dir, errors);
+        env_override("DEAD_LETTER_BACKEND", &mut self.dead_letter.backend, errors);
+
+        env_override_value("CHECKPOINT_BACKEND", &mut self.checkpoint.backend, errors);
+        env_override("CHECKPOINT_PATH", &mut self.checkpoint.path, errors);
+        env_override("CHECKPOINT_OUTPUT", &mut self.checkpoint.output, errors);
+
+        env_override_value(
+            "FOLLOW_CONFIRMATIONS",
+            &mut self.follow.confirmations,
+            errors,
+        );
+        env_override_value(
+            "FOLLOW_POLL_INTERVAL_MS",
+            &mut self.follow.poll_interval_ms,
+            errors,
+        );
+        env_override_value(
+            "FOLLOW_MAX_REORG_DEPTH",
+            &mut self.follow.max_reorg_depth,
+            errors,
+        );
+
+        env_override("NUM_EXTRACTOR_THREADS", &mut self.workers.threads, errors);
+        env_override_value("WORKER_UNIT_SIZE", &mut self.workers.unit_size, errors);
+
+        let pipeline = &mut self.pipeline;
+        env_override_value("PIPELINE_ENABLED", &mut pipeline.enabled, errors);
+        env_override_value(
+            "PIPELINE_EXTRACT_WORKERS",
+            &mut pipeline.extract_workers,
+            errors,
+        );
+        env_override_value(
+            "PIPELINE_TRANSFORM_WORKERS",
+            &mut pipeline.transform_workers,
+            errors,
+        );
+        env_override_value(
+            "PIPELINE_PUBLISH_WORKERS",
+            &mut pipeline.publish_workers,
+            errors,
+        );
+        env_override_value(
+            "PIPELINE_QUEUE_CAPACITY",
+            &mut pipeline.queue_capacity,
+            errors,
+        );
+        env_override_value("PIPELINE_QUEUE_BYTES", &mut pipeline.queue_bytes, errors);
+
+        let retry = &mut self.retry;
+        env_override_value(
+            "RETRY_INITIAL_DELAY_MS",
+            &mut retry.initial_delay_ms,
+            errors,
+        );
+        env_override_value("RETRY_MULTIPLIER", &mut retry.multiplier, errors);
+        env_override_value("RETRY_MAX_DELAY_MS", &mut retry.max_delay_ms, errors);
+        env_override_value("RETRY_JITTER", &mut retry.jitter, errors);
+        env_override("RETRY_MAX_ATTEMPTS", &mut retry.max_attempts, errors);
+        env_override("RETRY_MAX_ELAPSED_MS", &mut retry.max_elapsed_ms, errors);
+
+        env_override_value("METRICS_ADDRESS", &mut self.metrics.address, errors);
+        env_override("METRICS_PORT", &mut self.metrics.port, errors);
+
+        env_override("HEALTH_CHECKS_PORT", &mut self.health_checks.port, errors);
+    }
+
+    /// Overrides the configuration with the CLI flags that were passed.
+    fn apply_overrides(&mut self, overrides: &ConfigOverrides) {
+        if let Some(backends) = &overrides.output_backend {
+            self.output.backends = split_list(backends);
+        }
+        if let Some(output_dir) = &overrides.output_dir {
+            self.file.output_dir = Some(output_dir.clone());
+        }
+        if let Some(port) = overrides.metrics_port {
+            self.metrics.port = Some(port);
+        }
+    }
+
+    /// Returns the route of the tables that aren't routed individually: `output.backends`, or
+    /// the only backend compiled in if it isn't set.
+    fn default_route(&self) -> Result<OutputRoute, String> {
+        match self.output.backends.as_slice() {
+            [] => match OutputBackend::available().as_slice() {
+                [backend] => Ok(OutputRoute::Backends(vec![*backend])),
+                available => Err(format!(
+                    "should be set when multiple backends are compiled in ({})",
+                    available
+                        .iter()
+                        .map(|backend| backend.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )),
+            },
+            backends => OutputRoute::parse(&backends.join(",")),
+        }
+    }
+
+    /// Returns the route of the table published with `queue_env` (e.g. `QUEUE_NAME_BLOCKS`).
+    pub fn route(&self, queue_env: &str) -> Result<OutputRoute, String> {
+        match self.output.routes.get(&table_name(queue_env)) {
+            Some(backends) => OutputRoute::parse(&backends.join(",")),
+            None => self.default_route(),
+        }
+    }
+
+    /// Returns the queue name of the table published with `queue_env` (e.g. `QUEUE_NAME_BLOCKS`).
+    pub fn queue_name(&self, queue_env: &str) -> Option<&String> {
+        self.queues.get(&table_name(queue_env))
+    }
+
+    /// Checks that every output that can be selected has the settings it needs, and that the
+    /// features compiled in have theirs.  Returns every problem found.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+
+        let mut used_backends: Vec<OutputBackend> = Vec::new();
+        let routes = std::iter::once((String::from("output.backends"), self.default_route()))
+            .chain(self.output.routes.iter().map(|(table, backends)| {
+                (
+                    format!("output.routes.{}", table),
+                    OutputRoute::parse(&backends.join(",")),
+                )
+            }));
+        for (key, route) in routes {
+            match route {
+                Ok(OutputRoute::Backends(backends)) => used_backends.extend(backends),
+                Ok(OutputRoute::Drop) => (),
+                Err(reason) => errors.push(ConfigError::Invalid { key, reason }),
+            }
+        }
+        for name in self.output.skippable_backends.iter() {
+            if let Err(reason) = name.parse::<OutputBackend>() {
+                errors.push(ConfigError::Invalid {
+                    key: String::from("output.skippable_backends"),
+                    reason,
+                });
+            }
+        }
+        if let Some(name) = &self.dead_letter.backend {
+            match name.parse::<OutputBackend>() {
+                Ok(backend) => used_backends.push(backend),
+                Err(reason) => errors.push(ConfigError::Invalid {
+                    key: String::from("dead_letter.backend"),
+                    reason,
+                }),
+            }
+            if self.queue_name(DEAD_LETTER_QUEUE_ENVKEY).is_none() {
+                errors.push(ConfigError::Missing {
+                    key: String::from("queues.dead_letters"),
+                    reason: String::from("by dead_letter.backend"),
+                });
+            }
+        }
+        used_backends.dedup();
+
+        for backend in used_backends.iter() {
+            let reason = format!("by the {} output", backend);
+            let mut require = |key: &str, present: bool| {
+                if !present {
+                    errors.push(ConfigError::Missing {
+                        key: key.to_string(),
+                        reason: reason.clone(),
+                    });
+                }
+            };
+            match backend {
+                #[cfg(feature = "APACHE_KAFKA")]
+                OutputBackend::ApacheKafka => {
+                    if self.kafka.brokers.is_empty() {
+                        require("kafka.address", self.kafka.address.is_some());
+                        require("kafka.port", self.kafka.port.is_some());
+                    }
+                    if self.kafka.sasl_mechanism.is_some() {
+                        require("kafka.sasl_username", self.kafka.sasl_username.is_some());
+                        require("kafka.sasl_password", self.kafka.sasl_password.is_some());
+                    }
+                }
+                #[cfg(feature = "RABBITMQ_CLASSIC")]
+                OutputBackend::RabbitMQClassic => {
+                    require("rabbitmq.address", self.rabbitmq.address.is_some());
+                    require("rabbitmq.port", self.rabbitmq.port.is_some());
+                    require("rabbitmq.user", self.rabbitmq.user.is_some());
+                    require("rabbitmq.password", self.rabbitmq.password.is_some());
+                }
+                #[cfg(feature = "RABBITMQ_STREAM")]
+                OutputBackend::RabbitMQStream => {
+                    require("rabbitmq.address", self.rabbitmq.address.is_some());
+                    require("rabbitmq.port", self.rabbitmq.port.is_some());
+                }
+                #[cfg(feature = "JSONL")]
+                OutputBackend::JsonL => require("file.output_dir", self.file.output_dir.is_some()),
+                #[cfg(feature = "JSON")]
+                OutputBackend::Json => require("file.output_dir", self.file.output_dir.is_some()),
+                #[allow(unreachable_patterns)]
+                _ => (),
+            }
+        }
+
+        if !KAFKA_PARTITION_KEYS.contains(&self.kafka.partition_key.as_str()) {
+            errors.push(ConfigError::Invalid {
+                key: String::from("kafka.partition_key"),
+                reason: format!("should be one of: {}", KAFKA_PARTITION_KEYS.join(", ")),
+            });
+        }
+        if let Some(mechanism) = &self.kafka.sasl_mechanism {
+            if !KAFKA_SASL_MECHANISMS.contains(&mechanism.to_lowercase().as_str()) {
+                errors.push(ConfigError::Invalid {
+                    key: String::from("kafka.sasl_mechanism"),
+                    reason: format!("should be one of: {}", KAFKA_SASL_MECHANISMS.join(", ")),
+                });
+            }
+        }
+        if self.kafka.tls_client_cert.is_some() != self.kafka.tls_client_key.is_some() {
+            errors.push(ConfigError::Invalid {
+                key: String::from("kafka.tls_client_cert"),
+                reason: String::from("should be set along with kafka.tls_client_key"),
+            });
+        }
+        let compressions =
+            std::iter::once((String::from("kafka.compression"), &self.kafka.compression)).chain(
+                self.kafka
+                    .table_compression
+                    .iter()
+                    .map(|(table, compression)| {
+                        (format!("kafka.table_compression.{}", table), compression)
+                    }),
+            );
+        for (key, compression) in compressions {
+            if !KAFKA_COMPRESSIONS.contains(&compression.as_str()) {
+                errors.push(ConfigError::Invalid {
+                    key,
+                    reason: format!("should be one of: {}", KAFKA_COMPRESSIONS.join(", ")),
+                });
+            }
+        }
+        if self.kafka.batch_max_bytes == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("kafka.batch_max_bytes"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+        if self.kafka.transactional {
+            if self.checkpoint.backend != "kafka" {
+                errors.push(ConfigError::Invalid {
+                    key: String::from("checkpoint.backend"),
+                    reason: String::from("should be kafka when kafka.transactional is set"),
+                });
+            }
+            // NOTE: the tables of a worker share the producer of its transaction.
+            if !self.kafka.table_compression.is_empty() {
+                errors.push(ConfigError::Invalid {
+                    key: String::from("kafka.table_compression"),
+                    reason: String::from("can't be set when kafka.transactional is set"),
+                });
+            }
+            if self.kafka.transactional_id.is_empty() {
+                errors.push(ConfigError::Invalid {
+                    key: String::from("kafka.transactional_id"),
+                    reason: String::from("should not be empty"),
+                });
+            }
+        }
+        if (self.kafka.transactional || self.checkpoint.backend == "kafka")
+            && self.kafka.progress_topic.is_none()
+        {
+            errors.push(ConfigError::Missing {
+                key: String::from("kafka.progress_topic"),
+                reason: String::from("when kafka.transactional or the kafka checkpoints are used"),
+            });
+        }
+        if !KAFKA_PARTITIONERS.contains(&self.kafka.partitioner.as_str()) {
+            errors.push(ConfigError::Invalid {
+                key: String::from("kafka.partitioner"),
+                reason: format!("should be one of: {}", KAFKA_PARTITIONERS.join(", ")),
+            });
+        }
+
+        #[cfg(feature = "RPC")]
+        if self.source.endpoint.is_none() {
+            errors.push(ConfigError::Missing {
+                key: String::from("source.endpoint"),
+                reason: String::from("when compiled with RPC"),
+            });
+        }
+        if self.source.max_batch_size == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("source.max_batch_size"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+        let method_timeouts = std::iter::once((
+            String::from("source.method_timeout_secs"),
+            self.source.method_timeout_secs,
+        ))
+        .chain(
+            self.source
+                .method_timeouts
+                .iter()
+                .map(|(method, timeout)| (format!("source.method_timeouts.{}", method), *timeout)),
+        );
+        for (key, timeout) in method_timeouts {
+            if timeout == 0 {
+                errors.push(ConfigError::Invalid {
+                    key,
+                    reason: String::from("should be at least 1 second"),
+                });
+            }
+        }
+
+        #[cfg(feature = "GRPC")]
+        if self.source.grpc.endpoints(&self.source).is_empty() {
+            errors.push(ConfigError::Missing {
+                key: String::from("source.grpc.endpoints"),
+                reason: String::from("(or source.endpoint) when compiled with GRPC"),
+            });
+        }
+        let grpc = &self.source.grpc;
+        if let Some(compression) = &grpc.compression {
+            if !matches!(compression.as_str(), "gzip" | "zstd") {
+                errors.push(ConfigError::Invalid {
+                    key: String::from("source.grpc.compression"),
+                    reason: format!("should be gzip or zstd, not {}", compression),
+                });
+            }
+        }
+        if grpc.keepalive_interval_secs == Some(0) || grpc.keepalive_timeout_secs == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("source.grpc"),
+                reason: String::from(
+                    "keepalive intervals and timeouts should be at least 1 second",
+                ),
+            });
+        }
+        if grpc.max_message_size == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("source.grpc.max_message_size"),
+                reason: String::from("should be at least 1 byte"),
+            });
+        }
+
+        #[cfg(feature = "REST")]
+        if self.source.rest.base_url(&self.source).is_none() {
+            errors.push(ConfigError::Missing {
+                key: String::from("source.rest.base_url"),
+                reason: String::from("(or source.endpoint) when compiled with REST"),
+            });
+        }
+        if self.source.rest.page_size == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("source.rest.page_size"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+        if self.source.rest.etag_cache_size == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("source.rest.etag_cache_size"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+
+        if !CHECKPOINT_BACKENDS.contains(&self.checkpoint.backend.as_str()) {
+            errors.push(ConfigError::Invalid {
+                key: String::from("checkpoint.backend"),
+                reason: format!("should be one of: {}", CHECKPOINT_BACKENDS.join(", ")),
+            });
+        }
+        #[cfg(not(feature = "CHECKPOINT_REDB"))]
+        if self.checkpoint.backend == "redb" {
+            errors.push(ConfigError::Invalid {
+                key: String::from("checkpoint.backend"),
+                reason: String::from("redb requires the CHECKPOINT_REDB feature"),
+            });
+        }
+        #[cfg(not(feature = "APACHE_KAFKA"))]
+        if self.checkpoint.backend == "kafka" {
+            errors.push(ConfigError::Invalid {
+                key: String::from("checkpoint.backend"),
+                reason: String::from("kafka requires the APACHE_KAFKA feature"),
+            });
+        }
+
+        if self.output.ordered_buffer == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("output.ordered_buffer"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+        if self.workers.threads == Some(0) {
+            errors.push(ConfigError::Invalid {
+                key: String::from("workers.threads"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+        if self.workers.unit_size == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("workers.unit_size"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+        let pipeline = [
+            ("pipeline.extract_workers", self.pipeline.extract_workers),
+            (
+                "pipeline.transform_workers",
+                self.pipeline.transform_workers,
+            ),
+            ("pipeline.publish_workers", self.pipeline.publish_workers),
+            ("pipeline.queue_capacity", self.pipeline.queue_capacity),
+        ];
+        for (key, value) in pipeline {
+            if value == 0 {
+                errors.push(ConfigError::Invalid {
+                    key: String::from(key),
+                    reason: String::from("should be at least 1"),
+                });
+            }
+        }
+        if self.follow.poll_interval_ms == 0 {
+            errors.push(ConfigError::Invalid {
+                key: String::from("follow.poll_interval_ms"),
+                reason: String::from("should be at least 1"),
+            });
+        }
+
+        errors.extend(
+            self.retry
+                .validate()
+                .into_iter()
+                .map(|reason| ConfigError::Invalid {
+                    key: String::from("retry"),
+                    reason,
+                }),
+        );
+
+        #[cfg(feature = "METRICS")]
+        if self.metrics.port.is_none() {
+            errors.push(ConfigError::Missing {
+                key: String::from("metrics.port"),
+                reason: String::from("when compiled with METRICS"),
+            });
+        }
+
+        #[cfg(feature = "ORCHESTRATED")]
+        if self.health_checks.port.is_none() {
+            errors.push(ConfigError::Missing {
+                key: String::from("health_checks.port"),
+                reason: String::from("when compiled with ORCHESTRATED"),
+            });
+        }
+
+        errors
+    }
+
+    /// Returns a copy of the configuration with the secrets masked, for printing.
+    pub fn masked(&self) -> Config {
+        let mut masked = self.clone();
+        if masked.rabbitmq.password.is_some() {
+            masked.rabbitmq.password = Some(String::from(MASKED_SECRET));
+        }
+        if masked.kafka.sasl_password.is_some() {
+            masked.kafka.sasl_password = Some(String::from(MASKED_SECRET));
+        }
+        let grpc = &mut masked.source.grpc;
+        if grpc.auth_token.is_some() {
+            grpc.auth_token = Some(String::from(MASKED_SECRET));
+        }
+        for value in grpc.metadata.values_mut() {
+            *value = String::from(MASKED_SECRET);
+        }
+        let rest = &mut masked.source.rest;
+        if rest.auth_token.is_some() {
+            rest.auth_token = Some(String::from(MASKED_SECRET));
+        }
+        for value in rest.headers.values_mut() {
+            *value = String::from(MASKED_SECRET);
+        }
+        masked
+    }
+
+    /// Returns the configuration as TOML, with the secrets masked.
+    pub fn to_masked_toml(&self) -> String {
+        toml::to_string_pretty(&self.masked()).expect("configuration is serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_name() {
+        assert_eq!(table_name("QUEUE_NAME_BLOCKS"), "blocks");
+        assert_eq!(table_name("QUEUE_NAME_TOKEN_TRANSFERS"), "token_transfers");
+        assert_eq!(table_name("QUEUE_NAME"), "all");
+    }
+
+    #[test]
+    fn test_parse_and_mask() {
+        let config: Config = toml::from_str(
+            r#"
+            [queues]
+            blocks = "blocks-topic"
+
+            [rabbitmq]
+            user = "indexer"
+            password = "hunter2"
+
+            [source.grpc]
+            auth_token = "s3cret"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.queue_name("QUEUE_NAME_BLOCKS").unwrap(),
+            "blocks-topic"
+        );
+        assert_eq!(config.output.skip_timeout_ms, 5000);
+
+        let printed = config.to_masked_toml();
+        assert!(!printed.contains("hunter2"));
+        assert!(!printed.contains("s3cret"));
+        assert!(printed.contains(MASKED_SECRET));
+    }
+
+    #[test]
+    fn test_kafka_sasl_mechanisms() {
+        let invalid = |mechanism: &str| {
+            let config: Config =
+                toml::from_str(&format!("[kafka]\nsasl_mechanism = \"{}\"", mechanism)).unwrap();
+            config.validate().iter().any(|error| {
+                matches!(error, ConfigError::Invalid { key, .. } if key == "kafka.sasl_mechanism")
+            })
+        };
+        assert!(!invalid("plain"));
+        assert!(!invalid("SCRAM-SHA-256"));
+        assert!(!invalid("scram-sha-512"));
+        assert!(invalid("gssapi"));
+    }
+
+    #[test]
+    fn test_kafka_producer_settings() {
+        let invalid_keys = |config: &Config| {
+            config
+                .validate()
+                .into_iter()
+                .filter_map(|error| match error {
+                    ConfigError::Invalid { key, .. } if key.starts_with("kafka.") => Some(key),
+                    _ => None,
+                })
+                .collect::<Vec<String>>()
+        };
+
+        let config: Config = toml::from_str(
+            r#"
+            [kafka]
+            linger_ms = 20
+            batch_max_bytes = 1048576
+            compression = "zstd"
+
+            [kafka.table_compression]
+            transactions = "lz4"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.kafka.linger_ms, 20);
+        assert_eq!(config.kafka.batch_max_bytes, 1048576);
+        assert_eq!(config.kafka.compression("QUEUE_NAME_TRANSACTIONS"), "lz4");
+        assert_eq!(config.kafka.compression("QUEUE_NAME_BLOCKS"), "zstd");
+        assert!(invalid_keys(&config).is_empty());
+
+        let config: Config = toml::from_str(
+            r#"
+            [kafka]
+            batch_max_bytes = 0
+            compression = "brotli"
+
+            [kafka.table_compression]
+            blocks = "lzma"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            invalid_keys(&config),
+            [
+                "kafka.compression",
+                "kafka.table_compression.blocks",
+                "kafka.batch_max_bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kafka_transactional() {
+        let problems = |toml: &str| {
+            let config: Config = toml::from_str(toml).unwrap();
+            config
+                .validate()
+                .into_iter()
+                .filter_map(|error| match error {
+                    // NOTE: the brokers are also required when Kafka is the only backend.
+                    ConfigError::Invalid { key, .. } | ConfigError::Missing { key, .. }
+                        if (key.starts_with("kafka.")
+                            && key != "kafka.address"
+                            && key != "kafka.port")
+                            || key == "checkpoint.backend" =>
+                    {
+                        Some(key)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            problems("[kafka]\ntransactional = true\n[kafka.table_compression]\nblocks = \"lz4\""),
+            [
+                "checkpoint.backend",
+                "kafka.table_compression",
+                "kafka.progress_topic",
+            ]
+        );
+        assert_eq!(
+            problems("[checkpoint]\nbackend = \"kafka\""),
+            ["kafka.progress_topic"]
+                .into_iter()
+                .chain(cfg!(not(feature = "APACHE_KAFKA")).then_some("checkpoint.backend"))
+                .collect::<Vec<&str>>()
+        );
+
+        let config: Config = toml::from_str(
+            r#"
+            [kafka]
+            brokers = ["localhost:9092"]
+            transactional = true
+            progress_topic = "progress"
+
+            [checkpoint]
+            backend = "kafka"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.kafka.transactional_id, "blockchain-etl");
+        assert!(config.validate().iter().all(|error| !matches!(
+            error,
+            ConfigError::Invalid { key, .. } | ConfigError::Missing { key, .. }
+                if key.starts_with("kafka.")
+        )));
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[kafka]\nadress = \"localhost\"").is_err());
+    }
+}
@@ -227,6 +227,9 @@ fn build_protos() -> Result<(), ()> {
 
     let mut config = prost_build::Config::new();
 
+    // NOTE: the outputs name the records by their protobuf full name (`prost::Name`).
+    config.enable_type_names();
+
     config.message_attribute(".evm", "#[derive(serde::Serialize, serde::Deserialize)]");
     config.enum_attribute(".evm", "#[derive(serde::Serialize, serde::Deserialize)]");
 
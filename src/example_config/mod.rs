@@ -1,35 +1,126 @@
 // TODO: this file will contain the high-level logic (glue).
-//  e.g. main() will call the function in this file for the indexing logic as well as the data extraction and record outputting
-
-/// This function creates a pubsub subscription to create requests for what tx versions we
-/// want to index.
-#[cfg(feature = "ORCHESTRATED")]
-pub async fn subscribe_and_extract(
-    pubsub_subscription: google_cloud_pubsub::subscription::Subscription,
-    publisher: blockchain_generic::output::publish::StreamPublisher,
-    metrics: Option<Metrics>,
-) -> Result<(), ExtractionInteruptionError> {
-    todo!("write subscribe_and_extract function");
-}
+//  e.g. main() will call the trait functions implemented in this file for the indexing logic as well as the data extraction and record outputting
 
-/// The primary function for indexing, creates a stream for a given range and
-/// creates records to be sent to publishers based off the aptos stream response.
-pub async fn extract_range(
-    start: u64,
-    end: u64,
-    publisher: blockchain_generic::output::publish::StreamPublisher,
-    metrics: Option<Metrics>,
-    table_options: Option<TableOptions>,
-) -> Result<(), ExtractionInteruptionError> {
-    todo!("write extract_range function")
-}
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+use blockchain_generic::blockchain_config::{BlockchainConfig, ExtractionError};
+use blockchain_generic::metrics::Metrics;
+
+/// The example blockchain configuration.  Rename this (e.g. `AptosConfig`) and add it to
+/// `available_chains()` and `run_with_chain()` in `main.rs`.
+pub struct ExampleConfig {}
+
+#[async_trait]
+impl BlockchainConfig for ExampleConfig {
+    const NAME: &'static str = "example";
+
+    // TODO: the block type returned by the node
+    type RawBlock = aptos_protos::transaction::v1::Transaction;
+    // TODO: a struct holding the records of each table for one block
+    type Records = ();
+    // TODO: the StreamPublisher defined in `output/streampublisher.rs`
+    type StreamPublisher = streampublisher::StreamPublisher;
+
+    async fn new() -> Result<Self, ExtractionError> {
+        Ok(ExampleConfig {})
+    }
+
+    async fn connect_publisher(&self) -> Result<Self::StreamPublisher, ExtractionError> {
+        Ok(streampublisher::StreamPublisher::new(&Self::chain_info()).await?)
+    }
+
+    async fn disconnect_publisher(
+        &self,
+        publisher: Self::StreamPublisher,
+    ) -> Result<(), ExtractionError> {
+        Ok(publisher.disconnect().await?)
+    }
+
+    /// Requests a single block from the node.
+    async fn extract_block(
+        &self,
+        height: u64,
+        metrics: Option<Metrics>,
+    ) -> Result<Option<Self::RawBlock>, ExtractionError> {
+        todo!("write extract_block function")
+    }
+
+    /// Transforms a block into the records of each table.
+    fn transform_block(
+        &self,
+        height: u64,
+        block: Self::RawBlock,
+    ) -> Result<Self::Records, ExtractionError> {
+        todo!("write transform_block function")
+    }
+
+    /// Publishes the records of each table to their publishers.
+    async fn publish_records(
+        &self,
+        publisher: &Self::StreamPublisher,
+        records: Self::Records,
+    ) -> Result<(), ExtractionError> {
+        todo!("write publish_records function")
+    }
+
+    /// Begins the Kafka transaction of the block, with `KAFKA_TRANSACTIONAL`.
+    async fn begin_block(
+        &self,
+        publisher: &Self::StreamPublisher,
+        height: u64,
+    ) -> Result<(), ExtractionError> {
+        todo!("write begin_block function")
+    }
+
+    /// Flushes the publishers of each table, so the block is only checkpointed once its
+    /// records were delivered, and commits its Kafka transaction, with `KAFKA_TRANSACTIONAL`.
+    async fn finish_block(
+        &self,
+        publisher: &Self::StreamPublisher,
+        height: u64,
+    ) -> Result<(), ExtractionError> {
+        todo!("write finish_block function")
+    }
+
+    /// Extracts transactions, returns a Vec. If
+    /// provided an `outdir` [PathBuf], should serialize the values and save it in the directory.
+    async fn extract_txs(
+        &self,
+        start: u64,
+        end: u64,
+        outdir: Option<PathBuf>,
+    ) -> Result<Vec<Self::RawBlock>, ExtractionError> {
+        todo!("write extract_txs function")
+    }
+
+    /// Saves a range of blocks and their expected records as test examples.
+    async fn create_test_data(
+        &self,
+        start: u64,
+        end: u64,
+        dir: &Path,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError> {
+        todo!("write create_test_data function")
+    }
+
+    /// This function creates a pubsub subscription to create requests for what tx versions we
+    /// want to index.
+    #[cfg(feature = "ORCHESTRATED")]
+    async fn subscribe_and_extract(
+        &self,
+        pubsub_subscription: google_cloud_pubsub::subscription::Subscription,
+        publisher: Self::StreamPublisher,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError> {
+        todo!("write subscribe_and_extract function");
+    }
 
-/// Extracts transactions, returns a Vec. If
-/// provided an `outdir` [PathBuf], should serialize the values and save it in the directory.
-pub async fn extract_txs(
-    start: u64,
-    end: u64,
-    outdir: Option<PathBuf>,
-) -> Result<Vec<aptos_protos::transaction::v1::Transaction>, ExtractionInteruptionError> {
-    todo!("write extract_txs function")
+    #[cfg(feature = "APACHE_AVRO")]
+    fn avro_schema(queue_env: &str) -> Option<&'static str> {
+        Some(avro_helpers::table_to_avro(avro_helpers::env_key_to_table_name(
+            queue_env,
+        )))
+    }
 }
@@ -2,6 +2,7 @@
 /// selection is invalid.This module will raise a compiler error for commonly known feature selection
 /// contradictions (like using `RABBITMQ_QUEUE` and `RABBITMQ_STREAM` and when a key feature selection is
 /// missing (i.e. no block chain feature selected like `SOLANA`).
+/// Multiple blockchain features may be enabled at once, in which case the blockchain is selected with `--chain`.
 ///
 /// Feature contradiction / requirements should be added to this module as they are created.
 
@@ -31,5 +32,3 @@ compile_error!("Either `SINGLE_PUBLISHER` or `SEPARATE_PUBLISHERS` must be enabl
 compile_error!(
     "No blockchain feature has been enabled. Please select one, such as `SOLANA`, `APTOS`, `MANTRA`."
 );
-#[cfg(all(feature = "SOLANA", feature = "APTOS", feature = "MANTRA"))]
-compile_error!("Multiple blockchain features have been enabled. Please select only one, such as `SOLANA`, `APTOS`, `MANTRA`.");
@@ -0,0 +1,137 @@
+//! This module finds the heights that were never published, for `find-gaps`.
+//!
+//! The heights that were published are taken from the checkpoints of the output (see
+//! `checkpoint`), and optionally from the records in the directories written by the `JSON`
+//! and `JSONL` outputs.  The gaps are the ranges of heights that neither holds, and are written
+//! as a list that `index-list` reads, so they can be backfilled.
+
+use log::warn;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::checkpoint::HeightSet;
+
+/// The fields of the records that hold the height of their block, tried in order.
+pub const DEFAULT_HEIGHT_FIELDS: &[&str] = &["block_number", "slot", "height", "block_height"];
+
+/// Returns the heights of the records in the JSON and JSONL files under `dir`, each taken from
+/// the first of `fields` that a record holds (as a number or a string).  A file named after a
+/// height (e.g. `1200.json`) counts for that height.
+///
+/// NOTE: records that hold none of the fields are skipped (with a warning), so the heights of
+/// tables that don't record them are only known from the checkpoints.
+pub fn scan_output_dir(dir: &Path, fields: &[String]) -> io::Result<HeightSet> {
+    let mut heights = HeightSet::new();
+    let mut skipped = 0;
+    scan_dir(dir, fields, &mut heights, &mut skipped)?;
+    if skipped > 0 {
+        warn!(
+            "{} records in {:?} have none of the fields {:?}, skipping them...",
+            skipped, dir, fields
+        );
+    }
+    Ok(heights)
+}
+
+/// Adds the heights of the files under `dir` to `heights`, recursively.
+fn scan_dir(
+    dir: &Path,
+    fields: &[String],
+    heights: &mut HeightSet,
+    skipped: &mut u64,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            scan_dir(&path, fields, heights, skipped)?;
+            continue;
+        }
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if !matches!(extension, Some("json") | Some("jsonl")) {
+            continue;
+        }
+        if let Some(height) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            heights.insert(height, height + 1);
+        }
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Value>(&line)
+                .ok()
+                .and_then(|record| record_height(&record, fields))
+            {
+                Some(height) => heights.insert(height, height + 1),
+                None => *skipped += 1,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the height held by the first of `fields` that the record has.
+fn record_height(record: &Value, fields: &[String]) -> Option<u64> {
+    fields.iter().find_map(|field| match record.get(field)? {
+        Value::Number(height) => height.as_u64(),
+        Value::String(height) => height.parse().ok(),
+        _ => None,
+    })
+}
+
+/// Returns the heights of the gaps, in order.
+pub fn gap_heights(gaps: &[(u64, u64)]) -> impl Iterator<Item = u64> + Send + '_ {
+    gaps.iter().flat_map(|&(start, end)| start..end)
+}
+
+/// Writes the heights of the gaps to a list file that `index-list` reads, one height per
+/// line after a `height` header.  Returns the number of heights written.
+pub fn write_list(path: &Path, gaps: &[(u64, u64)]) -> io::Result<u64> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "height")?;
+    let mut count = 0;
+    for height in gap_heights(gaps) {
+        writeln!(writer, "{}", height)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_and_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("blocks")).unwrap();
+        fs::write(
+            dir.join("blocks/records.jsonl"),
+            "{\"slot\": 3}\n{\"block_number\": \"4\"}\n{\"other\": 1}\n",
+        )
+        .unwrap();
+        fs::write(dir.join("blocks/7.json"), "{}\n").unwrap();
+
+        let fields = DEFAULT_HEIGHT_FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect::<Vec<String>>();
+        let mut heights = scan_output_dir(dir, &fields).unwrap();
+        assert_eq!(heights.ranges().collect::<Vec<_>>(), [(3, 5), (7, 8)]);
+
+        heights.insert(0, 2);
+        let gaps = heights.gaps(0, 9);
+        assert_eq!(gaps, [(2, 3), (5, 7), (8, 9)]);
+        let list = dir.join("gaps.csv");
+        assert_eq!(write_list(&list, &gaps).unwrap(), 4);
+        assert_eq!(fs::read_to_string(&list).unwrap(), "height\n2\n5\n6\n8\n");
+    }
+}
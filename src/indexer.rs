@@ -0,0 +1,618 @@
+//! This module contains the indexing loops, which are generic over `BlockchainConfig`: a
+//! blockchain only describes how to extract, transform and publish a single block, and the
+//! loops decide which heights are indexed, by how many workers and in which order.
+//!
+//! - `index_range` indexes a range, resuming from the checkpoints.  Forward ranges with an end
+//!   are indexed by several workers (`extract_range`), or through the staged pipeline
+//!   (`index_pipeline`); other ranges are indexed in order.
+//! - `index_follow` follows the chain head.
+//! - `extract_list` indexes the heights of a list.
+//!
+//! The loops take the blockchain config in an `Arc`, so that their workers can be spawned as
+//! tasks of their own.
+
+use futures::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::blockchain_config::{BlockOutcome, BlockchainConfig, ErrorAction, ExtractionError};
+use crate::checkpoint::{Checkpoints, HeightSet};
+use crate::config;
+use crate::metrics::Metrics;
+use crate::output::ordered::ReorderBuffer;
+use crate::pipeline::{self, StageQueue};
+use crate::range::IndexRange;
+use crate::reorg::ReorgTracker;
+use crate::retry::RetryError;
+use crate::scheduler;
+
+/// The number of heights of each outcome when indexing a list of heights.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListReport {
+    /// The number of blocks that were indexed.
+    pub indexed: u64,
+    /// The number of heights without a block.
+    pub missing: u64,
+    /// The number of blocks that failed and were given up on.
+    pub failed: u64,
+    /// The number of values of the list that aren't heights.
+    pub invalid: u64,
+}
+
+/// The primary function for indexing.  Indexes every block in the range `[start, end)` that
+/// the checkpoints don't hold, with `NUM_EXTRACTOR_THREADS` workers taking units of
+/// `workers.unit_size` heights from a queue (see `scheduler`).  Each worker is spawned as a
+/// task, and prepares its own publisher there (see `BlockchainConfig::worker_publisher`).
+/// The heights of each unit that were indexed (or had no block) are recorded in the
+/// checkpoints once the unit is done.
+///
+/// NOTE: blocks are published out of order across units, unless `output.ordered` is set,
+/// in which case the range is indexed with the pipeline and its blocks are released to the
+/// output in height order (see `output::ordered`).  Heights of a unit interrupted by a
+/// crash aren't recorded, so they are indexed again on the next run.
+pub async fn extract_range<C: BlockchainConfig>(
+    chain: &Arc<C>,
+    start: u64,
+    end: u64,
+    checkpoints: &Checkpoints,
+    publisher: C::StreamPublisher,
+    metrics: Option<Metrics>,
+) -> Result<(), ExtractionError> {
+    let workers = &config::get().workers;
+    let units = scheduler::work_units(start, end, workers.unit_size, &checkpoints.completed()?);
+    let output = &config::get().output;
+    if config::get().pipeline.enabled || output.ordered {
+        info!("Indexing range [{}, {}) with the pipeline...", start, end);
+        let reorder = output.ordered.then(|| {
+            let mut heights = HeightSet::new();
+            for unit in &units {
+                heights.insert(unit.start, unit.end);
+            }
+            ReorderBuffer::new(heights, output.ordered_buffer)
+        });
+        let heights = units.into_iter().flat_map(|unit| unit.start..unit.end);
+        let report = index_pipeline(
+            chain,
+            heights,
+            checkpoints,
+            publisher,
+            reorder.as_ref(),
+            metrics,
+        )
+        .await?;
+        info!(
+            "Indexed range [{}, {}): {} indexed, {} missing, {} failed",
+            start, end, report.indexed, report.missing, report.failed
+        );
+        return Ok(());
+    }
+    info!(
+        "Indexing range [{}, {}) in {} units with {} workers...",
+        start,
+        end,
+        units.len(),
+        workers.threads()
+    );
+    scheduler::run(units, workers.threads(), |worker, queue| {
+        let (chain, checkpoints) = (chain.clone(), checkpoints.clone());
+        let (publisher, metrics) = (publisher.clone(), metrics.clone());
+        async move {
+            let publisher = chain.worker_publisher(&publisher, worker).await?;
+            while let Ok(unit) = queue.recv().await {
+                let mut done = HeightSet::new();
+                for height in unit.start..unit.end {
+                    match chain
+                        .index_block_or_skip(height, &publisher, None, metrics.clone())
+                        .await?
+                    {
+                        BlockOutcome::Indexed | BlockOutcome::Missing => {
+                            done.insert(height, height + 1)
+                        }
+                        BlockOutcome::Failed(_) => (),
+                    }
+                }
+                for (done_start, done_end) in done.ranges() {
+                    checkpoints.record(done_start, done_end).await?;
+                }
+                debug!("Worker {} indexed [{}, {})", worker, unit.start, unit.end);
+            }
+            Ok(())
+        }
+    })
+    .await
+}
+
+/// Indexes the blocks at `heights` with separate extract, transform and publish stages,
+/// each with its own number of workers (see `pipeline`), connected by queues bounded by
+/// `pipeline.queue_capacity` and `pipeline.queue_bytes`.  Each height that was indexed (or
+/// had no block) is recorded in the checkpoints.  Returns the number of heights of each
+/// outcome, or an error if indexing should stop.  With a `reorder` buffer, each height
+/// reserves its place in the buffer before it is extracted, and the records are published
+/// once every height before them is done.
+///
+/// Blocks are transformed on the blocking thread pool, so a slow transformation doesn't hold
+/// up the extract and publish stages.
+///
+/// NOTE: extraction is retried with the retry policy, while publishing relies on the
+/// retries of the outputs, as the records are handed over to `publish_block`.
+pub async fn index_pipeline<C, I>(
+    chain: &Arc<C>,
+    heights: I,
+    checkpoints: &Checkpoints,
+    publisher: C::StreamPublisher,
+    reorder: Option<&ReorderBuffer<C::Records>>,
+    metrics: Option<Metrics>,
+) -> Result<ListReport, ExtractionError>
+where
+    C: BlockchainConfig,
+    I: Iterator<Item = u64> + Send,
+{
+    let settings = &config::get().pipeline;
+    let heights = Mutex::new(heights);
+    let blocks = StageQueue::new("transform", settings.queue_capacity, settings.queue_bytes);
+    let records = StageQueue::new("publish", settings.queue_capacity, settings.queue_bytes);
+    let report = Mutex::new(ListReport::default());
+    let finish = |height: u64, outcome: BlockOutcome| {
+        let report = &report;
+        async move {
+            {
+                let mut report = report.lock().expect("pipeline report lock poisoned");
+                match outcome {
+                    BlockOutcome::Indexed => report.indexed += 1,
+                    BlockOutcome::Missing => report.missing += 1,
+                    BlockOutcome::Failed(_) => {
+                        report.failed += 1;
+                        return Ok::<(), ExtractionError>(());
+                    }
+                }
+            }
+            Ok(checkpoints.record_height(height).await?)
+        }
+    };
+    let finish = &finish;
+    // Decides what to do about a block that failed in a stage.
+    let failed = |height: u64, stage: &'static str, err: ExtractionError| async move {
+        if err.action() == ErrorAction::Abort {
+            return Err(err);
+        }
+        error!("Failed to {} block {}: {}, skipping...", stage, height, err);
+        finish(height, BlockOutcome::Failed(err.to_string())).await
+    };
+    let (blocks, records, metrics) = (&blocks, &records, &metrics);
+    // NOTE: the releases are published one at a time (see `ReorderBuffer::complete`), so they
+    // share a publisher of their own, in the slot after the publish workers.
+    let release_publisher = match reorder {
+        Some(_) => Some(
+            chain
+                .worker_publisher(&publisher, settings.publish_workers.max(1))
+                .await?,
+        ),
+        None => None,
+    };
+    let (publisher, release_publisher, failed) = (&publisher, &release_publisher, &failed);
+    // Completes a height in the reorder buffer, and publishes the blocks it releases.
+    let release = |height: u64, transformed: Option<C::Records>| async move {
+        let (Some(reorder), Some(release_publisher)) = (reorder, release_publisher) else {
+            return Ok::<(), ExtractionError>(());
+        };
+        let mut released = reorder.complete(height, transformed).await;
+        for (height, transformed) in released.blocks.drain(..) {
+            match chain
+                .publish_block(release_publisher, height, transformed)
+                .await
+            {
+                Ok(()) => finish(height, BlockOutcome::Indexed).await?,
+                Err(err) => failed(height, "publish", err).await?,
+            }
+        }
+        Ok(())
+    };
+    let release = &release;
+
+    let extract = pipeline::stage(
+        settings.extract_workers,
+        |_| async {
+            loop {
+                let Some(height) = heights.lock().expect("pipeline lock poisoned").next() else {
+                    return Ok::<(), ExtractionError>(());
+                };
+                if let Some(reorder) = reorder {
+                    reorder.reserve(height).await;
+                }
+                let block = config::get()
+                    .retry
+                    .run("extract_block", || {
+                        chain.extract_block(height, metrics.clone())
+                    })
+                    .await;
+                match block {
+                    Ok(Some(block)) => {
+                        let size = chain.raw_block_size(&block);
+                        if !blocks.send((height, block), size).await {
+                            return Ok(());
+                        }
+                    }
+                    Ok(None) => {
+                        warn!("No block at height {}, skipping...", height);
+                        finish(height, BlockOutcome::Missing).await?;
+                        release(height, None).await?
+                    }
+                    Err(RetryError::Permanent(err)) => {
+                        failed(height, "extract", err).await?;
+                        release(height, None).await?
+                    }
+                    Err(err) => {
+                        error!("Failed to extract block {}: {}, skipping...", height, err);
+                        finish(height, BlockOutcome::Failed(err.to_string())).await?;
+                        release(height, None).await?
+                    }
+                }
+            }
+        },
+        || blocks.close(),
+    );
+    let transform = pipeline::stage(
+        settings.transform_workers,
+        |_| async {
+            while let Some((height, block)) = blocks.recv().await {
+                let transforming = chain.clone();
+                let transformed = tokio::task::spawn_blocking(move || {
+                    transforming.transform_block(height, block)
+                })
+                .await;
+                let transformed = match transformed {
+                    Ok(transformed) => transformed,
+                    Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    Err(e) => return Err(e.into()),
+                };
+                match transformed {
+                    Ok(transformed) => {
+                        let size = chain.records_size(&transformed);
+                        if !records.send((height, transformed), size).await {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        failed(height, "transform", err).await?;
+                        release(height, None).await?
+                    }
+                }
+            }
+            Ok(())
+        },
+        || records.close(),
+    );
+    let publish = pipeline::stage(
+        settings.publish_workers,
+        |worker| async move {
+            let publisher = chain.worker_publisher(publisher, worker).await?;
+            while let Some((height, transformed)) = records.recv().await {
+                if reorder.is_some() {
+                    release(height, Some(transformed)).await?;
+                    continue;
+                }
+                match chain.publish_block(&publisher, height, transformed).await {
+                    Ok(()) => finish(height, BlockOutcome::Indexed).await?,
+                    Err(err) => failed(height, "publish", err).await?,
+                }
+            }
+            Ok(())
+        },
+        || (),
+    );
+    futures::try_join!(extract, transform, publish)?;
+    Ok(report.into_inner().expect("pipeline report lock poisoned"))
+}
+
+/// Indexes the blocks of a range, recording each height that was indexed (or had no block)
+/// in the checkpoints.  The heights that the checkpoints already hold are skipped, so a
+/// range that was partly indexed (by this run or by another worker) only indexes the rest;
+/// heights that failed are indexed again.  Forward ranges with an end are indexed by
+/// several workers (see `extract_range`), other ranges in order.  Ranges without end wait
+/// for new blocks once they reach the chain head (see `wait_for_height`).
+pub async fn index_range<C: BlockchainConfig>(
+    chain: &Arc<C>,
+    range: &IndexRange,
+    checkpoints: &Checkpoints,
+    publisher: C::StreamPublisher,
+    metrics: Option<Metrics>,
+) -> Result<(), ExtractionError> {
+    let completed = checkpoints.completed()?;
+    let bounds = range
+        .last()
+        .map(|last| (range.first().min(last), range.first().max(last) + 1));
+    if bounds.is_some_and(|(low, high)| completed.contains_all(low, high)) {
+        info!("Range {} was already indexed, stopping...", range);
+        return Ok(());
+    }
+    if let (Some(end), false) = (range.end, range.reverse) {
+        return extract_range(chain, range.start, end, checkpoints, publisher, metrics).await;
+    }
+    info!("Indexing range {} to {}...", range, checkpoints.output());
+
+    // NOTE: only ranges without end follow the chain head, where blocks can still be
+    // replaced by a reorganization.
+    let follow = &config::get().follow;
+    let reorgs = (range.end.is_none() && !range.reverse && follow.max_reorg_depth > 0)
+        .then(|| ReorgTracker::new(follow.max_reorg_depth));
+    let publisher = chain.worker_publisher(&publisher, 0).await?;
+    let mut head = None;
+    for height in range.heights() {
+        if completed.contains(height) {
+            continue;
+        }
+        // NOTE: ranges without end wait at the chain head, rather than skipping the blocks
+        // that don't exist yet.
+        if range.end.is_none() && !range.reverse {
+            head = wait_for_height(chain.as_ref(), height, head, metrics.clone()).await?;
+        }
+        match chain
+            .index_block_or_skip(height, &publisher, reorgs.as_ref(), metrics.clone())
+            .await?
+        {
+            BlockOutcome::Indexed | BlockOutcome::Missing => {
+                checkpoints.record_height(height).await?
+            }
+            BlockOutcome::Failed(_) => (),
+        }
+    }
+    info!("Indexed range {}", range);
+    Ok(())
+}
+
+/// Waits until the block at `height` has `follow.confirmations` blocks on top of it,
+/// polling the chain head every `follow.poll_interval_ms`.  `head` is the last chain head
+/// known, if any.  Returns the chain head once the block can be indexed, or `None` if the
+/// blockchain can't tell (in which case the block is indexed without waiting).
+///
+/// NOTE: requests for the chain head are retried with the retry policy, and if they keep
+/// failing (e.g. while the node is down) the wait goes on, so a node outage only pauses
+/// indexing.
+pub async fn wait_for_height<C: BlockchainConfig>(
+    chain: &C,
+    height: u64,
+    mut head: Option<u64>,
+    metrics: Option<Metrics>,
+) -> Result<Option<u64>, ExtractionError> {
+    let follow = &config::get().follow;
+    let confirmed = |head: Option<u64>| {
+        head.is_some_and(|head| height.saturating_add(follow.confirmations) <= head)
+    };
+    while !confirmed(head) {
+        let latest = config::get()
+            .retry
+            .run("chain_head", || chain.chain_head(metrics.clone()))
+            .await;
+        match latest {
+            Ok(Some(latest)) => head = Some(latest),
+            Ok(None) => return Ok(None),
+            Err(RetryError::Permanent(err)) if err.action() == ErrorAction::Abort => {
+                return Err(err)
+            }
+            Err(err) => warn!("Failed to request the chain head: {}, waiting...", err),
+        }
+        if !confirmed(head) {
+            tokio::time::sleep(Duration::from_millis(follow.poll_interval_ms)).await;
+        }
+    }
+    Ok(head)
+}
+
+/// Follows the chain head, indexing new blocks once they have `follow.confirmations`
+/// blocks on top of them, until it is stopped.  Starts from `start`, or else after the
+/// last height of the checkpoints, or else from the chain head.  Each height is recorded in
+/// the checkpoints, so following again after a restart continues without gaps (heights
+/// that failed are left to `find-gaps`).
+pub async fn index_follow<C: BlockchainConfig>(
+    chain: &Arc<C>,
+    start: Option<u64>,
+    checkpoints: &Checkpoints,
+    publisher: C::StreamPublisher,
+    metrics: Option<Metrics>,
+) -> Result<(), ExtractionError> {
+    let start = match (start, checkpoints.completed()?.end()) {
+        (Some(start), _) => start,
+        (None, Some(end)) => {
+            info!("Resuming after the last height indexed, {}...", end - 1);
+            end
+        }
+        (None, None) => match wait_for_height(chain.as_ref(), 0, None, metrics.clone()).await? {
+            Some(head) => head.saturating_sub(config::get().follow.confirmations),
+            None => {
+                return Err(ExtractionError::Interrupted(String::from(
+                    "a start height is needed, as the blockchain can't tell its chain head",
+                )))
+            }
+        },
+    };
+    let range = IndexRange::new(start, None, false).map_err(ExtractionError::Interrupted)?;
+    index_range(chain, &range, checkpoints, publisher, metrics).await
+}
+
+/// Indexes the blocks at the heights of a list, at most `concurrency` at a time, calling
+/// `on_outcome` with the outcome of each height as it completes (so not necessarily in the
+/// order of the list).  Values of the list that aren't heights are logged and counted as
+/// invalid.  The heights that were indexed (or had no block) are recorded in the
+/// checkpoints.  Returns the number of heights of each outcome, or an error if indexing
+/// should stop.
+///
+/// NOTE: the list is consumed as a stream, so it is never held in memory as a whole.  Each
+/// of the `concurrency` heights indexed at a time gets a publisher of its own (see
+/// `BlockchainConfig::worker_publisher`), prepared once and handed on to the next height.
+pub async fn extract_list<C, S, F>(
+    chain: &Arc<C>,
+    heights: S,
+    concurrency: usize,
+    checkpoints: &Checkpoints,
+    publisher: C::StreamPublisher,
+    metrics: Option<Metrics>,
+    mut on_outcome: F,
+) -> Result<ListReport, ExtractionError>
+where
+    C: BlockchainConfig,
+    S: Stream<Item = Result<u64, String>> + Send,
+    F: FnMut(u64, &BlockOutcome) + Send,
+{
+    let concurrency = concurrency.max(1);
+    let (idle, publishers) = async_channel::bounded(concurrency);
+    for worker in 0..concurrency {
+        let _ = idle.try_send(chain.worker_publisher(&publisher, worker).await?);
+    }
+    let (idle, publishers, metrics) = (&idle, &publishers, &metrics);
+    let mut outcomes = std::pin::pin!(heights
+        .map(|height| async move {
+            let height = match height {
+                Ok(height) => height,
+                Err(value) => {
+                    warn!("Invalid height {:?} in the list, skipping...", value);
+                    return Ok(None);
+                }
+            };
+            // NOTE: at most `concurrency` heights are indexed at a time, so a publisher is
+            // always idle by then.
+            let publisher = publishers
+                .recv()
+                .await
+                .expect("a publisher for each height indexed at a time");
+            let outcome = chain
+                .index_block_or_skip(height, &publisher, None, metrics.clone())
+                .await;
+            let _ = idle.try_send(publisher);
+            outcome.map(|outcome| Some((height, outcome)))
+        })
+        .buffer_unordered(concurrency));
+
+    let mut report = ListReport::default();
+    while let Some(outcome) = outcomes.next().await {
+        let Some((height, outcome)) = outcome? else {
+            report.invalid += 1;
+            continue;
+        };
+        match outcome {
+            BlockOutcome::Indexed => report.indexed += 1,
+            BlockOutcome::Missing => report.missing += 1,
+            BlockOutcome::Failed(_) => report.failed += 1,
+        }
+        if !matches!(outcome, BlockOutcome::Failed(_)) {
+            checkpoints.record_height(height).await?;
+        }
+        on_outcome(height, &outcome);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{checkpoints, init_config, MockChain, MockPublisher};
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn test_reverse_range_prepares_its_publisher() {
+        init_config();
+        let chain = Arc::new(MockChain::default());
+        let checkpoints = checkpoints();
+        checkpoints.record_height(12).await.unwrap();
+        let publisher = MockPublisher::default();
+        let range = IndexRange::new(10, Some(15), true).unwrap();
+        index_range(&chain, &range, &checkpoints, publisher.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(publisher.published(), [14, 13, 11, 10]);
+        assert_eq!(publisher.begun(), publisher.published());
+        assert_eq!(publisher.finished(), publisher.published());
+        assert!(checkpoints.completed().unwrap().contains_all(10, 15));
+    }
+
+    #[tokio::test]
+    async fn test_worker_slots_are_stable() {
+        init_config();
+        let chain = Arc::new(MockChain::default());
+        for _ in 0..2 {
+            let publisher = MockPublisher::default();
+            let heights = futures::stream::iter((0..10).map(Ok));
+            extract_list(
+                &chain,
+                heights,
+                3,
+                &checkpoints(),
+                publisher.clone(),
+                None,
+                |_, _| (),
+            )
+            .await
+            .unwrap();
+            assert_eq!(publisher.workers(), [0, 1, 2]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_prepares_its_publisher() {
+        init_config();
+        let chain = Arc::new(MockChain {
+            head: Some(10),
+            ..MockChain::default()
+        });
+        let checkpoints = checkpoints();
+        checkpoints.record(0, 7).await.unwrap();
+        let publisher = MockPublisher::default();
+        // NOTE: following never ends, so it is stopped while it waits for block 11.
+        let following = index_follow(&chain, None, &checkpoints, publisher.clone(), None);
+        assert!(tokio::time::timeout(Duration::from_millis(200), following)
+            .await
+            .is_err());
+        assert_eq!(publisher.published(), [7, 8, 9, 10]);
+        assert_eq!(checkpoints.completed().unwrap().end(), Some(11));
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_stages_overlap() {
+        init_config();
+        let chain = Arc::new(MockChain {
+            extract_delay: Duration::from_millis(2),
+            transform_delay: Duration::from_millis(20),
+            ..MockChain::default()
+        });
+        let checkpoints = checkpoints();
+        let publisher = MockPublisher::default();
+        let report = index_pipeline(&chain, 0..20, &checkpoints, publisher.clone(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(report.indexed, 20);
+        assert_eq!(publisher.published().len(), 20);
+        assert!(checkpoints.completed().unwrap().contains_all(0, 20));
+        // NOTE: transformations block their thread, so blocks can only be extracted during
+        // one if it runs on a thread of its own.
+        assert!(chain.overlaps.load(Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_ordered_pipeline_prepares_its_publisher() {
+        init_config();
+        let chain = Arc::new(MockChain {
+            extract_delay: Duration::from_millis(1),
+            ..MockChain::default()
+        });
+        let checkpoints = checkpoints();
+        let publisher = MockPublisher::default();
+        let mut heights = HeightSet::new();
+        heights.insert(0, 30);
+        let reorder = ReorderBuffer::new(heights, 8);
+        let report = index_pipeline(
+            &chain,
+            0..30,
+            &checkpoints,
+            publisher.clone(),
+            Some(&reorder),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.indexed, 30);
+        assert_eq!(publisher.published(), (0..30).collect::<Vec<u64>>());
+        assert_eq!(publisher.begun(), publisher.published());
+        assert_eq!(publisher.finished(), publisher.published());
+        assert!(checkpoints.completed().unwrap().contains_all(0, 30));
+    }
+}
@@ -1,19 +1,31 @@
 #![doc = include_str!("README.md")]
 
+pub mod blockchain_config;
+pub mod checkpoint;
+pub mod config;
+pub mod gaps;
+pub mod indexer;
 pub mod metrics;
 pub mod output;
+pub mod pipeline;
+pub mod range;
+pub mod reorg;
+pub mod retry;
+pub mod scheduler;
+pub mod source;
 
+#[cfg(test)]
+mod testing;
+
+// Each blockchain config implements `blockchain_config::BlockchainConfig`, and any number of them
+// can be compiled in.  The one used is selected at runtime with `--chain`.
 /*#[cfg(feature = "SOLANA")]
 //#[rustfmt::skip]
 pub mod solana_config;
-#[cfg(feature = "SOLANA")]
-pub use solana_config as blockchain_config;
 */
 #[cfg(feature = "APTOS")]
 //#[rustfmt::skip]
 pub mod aptos_config;
-#[cfg(feature = "APTOS")]
-pub use aptos_config as blockchain_config;
 
 #[cfg(feature = "RPC")]
 pub use source::json_rpc::*;
@@ -8,11 +8,12 @@ use clap::{Args, Parser, Subcommand};
 use log::{error, info};
 use std::error::Error;
 #[cfg(not(feature = "ORCHESTRATED"))]
-use std::fs::{create_dir, read_dir, File};
+use std::fs::File;
 #[cfg(not(feature = "ORCHESTRATED"))]
 use std::io::{BufRead, BufReader};
 #[allow(unused_imports)]
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 #[cfg(any(feature = "METRICS", feature = "ORCHESTRATED"))]
 use {
     actix_web::{get, App, HttpServer, Responder},
@@ -24,8 +25,18 @@ use {
     google_cloud_pubsub::client::{Client, ClientConfig},
 };
 
-use blockchain_etl_indexer::blockchain_config;
-use blockchain_etl_indexer::{metrics::Metrics, output::publish::StreamPublisher};
+use blockchain_etl_indexer::blockchain_config::BlockchainConfig;
+use blockchain_etl_indexer::checkpoint::Checkpoints;
+use blockchain_etl_indexer::config::{self, Config, ConfigOverrides};
+use blockchain_etl_indexer::gaps;
+use blockchain_etl_indexer::indexer;
+use blockchain_etl_indexer::metrics::Metrics;
+use blockchain_etl_indexer::output::dead_letter;
+#[cfg(not(feature = "ORCHESTRATED"))]
+use blockchain_etl_indexer::range::IndexRange;
+
+#[cfg(feature = "APTOS")]
+use blockchain_etl_indexer::aptos_config::AptosConfig;
 
 /// The directory containing the examples test range
 pub const TEST_EXAMPLE_DIRECTORY: &str = "./tests/examples";
@@ -38,6 +49,11 @@ use blockchain_etl_indexer::solana_config::data_sources::bigtable;
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// The blockchain to index.  Optional if only one blockchain is compiled in.
+    #[clap(long, global = true)] // Long flag format ('--chain aptos')
+    chain: Option<String>,
+    #[command(flatten)]
+    config: ConfigOverrides,
     #[command(subcommand)]
     command: Commands,
 }
@@ -53,10 +69,31 @@ enum Commands {
     /// Extract blocks from a list
     #[cfg(not(feature = "ORCHESTRATED"))]
     IndexList(IndexListArgs),
+    /// Extract new blocks as they are produced, following the chain head
+    #[cfg(not(feature = "ORCHESTRATED"))]
+    IndexFollow(IndexFollowArgs),
+    /// Find the blocks that were never published, and optionally index them
+    FindGaps(FindGapsArgs),
     /// Save range
     SaveRange(SaveRangeArgs),
     // Creates a test range
     CreateTestSet(CreateTestRangeArgs),
+    /// Publish the dead-lettered records again
+    ReplayDeadLetters(ReplayDeadLettersArgs),
+    /// Inspect the configuration
+    Config(ConfigArgs),
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommands,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Validate the configuration and print it, with secrets masked
+    Check,
 }
 
 /// Arguments relating the the indexing of the crypto currency, particularly output,
@@ -73,9 +110,10 @@ struct IndexSubscriptionArgs {
 #[cfg(not(feature = "ORCHESTRATED"))]
 #[derive(Args)]
 struct IndexRangeArgs {
-    /// The slot to begin indexing from
+    /// The slot to begin indexing from (the lowest slot indexed, when indexing backwards)
     start: u64,
-    /// The slot to stop indexing at
+    /// The slot to stop indexing at (exclusive).  Without it, indexing continues with new blocks,
+    /// or down to the genesis block when indexing backwards from `start`
     end: Option<u64>,
     /// Index backwards towards the genesis block
     #[clap(long)] // Long flag format ('--reverse')
@@ -104,6 +142,15 @@ struct CreateTestRangeArgs {
     dir: Option<PathBuf>,
 }
 
+#[derive(Args)]
+struct ReplayDeadLettersArgs {
+    /// The dead-letter directory [default: dead_letter.dir (DEAD_LETTER_DIR)]
+    dir: Option<PathBuf>,
+    /// Only replay the dead letters of this table (e.g. `blocks`)
+    #[clap(long)]
+    table: Option<String>,
+}
+
 /// Arguments relating the the indexing of the crypto currency, particularly output,
 /// start point, and direction (reverse)
 #[cfg(not(feature = "ORCHESTRATED"))]
@@ -111,6 +158,49 @@ struct CreateTestRangeArgs {
 struct IndexListArgs {
     /// The path to a list of blocks to index.
     list: String,
+    /// The number of blocks indexed at the same time
+    #[clap(long, default_value_t = 8)]
+    concurrency: usize,
+    /// The path of the report of each height's outcome [default: <list>.report.csv]
+    #[clap(long)]
+    report: Option<PathBuf>,
+}
+
+/// Arguments for following the chain head.  The confirmation depth and polling interval are
+/// configured in `[follow]`
+#[cfg(not(feature = "ORCHESTRATED"))]
+#[derive(Args)]
+struct IndexFollowArgs {
+    /// The slot to begin indexing from [default: after the last slot indexed, or the chain head]
+    #[clap(long)]
+    start: Option<u64>,
+}
+
+/// Arguments for finding the heights that were never published, from the checkpoints of the
+/// output and optionally from the records in JSON/JSONL output directories
+#[derive(Args)]
+struct FindGapsArgs {
+    /// The first height checked
+    #[clap(long, default_value_t = 0)]
+    start: u64,
+    /// The height to stop checking at (exclusive) [default: after the last height published]
+    #[clap(long)]
+    end: Option<u64>,
+    /// A JSON/JSONL output directory whose records count as published (can be repeated)
+    #[clap(long)]
+    scan: Vec<PathBuf>,
+    /// The fields of the scanned records holding their height, tried in order
+    #[clap(long, value_delimiter = ',', default_values_t = gaps::DEFAULT_HEIGHT_FIELDS.iter().map(|field| field.to_string()))]
+    height_fields: Vec<String>,
+    /// The path of the list of missing heights, which `index-list` reads
+    #[clap(long, default_value = "gaps.csv")]
+    list: PathBuf,
+    /// Index the missing heights once they are found
+    #[clap(long)]
+    backfill: bool,
+    /// The number of blocks indexed at the same time when backfilling
+    #[clap(long, default_value_t = 8)]
+    concurrency: usize,
 }
 
 /// Returns Welcome message when accessing the base-url of the server
@@ -132,114 +222,346 @@ async fn readiness_probe() -> impl Responder {
     HttpResponse::Ok().body("Ready")
 }
 
-/// Reads in a CSV of u64 values and returns an iterator over the values.
+/// The values of a block list as they are read, with the values that aren't heights as errors.
+#[cfg(not(feature = "ORCHESTRATED"))]
+type BlockList = async_channel::Receiver<Result<u64, String>>;
+
+/// How many values of a block list are read ahead of the heights being indexed.
 #[cfg(not(feature = "ORCHESTRATED"))]
-pub fn read_block_list_csv(file_path: &Path) -> Box<dyn Iterator<Item = u64>> {
+const BLOCK_LIST_READ_AHEAD: usize = 1024;
+
+/// Opens a CSV of u64 values, with headers if its first line doesn't seem to hold values.
+#[cfg(not(feature = "ORCHESTRATED"))]
+fn open_block_list_csv(file_path: &Path) -> csv::Result<csv::Reader<File>> {
     // determine if the first line of the csv seems like a header
     let has_headers = {
-        let file = File::open(file_path).expect("file exists");
+        let file = File::open(file_path)?;
         let mut buf_reader = BufReader::new(file);
         let mut first_line = String::new();
-        buf_reader
-            .read_line(&mut first_line)
-            .expect("file is readable");
+        buf_reader.read_line(&mut first_line)?;
         first_line
             .split(',')
-            .all(|field| field.parse::<u64>().is_err())
+            .all(|field| field.trim().parse::<u64>().is_err())
             && first_line.trim().parse::<u64>().is_err()
     };
 
     // create the csv reader with the apparent header setting
-    let rdr = csv::ReaderBuilder::new()
+    csv::ReaderBuilder::new()
         .has_headers(has_headers)
+        .flexible(true)
         .from_path(file_path)
-        .expect("csv exists and is readable");
-
-    // read the csv as string values
-    let raw_records: Vec<csv::StringRecord> = rdr
-        .into_records()
-        .map(|rec_result| rec_result.unwrap())
-        .collect();
-
-    // convert the strings to integers
-    let parsed_records: Vec<Vec<u64>> = raw_records
-        .into_iter()
-        .map(|record| {
-            record
-                .into_iter()
-                .map(|val| val.parse::<u64>().expect("value in record is a u64"))
-                .collect::<Vec<u64>>()
-        })
-        .collect();
+}
 
-    // flatten all records into a single vector
-    let values: Vec<u64> = parsed_records.into_iter().flatten().collect();
+/// Reads a CSV of u64 values and returns a stream of the values, in the order of the file.
+/// The file is read on the blocking thread pool as the stream is consumed (at most
+/// `BLOCK_LIST_READ_AHEAD` values ahead) rather than all at once.  Values that aren't u64s
+/// (or records that can't be read) are returned as errors.
+#[cfg(not(feature = "ORCHESTRATED"))]
+pub async fn read_block_list_csv(file_path: &Path) -> Result<BlockList, Box<dyn Error>> {
+    let file_path = file_path.to_path_buf();
+    let rdr = tokio::task::spawn_blocking(move || open_block_list_csv(&file_path)).await??;
+    let (sender, values) = async_channel::bounded(BLOCK_LIST_READ_AHEAD);
+
+    // parse the values of each record as they are read, until the stream is dropped
+    tokio::task::spawn_blocking(move || {
+        for record in rdr.into_records() {
+            let record_values = match record {
+                Ok(record) => record
+                    .iter()
+                    .filter(|value| !value.trim().is_empty())
+                    .map(|value| value.trim().parse::<u64>().map_err(|_| value.to_string()))
+                    .collect::<Vec<Result<u64, String>>>(),
+                Err(e) => vec![Err(e.to_string())],
+            };
+            for value in record_values {
+                if sender.send_blocking(value).is_err() {
+                    return;
+                }
+            }
+        }
+    });
 
-    // return an iterator over the values
-    let values_iter = values.into_iter();
+    Ok(values)
+}
 
-    Box::new(values_iter)
+/// Returns the names of the blockchains compiled into this binary.
+fn available_chains() -> Vec<&'static str> {
+    vec![
+        #[cfg(feature = "APTOS")]
+        AptosConfig::NAME,
+    ]
 }
 
-/// Opens the directory of indexed block numbers and determine where to pick up from.
-#[cfg(not(feature = "ORCHESTRATED"))]
-pub fn pick_up_from_previous_range(
-    start: u64,
-    end: Option<u64>,
-    is_reverse: bool,
-) -> (u64, Option<u64>) {
-    let mut range_start = start;
-    let mut range_end = end;
-    // check for the index logs to pick up from a terminated run.
-    //      - create the directory if it doesn't exist.
-    let indexed_blocks_dir = Path::new("./indexed_blocks/");
-    if indexed_blocks_dir.exists() {
-        let indexed_blocks_files = read_dir(indexed_blocks_dir).unwrap();
-        // the order that the files are iterated through is platform-dependent,
-        // so we treat it as an unsorted list.
-        for file in indexed_blocks_files {
-            let path = file.unwrap().path();
-            let filename = path.file_name().unwrap().to_str().unwrap();
-
-            if is_reverse {
-                let previous_start: u64 = filename.parse().expect("file name is a u64");
-                match range_end {
-                    Some(e) => {
-                        if previous_start <= e {
-                            range_end = Some(previous_start - 1);
-                        }
-                    }
-                    None => {
-                        if previous_start <= range_start {
-                            range_start = previous_start - 1;
-                        }
-                    }
-                }
-            } else {
-                let previous_end: u64 = filename.parse().expect("file name is a u64");
-                if previous_end >= range_start {
-                    range_start = previous_end + 1;
-                    info!("setting range_start to {range_start}");
+/// Creates the blockchain config selected by `--chain` and runs the command with it.
+#[allow(unused_variables)]
+async fn run_with_chain(
+    chain: Option<String>,
+    command: Commands,
+    metrics: Option<Metrics>,
+) -> Result<(), Box<dyn Error>> {
+    let available = available_chains();
+    let chain_name = match chain {
+        Some(name) => name,
+        None => match available.as_slice() {
+            [name] => name.to_string(),
+            [] => {
+                return Err(
+                    "no blockchain config is compiled in, see docs/develop.md to add one".into(),
+                )
+            }
+            _ => {
+                return Err(format!(
+                    "--chain is required when multiple blockchains are compiled in ({})",
+                    available.join(", ")
+                )
+                .into())
+            }
+        },
+    };
+
+    match chain_name.as_str() {
+        #[cfg(feature = "APTOS")]
+        AptosConfig::NAME => run(AptosConfig::new().await?, command, metrics).await,
+        other => Err(format!(
+            "unknown or not compiled in blockchain `{}`, expected one of: {}",
+            other,
+            available.join(", ")
+        )
+        .into()),
+    }
+}
+
+/// Runs the command with the given blockchain config.
+async fn run<C: BlockchainConfig>(
+    chain: C,
+    command: Commands,
+    metrics: Option<Metrics>,
+) -> Result<(), Box<dyn Error>> {
+    // NOTE: shared with the workers of the indexing loops, which run as tasks of their own.
+    let chain = Arc::new(chain);
+    match command {
+        #[cfg(feature = "ORCHESTRATED")]
+        Commands::IndexSubscription(args) => {
+            let subscription_arg = args.subscription;
+
+            let gcp_config = match &config::get().gcp.credentials_json_path {
+                Some(key_path) => {
+                    let cred_file = CredentialsFile::new_from_file(key_path.to_owned())
+                        .await
+                        .expect("GCP credentials file exists");
+                    // authenticate using the key file
+                    ClientConfig::default()
+                        .with_credentials(cred_file)
+                        .await
+                        .unwrap()
                 }
+                None => ClientConfig::default().with_auth().await.unwrap(),
+            };
+
+            // Attempt to create the client using the configuration from above
+            let gcp_client = Client::new(gcp_config).await.unwrap();
+            let subscription = gcp_client.subscription(&subscription_arg);
+
+            let publisher = chain.connect_publisher().await?;
+
+            let cur_publisher = publisher.clone();
+
+            // NOTE: the publisher is disconnected (flushing what was published) even when
+            // indexing stops on an error.
+            let result = chain
+                .subscribe_and_extract(subscription, cur_publisher, metrics)
+                .await;
+
+            chain.disconnect_publisher(publisher).await?;
+            result?;
+        }
+        #[cfg(not(feature = "ORCHESTRATED"))]
+        Commands::IndexRange(args) => {
+            let range = IndexRange::new(args.start, args.end, args.reverse)?;
+            let checkpoints = Checkpoints::open(config::get())?;
+
+            let publisher = chain.connect_publisher().await?;
 
-                // ensure that we don't continue indexing beyond the range end, if passed in.
-                if let Some(e) = end {
-                    if previous_end >= e {
-                        panic!("This range has already been indexed. Stopping...");
+            let cur_publisher = publisher.clone();
+
+            // NOTE: the publisher is disconnected (flushing what was published) even when
+            // indexing stops on an error.
+            let result =
+                indexer::index_range(&chain, &range, &checkpoints, cur_publisher, metrics).await;
+
+            chain.disconnect_publisher(publisher).await?;
+            result?;
+        }
+        #[cfg(not(feature = "ORCHESTRATED"))]
+        Commands::IndexList(args) => {
+            let heights = read_block_list_csv(Path::new(&args.list)).await?;
+            let checkpoints = Checkpoints::open(config::get())?;
+            let report_path = args
+                .report
+                .unwrap_or_else(|| PathBuf::from(format!("{}.report.csv", args.list)));
+            let mut report_writer = csv::Writer::from_path(&report_path)?;
+            report_writer.write_record(["height", "status", "error"])?;
+
+            let publisher = chain.connect_publisher().await?;
+
+            let cur_publisher = publisher.clone();
+
+            // NOTE: the publisher is disconnected (flushing what was published) even when
+            // indexing stops on an error.
+            let result = indexer::extract_list(
+                &chain,
+                heights,
+                args.concurrency,
+                &checkpoints,
+                cur_publisher,
+                metrics,
+                |height, outcome| {
+                    let error = match outcome {
+                        blockchain_etl_indexer::blockchain_config::BlockOutcome::Failed(reason) => {
+                            reason.as_str()
+                        }
+                        _ => "",
+                    };
+                    if let Err(e) = report_writer.write_record([
+                        height.to_string().as_str(),
+                        outcome.status(),
+                        error,
+                    ]) {
+                        error!(
+                            "Failed to write the outcome of {} to the report: {}",
+                            height, e
+                        );
                     }
+                },
+            )
+            .await;
+
+            chain.disconnect_publisher(publisher).await?;
+            report_writer.flush()?;
+            let report = result?;
+            info!(
+                "Indexed the list {}: {} indexed, {} missing, {} failed, {} invalid values. Report written to {:?}",
+                args.list, report.indexed, report.missing, report.failed, report.invalid, report_path
+            );
+        }
+        #[cfg(not(feature = "ORCHESTRATED"))]
+        Commands::IndexFollow(args) => {
+            let checkpoints = Checkpoints::open(config::get())?;
+
+            let publisher = chain.connect_publisher().await?;
+
+            let cur_publisher = publisher.clone();
+
+            // NOTE: the publisher is disconnected (flushing what was published) even when
+            // indexing stops on an error.
+            let result =
+                indexer::index_follow(&chain, args.start, &checkpoints, cur_publisher, metrics)
+                    .await;
+
+            chain.disconnect_publisher(publisher).await?;
+            result?;
+        }
+        Commands::FindGaps(args) => {
+            let checkpoints = Checkpoints::open(config::get())?;
+            let mut published = checkpoints.completed()?;
+            for dir in args.scan.iter() {
+                published.extend(&gaps::scan_output_dir(dir, &args.height_fields)?);
+            }
+            let end = args.end.or(published.end()).unwrap_or(args.start);
+            let missing = published.gaps(args.start, end);
+            for (start, end) in missing.iter() {
+                println!("{}\t{}", start, end);
+            }
+            let count = gaps::write_list(&args.list, &missing)?;
+            info!(
+                "Found {} missing heights in {} gaps of [{}, {}) for {}, written to {:?}",
+                count,
+                missing.len(),
+                args.start,
+                end,
+                checkpoints.output(),
+                args.list
+            );
+
+            if args.backfill && count > 0 {
+                let publisher = chain.connect_publisher().await?;
+
+                let cur_publisher = publisher.clone();
+
+                // NOTE: the publisher is disconnected (flushing what was published) even when
+                // indexing stops on an error.
+                let result = indexer::extract_list(
+                    &chain,
+                    futures::stream::iter(gaps::gap_heights(&missing).map(Ok)),
+                    args.concurrency,
+                    &checkpoints,
+                    cur_publisher,
+                    metrics,
+                    |_, _| (),
+                )
+                .await;
+
+                chain.disconnect_publisher(publisher).await?;
+                let report = result?;
+                info!(
+                    "Backfilled the gaps: {} indexed, {} missing, {} failed",
+                    report.indexed, report.missing, report.failed
+                );
+            }
+        }
+        Commands::SaveRange(args) => {
+            match chain
+                .extract_txs(args.start, args.end, Some(args.outdir.clone()))
+                .await
+            {
+                Ok(_) => info!(
+                    "Successfully saved [{}, {}] to {:?}",
+                    args.start, args.end, args.outdir
+                ),
+                Err(error) => {
+                    error!(
+                        "Failed to save range [{},{}] due to error: {:?}",
+                        args.start, args.end, error
+                    );
+                    panic!(
+                        "Failed to save range [{},{}] due to error: {:?}",
+                        args.start, args.end, error
+                    );
                 }
+            };
+        }
+        Commands::CreateTestSet(args) => {
+            let pdir = args.dir.unwrap_or(TEST_EXAMPLE_DIRECTORY.into());
+            let dir = pdir.join(format!("{}_{}_{}", &args.name, &args.start, &args.end));
+
+            match chain
+                .create_test_data(args.start, args.end, &dir, None)
+                .await
+            {
+                Ok(()) => info!("Created test data: {:?}", dir),
+                Err(err) => error!("Failed to create test data: {}", err),
             }
         }
-    } else {
-        create_dir(indexed_blocks_dir).expect("filesystem is writable");
+        Commands::ReplayDeadLetters(args) => {
+            let dir = args
+                .dir
+                .or_else(|| config::get().dead_letter.dir.as_ref().map(PathBuf::from))
+                .ok_or("a dead-letter directory should be given or configured (DEAD_LETTER_DIR)")?;
+            let report = dead_letter::replay(&dir, args.table.as_deref(), &C::chain_info()).await?;
+            info!(
+                "Replayed {} dead letters from {:?}, {} failed again and were kept",
+                report.replayed, dir, report.failed
+            );
+        }
+        Commands::Config(_) => unreachable!("handled before the blockchain config is created"),
     }
 
-    (range_start, range_end)
+    Ok(())
 }
 
 /// Main function for the ETL-Core code.  Performs the following startup-tasks:
 /// - Setup the logging system
-/// - Loads in the .env
+/// - Loads and validates the configuration (config file, .env and CLI flags)
 /// - Set up the RequestBuilder (reuse the same client)
 /// - Setup the Prometheus metrics system
 /// - Setup the stream connection (whether it is pubsub, rabbitmq, etc)
@@ -250,42 +572,47 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Set up the logger
     env_logger::init();
 
-    // Loads the .env file, raises an error otherwise
-    dotenvy::dotenv().expect(".env file is required");
+    let cli = Cli::parse();
 
-    // Set up the RequestBuilder to be used in the ETL-Core code.
-    // NOTE: the reqwest docs suggest reusing a single client, rather than using multiple
-    // the endpoint and request headers will be the same for every request, so
-    // we will clone this request builder, rather than constructing a new one every time.
+    // Loads the configuration, reporting every problem with it at once
+    if let Commands::Config(ConfigArgs {
+        command: ConfigCommands::Check,
+    }) = cli.command
+    {
+        let (config, errors) = Config::resolve(&cli.config);
+        print!("{}", config.to_masked_toml());
+        if errors.is_empty() {
+            return Ok(());
+        }
+        eprint!("{}", config::ConfigErrors(errors));
+        std::process::exit(1);
+    }
+    let config = Config::load(&cli.config).unwrap_or_else(|errors| {
+        eprint!("{}", errors);
+        std::process::exit(1);
+    });
+    config::init(config);
+
+    // Set up the requests to the node, used in the ETL-Core code.
+    // NOTE: the reqwest docs suggest reusing a single client, rather than using multiple,
+    // so the request config shares one client across every request (see `source::config`).
     #[cfg(feature = "SOLANA")]
-    let request_builder = {
-        let endpoint = dotenvy::var("ENDPOINT")
-            .expect("ENDPOINT should exist in .env file")
-            .parse::<String>()
-            .unwrap();
-        let connection_timeout = std::time::Duration::from_secs(constants::CONNECTION_TIMEOUT);
-        let client_builder = reqwest::Client::builder().connect_timeout(connection_timeout);
-
-        let client = client_builder.build().unwrap();
-        let headers = request::get_headers();
-        client.post(endpoint).headers(headers)
-    };
-
-    let cli = Cli::parse();
+    let request_config =
+        blockchain_etl_indexer::source::config::RequestConfig::from_config(&config::get().source)?;
 
     // metrics setup
-    // - Reads in the metrics address and port from the .env file
+    // - Reads in the metrics address and port from the configuration
     // - Sets up th prometheus metrics server
     #[cfg(feature = "METRICS")]
     let (metrics, metrics_srv_handle) = {
         let (metrics_address, metrics_port) = {
+            let metrics_config = &config::get().metrics;
             // env:metrics_address = Address for connecting to the Prometheus server
-            let metrics_address = "127.0.0.1";
+            let metrics_address = metrics_config.address.as_str();
             // env:metrics_port = Port for connecting to the Prometheus server
-            let metrics_port = dotenvy::var("METRICS_PORT")
-                .expect("METRICS_PORT should exist in .env file")
-                .parse::<u16>()
-                .unwrap();
+            let metrics_port = metrics_config
+                .port
+                .expect("validated when the configuration is loaded");
             (metrics_address, metrics_port)
         };
 
@@ -294,22 +621,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .build()
             .unwrap();
 
-        let request_count =
-            prometheus::IntCounter::new("request_count", "Total number of requests for all APIs")
-                .unwrap();
-        let failed_request_count = prometheus::IntCounter::new(
-            "failed_request_count",
-            "Total number of request failures for all APIs",
-        )
-        .unwrap();
-        prometheus
-            .registry
-            .register(Box::new(request_count.clone()))
-            .unwrap();
-        prometheus
-            .registry
-            .register(Box::new(failed_request_count.clone()))
-            .unwrap();
+        let metrics = Metrics::register(&prometheus.registry)?;
+        blockchain_etl_indexer::metrics::set_global(metrics.clone());
 
         let srv = HttpServer::new(move || App::new().wrap(prometheus.clone()).service(index))
             .bind((metrics_address, metrics_port))?
@@ -319,10 +632,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
         tokio::task::spawn(srv);
 
-        let metrics = Metrics {
-            request_count,
-            failed_request_count,
-        };
         (Some(metrics), srv_handle)
     };
 
@@ -332,12 +641,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Kubernetes needs to be able to make health checks, so we spawn web servers for this here.
     #[cfg(feature = "ORCHESTRATED")]
     let health_check_srv_handle = {
-        let health_checks_port = dotenvy::var("HEALTH_CHECKS_PORT")
-            .expect("HEALTH_CHECKS_PORT should exist in .env file")
-            .parse::<String>()
-            .unwrap();
+        let health_checks_port = config::get()
+            .health_checks
+            .port
+            .expect("validated when the configuration is loaded");
 
-        let health_checks_address = ["0.0.0.0:", &health_checks_port].concat();
+        let health_checks_address = ("0.0.0.0", health_checks_port);
 
         let srv = HttpServer::new(|| {
             App::new()
@@ -352,115 +661,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         srv_handle
     };
 
-    match cli.command {
-        #[cfg(feature = "ORCHESTRATED")]
-        Commands::IndexSubscription(args) => {
-            let subscription_arg = args.subscription;
-
-            let gcp_config = match dotenvy::var("GOOGLE_APPLICATION_CREDENTIALS") {
-                Ok(env_var) => {
-                    let key_path = env_var.parse::<String>().unwrap();
-                    let cred_file = CredentialsFile::new_from_file(key_path.to_owned())
-                        .await
-                        .expect("GCP credentials file exists");
-                    // authenticate using the key file
-                    ClientConfig::default()
-                        .with_credentials(cred_file)
-                        .await
-                        .unwrap()
-                }
-                Err(_) => ClientConfig::default().with_auth().await.unwrap(),
-            };
-
-            // Attempt to create the client using the configuration from above
-            let gcp_client = Client::new(gcp_config).await.unwrap();
-            let subscription = gcp_client.subscription(&subscription_arg);
-
-            let publisher = StreamPublisher::new().await;
-
-            let cur_publisher = publisher.clone();
-
-            blockchain_config::subscribe_and_extract(subscription, cur_publisher, metrics)
-                .await
-                .unwrap();
-
-            #[cfg(feature = "REQUIRES_DISCONNECT")]
-            publisher.disconnect().await;
-        }
-        #[cfg(not(feature = "ORCHESTRATED"))]
-        Commands::IndexRange(args) => {
-            if args.start == 0 && args.end.is_none() && args.reverse {
-                panic!("FATAL: cannot index backwards from genesis");
-            }
-            /*
-                        let (start, opt_end) = pick_up_from_previous_range(args.start, args.end, args.reverse);
-
-                        #[allow(clippy::collapsible_else_if)]
-                        let indexing_range: Box<dyn Iterator<Item = u64>> = if args.reverse {
-                            if let Some(end) = opt_end {
-                                Box::new((start..end).rev())
-                            } else {
-                                Box::new((0..start).rev())
-                            }
-                        } else {
-                            if let Some(end) = opt_end {
-                                Box::new(start..end)
-                            } else {
-                                Box::new(start..)
-                            }
-                        };
-            */
-            let publisher = StreamPublisher::new().await;
-
-            let cur_publisher = publisher.clone();
-
-            blockchain_config::extract_range(
-                args.start,
-                args.end.unwrap(),
-                cur_publisher,
-                metrics,
-                None,
-            )
-            .await
-            .unwrap();
-
-            #[cfg(feature = "REQUIRES_DISCONNECT")]
-            publisher.disconnect().await;
-        }
-        #[cfg(not(feature = "ORCHESTRATED"))]
-        Commands::IndexList(_) => {
-            unreachable!("IndexList not supported")
-        }
-        Commands::SaveRange(args) => {
-            match blockchain_config::extract_txs(args.start, args.end, Some(args.outdir.clone()))
-                .await
-            {
-                Ok(_) => info!(
-                    "Successfully saved [{}, {}] to {:?}",
-                    args.start, args.end, args.outdir
-                ),
-                Err(error) => {
-                    error!(
-                        "Failed to save range [{},{}] due to error: {:?}",
-                        args.start, args.end, error
-                    );
-                    panic!(
-                        "Failed to save range [{},{}] due to error: {:?}",
-                        args.start, args.end, error
-                    );
-                }
-            };
-        }
-        Commands::CreateTestSet(args) => {
-            let pdir = args.dir.unwrap_or(TEST_EXAMPLE_DIRECTORY.into());
-            let dir = pdir.join(format!("{}_{}_{}", &args.name, &args.start, &args.end));
-
-            match blockchain_config::create_test_data(args.start, args.end, &dir, None).await {
-                Ok(()) => info!("Created test data: {:?}", dir),
-                Err(err) => error!("Failed to create test data: {}", err),
-            }
-        }
-    }
+    run_with_chain(cli.chain, cli.command, metrics).await?;
 
     #[cfg(feature = "ORCHESTRATED")]
     health_check_srv_handle.stop(false).await;
@@ -1,4 +1,5 @@
-use prometheus::IntCounter;
+use once_cell::sync::OnceCell;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
 
 /// A wrapper struct around each of our metrics.
 #[derive(Clone)]
@@ -7,4 +8,165 @@ pub struct Metrics {
     pub request_count: IntCounter,
     // Total number of failed requests.
     pub failed_request_count: IntCounter,
+    // Number of retries, by operation (e.g. `apache_kafka publish`).
+    pub retry_count: IntCounterVec,
+    // Number of operations given up on after exhausting their retries, by operation.
+    pub retry_exhausted_count: IntCounterVec,
+    // Number of records written as dead letters, by table.
+    pub dead_letter_count: IntCounterVec,
+    // Number of chain reorganizations seen.
+    pub reorg_count: IntCounter,
+    // Number of blocks replaced by chain reorganizations.
+    pub reorged_block_count: IntCounter,
+    // Number of items waiting in each pipeline queue, by the stage consuming it.
+    pub pipeline_queue_depth: IntGaugeVec,
+    // Number of bytes waiting in each pipeline queue, by the stage consuming it.
+    pub pipeline_queue_bytes: IntGaugeVec,
+    // How far ahead of the release point of the ordered output the workers are, in blocks.
+    pub reorder_ahead_blocks: IntGauge,
+}
+
+impl Metrics {
+    /// Creates every metric and registers it with the registry.
+    pub fn register(registry: &Registry) -> Result<Metrics, prometheus::Error> {
+        let request_count =
+            IntCounter::new("request_count", "Total number of requests for all APIs")?;
+        let failed_request_count = IntCounter::new(
+            "failed_request_count",
+            "Total number of request failures for all APIs",
+        )?;
+        let retry_count = IntCounterVec::new(
+            Opts::new("retry_count", "Number of retried operations"),
+            &["operation"],
+        )?;
+        let retry_exhausted_count = IntCounterVec::new(
+            Opts::new(
+                "retry_exhausted_count",
+                "Number of operations given up on after exhausting their retries",
+            ),
+            &["operation"],
+        )?;
+        let dead_letter_count = IntCounterVec::new(
+            Opts::new(
+                "dead_letter_count",
+                "Number of records that couldn't be published and were dead-lettered",
+            ),
+            &["table"],
+        )?;
+        let reorg_count = IntCounter::new("reorg_count", "Number of chain reorganizations seen")?;
+        let reorged_block_count = IntCounter::new(
+            "reorged_block_count",
+            "Number of blocks replaced by chain reorganizations",
+        )?;
+        let pipeline_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "pipeline_queue_depth",
+                "Number of items waiting for a pipeline stage",
+            ),
+            &["stage"],
+        )?;
+        let pipeline_queue_bytes = IntGaugeVec::new(
+            Opts::new(
+                "pipeline_queue_bytes",
+                "Number of bytes waiting for a pipeline stage",
+            ),
+            &["stage"],
+        )?;
+        let reorder_ahead_blocks = IntGauge::new(
+            "reorder_ahead_blocks",
+            "How far ahead of the release point of the ordered output the workers are",
+        )?;
+        registry.register(Box::new(request_count.clone()))?;
+        registry.register(Box::new(failed_request_count.clone()))?;
+        registry.register(Box::new(retry_count.clone()))?;
+        registry.register(Box::new(retry_exhausted_count.clone()))?;
+        registry.register(Box::new(dead_letter_count.clone()))?;
+        registry.register(Box::new(reorg_count.clone()))?;
+        registry.register(Box::new(reorged_block_count.clone()))?;
+        registry.register(Box::new(pipeline_queue_depth.clone()))?;
+        registry.register(Box::new(pipeline_queue_bytes.clone()))?;
+        registry.register(Box::new(reorder_ahead_blocks.clone()))?;
+        Ok(Metrics {
+            request_count,
+            failed_request_count,
+            retry_count,
+            retry_exhausted_count,
+            dead_letter_count,
+            reorg_count,
+            reorged_block_count,
+            pipeline_queue_depth,
+            pipeline_queue_bytes,
+            reorder_ahead_blocks,
+        })
+    }
+}
+
+/// The metrics of the running indexer, for the code that isn't passed `Option<Metrics>` (e.g.
+/// the publishers and the retry policy).
+static GLOBAL_METRICS: OnceCell<Metrics> = OnceCell::new();
+
+/// Makes the metrics available through `global()`.  Called once the metrics server is set up.
+pub fn set_global(metrics: Metrics) {
+    let _ = GLOBAL_METRICS.set(metrics);
+}
+
+/// Returns the metrics of the running indexer, if the metrics server is enabled.
+pub fn global() -> Option<&'static Metrics> {
+    GLOBAL_METRICS.get()
+}
+
+/// Counts a retry of the operation.
+pub fn record_retry(operation: &str) {
+    if let Some(metrics) = global() {
+        metrics.retry_count.with_label_values(&[operation]).inc();
+    }
+}
+
+/// Counts an operation given up on after exhausting its retries.
+pub fn record_retries_exhausted(operation: &str) {
+    if let Some(metrics) = global() {
+        metrics
+            .retry_exhausted_count
+            .with_label_values(&[operation])
+            .inc();
+    }
+}
+
+/// Counts records written as dead letters.
+pub fn record_dead_letters(table: &str, count: usize) {
+    if let Some(metrics) = global() {
+        metrics
+            .dead_letter_count
+            .with_label_values(&[table])
+            .inc_by(count as u64);
+    }
+}
+
+/// Counts a chain reorganization, and the blocks it replaced.
+pub fn record_reorg(depth: usize) {
+    if let Some(metrics) = global() {
+        metrics.reorg_count.inc();
+        metrics.reorged_block_count.inc_by(depth as u64);
+    }
+}
+
+/// Sets the items and bytes waiting for a pipeline stage.
+pub fn record_queue_depth(stage: &str, items: usize, bytes: usize) {
+    if let Some(metrics) = global() {
+        metrics
+            .pipeline_queue_depth
+            .with_label_values(&[stage])
+            .set(items as i64);
+        metrics
+            .pipeline_queue_bytes
+            .with_label_values(&[stage])
+            .set(bytes as i64);
+    }
+}
+
+/// Sets how far ahead of the release point of the ordered output the workers are.
+pub fn record_reorder_ahead(blocks: u64) {
+    if let Some(metrics) = global() {
+        metrics.reorder_ahead_blocks.set(blocks as i64);
+    }
 }
@@ -1,115 +1,675 @@
 //! This module contains implementation details for
-//! StreamPublisherConnection when the `APACHE_KAFKA`
-//! feature is enabled.  This allows StreamPublisherConnection
+//! the Apache Kafka output backend, enabled with the `APACHE_KAFKA`
+//! feature.  This allows StreamPublisherConnection
 //! to connect and publish to Apache Kafka.
+//!
+//! Records are published to every partition of the topic, keyed and routed as described in
+//! `kafka_partition`.  Each record carries headers describing it (`table`, `message_type`,
+//! `schema_version`, `block_number`, `chain_id`, `indexer_version` and `content_encoding`),
+//! so consumers reading several tables from one topic can dispatch on them without decoding
+//! the payload.
+//!
+//! Each table has a `librdkafka` producer (through `rdkafka`), which aggregates records into
+//! batches of up to `KAFKA_BATCH_MAX_BYTES`, waiting `KAFKA_LINGER_MS` for more records, and
+//! compresses them with the table's `KAFKA_COMPRESSION`.  Producers are thread-safe, so the
+//! clones of a publisher share it.  Publishing only hands the records to the producer: their
+//! deliveries are awaited by `flush()`, once the block is published (see
+//! `BlockchainConfig::finish_block`).
+//!
+//! The client bootstraps from `KAFKA_BROKERS`, and can authenticate with SASL PLAIN or SCRAM
+//! and connect over TLS, with custom certificate authorities and client certificates.
+//!
+//! NOTE: records are published at least once: the tables of a block interrupted by a crash are
+//! published again on restart (see the output README), unless `KAFKA_TRANSACTIONAL` is set, in
+//! which case the records of each block are published in a transaction (see
+//! `kafka_transaction`).
 
 use super::environment::*;
-use super::publish::{StreamPublisherConnection, StreamPublisherConnectionClient};
+use super::error::PublishError;
+use super::kafka_partition::{self, PartitionKey, Partitioner};
+use super::kafka_transaction::KafkaTransaction;
+use super::publish::{ChainInfo, Publisher, RecordContext};
+use crate::config::{self, KafkaConfig};
+use async_trait::async_trait;
 use chrono::Utc;
 use log::{info, warn};
-use prost::Message;
+use prost::{Message, Name};
+use serde::Serialize;
 use std::collections::BTreeMap;
-use std::sync::Arc;
-use std::time::{self, Duration};
-use tokio::time::sleep;
-
-use rskafka::{
-    client::{
-        partition::UnknownTopicHandling,
-        producer::{aggregator::RecordAggregator, BatchProducer, BatchProducerBuilder},
-        ClientBuilder,
-    },
-    record::Record,
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rdkafka::{
+    config::ClientConfig,
+    error::{KafkaError, RDKafkaErrorCode},
+    message::{Header, OwnedHeaders},
+    producer::{DeliveryFuture, FutureProducer, FutureRecord, Producer},
+    util::Timeout,
 };
 
-//use amqprs::channel::Channel;
-
-/// Connects to Apache Kafka.
-/// Expects the following parameters to be stored in the .env file:
-/// - `KAFKA_ADDRESS`
-/// - `KAFKA_PORT`
-pub async fn connect(queue_name: &str) -> StreamPublisherConnection {
-    // Extract necessary information from the .env from the queue
-    let topic_name = dotenvy::var(queue_name)
-        .unwrap_or_else(|_| panic!("{} should exist in .env file", queue_name))
-        .parse::<String>()
-        .unwrap();
-
-    let address = get_kafka_addr();
-    let port = get_kafka_port();
-    let connection = format!("{}:{}", address, port);
-    info!("Creating kafka environment...");
-    let client = ClientBuilder::new(vec![connection]).build().await.unwrap();
-    let partition_client = Arc::new(
-        client
-            .partition_client(topic_name.clone(), 0, UnknownTopicHandling::Retry)
-            .await
-            .unwrap(),
+/// The name of this backend, used in errors.
+pub(super) const BACKEND: &str = "apache_kafka";
+
+/// The encoding of the records' payload, set in the `content_encoding` header.
+const CONTENT_ENCODING: &str = "application/x-protobuf";
+
+/// How long requesting the partitions of a topic may take.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most records a publisher hands to the producer before it waits for their deliveries,
+/// like the default `queue.buffering.max.messages` of `librdkafka`.
+const MAX_PENDING_DELIVERIES: usize = 100_000;
+
+/// The delivery of a record handed to the producer, with the record to publish it again if
+/// the delivery fails.
+type PendingDelivery = (DeliveryFuture, RoutedRecord);
+
+/// A record that couldn't be delivered, with the reason.
+pub(super) type FailedDelivery = (RoutedRecord, PublishError);
+
+/// A connection to an Apache Kafka topic.
+pub struct ApacheKafkaPublisher {
+    /// The producer of the topic, shared by the clones of the publisher.
+    pub producer: FutureProducer,
+    /// The name of the Kafka topic.
+    pub topic_name: String,
+    /// The number of partitions of the topic.
+    pub partitions: usize,
+    /// The headers shared by every record of the table.
+    pub headers: Arc<BTreeMap<String, Vec<u8>>>,
+    /// What the records are keyed by, if anything.
+    pub partition_key: Option<Arc<PartitionKey>>,
+    /// Routes the records to the partitions.
+    pub partitioner: Arc<dyn Partitioner>,
+    /// Whether the records are produced in a transaction (see `with_transaction`).
+    transactional: bool,
+    /// The deliveries of the records handed to the producer since the last flush.
+    /// Not carried over by `clone()`, so each worker only waits for its own records.
+    pending: Arc<Mutex<Vec<PendingDelivery>>>,
+    /// The records that couldn't be delivered since the last flush, returned by the next one.
+    /// Not carried over by `clone()`, like `pending`.
+    failed: Arc<Mutex<Vec<FailedDelivery>>>,
+}
+
+impl Clone for ApacheKafkaPublisher {
+    fn clone(&self) -> ApacheKafkaPublisher {
+        ApacheKafkaPublisher {
+            producer: self.producer.clone(),
+            topic_name: self.topic_name.clone(),
+            partitions: self.partitions,
+            headers: self.headers.clone(),
+            partition_key: self.partition_key.clone(),
+            partitioner: self.partitioner.clone(),
+            transactional: self.transactional,
+            pending: Arc::default(),
+            failed: Arc::default(),
+        }
+    }
+}
+
+/// A record routed to a partition, ready to be produced.
+pub(super) struct RoutedRecord {
+    /// The context the record was published with, with the record's own timestamp and its
+    /// message type, to dead-letter it if it can't be delivered.
+    pub(super) context: RecordContext,
+    /// The partition of the topic the record is published to.
+    partition: i32,
+    /// The key of the record, if it is keyed.
+    key: Option<Vec<u8>>,
+    /// The headers of the record.
+    headers: BTreeMap<String, Vec<u8>>,
+    /// The Protocol Buffers encoding of the record.
+    pub(super) payload: Vec<u8>,
+}
+
+/// Connects to Apache Kafka, with a producer for the topic.
+/// Expects the following parameters to be configured (in the configuration file or the .env file):
+/// - `KAFKA_BROKERS`, or `KAFKA_ADDRESS` and `KAFKA_PORT`
+/// - `KAFKA_SASL_USERNAME` and `KAFKA_SASL_PASSWORD`, with `KAFKA_SASL_MECHANISM`
+pub async fn connect(
+    queue_name: &str,
+    chain: &ChainInfo,
+) -> Result<ApacheKafkaPublisher, PublishError> {
+    // Extract necessary information from the configuration for the queue
+    let topic_name = get_queue_name(queue_name)?.clone();
+
+    let kafka = &config::get().kafka;
+    let producer: FutureProducer = producer_config(kafka, kafka.compression(queue_name))?
+        .create()
+        .map_err(|e| classify_error(&e))?;
+
+    let partitions = topic_partitions(&producer, &topic_name).await?;
+    info!(
+        "Publishing to {} partitions of topic {}...",
+        partitions, topic_name
     );
+    Ok(ApacheKafkaPublisher {
+        producer,
+        topic_name,
+        partitions,
+        headers: Arc::new(table_headers(queue_name, chain)),
+        partition_key: PartitionKey::new(&kafka.partition_key, &kafka.key_fields)?.map(Arc::new),
+        partitioner: kafka_partition::partitioner(&kafka.partitioner)?,
+        transactional: false,
+        pending: Arc::default(),
+        failed: Arc::default(),
+    })
+}
+
+/// Returns the settings of a client of the configured brokers, with the security settings.
+pub fn client_config(kafka: &KafkaConfig) -> Result<ClientConfig, PublishError> {
+    let brokers = get_kafka_brokers()?;
+    info!("Creating kafka environment with brokers {:?}...", brokers);
+    let mut client_config = ClientConfig::new();
+    client_config.set("bootstrap.servers", brokers.join(","));
+    security_config(kafka, &mut client_config)?;
+    Ok(client_config)
+}
+
+/// Returns the settings of a producer batching records as configured, and compressing them
+/// with `compression`.
+pub(super) fn producer_config(
+    kafka: &KafkaConfig,
+    compression_name: &str,
+) -> Result<ClientConfig, PublishError> {
+    let mut client_config = client_config(kafka)?;
+    client_config
+        .set("linger.ms", kafka.linger_ms.to_string())
+        .set("batch.size", kafka.batch_max_bytes.to_string())
+        .set("compression.type", compression(compression_name)?);
+    Ok(client_config)
+}
+
+/// Returns the number of partitions of a topic.
+async fn topic_partitions(
+    producer: &FutureProducer,
+    topic_name: &str,
+) -> Result<usize, PublishError> {
+    let (producer, topic) = (producer.clone(), topic_name.to_string());
+    let metadata = tokio::task::spawn_blocking(move || {
+        producer
+            .client()
+            .fetch_metadata(Some(&topic), METADATA_TIMEOUT)
+    })
+    .await
+    .map_err(|e| PublishError::retryable(BACKEND, e))?
+    .map_err(|e| classify_error(&e))?;
+    // NOTE: a topic that doesn't exist yet may be created by the broker on first use, with a
+    // single partition.
+    let partitions = metadata
+        .topics()
+        .iter()
+        .find(|topic| topic.name() == topic_name && topic.error().is_none())
+        .map(|topic| topic.partitions().len())
+        .unwrap_or(0);
+    if partitions == 0 {
+        warn!(
+            "Topic {} was not found, publishing to partition 0...",
+            topic_name
+        );
+        return Ok(1);
+    }
+    Ok(partitions)
+}
+
+/// Returns the `librdkafka` name of the SASL mechanism named `name`, one of
+/// `config::KAFKA_SASL_MECHANISMS`.
+fn sasl_mechanism(name: &str) -> Result<&'static str, PublishError> {
+    match name.to_lowercase().as_str() {
+        "plain" => Ok("PLAIN"),
+        "scram-sha-256" => Ok("SCRAM-SHA-256"),
+        "scram-sha-512" => Ok("SCRAM-SHA-512"),
+        _ => Err(PublishError::configuration(
+            BACKEND,
+            format!("unsupported SASL mechanism: {}", name),
+        )),
+    }
+}
+
+/// Sets the SASL settings, if `KAFKA_SASL_MECHANISM` is set, and the TLS settings, if
+/// `KAFKA_TLS` is set.  The brokers are verified against `KAFKA_TLS_CA_CERT`, or the
+/// certificate authorities of the system if it isn't set, and the client authenticates with
+/// `KAFKA_TLS_CLIENT_CERT` and `KAFKA_TLS_CLIENT_KEY`, if they are set.
+fn security_config(
+    kafka: &KafkaConfig,
+    client_config: &mut ClientConfig,
+) -> Result<(), PublishError> {
+    let protocol = match (&kafka.sasl_mechanism, kafka.tls) {
+        (Some(_), true) => "SASL_SSL",
+        (Some(_), false) => "SASL_PLAINTEXT",
+        (None, true) => "SSL",
+        (None, false) => "PLAINTEXT",
+    };
+    client_config.set("security.protocol", protocol);
+
+    if let Some(mechanism) = &kafka.sasl_mechanism {
+        let credential = |value: &Option<String>, key: &str| {
+            value.clone().ok_or_else(|| {
+                PublishError::configuration(BACKEND, format!("{} should be configured", key))
+            })
+        };
+        client_config
+            .set("sasl.mechanism", sasl_mechanism(mechanism)?)
+            .set(
+                "sasl.username",
+                credential(&kafka.sasl_username, "kafka.sasl_username")?,
+            )
+            .set(
+                "sasl.password",
+                credential(&kafka.sasl_password, "kafka.sasl_password")?,
+            );
+    }
+
+    if kafka.tls {
+        client_config.set(
+            "ssl.ca.location",
+            kafka.tls_ca_cert.as_deref().unwrap_or("probe"),
+        );
+        if let (Some(cert_path), Some(key_path)) = (&kafka.tls_client_cert, &kafka.tls_client_key) {
+            client_config
+                .set("ssl.certificate.location", cert_path)
+                .set("ssl.key.location", key_path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `librdkafka` name of the compression codec named `name`, one of
+/// `config::KAFKA_COMPRESSIONS`.
+fn compression(name: &str) -> Result<&'static str, PublishError> {
+    match name {
+        "none" => Ok("none"),
+        "gzip" => Ok("gzip"),
+        "lz4" => Ok("lz4"),
+        "snappy" => Ok("snappy"),
+        "zstd" => Ok("zstd"),
+        _ => Err(PublishError::configuration(
+            BACKEND,
+            format!("unknown compression: {}", name),
+        )),
+    }
+}
+
+/// Classifies an error returned by the Kafka client.  Records the broker rejects are fatal,
+/// missing topics, permissions and invalid settings are configuration errors, anything else
+/// (e.g. a broker that is unreachable or busy) is retryable.
+pub(super) fn classify_error(error: &KafkaError) -> PublishError {
+    if matches!(
+        error,
+        KafkaError::ClientConfig(..) | KafkaError::ClientCreation(_)
+    ) {
+        return PublishError::configuration(BACKEND, error);
+    }
+    match error.rdkafka_error_code() {
+        Some(
+            RDKafkaErrorCode::MessageSizeTooLarge
+            | RDKafkaErrorCode::InvalidMessageSize
+            | RDKafkaErrorCode::InvalidMessage
+            | RDKafkaErrorCode::InvalidRecord,
+        ) => PublishError::fatal(BACKEND, error),
+        Some(
+            RDKafkaErrorCode::UnknownTopicOrPartition
+            | RDKafkaErrorCode::UnknownTopic
+            | RDKafkaErrorCode::TopicAuthorizationFailed
+            | RDKafkaErrorCode::ClusterAuthorizationFailed
+            | RDKafkaErrorCode::Authentication
+            | RDKafkaErrorCode::SaslAuthenticationFailed,
+        ) => PublishError::configuration(BACKEND, error),
+        _ => PublishError::retryable(BACKEND, error),
+    }
+}
+
+/// Returns the headers shared by every record of a table, with the name of the `chain` as
+/// `chain_id` unless `KAFKA_CHAIN_ID` is set.
+fn table_headers(queue_name: &str, chain: &ChainInfo) -> BTreeMap<String, Vec<u8>> {
+    let kafka = &config::get().kafka;
+    let chain_id = kafka.chain_id.as_deref().unwrap_or(chain.name);
+    let headers = [
+        ("table", config::table_name(queue_name)),
+        ("schema_version", kafka.schema_version.clone()),
+        ("chain_id", String::from(chain_id)),
+        ("indexer_version", String::from(env!("CARGO_PKG_VERSION"))),
+        ("content_encoding", String::from(CONTENT_ENCODING)),
+    ];
+    headers
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.into_bytes()))
+        .collect()
+}
 
-    StreamPublisherConnection {
-        client: StreamPublisherConnectionClient::ApacheKafka(partition_client),
-        queue_name: topic_name,
-        producer: None,
-    }
-}
-
-/// creates a kafka record object using the bytes
-fn prepare_message(serialized_message: Vec<u8>) -> Record {
-    // some notes:
-    // 1. we're setting the timestamp here, though it might be slightly better to set the timestamp earlier on. in reality, the difference in the timestamp would be a only a few milliseconds, if even that.
-    // 2. we're not using any headers or key. but this could change in the future
-    Record {
-        key: None,
-        value: Some(serialized_message),
-        headers: BTreeMap::new(),
-        timestamp: Utc::now(),
-    }
-}
-
-/// Publishes a record to apache kafka.
-/// Each time publishing fails, the sleep time is increased by 1 second.
-async fn publish_with_backoff(publisher: &BatchProducer<RecordAggregator>, message: Record) {
-    let mut pub_res = publisher.produce(message.clone()).await;
-    let mut backoff = 0;
-    while pub_res.is_err() {
-        info!("Message publish result: {:?}", pub_res);
-        match pub_res {
-            Ok(_) => break,
-            Err(_) => {
-                warn!("publish failed for publisher: {:?}", publisher);
-                let seconds = time::Duration::from_secs(backoff);
-                sleep(seconds).await;
-                backoff += 1;
-                pub_res = publisher.produce(message.clone()).await;
+/// Converts the headers of a record to Kafka headers.
+fn kafka_headers(headers: &BTreeMap<String, Vec<u8>>) -> OwnedHeaders {
+    headers
+        .iter()
+        .fold(OwnedHeaders::new(), |kafka_headers, (name, value)| {
+            kafka_headers.insert(Header {
+                key: name,
+                value: Some(value),
+            })
+        })
+}
+
+impl ApacheKafkaPublisher {
+    /// Returns the publisher routing records with `partitioner` instead of the configured one,
+    /// for blockchains that partition their topics their own way.
+    pub fn with_partitioner(self, partitioner: Arc<dyn Partitioner>) -> ApacheKafkaPublisher {
+        ApacheKafkaPublisher {
+            partitioner,
+            ..self
+        }
+    }
+
+    /// Returns the publisher producing its records with the producer of a transaction, so
+    /// they are only visible to consumers reading committed records once it is committed.
+    pub fn with_transaction(&self, transaction: &KafkaTransaction) -> ApacheKafkaPublisher {
+        ApacheKafkaPublisher {
+            producer: transaction.producer().clone(),
+            transactional: true,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the partition, key and headers of a message, the `index`th of its batch.
+    fn route<T>(
+        &self,
+        context: &RecordContext,
+        index: usize,
+        msg: &T,
+    ) -> Result<RoutedRecord, PublishError>
+    where
+        T: Message + Name + Serialize,
+    {
+        let key = self
+            .partition_key
+            .as_ref()
+            .and_then(|partition_key| partition_key.key(context, msg));
+        let message_type = context.message_type::<T>();
+        let mut headers = (*self.headers).clone();
+        headers.insert(
+            String::from("message_type"),
+            message_type.clone().into_bytes(),
+        );
+        if let Some(block_number) = context.block_number {
+            headers.insert(
+                String::from("block_number"),
+                block_number.to_string().into_bytes(),
+            );
+        }
+        let partition = self.partitioner.partition(key.as_deref(), self.partitions);
+        if partition >= self.partitions {
+            return Err(PublishError::configuration(
+                BACKEND,
+                format!(
+                    "the partitioner chose partition {} out of {}",
+                    partition, self.partitions
+                ),
+            ));
+        }
+        Ok(RoutedRecord {
+            context: RecordContext {
+                name: context.name.clone(),
+                timestamps: context.timestamps.get(index).into_iter().copied().collect(),
+                block_number: context.block_number,
+                message_type: Some(message_type),
+            },
+            partition: partition as i32,
+            key,
+            headers,
+            payload: msg.encode_to_vec(),
+        })
+    }
+
+    /// Returns the Kafka record of a routed record.
+    fn kafka_record<'a>(&'a self, record: &'a RoutedRecord) -> FutureRecord<'a, Vec<u8>, Vec<u8>> {
+        // NOTE: we're setting the timestamp here, though it might be slightly better to set
+        // the timestamp earlier on.  In reality, the difference in the timestamp would only be
+        // a few milliseconds, if even that.
+        let kafka_record = FutureRecord::to(&self.topic_name)
+            .partition(record.partition)
+            .payload(&record.payload)
+            .headers(kafka_headers(&record.headers))
+            .timestamp(Utc::now().timestamp_millis());
+        match &record.key {
+            Some(key) => kafka_record.key(key),
+            None => kafka_record,
+        }
+    }
+
+    /// Hands a record to the producer, keeping its delivery to be awaited by `flush()`.  Waits
+    /// for the pending deliveries first if there are `MAX_PENDING_DELIVERIES` of them (the
+    /// records that failed are returned by the next flush).  While the queue of the producer is
+    /// full, the record is handed over again according to the configured retry policy, until it
+    /// gives up with `PublishError::Exhausted`.
+    async fn enqueue(&self, record: RoutedRecord) -> Result<(), PublishError> {
+        if self.pending.lock().unwrap().len() >= MAX_PENDING_DELIVERIES {
+            self.await_deliveries().await;
+        }
+        // NOTE: the producer is shared by the workers, so its queue can be full of their
+        // records rather than ours.
+        let delivery = config::get()
+            .retry
+            .run("apache_kafka enqueue", || async {
+                self.producer
+                    .send_result(self.kafka_record(&record))
+                    .map_err(|(e, _)| match e.rdkafka_error_code() {
+                        Some(RDKafkaErrorCode::QueueFull) => PublishError::retryable(BACKEND, e),
+                        _ => classify_error(&e),
+                    })
+            })
+            .await
+            .map_err(PublishError::from)?;
+        self.pending.lock().unwrap().push((delivery, record));
+        Ok(())
+    }
+
+    /// Publishes a record again and waits until it is delivered, retrying retryable failures
+    /// according to the configured retry policy.
+    async fn publish_with_backoff(&self, record: &RoutedRecord) -> Result<(), PublishError> {
+        config::get()
+            .retry
+            .run("apache_kafka publish", || async {
+                self.producer
+                    .send(self.kafka_record(record), Timeout::Never)
+                    .await
+                    .map(|_| ())
+                    .map_err(|(e, _)| classify_error(&e))
+            })
+            .await
+            .map_err(PublishError::from)
+    }
+
+    /// Waits until the records handed to the producer are delivered, keeping the ones that
+    /// couldn't be in `failed`.  Records whose delivery failed with a retryable error are
+    /// published again, according to the configured retry policy, unless they are published in
+    /// a transaction: the block is then retried in a new transaction once this one is aborted.
+    async fn await_deliveries(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (delivery, record) in pending {
+            let error = match delivery.await {
+                Ok(Ok(_)) => continue,
+                Ok(Err((e, _))) => classify_error(&e),
+                Err(_) => PublishError::retryable(BACKEND, "the delivery of a record was canceled"),
+            };
+            let delivered = match error {
+                PublishError::Retryable { .. } if !self.transactional => {
+                    warn!(
+                        "Failed to deliver a record to {}: {}, publishing it again...",
+                        self.topic_name, error
+                    );
+                    self.publish_with_backoff(&record).await
+                }
+                error => Err(error),
+            };
+            if let Err(e) = delivered {
+                self.failed.lock().unwrap().push((record, e));
             }
         }
     }
+
+    /// Waits until every record handed to the producer since the last flush is delivered, and
+    /// returns the records that couldn't be, in order, with their error (e.g. so they can be
+    /// dead-lettered).
+    pub(super) async fn flush_failed(&self) -> Vec<FailedDelivery> {
+        self.await_deliveries().await;
+        std::mem::take(&mut *self.failed.lock().unwrap())
+    }
 }
 
-impl StreamPublisherConnection {
-    pub async fn with_producer(self) -> StreamPublisherConnection {
-        let StreamPublisherConnectionClient::ApacheKafka(inner_client) = self.client;
-        let queue_name = self.queue_name;
-        let this_inner_client = inner_client.clone();
-        // Create a partition client with the builder
-        let producer = BatchProducerBuilder::new(this_inner_client.clone())
-            .with_linger(Duration::ZERO)
-            .build(RecordAggregator::new(1024)); // NOTE: the official docs use 1024 in the usage example, but it is unclear if this was an arbitrary or meaningful decision on their part.
-        StreamPublisherConnection {
-            client: StreamPublisherConnectionClient::ApacheKafka(this_inner_client),
-            queue_name,
-            producer: Some(producer),
+#[async_trait]
+impl Publisher for ApacheKafkaPublisher {
+    /// Hands the message to the producer, on the partition of its key.  Its delivery is
+    /// awaited by `flush()`.
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        let record = self.route(context, 0, &msg)?;
+        self.enqueue(record).await
+    }
+
+    /// Hands every message of the batch to the producer, so they are sent in as few requests
+    /// as the batch size allows.  Their deliveries are awaited by `flush()`.
+    ///
+    /// NOTE: the messages are handed over in order, so the messages of a key keep their order
+    /// on its partition, unless one of them has to be retried.
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        let routed = msg_batch
+            .iter()
+            .enumerate()
+            .map(|(index, msg)| self.route(context, index, msg))
+            .collect::<Result<Vec<RoutedRecord>, PublishError>>()?;
+        for record in routed {
+            self.enqueue(record).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until every record handed to the producer since the last flush is delivered (see
+    /// `flush_failed()`).  Returns the first error of the records that couldn't be delivered,
+    /// once every delivery is known.
+    async fn flush(&self) -> Result<(), PublishError> {
+        match self.flush_failed().await.into_iter().next() {
+            Some((_, error)) => Err(error),
+            None => Ok(()),
         }
     }
 
-    /// Sends the message to the client
-    pub async fn publish<T: Message>(&self, msg: T) {
-        let serialized_msg = msg.encode_to_vec();
-        let prepared_msg = prepare_message(serialized_msg);
-        let producer = self.producer.as_ref().expect(
-            "producer should have been constructed with StreamPublisherConnection.with_producer()",
+    /// Waits for the records handed to the producer.  The producer is closed when its last
+    /// clone is dropped.
+    async fn disconnect(self) -> Result<(), PublishError> {
+        self.flush().await
+    }
+}
+
+/// Returns a publisher to a topic of a single partition, on a mock cluster.
+#[cfg(test)]
+pub(super) fn mock_publisher(bootstrap_servers: &str, topic_name: &str) -> ApacheKafkaPublisher {
+    ApacheKafkaPublisher {
+        producer: ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .create()
+            .unwrap(),
+        topic_name: String::from(topic_name),
+        partitions: 1,
+        headers: Arc::default(),
+        partition_key: None,
+        partitioner: Arc::new(kafka_partition::RoundRobinPartitioner::default()),
+        transactional: false,
+        pending: Arc::default(),
+        failed: Arc::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reorg::Retraction;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+
+    /// Publishes a record to a local broker, configured with the `KAFKA_*` variables (e.g.
+    /// with SASL and TLS) and `QUEUE_NAME_BLOCKS`.  Run with `--ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_publish_to_local_broker() {
+        let publisher = connect("QUEUE_NAME_BLOCKS", &ChainInfo::new("test"))
+            .await
+            .unwrap();
+        let record = Retraction {
+            block_number: 1,
+            ..Default::default()
+        };
+        let context = RecordContext::new("1").with_block_number(1);
+        publisher.publish(&context, record).await.unwrap();
+        publisher.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_awaits_deliveries() {
+        crate::testing::init_config();
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("blocks", 1, 1).unwrap();
+        let publisher = mock_publisher(&cluster.bootstrap_servers(), "blocks");
+        let context = RecordContext::new("1").with_block_number(1);
+        let records = (1..=3)
+            .map(|block_number| Retraction {
+                block_number,
+                ..Default::default()
+            })
+            .collect();
+        publisher.publish_batch(&context, records).await.unwrap();
+        assert_eq!(publisher.pending.lock().unwrap().len(), 3);
+        assert!(publisher.clone().pending.lock().unwrap().is_empty());
+        publisher.flush().await.unwrap();
+        assert!(publisher.pending.lock().unwrap().is_empty());
+
+        // NOTE: the broker rejects the next record, which is only known once it is flushed.
+        cluster.request_errors(
+            RDKafkaApiKey::Produce,
+            &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_INVALID_RECORD],
         );
-        publish_with_backoff(producer, prepared_msg).await;
+        publisher
+            .publish(&context, Retraction::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            publisher.flush().await,
+            Err(PublishError::Fatal { .. })
+        ));
+    }
+
+    #[test]
+    fn test_security_config() {
+        let kafka = KafkaConfig {
+            sasl_mechanism: Some(String::from("SCRAM-SHA-512")),
+            sasl_username: Some(String::from("indexer")),
+            sasl_password: Some(String::from("hunter2")),
+            tls: true,
+            tls_ca_cert: Some(String::from("ca.pem")),
+            ..KafkaConfig::default()
+        };
+        let mut client_config = ClientConfig::new();
+        security_config(&kafka, &mut client_config).unwrap();
+        assert_eq!(client_config.get("security.protocol"), Some("SASL_SSL"));
+        assert_eq!(client_config.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(client_config.get("sasl.username"), Some("indexer"));
+        assert_eq!(client_config.get("ssl.ca.location"), Some("ca.pem"));
+        assert_eq!(client_config.get("ssl.certificate.location"), None);
+
+        assert_eq!(sasl_mechanism("scram-sha-256").unwrap(), "SCRAM-SHA-256");
+        assert_eq!(sasl_mechanism("plain").unwrap(), "PLAIN");
+        assert!(sasl_mechanism("gssapi").is_err());
+
+        let mut client_config = ClientConfig::new();
+        security_config(&KafkaConfig::default(), &mut client_config).unwrap();
+        assert_eq!(client_config.get("security.protocol"), Some("PLAINTEXT"));
+        assert_eq!(client_config.get("sasl.mechanism"), None);
     }
 }
@@ -0,0 +1,735 @@
+//! This module contains the dead-letter publisher, which keeps records that can't be published
+//! from stalling indexing.  When publishing fails with an error that won't go away by itself
+//! (the record is rejected, or the retry policy gave up), the records are written as
+//! `DeadLetter`s to the dead-letter directory (`DEAD_LETTER_DIR`), to a secondary output
+//! (`DEAD_LETTER_BACKEND`), or both, and publishing is reported as successful.  The records of
+//! a Kafka output are only known to have failed once it is flushed, so they are dead-lettered
+//! by `flush()`.
+//!
+//! Each table's dead letters are appended to `<DEAD_LETTER_DIR>/<table>.jsonl`, one JSON object
+//! per line.  They can be published again with the `replay-dead-letters` subcommand (see
+//! `replay()`), which removes the ones that succeed from the files.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use prost::bytes::{Buf, BufMut};
+use prost::encoding::{skip_field, DecodeContext, WireType};
+use prost::{DecodeError, Message, Name};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::error::PublishError;
+use super::publish::{
+    connect_backend, connect_without_dead_letters, ChainInfo, Publisher, RecordContext,
+    StreamPublisherConnection, StreamPublisherConnectionClient,
+};
+use crate::config::{self, table_name};
+use crate::metrics;
+
+/// The name of this output, used in errors.
+const BACKEND: &str = "dead_letter";
+
+/// The queue env key of the queue that dead letters are published to with `DEAD_LETTER_BACKEND`.
+pub const DEAD_LETTER_QUEUE_ENVKEY: &str = "QUEUE_NAME_DEAD_LETTERS";
+
+/// Serializes the dead letters from different publishers, so their lines don't interleave.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A record that couldn't be published, with what is needed to publish it again.
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The queue env key of the record's table (e.g. `QUEUE_NAME_BLOCKS`).
+    #[prost(string, tag = "1")]
+    pub queue_env: String,
+    /// The name of the record in its `RecordContext`.
+    #[prost(string, tag = "2")]
+    pub name: String,
+    /// The height of the block the record was extracted from, if known.
+    #[prost(uint64, optional, tag = "3")]
+    pub block_number: Option<u64>,
+    /// The timestamp of the record in its `RecordContext`, as RFC 3339.
+    #[prost(string, optional, tag = "4")]
+    pub timestamp: Option<String>,
+    /// Why the record couldn't be published.
+    #[prost(string, tag = "5")]
+    pub error: String,
+    /// When publishing the record was given up on, as RFC 3339.
+    #[prost(string, tag = "6")]
+    pub failed_at: String,
+    /// The Protocol Buffers encoding of the record, base64 encoded in JSON.
+    #[prost(bytes = "vec", tag = "7")]
+    #[serde(with = "base64_bytes")]
+    pub record: Vec<u8>,
+    /// The JSON encoding of the record, used when replaying to the file outputs.
+    #[prost(string, tag = "8")]
+    pub record_json: String,
+    /// The protobuf full name of the record (e.g. `evm.blocks.Block`), empty in dead letters
+    /// written before it was kept.
+    #[prost(string, tag = "9")]
+    #[serde(default)]
+    pub message_type: String,
+}
+
+impl Name for DeadLetter {
+    const NAME: &'static str = "DeadLetter";
+    const PACKAGE: &'static str = "blockchain_etl";
+}
+
+/// (De)serializes bytes as a base64 string.
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A record that was already encoded, published as is when replaying dead letters.
+///
+/// NOTE: the protobuf encoding is used by the message queues and the JSON encoding by the file
+/// outputs, as with any other record.  Avro outputs serialize the JSON value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawRecord {
+    /// The Protocol Buffers encoding of the record.
+    pub protobuf: Vec<u8>,
+    /// The JSON encoding of the record.
+    pub json: serde_json::Value,
+}
+
+impl Message for RawRecord {
+    fn encode_raw<B>(&self, buf: &mut B)
+    where
+        B: BufMut,
+    {
+        buf.put_slice(&self.protobuf);
+    }
+
+    fn merge_field<B>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        B: Buf,
+    {
+        skip_field(wire_type, tag, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.protobuf.len()
+    }
+
+    fn clear(&mut self) {
+        self.protobuf.clear();
+        self.json = serde_json::Value::Null;
+    }
+}
+
+// NOTE: the name of the record's own message isn't known, so raw records are named after
+// themselves.
+impl Name for RawRecord {
+    const NAME: &'static str = "RawRecord";
+    const PACKAGE: &'static str = "blockchain_etl";
+}
+
+impl Serialize for RawRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.json.serialize(serializer)
+    }
+}
+
+impl DeadLetter {
+    /// Returns the record as it was before it failed, or an error if its JSON encoding is
+    /// corrupt.  Records that couldn't be serialized as JSON have an empty one, read as `null`.
+    pub fn raw_record(&self) -> Result<RawRecord, PublishError> {
+        let json = match self.record_json.as_str() {
+            "" => serde_json::Value::Null,
+            json => serde_json::from_str(json).map_err(|e| PublishError::fatal(BACKEND, e))?,
+        };
+        Ok(RawRecord {
+            protobuf: self.record.clone(),
+            json,
+        })
+    }
+
+    /// Returns the context the record was published with.
+    pub fn context(&self) -> RecordContext {
+        let timestamps = self
+            .timestamp
+            .iter()
+            .filter_map(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+            .map(|timestamp| timestamp.with_timezone(&Utc))
+            .collect();
+        RecordContext {
+            name: self.name.clone(),
+            timestamps,
+            block_number: self.block_number,
+            message_type: Some(self.message_type.clone()).filter(|name| !name.is_empty()),
+        }
+    }
+}
+
+/// Returns whether records that failed with this error should be dead-lettered.  Retryable
+/// errors are left to the caller, and configuration errors stop indexing.
+fn should_dead_letter(error: &PublishError) -> bool {
+    matches!(
+        error,
+        PublishError::Fatal { .. } | PublishError::Exhausted { .. }
+    )
+}
+
+/// Wraps another connection, dead-lettering the records it fails to publish.
+#[derive(Clone)]
+pub struct DeadLetterPublisher {
+    /// The connection the records are published to.
+    pub inner: Box<StreamPublisherConnection>,
+    /// The queue env key of the table (e.g. `QUEUE_NAME_BLOCKS`).
+    pub queue_env: String,
+    /// The directory the dead letters are written to, if any.
+    pub dir: Option<PathBuf>,
+    /// The output the dead letters are published to, if any.
+    pub secondary: Option<Box<StreamPublisherConnection>>,
+}
+
+/// Wraps the connection of the table published with `queue_env` in a `DeadLetterPublisher`,
+/// if a dead-letter directory or backend is configured.
+pub async fn wrap(
+    connection: StreamPublisherConnection,
+    queue_env: &str,
+    chain: &ChainInfo,
+) -> Result<StreamPublisherConnection, PublishError> {
+    let dead_letter_config = &config::get().dead_letter;
+    if matches!(
+        connection.client,
+        StreamPublisherConnectionClient::Discard(_)
+    ) || (dead_letter_config.dir.is_none() && dead_letter_config.backend.is_none())
+    {
+        return Ok(connection);
+    }
+
+    let dir = match &dead_letter_config.dir {
+        Some(dir) => {
+            create_dir_all(dir).map_err(|e| PublishError::io(BACKEND, e))?;
+            Some(PathBuf::from(dir))
+        }
+        None => None,
+    };
+    let secondary = match &dead_letter_config.backend {
+        Some(name) => {
+            let backend = name
+                .parse()
+                .map_err(|e| PublishError::configuration(BACKEND, e))?;
+            Some(Box::new(
+                connect_backend(backend, DEAD_LETTER_QUEUE_ENVKEY, chain).await?,
+            ))
+        }
+        None => None,
+    };
+
+    let queue_name = connection.queue_name.clone();
+    Ok(StreamPublisherConnection {
+        client: StreamPublisherConnectionClient::DeadLetter(DeadLetterPublisher {
+            inner: Box::new(connection),
+            queue_env: queue_env.to_string(),
+            dir,
+            secondary,
+        }),
+        queue_name,
+    })
+}
+
+impl DeadLetterPublisher {
+    /// Returns the publisher with a RabbitMQ channel created for the connections that need one.
+    #[cfg(feature = "RABBITMQ_CLASSIC")]
+    pub async fn with_channel(self) -> Result<DeadLetterPublisher, PublishError> {
+        let inner = Box::new(Box::pin(self.inner.with_channel()).await?);
+        let secondary = match self.secondary {
+            Some(secondary) => Some(Box::new(Box::pin(secondary.with_channel()).await?)),
+            None => None,
+        };
+        Ok(DeadLetterPublisher {
+            inner,
+            secondary,
+            ..self
+        })
+    }
+
+    /// Returns the publisher with its Kafka connections publishing in a transaction.
+    #[cfg(feature = "APACHE_KAFKA")]
+    pub fn with_transaction(
+        self,
+        transaction: &super::kafka_transaction::KafkaTransaction,
+    ) -> DeadLetterPublisher {
+        DeadLetterPublisher {
+            inner: Box::new(self.inner.with_transaction(transaction)),
+            secondary: self
+                .secondary
+                .map(|secondary| Box::new(secondary.with_transaction(transaction))),
+            ..self
+        }
+    }
+
+    /// Writes the records that failed with `error` as dead letters, to the directory and the
+    /// secondary output.
+    async fn dead_letter<T>(
+        &self,
+        context: &RecordContext,
+        records: &[T],
+        error: &PublishError,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize,
+    {
+        let message_type = context.message_type::<T>();
+        let failed_at = Utc::now().to_rfc3339();
+        let letters = records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| DeadLetter {
+                queue_env: self.queue_env.clone(),
+                name: context.name.clone(),
+                block_number: context.block_number,
+                timestamp: context.timestamps.get(i).map(|t| t.to_rfc3339()),
+                error: error.to_string(),
+                failed_at: failed_at.clone(),
+                record: record.encode_to_vec(),
+                // NOTE: the protobuf encoding is enough for the message queues, so a record
+                // that can't be serialized as JSON is still kept.
+                record_json: serde_json::to_string(record).unwrap_or_default(),
+                message_type: message_type.clone(),
+            })
+            .collect::<Vec<DeadLetter>>();
+
+        let table = table_name(&self.queue_env);
+        warn!(
+            "Dead-lettering {} records of {} (block {:?}): {}",
+            letters.len(),
+            table,
+            context.block_number,
+            error
+        );
+        if let Some(dir) = &self.dir {
+            append(&dir.join(format!("{}.jsonl", table)), &letters)?;
+        }
+        if let Some(secondary) = &self.secondary {
+            secondary.publish_batch(context, letters.clone()).await?;
+        }
+        metrics::record_dead_letters(&table, letters.len());
+        Ok(())
+    }
+
+    /// Flushes the output.  The records of a Kafka output whose delivery failed are
+    /// dead-lettered like the records that fail to publish, and the first error of the others
+    /// is returned.  Other outputs report their failures when publishing.
+    async fn flush_inner(&self) -> Result<(), PublishError> {
+        #[cfg(feature = "APACHE_KAFKA")]
+        if let StreamPublisherConnectionClient::ApacheKafka(kafka) = &self.inner.client {
+            let mut flushed = Ok(());
+            for (record, error) in kafka.flush_failed().await {
+                let dead_lettered = if should_dead_letter(&error) {
+                    // NOTE: the JSON encoding of a record isn't kept once it is handed to the
+                    // producer, so its dead letter only has the protobuf encoding.
+                    let raw = RawRecord {
+                        protobuf: record.payload,
+                        json: serde_json::Value::Null,
+                    };
+                    self.dead_letter(&record.context, &[raw], &error).await
+                } else {
+                    Err(error)
+                };
+                if let (Err(e), true) = (dead_lettered, flushed.is_ok()) {
+                    flushed = Err(e);
+                }
+            }
+            return flushed;
+        }
+        self.inner.flush().await
+    }
+}
+
+/// Appends the dead letters to a JSONL file.
+fn append(path: &Path, letters: &[DeadLetter]) -> Result<(), PublishError> {
+    let lines = letters
+        .iter()
+        .map(|letter| serde_json::to_string(letter).map(|json| json + "\n"))
+        .collect::<Result<String, serde_json::Error>>()
+        .map_err(|e| PublishError::fatal(BACKEND, e))?;
+
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| PublishError::io(BACKEND, e))?;
+    file.write_all(lines.as_bytes())
+        .map_err(|e| PublishError::io(BACKEND, e))
+}
+
+#[async_trait]
+impl Publisher for DeadLetterPublisher {
+    /// Publishes the record, dead-lettering it if it can't be published.
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        match self.inner.publish(context, msg.clone()).await {
+            Err(e) if should_dead_letter(&e) => self.dead_letter(context, &[msg], &e).await,
+            result => result,
+        }
+    }
+
+    /// Publishes the batch, dead-lettering every record of it if it can't be published.
+    ///
+    /// NOTE: outputs don't report which records of a batch failed, so some of the dead
+    /// letters may have been published.
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        match self.inner.publish_batch(context, msg_batch.clone()).await {
+            Err(e) if should_dead_letter(&e) => self.dead_letter(context, &msg_batch, &e).await,
+            result => result,
+        }
+    }
+
+    /// Flushes the output and the secondary output.  The records the output fails to deliver
+    /// are dead-lettered (see `flush_inner`).
+    async fn flush(&self) -> Result<(), PublishError> {
+        let flushed = self.flush_inner().await;
+        if let Some(secondary) = &self.secondary {
+            secondary.flush().await?;
+        }
+        flushed
+    }
+
+    /// Disconnects from the output and the secondary output.
+    async fn disconnect(self) -> Result<(), PublishError> {
+        let disconnected = self.inner.disconnect().await;
+        if let Some(secondary) = self.secondary {
+            secondary.disconnect().await?;
+        }
+        disconnected
+    }
+}
+
+/// The outcome of replaying dead letters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// The number of dead letters that were published and removed.
+    pub replayed: usize,
+    /// The number of dead letters that failed again (or couldn't be read) and were kept.
+    pub failed: usize,
+}
+
+/// Publishes the dead letters in `dir` again, to the current route of their tables, keeping
+/// only the ones that fail again.  If `table` is given, only that table's dead letters are
+/// replayed.  `chain` is the blockchain the dead letters were written by.
+///
+/// NOTE: this should not run while an indexer is writing dead letters to the same directory,
+/// since the files are rewritten with the dead letters that are left.
+pub async fn replay(
+    dir: &Path,
+    table: Option<&str>,
+    chain: &ChainInfo,
+) -> Result<ReplayReport, PublishError> {
+    let mut paths = read_dir(dir)
+        .map_err(|e| PublishError::io(BACKEND, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "jsonl")
+        })
+        .filter(|path| match table {
+            Some(table) => path.file_stem().is_some_and(|stem| stem == table),
+            None => true,
+        })
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+
+    let mut report = ReplayReport::default();
+    let mut publishers: BTreeMap<String, StreamPublisherConnection> = BTreeMap::new();
+    let mut result = Ok(());
+    for path in paths.iter() {
+        info!("Replaying the dead letters in {}...", path.display());
+        if let Err(e) = replay_file(path, chain, &mut publishers, &mut report).await {
+            result = Err(e);
+            break;
+        }
+    }
+    for (_, publisher) in publishers.into_iter() {
+        publisher.disconnect().await?;
+    }
+    result.map(|_| report)
+}
+
+/// Replays the dead letters of a single file, then rewrites it with the ones left (or removes
+/// it if there are none).  Each letter is only replayed once its publisher was flushed, so the
+/// letters whose delivery fails are kept.
+async fn replay_file(
+    path: &Path,
+    chain: &ChainInfo,
+    publishers: &mut BTreeMap<String, StreamPublisherConnection>,
+    report: &mut ReplayReport,
+) -> Result<(), PublishError> {
+    let file = std::fs::File::open(path).map_err(|e| PublishError::io(BACKEND, e))?;
+    let mut remaining = String::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| PublishError::io(BACKEND, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let letter = match serde_json::from_str::<DeadLetter>(&line) {
+            Ok(letter) => letter,
+            Err(e) => {
+                error!("Invalid dead letter in {}: {}", path.display(), e);
+                report.failed += 1;
+                remaining.push_str(&line);
+                remaining.push('\n');
+                continue;
+            }
+        };
+
+        if !publishers.contains_key(&letter.queue_env) {
+            let publisher = connect_without_dead_letters(&letter.queue_env, chain).await?;
+            #[cfg(feature = "RABBITMQ_CLASSIC")]
+            let publisher = publisher.with_channel().await?;
+            publishers.insert(letter.queue_env.clone(), publisher);
+        }
+        let publisher = &publishers[&letter.queue_env];
+
+        // NOTE: outputs like Kafka only know whether a record was delivered once they are
+        // flushed.
+        let replayed = match letter.raw_record() {
+            Ok(record) => match publisher.publish(&letter.context(), record).await {
+                Ok(()) => publisher.flush().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match replayed {
+            Ok(()) => report.replayed += 1,
+            Err(e) => {
+                error!(
+                    "Failed to replay a dead letter of {} (block {:?}): {}",
+                    letter.queue_env, letter.block_number, e
+                );
+                report.failed += 1;
+                remaining.push_str(&line);
+                remaining.push('\n');
+            }
+        }
+    }
+
+    if remaining.is_empty() {
+        return std::fs::remove_file(path).map_err(|e| PublishError::io(BACKEND, e));
+    }
+    // NOTE: written to a temporary file first, so the dead letters aren't lost if this is
+    // interrupted.
+    let tmp_path = path.with_extension("jsonl.tmp");
+    std::fs::write(&tmp_path, remaining).map_err(|e| PublishError::io(BACKEND, e))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| PublishError::io(BACKEND, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::routing::DiscardPublisher;
+    use crate::reorg::Retraction;
+
+    #[test]
+    fn test_dead_letter_roundtrip() {
+        let mut letter = DeadLetter {
+            queue_env: String::from("QUEUE_NAME_BLOCKS"),
+            name: String::from("block_42"),
+            block_number: Some(42),
+            timestamp: Some(String::from("2024-01-01T00:00:00+00:00")),
+            error: String::from("apache_kafka: message too large (not retryable)"),
+            failed_at: String::from("2024-01-01T00:00:01+00:00"),
+            record: vec![8, 42],
+            record_json: String::from(r#"{"height":42}"#),
+            message_type: String::from("evm.blocks.Block"),
+        };
+        let json = serde_json::to_string(&letter).unwrap();
+        assert_eq!(serde_json::from_str::<DeadLetter>(&json).unwrap(), letter);
+
+        let raw = letter.raw_record().unwrap();
+        assert_eq!(raw.encode_to_vec(), vec![8, 42]);
+        assert_eq!(
+            serde_json::to_string(&raw).unwrap(),
+            letter.record_json.as_str()
+        );
+        let context = letter.context();
+        assert_eq!(context.block_number, Some(42));
+        assert_eq!(context.timestamps.len(), 1);
+        assert_eq!(context.message_type::<RawRecord>(), "evm.blocks.Block");
+
+        letter.record_json = String::from(r#"{"height":4"#);
+        assert!(letter.raw_record().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_keep_message_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let publisher = DeadLetterPublisher {
+            inner: Box::new(StreamPublisherConnection {
+                client: StreamPublisherConnectionClient::Discard(DiscardPublisher::default()),
+                queue_name: String::from("blocks"),
+            }),
+            queue_env: String::from("QUEUE_NAME_BLOCKS"),
+            dir: Some(dir.path().to_path_buf()),
+            secondary: None,
+        };
+        let retraction = Retraction {
+            block_number: 42,
+            ..Retraction::default()
+        };
+        let error = PublishError::fatal("test", "rejected");
+        publisher
+            .dead_letter(&RecordContext::new("block_42"), &[retraction], &error)
+            .await
+            .unwrap();
+
+        let file = std::fs::File::open(dir.path().join("blocks.jsonl")).unwrap();
+        let line = BufReader::new(file).lines().next().unwrap().unwrap();
+        let letter = serde_json::from_str::<DeadLetter>(&line).unwrap();
+        assert_eq!(letter.message_type, "blockchain_etl.Retraction");
+        assert_eq!(
+            letter.context().message_type::<RawRecord>(),
+            "blockchain_etl.Retraction"
+        );
+    }
+
+    #[cfg(feature = "APACHE_KAFKA")]
+    #[tokio::test]
+    async fn test_failed_deliveries_are_dead_lettered_on_flush() {
+        use crate::output::apache_kafka::mock_publisher;
+        use rdkafka::mocking::MockCluster;
+        use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+
+        crate::testing::init_config();
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("blocks", 1, 1).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let publisher = DeadLetterPublisher {
+            inner: Box::new(StreamPublisherConnection {
+                client: StreamPublisherConnectionClient::ApacheKafka(mock_publisher(
+                    &cluster.bootstrap_servers(),
+                    "blocks",
+                )),
+                queue_name: String::from("blocks"),
+            }),
+            queue_env: String::from("QUEUE_NAME_BLOCKS"),
+            dir: Some(dir.path().to_path_buf()),
+            secondary: None,
+        };
+
+        // NOTE: the broker rejects the record, which is only known once it is flushed.
+        cluster.request_errors(
+            RDKafkaApiKey::Produce,
+            &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_INVALID_RECORD],
+        );
+        let retraction = Retraction {
+            block_number: 42,
+            ..Retraction::default()
+        };
+        let context = RecordContext::new("block_42").with_block_number(42);
+        publisher.publish(&context, retraction).await.unwrap();
+        publisher.flush().await.unwrap();
+
+        let file = std::fs::File::open(dir.path().join("blocks.jsonl")).unwrap();
+        let letters = BufReader::new(file)
+            .lines()
+            .map(|line| serde_json::from_str::<DeadLetter>(&line.unwrap()).unwrap())
+            .collect::<Vec<DeadLetter>>();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].block_number, Some(42));
+        assert_eq!(letters[0].message_type, "blockchain_etl.Retraction");
+        assert_eq!(
+            letters[0].record,
+            Retraction {
+                block_number: 42,
+                ..Retraction::default()
+            }
+            .encode_to_vec()
+        );
+    }
+
+    #[cfg(feature = "APACHE_KAFKA")]
+    #[tokio::test]
+    async fn test_replay_keeps_undelivered_letters() {
+        use crate::output::apache_kafka::mock_publisher;
+        use rdkafka::mocking::MockCluster;
+        use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+
+        crate::testing::init_config();
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("blocks", 1, 1).unwrap();
+        let mut publishers = BTreeMap::from([(
+            String::from("QUEUE_NAME_BLOCKS"),
+            StreamPublisherConnection {
+                client: StreamPublisherConnectionClient::ApacheKafka(mock_publisher(
+                    &cluster.bootstrap_servers(),
+                    "blocks",
+                )),
+                queue_name: String::from("blocks"),
+            },
+        )]);
+        let letters = [41, 42].map(|block_number| DeadLetter {
+            queue_env: String::from("QUEUE_NAME_BLOCKS"),
+            name: format!("block_{}", block_number),
+            block_number: Some(block_number),
+            error: String::from("apache_kafka: broker unreachable"),
+            record: Retraction {
+                block_number,
+                ..Retraction::default()
+            }
+            .encode_to_vec(),
+            message_type: String::from("blockchain_etl.Retraction"),
+            ..DeadLetter::default()
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.jsonl");
+        append(&path, &letters).unwrap();
+
+        // NOTE: the broker rejects the first letter, which is only known once it is flushed.
+        cluster.request_errors(
+            RDKafkaApiKey::Produce,
+            &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_INVALID_RECORD],
+        );
+        let mut report = ReplayReport::default();
+        replay_file(&path, &ChainInfo::new("test"), &mut publishers, &mut report)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            ReplayReport {
+                replayed: 1,
+                failed: 1
+            }
+        );
+        let file = std::fs::File::open(&path).unwrap();
+        let left = BufReader::new(file)
+            .lines()
+            .map(|line| serde_json::from_str::<DeadLetter>(&line.unwrap()).unwrap())
+            .collect::<Vec<DeadLetter>>();
+        assert_eq!(left, letters[..1]);
+    }
+}
@@ -1,32 +1,38 @@
-#![allow(clippy::expect_fun_call)]
-use once_cell::sync::OnceCell;
+use crate::config;
+use crate::output::error::PublishError;
 
+/// Environment key to access the Kafka bootstrap brokers, as comma-separated `host:port`
+pub const KAFKA_BROKERS_ENVKEY: &str = "KAFKA_BROKERS";
 /// Environment key to access the Kafka address
 pub const KAFKA_ADDR_ENVKEY: &str = "KAFKA_ADDRESS";
 /// Environment key to access the Kafka port, should be a u16
 pub const KAFKA_PORT_ENVKEY: &str = "KAFKA_PORT";
 
-/// Kafka Address
-pub static KAFKA_ADDR: OnceCell<String> = OnceCell::new();
-/// Kafka Port
-pub static KAFKA_PORT: OnceCell<u16> = OnceCell::new();
-
-/// Returns the RabbitMQ Address
-pub fn get_kafka_addr() -> &'static String {
-    KAFKA_ADDR.get_or_init(|| {
-        dotenvy::var(KAFKA_ADDR_ENVKEY)
-            .expect(&format!("{} should exist in .env file", KAFKA_ADDR_ENVKEY))
-            .parse::<String>()
-            .unwrap()
+/// Returns the Kafka Address
+pub fn get_kafka_addr() -> Result<&'static String, PublishError> {
+    config::get().kafka.address.as_ref().ok_or_else(|| {
+        PublishError::configuration(
+            "apache_kafka",
+            format!("kafka.address ({}) should be configured", KAFKA_ADDR_ENVKEY),
+        )
     })
 }
 
-/// Returns the RabbitMQ port
-pub fn get_kafka_port() -> &'static u16 {
-    KAFKA_PORT.get_or_init(|| {
-        dotenvy::var(KAFKA_PORT_ENVKEY)
-            .expect(&format!("{} should exist in .env file", KAFKA_PORT_ENVKEY))
-            .parse::<u16>()
-            .unwrap()
+/// Returns the Kafka port
+pub fn get_kafka_port() -> Result<&'static u16, PublishError> {
+    config::get().kafka.port.as_ref().ok_or_else(|| {
+        PublishError::configuration(
+            "apache_kafka",
+            format!("kafka.port ({}) should be configured", KAFKA_PORT_ENVKEY),
+        )
     })
 }
+
+/// Returns the Kafka bootstrap brokers, or `KAFKA_ADDRESS:KAFKA_PORT` if none are listed
+pub fn get_kafka_brokers() -> Result<Vec<String>, PublishError> {
+    let brokers = &config::get().kafka.brokers;
+    if !brokers.is_empty() {
+        return Ok(brokers.clone());
+    }
+    Ok(vec![format!("{}:{}", get_kafka_addr()?, get_kafka_port()?)])
+}
@@ -1,16 +1,17 @@
-use dotenvy;
-use once_cell::sync::OnceCell;
+use crate::config;
+use crate::output::error::PublishError;
 
 /// The environment key leading to the path for the output directory
 pub const OUTPUT_DIR_ENVKEY: &str = "OUTPUT_DIR";
-/// Stores the Output Directory
-pub static OUTPUT_DIR: OnceCell<String> = OnceCell::new();
-/// Returns the output directory from the .env
-pub fn get_output_dir() -> &'static String {
-    OUTPUT_DIR.get_or_init(|| {
-        dotenvy::var(OUTPUT_DIR_ENVKEY)
-            .unwrap_or_else(|_| panic!("{} should exist in .env file", OUTPUT_DIR_ENVKEY))
-            .parse::<String>()
-            .unwrap()
+/// Returns the output directory from the configuration
+pub fn get_output_dir() -> Result<&'static String, PublishError> {
+    config::get().file.output_dir.as_ref().ok_or_else(|| {
+        PublishError::configuration(
+            "file",
+            format!(
+                "file.output_dir ({}) should be configured",
+                OUTPUT_DIR_ENVKEY
+            ),
+        )
     })
 }
@@ -1,3 +1,6 @@
+mod output;
+pub use output::*;
+
 #[cfg(any(feature = "JSON", feature = "JSONL"))]
 mod file;
 #[cfg(any(feature = "JSON", feature = "JSONL"))]
@@ -0,0 +1,38 @@
+use crate::config;
+use crate::output::error::PublishError;
+
+pub use crate::config::{QUEUE_NAME_ENVKEY_PREFIX, TABLE_OUTPUT_BACKEND_ENVKEY_PREFIX};
+
+/// The environment key to select the output backend (e.g. `apache_kafka`), or a comma-separated
+/// list of backends to publish to all of them (e.g. `apache_kafka,jsonl`)
+pub const OUTPUT_BACKEND_ENVKEY: &str = "OUTPUT_BACKEND";
+
+/// The environment key listing the backends that are skipped when slow or failing, when
+/// publishing to several backends at once
+pub const OUTPUT_SKIPPABLE_BACKENDS_ENVKEY: &str = "OUTPUT_SKIPPABLE_BACKENDS";
+/// Returns the names of the backends that are skipped when slow or failing
+pub fn get_output_skippable_backends() -> &'static Vec<String> {
+    &config::get().output.skippable_backends
+}
+
+/// The environment key for how long (in milliseconds) a skippable backend may take before it is skipped
+pub const OUTPUT_SKIP_TIMEOUT_MS_ENVKEY: &str = "OUTPUT_SKIP_TIMEOUT_MS";
+/// Returns the timeout for skippable backends, in milliseconds
+pub fn get_output_skip_timeout_ms() -> &'static u64 {
+    &config::get().output.skip_timeout_ms
+}
+
+/// Returns the queue name (topic, queue, stream, subdirectory or bucket) of the table published
+/// with `queue_env` (e.g. `QUEUE_NAME_BLOCKS`)
+pub fn get_queue_name(queue_env: &str) -> Result<&'static String, PublishError> {
+    config::get().queue_name(queue_env).ok_or_else(|| {
+        PublishError::configuration(
+            "output",
+            format!(
+                "queues.{} ({}) should be configured",
+                config::table_name(queue_env),
+                queue_env
+            ),
+        )
+    })
+}
@@ -1,6 +1,5 @@
-#![allow(clippy::expect_fun_call)]
-
-use once_cell::sync::OnceCell;
+use crate::config;
+use crate::output::error::PublishError;
 
 /// Environment key to access the RABBITMQ address
 pub const RABBITMQ_ADDR_ENVKEY: &str = "RABBITMQ_ADDRESS";
@@ -11,63 +10,46 @@ pub const RABBITMQ_USER_ENVKEY: &str = "RABBITMQ_USER";
 /// Environment key to access the RABBITMQ password
 pub const RABBITMQ_PASS_ENVKEY: &str = "RABBITMQ_PASSWORD";
 
-/// RabbitMQ Address
-pub static RABBITMQ_ADDR: OnceCell<String> = OnceCell::new();
-/// RabbitMQ Port
-pub static RABBITMQ_PORT: OnceCell<u16> = OnceCell::new();
-/// RabbitMQ User
-pub static RABBITMQ_USER: OnceCell<String> = OnceCell::new();
-/// RabbitMQ Password
-pub static RABBITMQ_PASS: OnceCell<String> = OnceCell::new();
+/// Returns the error for a missing RabbitMQ setting
+fn missing(key: &str, envkey: &str) -> PublishError {
+    PublishError::configuration(
+        "rabbitmq",
+        format!("rabbitmq.{} ({}) should be configured", key, envkey),
+    )
+}
 
 /// Returns the RabbitMQ Address
-pub fn get_rabbitmq_addr() -> &'static String {
-    RABBITMQ_ADDR.get_or_init(|| {
-        dotenvy::var(RABBITMQ_ADDR_ENVKEY)
-            .expect(&format!(
-                "{} should exist in .env file",
-                RABBITMQ_ADDR_ENVKEY
-            ))
-            .parse::<String>()
-            .unwrap()
-    })
+pub fn get_rabbitmq_addr() -> Result<&'static String, PublishError> {
+    config::get()
+        .rabbitmq
+        .address
+        .as_ref()
+        .ok_or_else(|| missing("address", RABBITMQ_ADDR_ENVKEY))
 }
 
 /// Returns the RabbitMQ port
-pub fn get_rabbitmq_port() -> &'static u16 {
-    RABBITMQ_PORT.get_or_init(|| {
-        dotenvy::var(RABBITMQ_PORT_ENVKEY)
-            .expect(&format!(
-                "{} should exist in .env file",
-                RABBITMQ_PORT_ENVKEY
-            ))
-            .parse::<u16>()
-            .unwrap()
-    })
+pub fn get_rabbitmq_port() -> Result<&'static u16, PublishError> {
+    config::get()
+        .rabbitmq
+        .port
+        .as_ref()
+        .ok_or_else(|| missing("port", RABBITMQ_PORT_ENVKEY))
 }
 
 /// Returns the RabbitMQ username
-pub fn get_rabbitmq_username() -> &'static String {
-    RABBITMQ_USER.get_or_init(|| {
-        dotenvy::var(RABBITMQ_USER_ENVKEY)
-            .expect(&format!(
-                "{} should exist in .env file",
-                RABBITMQ_USER_ENVKEY
-            ))
-            .parse::<String>()
-            .unwrap()
-    })
+pub fn get_rabbitmq_username() -> Result<&'static String, PublishError> {
+    config::get()
+        .rabbitmq
+        .user
+        .as_ref()
+        .ok_or_else(|| missing("user", RABBITMQ_USER_ENVKEY))
 }
 
 /// Returns the RabbitMQ password (secrets beaware)
-pub fn get_rabbitmq_password() -> &'static String {
-    RABBITMQ_PASS.get_or_init(|| {
-        dotenvy::var(RABBITMQ_PASS_ENVKEY)
-            .expect(&format!(
-                "{} should exist in .env file",
-                RABBITMQ_PASS_ENVKEY
-            ))
-            .parse::<String>()
-            .unwrap()
-    })
+pub fn get_rabbitmq_password() -> Result<&'static String, PublishError> {
+    config::get()
+        .rabbitmq
+        .password
+        .as_ref()
+        .ok_or_else(|| missing("password", RABBITMQ_PASS_ENVKEY))
 }
@@ -0,0 +1,119 @@
+//! This module defines `PublishError`, the error returned by every output backend.  Its
+//! variant tells the caller what to do about a failure: retry the operation, skip the record,
+//! or stop indexing.
+
+use std::fmt;
+use thiserror::Error;
+
+use crate::retry::{RetryError, Retryable};
+
+/// An error returned when connecting, publishing, flushing or disconnecting.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum PublishError {
+    /// A transient failure (e.g. the broker is unreachable or overloaded).  The operation may
+    /// succeed if it is retried.
+    #[error("{backend}: {reason}")]
+    Retryable {
+        backend: &'static str,
+        reason: String,
+    },
+    /// The operation can never succeed (e.g. the record exceeds the broker's size limit or is
+    /// rejected).  Retrying is pointless, the record should be skipped.
+    #[error("{backend}: {reason} (not retryable)")]
+    Fatal {
+        backend: &'static str,
+        reason: String,
+    },
+    /// The output is misconfigured (e.g. a missing setting, topic or permission), so nothing
+    /// published to it can succeed.  Indexing should stop.
+    #[error("{backend} is misconfigured: {reason}")]
+    Configuration {
+        backend: &'static str,
+        reason: String,
+    },
+    /// The operation kept failing with retryable errors until the retry policy gave up.  Like
+    /// `Fatal`, the record should be skipped.
+    #[error("{backend}: gave up after {attempts} attempts: {reason}")]
+    Exhausted {
+        backend: &'static str,
+        attempts: u32,
+        reason: String,
+    },
+}
+
+impl PublishError {
+    /// Creates a `PublishError::Retryable`.
+    pub fn retryable(backend: &'static str, reason: impl fmt::Display) -> PublishError {
+        PublishError::Retryable {
+            backend,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Creates a `PublishError::Fatal`.
+    pub fn fatal(backend: &'static str, reason: impl fmt::Display) -> PublishError {
+        PublishError::Fatal {
+            backend,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Creates a `PublishError::Configuration`.
+    pub fn configuration(backend: &'static str, reason: impl fmt::Display) -> PublishError {
+        PublishError::Configuration {
+            backend,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Classifies an error from reading or writing local files.  Missing directories and
+    /// permissions are configuration errors, anything else (e.g. a full disk) may be transient.
+    pub fn io(backend: &'static str, error: std::io::Error) -> PublishError {
+        match error.kind() {
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+                PublishError::configuration(backend, error)
+            }
+            _ => PublishError::retryable(backend, error),
+        }
+    }
+
+    /// Returns whether the operation may succeed if it is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, PublishError::Retryable { .. })
+    }
+
+    /// Returns the name of the backend that failed.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            PublishError::Retryable { backend, .. }
+            | PublishError::Fatal { backend, .. }
+            | PublishError::Configuration { backend, .. }
+            | PublishError::Exhausted { backend, .. } => backend,
+        }
+    }
+}
+
+impl Retryable for PublishError {
+    fn is_retryable(&self) -> bool {
+        PublishError::is_retryable(self)
+    }
+}
+
+impl From<RetryError<PublishError>> for PublishError {
+    /// Returns an error that isn't retryable as is, or a `PublishError::Exhausted` describing
+    /// the last failure if the retry policy gave up.
+    fn from(error: RetryError<PublishError>) -> PublishError {
+        match error {
+            RetryError::Permanent(error) => error,
+            RetryError::Exhausted {
+                attempts,
+                last_error,
+                ..
+            } => PublishError::Exhausted {
+                backend: last_error.backend(),
+                attempts,
+                reason: last_error.to_string(),
+            },
+        }
+    }
+}
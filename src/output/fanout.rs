@@ -0,0 +1,320 @@
+//! This module contains the fan-out publisher, which wraps several output backends and
+//! publishes every record to all of them (e.g. Apache Kafka for realtime consumers and JSONL
+//! files for backfill audits).  It is used when `OUTPUT_BACKEND` lists more than one backend.
+//!
+//! Each sink is either blocking, where publishing waits until the sink has accepted the record
+//! and its errors are returned (combined, when several sinks fail), or skippable, where a sink
+//! that is slower than `OUTPUT_SKIP_TIMEOUT_MS` (or fails) is skipped for that record so it
+//! doesn't hold back the other sinks.
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use futures::FutureExt;
+use log::{error, warn};
+use prost::{Message, Name};
+use serde::Serialize;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::error::PublishError;
+use super::publish::{Publisher, RecordContext, StreamPublisherConnection};
+
+/// How the fan-out publisher treats a sink that is slow or failing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SinkMode {
+    /// Wait until the sink accepts the record.  A failure of this sink is a failure of the
+    /// whole publish.
+    Block,
+    /// Give up on the record for this sink if it takes longer than the timeout or fails.
+    Skip(Duration),
+}
+
+/// One of the outputs of the fan-out publisher.
+#[derive(Clone)]
+pub struct FanoutSink {
+    /// The name of the sink's backend, used when reporting failures.
+    pub name: String,
+    /// The connection to the sink.
+    pub connection: StreamPublisherConnection,
+    /// Whether a slow or failing sink blocks publishing or is skipped.
+    pub mode: SinkMode,
+    /// The number of records this sink has skipped.  Shared between clones.
+    failures: Arc<AtomicU64>,
+}
+
+impl FanoutSink {
+    /// Creates a sink with no recorded failures.
+    pub fn new(name: String, connection: StreamPublisherConnection, mode: SinkMode) -> FanoutSink {
+        FanoutSink {
+            name,
+            connection,
+            mode,
+            failures: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the number of records this sink has skipped.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Runs an operation on the sink according to its mode.  Skippable sinks log and count
+    /// errors, timeouts and panics instead of returning them.
+    async fn run<F>(&self, operation: &str, fut: F) -> Result<(), PublishError>
+    where
+        F: Future<Output = Result<(), PublishError>>,
+    {
+        match self.mode {
+            SinkMode::Block => fut.await,
+            SinkMode::Skip(timeout) => {
+                match tokio::time::timeout(timeout, AssertUnwindSafe(fut).catch_unwind()).await {
+                    Ok(Ok(Ok(()))) => (),
+                    Ok(Ok(Err(e))) => {
+                        self.failures.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            "{} failed for sink {}: {}, skipping...",
+                            operation, self.name, e
+                        );
+                    }
+                    Ok(Err(_)) => {
+                        self.failures.fetch_add(1, Ordering::Relaxed);
+                        error!("{} panicked for sink {}, skipping...", operation, self.name);
+                    }
+                    Err(_) => {
+                        self.failures.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "{} timed out after {:?} for sink {}, skipping...",
+                            operation, timeout, self.name
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Publishes every record to all of its sinks concurrently.
+#[derive(Clone)]
+pub struct FanoutPublisher {
+    pub sinks: Vec<FanoutSink>,
+}
+
+impl FanoutPublisher {
+    /// Returns the number of skipped records for each sink, by sink name.
+    pub fn failures(&self) -> Vec<(String, u64)> {
+        self.sinks
+            .iter()
+            .map(|sink| (sink.name.clone(), sink.failures()))
+            .collect()
+    }
+
+    /// Returns the publisher with a RabbitMQ channel created for every sink that needs one.
+    #[cfg(feature = "RABBITMQ_CLASSIC")]
+    pub async fn with_channel(self) -> Result<FanoutPublisher, PublishError> {
+        let mut sinks = Vec::with_capacity(self.sinks.len());
+        for sink in self.sinks.into_iter() {
+            let connection = Box::pin(sink.connection.with_channel()).await?;
+            sinks.push(FanoutSink { connection, ..sink });
+        }
+        Ok(FanoutPublisher { sinks })
+    }
+
+    /// Returns the publisher with its Kafka sinks publishing in a transaction.
+    #[cfg(feature = "APACHE_KAFKA")]
+    pub fn with_transaction(
+        self,
+        transaction: &super::kafka_transaction::KafkaTransaction,
+    ) -> FanoutPublisher {
+        let sinks = self
+            .sinks
+            .into_iter()
+            .map(|sink| FanoutSink {
+                connection: sink.connection.with_transaction(transaction),
+                ..sink
+            })
+            .collect();
+        FanoutPublisher { sinks }
+    }
+}
+
+/// The name of the fan-out publisher, used in the errors of several sinks.
+const BACKEND: &str = "fanout";
+
+/// Returns how serious a failure is, so that the error of several sinks is handled like the
+/// worst of them: a misconfigured sink stops indexing even if another one can be retried.
+fn severity(error: &PublishError) -> u8 {
+    match error {
+        PublishError::Retryable { .. } => 0,
+        PublishError::Exhausted { .. } => 1,
+        PublishError::Fatal { .. } => 2,
+        PublishError::Configuration { .. } => 3,
+    }
+}
+
+/// Returns the errors of the sinks, if any.  The error of a single sink is returned as is,
+/// the errors of several sinks are combined into one naming each of them, of the kind of the
+/// most serious one.
+fn sink_errors(results: Vec<Result<(), PublishError>>) -> Result<(), PublishError> {
+    let mut errors = results
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<PublishError>>();
+    if errors.len() <= 1 {
+        return errors.pop().map_or(Ok(()), Err);
+    }
+    let reason = errors
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<String>>()
+        .join("; ");
+    let worst = errors
+        .into_iter()
+        .max_by_key(severity)
+        .expect("there are several errors");
+    Err(match worst {
+        PublishError::Retryable { .. } => PublishError::retryable(BACKEND, reason),
+        PublishError::Fatal { .. } => PublishError::fatal(BACKEND, reason),
+        PublishError::Configuration { .. } => PublishError::configuration(BACKEND, reason),
+        PublishError::Exhausted { attempts, .. } => PublishError::Exhausted {
+            backend: BACKEND,
+            attempts,
+            reason,
+        },
+    })
+}
+
+#[async_trait]
+impl Publisher for FanoutPublisher {
+    /// Publishes the record to every sink.  Fails if any blocking sink fails, with the errors
+    /// of all of them.
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        sink_errors(
+            join_all(
+                self.sinks
+                    .iter()
+                    .map(|sink| sink.run("publish", sink.connection.publish(context, msg.clone()))),
+            )
+            .await,
+        )
+    }
+
+    /// Publishes the batch to every sink.
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        sink_errors(
+            join_all(self.sinks.iter().map(|sink| {
+                sink.run(
+                    "publish_batch",
+                    sink.connection.publish_batch(context, msg_batch.clone()),
+                )
+            }))
+            .await,
+        )
+    }
+
+    /// Flushes every sink.
+    async fn flush(&self) -> Result<(), PublishError> {
+        sink_errors(
+            join_all(
+                self.sinks
+                    .iter()
+                    .map(|sink| sink.run("flush", sink.connection.flush())),
+            )
+            .await,
+        )
+    }
+
+    /// Disconnects from every sink, reporting how many records each one skipped.  Every sink
+    /// is disconnected even if some of them fail.
+    async fn disconnect(self) -> Result<(), PublishError> {
+        let mut results = Vec::with_capacity(self.sinks.len());
+        for sink in self.sinks.into_iter() {
+            let failures = sink.failures();
+            if failures > 0 {
+                warn!("Sink {} skipped {} records", sink.name, failures);
+            }
+            results.push(sink.connection.disconnect().await);
+        }
+        sink_errors(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::publish::StreamPublisherConnectionClient;
+    use crate::output::routing::DiscardPublisher;
+
+    /// Returns a sink that drops its records, in the given mode.
+    fn sink(name: &str, mode: SinkMode) -> FanoutSink {
+        let connection = StreamPublisherConnection {
+            client: StreamPublisherConnectionClient::Discard(DiscardPublisher::default()),
+            queue_name: String::from("blocks"),
+        };
+        FanoutSink::new(name.to_string(), connection, mode)
+    }
+
+    #[tokio::test]
+    async fn test_block_and_skip_modes() {
+        let failing = || async { Err(PublishError::fatal("jsonl", "disk full")) };
+
+        let blocking = sink("jsonl", SinkMode::Block);
+        assert_eq!(
+            blocking.run("publish", failing()).await,
+            Err(PublishError::fatal("jsonl", "disk full"))
+        );
+        assert_eq!(blocking.failures(), 0);
+
+        let skippable = sink("jsonl", SinkMode::Skip(Duration::from_millis(20)));
+        assert_eq!(skippable.run("publish", failing()).await, Ok(()));
+        let slow = tokio::time::sleep(Duration::from_secs(5)).map(Ok);
+        assert_eq!(skippable.run("publish", slow).await, Ok(()));
+        assert_eq!(skippable.run("publish", async { Ok(()) }).await, Ok(()));
+        assert_eq!(skippable.failures(), 2);
+
+        let publisher = FanoutPublisher {
+            sinks: vec![blocking, skippable],
+        };
+        assert_eq!(
+            publisher.failures(),
+            [(String::from("jsonl"), 0), (String::from("jsonl"), 2)]
+        );
+        let record = crate::reorg::Retraction::default();
+        assert_eq!(
+            publisher.publish(&RecordContext::new("1"), record).await,
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_errors_of_several_sinks() {
+        assert_eq!(sink_errors(vec![Ok(()), Ok(())]), Ok(()));
+        let retryable = PublishError::retryable("apache_kafka", "broker unreachable");
+        assert_eq!(
+            sink_errors(vec![Ok(()), Err(retryable.clone())]),
+            Err(retryable.clone())
+        );
+
+        let misconfigured = PublishError::configuration("jsonl", "no such directory");
+        assert_eq!(
+            sink_errors(vec![Err(retryable), Ok(()), Err(misconfigured)]),
+            Err(PublishError::configuration(
+                BACKEND,
+                "apache_kafka: broker unreachable; jsonl is misconfigured: no such directory"
+            ))
+        );
+    }
+}
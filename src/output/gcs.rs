@@ -1,79 +1,164 @@
 //! This module contains implementation details for
-//! StreamPublisherConnection when the `GOOGLE_CLOUD_STORAGE` feature is
-//! enabled. This allows StreamPublisherConnection to
+//! the Google Cloud Storage output backend, enabled with the `GOOGLE_CLOUD_STORAGE` feature.
+//! This allows StreamPublisherConnection to
 //! publish jsonl files to GCS.
 
+use async_trait::async_trait;
 use chrono::Timelike;
-use chrono::{TimeZone, Utc};
-use log::{error, info, warn};
-use prost::Message;
+use log::info;
+use prost::{Message, Name};
 use serde::Serialize;
 
 use google_cloud_storage::client::google_cloud_auth::credentials::CredentialsFile; // can get a "similar names but distinct types" error if we import this from the google_cloud_auth crate with mismatched crate versions
 use google_cloud_storage::client::{Client, ClientConfig};
 use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
-
-use crate::blockchain_config::proto_codegen::aptos::common::UnixTimestamp;
+use google_cloud_storage::http::Error as GcsError;
 
 use super::environment::*;
-use super::publish::{StreamPublisherConnection, StreamPublisherConnectionClient};
+use super::error::PublishError;
+use super::publish::{Publisher, RecordContext};
+use crate::config;
+
+/// The name of this backend, used in errors.
+const BACKEND: &str = "google_cloud_storage";
+
+/// A connection to a Google Cloud Storage bucket.
+#[derive(Clone)]
+pub struct GcsPublisher {
+    /// The GCS client.
+    pub client: Client,
+    /// The name of the bucket files are uploaded to.
+    pub bucket_name: String,
+}
 
-/// Opens the connection to a JSONL file.
-pub async fn connect(queue_env: &str) -> StreamPublisherConnection {
+/// Opens the connection to a GCS bucket.
+pub async fn connect(queue_env: &str) -> Result<GcsPublisher, PublishError> {
     let gcp_config = {
         match get_gcp_credentials_json_path() {
             Some(key_path) => {
                 let cred_file = CredentialsFile::new_from_file(key_path.to_owned())
                     .await
-                    .expect("GCP credentials file exists");
+                    .map_err(|e| PublishError::configuration(BACKEND, e))?;
                 // authenticate using the key file
                 ClientConfig::default()
                     .with_credentials(cred_file)
                     .await
-                    .unwrap()
+                    .map_err(|e| PublishError::configuration(BACKEND, e))?
             }
-            None => ClientConfig::default().with_auth().await.unwrap(),
+            None => ClientConfig::default()
+                .with_auth()
+                .await
+                .map_err(|e| PublishError::configuration(BACKEND, e))?,
         }
     };
 
-    let bucket_name = dotenvy::var(queue_env)
-        .unwrap_or_else(|_| panic!("{} should exist in .env file", queue_env))
-        .parse::<String>()
-        .unwrap();
+    let bucket_name = get_queue_name(queue_env)?.clone();
 
     // Attempt to create the client using the configuration from above
     let gcp_client = Client::new(gcp_config);
 
     // Return the created connection
-    StreamPublisherConnection {
-        client: StreamPublisherConnectionClient::GcsBucket(gcp_client),
-        queue_name: bucket_name,
+    Ok(GcsPublisher {
+        client: gcp_client,
+        bucket_name,
+    })
+}
+
+/// Classifies an error returned by GCS, by its HTTP status.  Rejected requests are fatal,
+/// missing buckets and permissions are configuration errors, anything else (e.g. rate limits,
+/// server errors and network failures) is retryable.
+fn classify_error(error: GcsError) -> PublishError {
+    match &error {
+        GcsError::Response(response) => match response.code {
+            401 | 403 | 404 => PublishError::configuration(BACKEND, error),
+            408 | 429 => PublishError::retryable(BACKEND, error),
+            400..=499 => PublishError::fatal(BACKEND, error),
+            _ => PublishError::retryable(BACKEND, error),
+        },
+        _ => PublishError::retryable(BACKEND, error),
+    }
+}
+
+impl GcsPublisher {
+    /// Uploads the contents to the given destination in the bucket, retrying retryable
+    /// failures according to the configured retry policy.
+    async fn upload_with_retry(
+        &self,
+        file_destination: String,
+        contents: String,
+    ) -> Result<(), PublishError> {
+        let upload_type = UploadType::Simple(Media::new(file_destination));
+        config::get()
+            .retry
+            .run("google_cloud_storage upload", || async {
+                self.client
+                    .upload_object(
+                        &UploadObjectRequest {
+                            bucket: self.bucket_name.to_owned(),
+                            ..Default::default()
+                        },
+                        contents.clone(),
+                        &upload_type,
+                    )
+                    .await
+                    .map(|_| ())
+                    .map_err(classify_error)
+            })
+            .await
+            .map_err(PublishError::from)
     }
 }
 
-impl StreamPublisherConnectionClient {
-    /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish_batch<T: Serialize + Message>(
+#[async_trait]
+impl Publisher for GcsPublisher {
+    /// Publish a prost message to a JSON file in the bucket
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        // TODO: try using serde_json::to_vec()
+        let record_string =
+            serde_json::to_string::<T>(&msg).map_err(|e| PublishError::fatal(BACKEND, e))?;
+
+        let filename = [context.name.as_str(), ".json"].concat();
+
+        self.upload_with_retry(filename, record_string).await
+    }
+
+    /// Publish a batch of prost messages to JSONL files, partitioned into half-hour
+    /// directories by the timestamps in the context.
+    async fn publish_batch<T>(
         &self,
-        bucket: &str,
-        name: &str,
-        timestamps: Vec<UnixTimestamp>,
+        context: &RecordContext,
         msg_batch: Vec<T>,
-    ) {
-        assert!(timestamps.len() == msg_batch.len());
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        let name = context.name.as_str();
+        let timestamps = &context.timestamps;
+        if timestamps.len() != msg_batch.len() {
+            return Err(PublishError::fatal(
+                BACKEND,
+                format!(
+                    "{} records but {} timestamps in the batch",
+                    msg_batch.len(),
+                    timestamps.len()
+                ),
+            ));
+        }
         if timestamps.is_empty() {
             // TODO: remove this later.
             info!("skipping empty record batch...");
-            return;
+            return Ok(());
         }
-        let StreamPublisherConnectionClient::GcsBucket(gcs_client) = self;
 
         // Converts the records into strings
         let mut record_strings: Vec<String> = msg_batch
             .into_iter()
-            .map(|record| serde_json::to_string::<T>(&record).unwrap())
-            .collect();
+            .map(|record| serde_json::to_string::<T>(&record))
+            .collect::<Result<Vec<String>, serde_json::Error>>()
+            .map_err(|e| PublishError::fatal(BACKEND, e))?;
 
         /*
         we have a vector of timestamps
@@ -88,157 +173,59 @@ impl StreamPublisherConnectionClient {
 
          */
 
-        #[allow(unused_assignments)]
-        let mut directory_destination = String::new();
-        let mut cur_dir = None;
+        let mut cur_dir: Option<String> = None;
         let mut prev_i = 0;
         let mut filename: Option<String> = None;
-        for (i, ts) in timestamps.into_iter().enumerate() {
+        for (i, dt) in timestamps.iter().enumerate() {
             filename = Some([name, "_", &prev_i.to_string(), ".jsonl"].concat());
 
-            let dt = match Utc.timestamp_opt(ts.seconds, ts.nanos) {
-                chrono::LocalResult::None => panic!("timestamp should be valid and parseable"),
-                chrono::LocalResult::Single(s) => s,
-                chrono::LocalResult::Ambiguous(_, _) => panic!("UTC doesn't have DST"),
-            };
-
-            directory_destination = if dt.minute() < 30 {
-                let date = dt.date_naive().to_string();
-                let hour = dt.hour().to_string();
-                let minute = String::from("0");
-                [date, hour, minute].join("/")
+            let date = dt.date_naive().to_string();
+            let hour = dt.hour().to_string();
+            let minute = if dt.minute() < 30 {
+                String::from("0")
             } else {
-                let date = dt.date_naive().to_string();
-                let hour = dt.hour().to_string();
-                let minute = String::from("30");
-                [date, hour, minute].join("/")
+                String::from("30")
             };
+            let directory_destination = [date, hour, minute].join("/");
 
-            match cur_dir {
-                None => {}
-                Some(ref c) => {
-                    if c == &directory_destination {
-                        continue;
-                    } else {
-                        // TODO: consider using a multi-part upload if the file content is really large
-                        let c_string: String = String::from(c);
-                        let file_destination = [c_string, filename.clone().unwrap()].join("/");
-                        let upload_type = UploadType::Simple(Media::new(file_destination));
-                        loop {
-                            // if we want to use transactions at indices 0 and 1,
-                            // then we need to split_off() with a value of 2. so if i=1 and prev_i=0, then 1-0+1 = 2.
-                            let num_to_drain = i - prev_i;
-                            let batch: Vec<String> =
-                                record_strings.drain(0..num_to_drain).collect();
-                            // Joins the record strings with a newline character between them
-                            let concatenated_records: String = batch
-                                .into_iter()
-                                .reduce(|acc, record| acc + "\n" + &record)
-                                .unwrap_or(String::new()); // create an empty file if there are no records
-                            let uploaded = gcs_client
-                                .upload_object(
-                                    &UploadObjectRequest {
-                                        bucket: bucket.to_owned(),
-                                        ..Default::default()
-                                    },
-                                    concatenated_records.clone(),
-                                    &upload_type,
-                                )
-                                .await;
-                            match uploaded {
-                                Ok(_) => break,
-                                Err(e) => {
-                                    error!("Failed to upload to GCS. Error: {:?}", e);
-                                    warn!("Retrying GCS upload...");
-                                    //panic!("Panicking due to failed GCS upload. Perhaps this should be uploaded as multi-part instead of simple? Or simply retried with backoff until success? Or maybe something has been misconfigured.");
-                                }
-                            }
-                        }
-                        prev_i = i;
-                    }
+            if let Some(ref c) = cur_dir {
+                if c == &directory_destination {
+                    continue;
                 }
+                // TODO: consider using a multi-part upload if the file content is really large
+                let file_destination = [c.clone(), filename.clone().unwrap()].join("/");
+                // if we want to use transactions at indices 0 and 1,
+                // then we need to split_off() with a value of 2. so if i=1 and prev_i=0, then 1-0+1 = 2.
+                let num_to_drain = i - prev_i;
+                let batch: Vec<String> = record_strings.drain(0..num_to_drain).collect();
+                // Joins the record strings with a newline character between them
+                let concatenated_records: String = batch
+                    .into_iter()
+                    .reduce(|acc, record| acc + "\n" + &record)
+                    .unwrap_or(String::new()); // create an empty file if there are no records
+                self.upload_with_retry(file_destination, concatenated_records)
+                    .await?;
+                prev_i = i;
             }
-            cur_dir = Some(directory_destination.clone());
+            cur_dir = Some(directory_destination);
         }
         let file_destination = [cur_dir.unwrap(), filename.unwrap()].join("/");
-        let upload_type = UploadType::Simple(Media::new(file_destination));
         // Joins the record strings with a newline character between them
         let concatenated_records: String = record_strings
-            .clone()
             .into_iter()
             .reduce(|acc, record| acc + "\n" + &record)
             .unwrap_or(String::new()); // create an empty file if there are no records
-        loop {
-            let uploaded = gcs_client
-                .upload_object(
-                    &UploadObjectRequest {
-                        bucket: bucket.to_owned(),
-                        ..Default::default()
-                    },
-                    concatenated_records.clone(),
-                    &upload_type,
-                )
-                .await;
-            match uploaded {
-                Ok(_) => break,
-                Err(e) => {
-                    error!("Failed to upload to GCS. Error: {:?}", e);
-                    warn!("Retrying GCS upload...");
-                    //panic!("Panicking due to failed GCS upload. Perhaps this should be uploaded as multi-part instead of simple? Or simply retried with backoff until success? Or maybe something has been misconfigured.");
-                }
-            }
-        }
+        self.upload_with_retry(file_destination, concatenated_records)
+            .await
     }
 
-    /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish<T: Serialize + Message>(&self, bucket: &str, name: &str, msg: T) {
-        let StreamPublisherConnectionClient::GcsBucket(gcs_client) = self;
-
-        // TODO: try using serde_json::to_vec()
-        let record_string = serde_json::to_string::<T>(&msg).unwrap();
-
-        let filename = [name, ".json"].concat();
-
-        let upload_type = UploadType::Simple(Media::new(filename));
-
-        let uploaded = gcs_client
-            .upload_object(
-                &UploadObjectRequest {
-                    bucket: bucket.to_owned(),
-                    ..Default::default()
-                },
-                record_string,
-                &upload_type,
-            )
-            .await;
-        match uploaded {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to upload to GCS. Error: {:?}", e);
-                panic!("Panicking due to failed GCS upload. Perhaps this should be uploaded as multi-part instead of simple? Or simply retried with backoff until success? Or maybe something has been misconfigured.");
-            }
-        }
-    }
-}
-
-impl StreamPublisherConnection {
-    /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish_batch<T: Serialize + Message>(
-        &self,
-        filename: &str,
-        timestamp: Vec<UnixTimestamp>,
-        msg_batch: Vec<T>,
-    ) {
-        self.client
-            .publish_batch(&self.queue_name, filename, timestamp, msg_batch)
-            .await;
+    /// Uploads complete before `publish` returns, so there is nothing to flush.
+    async fn flush(&self) -> Result<(), PublishError> {
+        Ok(())
     }
 
-    /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish<T: Serialize + Message>(&self, filename: &str, msg: T) {
-        self.client.publish(&self.queue_name, filename, msg).await;
+    /// The connection is closed when the client is dropped.
+    async fn disconnect(self) -> Result<(), PublishError> {
+        Ok(())
     }
 }
@@ -1,14 +1,10 @@
 //! This module contains implementation details for
-//! StreamPublisherConnection when `JSON` feature is
-//! enabeld.  This allows StreamPublisherConnection to
+//! the JSON output backend, enabled with the `JSON` feature.
+//! This allows StreamPublisherConnection to
 //! publish to json files in a directory.
-//!
-//! //! This module contains implementation deatils for
-//! StreamPublisherConnection when `JSON` feature is
-//! enabled.  This allows StreamPublisherConnection
-//! to publish to a local JSONL file
 
-use prost::Message;
+use async_trait::async_trait;
+use prost::{Message, Name};
 use serde::Serialize;
 use std::fs::create_dir_all;
 use std::fs::OpenOptions;
@@ -16,57 +12,91 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use super::environment::*;
-use super::publish::{StreamPublisherConnection, StreamPublisherConnectionClient};
+use super::error::PublishError;
+use super::publish::{Publisher, RecordContext};
 
-/// Opens the connection to a JSONL file.
-pub async fn connect(queue_env: &str) -> StreamPublisherConnection {
+/// The name of this backend, used in errors.
+const BACKEND: &str = "json";
+
+/// An output directory that each record is written to as a separate JSON file.
+#[derive(Clone)]
+pub struct JsonPublisher {
+    /// The directory the files are created in.
+    pub directory: PathBuf,
+    /// The name of the subdirectory (within the output directory) for this table.
+    pub subdirectory: String,
+}
+
+/// Opens the connection to a directory of JSON files.
+pub async fn connect(queue_env: &str) -> Result<JsonPublisher, PublishError> {
     // Get expected output directory as a string
-    let output_dir_string = get_output_dir();
+    let output_dir_string = get_output_dir()?;
 
     // transform it into a path object
     let mut output_dir = PathBuf::new();
     output_dir.push(output_dir_string);
-    let subdirectory = dotenvy::var(queue_env)
-        .unwrap_or_else(|_| panic!("{} should exist in the .env file", queue_env));
+    let subdirectory = get_queue_name(queue_env)?.clone();
     output_dir.push(subdirectory.clone());
     // transform it into a path object
-    create_dir_all(&output_dir).expect("directory creation permissions and storage available");
+    create_dir_all(&output_dir).map_err(|e| PublishError::io(BACKEND, e))?;
 
     // Return the created connection
-    StreamPublisherConnection {
-        client: StreamPublisherConnectionClient::Json(output_dir),
-        queue_name: subdirectory.to_string(),
-    }
+    Ok(JsonPublisher {
+        directory: output_dir,
+        subdirectory,
+    })
 }
 
-impl StreamPublisherConnectionClient {
-    /// Publish a prost message to a json file with the given name
-    #[inline]
-    pub async fn publish<T: Serialize + Message>(&self, name: &str, msg: T) {
-        // Extract the jsonfile_arc
-        let StreamPublisherConnectionClient::Json(directory) = self;
+impl JsonPublisher {
+    /// Writes a prost message to a json file with the given name
+    fn write<T: Serialize>(&self, name: &str, msg: &T) -> Result<(), PublishError> {
+        let json = serde_json::to_string::<T>(msg).map_err(|e| PublishError::fatal(BACKEND, e))?;
         // Create an example filepath
-        let mut filepath = directory.join(String::from(name) + ".json");
-        // Recreate the filepath
-        while filepath.exists() {
-            filepath = directory.join(String::from(name) + ".json");
-        }
+        let filepath = self.directory.join(String::from(name) + ".json");
         // Create and write to the file
         let mut file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .open(filepath)
-            .expect("Failed to open file");
-        let json = serde_json::to_string::<T>(&msg).unwrap();
-        writeln!(file, "{}", json).expect("storage is writable");
+            .map_err(|e| PublishError::io(BACKEND, e))?;
+        writeln!(file, "{}", json).map_err(|e| PublishError::io(BACKEND, e))
     }
 }
 
-impl StreamPublisherConnection {
-    /// Publish a prost message to a JSON file with the given name
-    #[inline]
-    pub async fn publish<T: Serialize + Message>(&self, name: &str, msg: T) {
-        self.client.publish(name, msg).await;
+#[async_trait]
+impl Publisher for JsonPublisher {
+    /// Publish a prost message to a JSON file named after the context
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        self.write(&context.name, &msg)
+    }
+
+    /// Publish each prost message of the batch to its own JSON file, suffixed with the
+    /// index of the record in the batch
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        for (i, msg) in msg_batch.iter().enumerate() {
+            self.write(&format!("{}_{}", context.name, i), msg)?;
+        }
+        Ok(())
+    }
+
+    /// Files are written as records are published, so there is nothing to flush.
+    async fn flush(&self) -> Result<(), PublishError> {
+        Ok(())
+    }
+
+    /// There is no connection to close for local files.
+    async fn disconnect(self) -> Result<(), PublishError> {
+        Ok(())
     }
 }
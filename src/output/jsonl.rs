@@ -1,9 +1,10 @@
 //! This module contains implementation deatils for
-//! StreamPublisherConnection when `JSON` feature is
-//! enabled.  This allows StreamPublisherConnection
+//! the JSONL output backend, enabled with the `JSONL` feature.
+//! This allows StreamPublisherConnection
 //! to publish to a local JSONL file
 
-use prost::Message;
+use async_trait::async_trait;
+use prost::{Message, Name};
 use serde::Serialize;
 use std::fs::create_dir_all;
 use std::fs::OpenOptions;
@@ -11,94 +12,106 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use super::environment::*;
-use super::publish::{StreamPublisherConnection, StreamPublisherConnectionClient};
+use super::error::PublishError;
+use super::publish::{Publisher, RecordContext};
+
+/// The name of this backend, used in errors.
+const BACKEND: &str = "jsonl";
+
+/// An output directory that records are written to as JSONL files.
+#[derive(Clone)]
+pub struct JsonlPublisher {
+    /// The directory the files are created in.
+    pub directory: PathBuf,
+    /// The name of the subdirectory (within the output directory) for this table.
+    pub subdirectory: String,
+}
 
 /// Opens the connection to a JSONL file.
-pub async fn connect(queue_env: &str) -> StreamPublisherConnection {
+pub async fn connect(queue_env: &str) -> Result<JsonlPublisher, PublishError> {
     // Get expected output directory as a string
-    let output_dir_string = get_output_dir();
+    let output_dir_string = get_output_dir()?;
 
     // transform it into a path object
     let mut output_dir = PathBuf::new();
     output_dir.push(output_dir_string);
-    let subdirectory = dotenvy::var(queue_env)
-        .unwrap_or_else(|_| panic!("{} should exist in the .env file", queue_env));
+    let subdirectory = get_queue_name(queue_env)?.clone();
     output_dir.push(subdirectory.clone());
     // transform it into a path object
-    create_dir_all(&output_dir).expect("directory creation permissions and storage available");
+    create_dir_all(&output_dir).map_err(|e| PublishError::io(BACKEND, e))?;
 
     // Return the created connection
-    StreamPublisherConnection {
-        client: StreamPublisherConnectionClient::JsonL(output_dir),
-        queue_name: subdirectory.to_string(),
-    }
+    Ok(JsonlPublisher {
+        directory: output_dir,
+        subdirectory,
+    })
 }
 
-impl StreamPublisherConnectionClient {
+#[async_trait]
+impl Publisher for JsonlPublisher {
     /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish_batch<T: Serialize + Message>(&self, filename: &str, msg_batch: Vec<T>) {
-        if msg_batch.is_empty() {
-            return;
-        }
-
-        let StreamPublisherConnectionClient::JsonL(directory) = self;
+    // NOTE: this is intended to be used in cases where a block/transaction has only generated a single record for a table.
+    //  for example, a single Solana block generates a single record for the Blocks table. This is why it creates a .json file.
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
         // Create an example filepath
-        let filepath = directory.join(String::from(filename) + ".jsonl");
-        // Recreate the filepath
-        // while filepath.exists() {
-        //     filepath = directory.join(String::from(filename) + ".jsonl");
-        // }
+        let filepath = self.directory.join(context.name.clone() + ".json");
 
         // Create and append to the file
         let mut file = OpenOptions::new()
             .create(true)
-            .append(true)
+            .truncate(true)
+            .write(true)
             .open(filepath)
-            .expect("Failed to open file");
+            .map_err(|e| PublishError::io(BACKEND, e))?;
 
-        for record in msg_batch.into_iter() {
-            let json = serde_json::to_string::<T>(&record).unwrap();
-            writeln!(file, "{}", json).expect("storage is writable");
-        }
+        let json = serde_json::to_string::<T>(&msg).map_err(|e| PublishError::fatal(BACKEND, e))?;
+        writeln!(file, "{}", json).map_err(|e| PublishError::io(BACKEND, e))
     }
 
-    /// Publish a prost message to the JSON file
-    // NOTE: this is intended to be used in cases where a block/transaction has only generated a single record for a table.
-    //  for example, a single Solana block generates a single record for the Blocks table. This is why it creates a .json file.
-    #[inline]
-    pub async fn publish<T: Serialize + Message>(&self, name: &str, msg: T) {
-        let StreamPublisherConnectionClient::JsonL(directory) = self;
-        // Create an example filepath
-        let mut filepath = directory.join(String::from(name) + ".json");
-        // Recreate the filepath
-        while filepath.exists() {
-            filepath = directory.join(String::from(name) + ".json");
+    /// Publish a batch of prost messages to the JSONL file
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        if msg_batch.is_empty() {
+            return Ok(());
         }
 
+        // Create an example filepath
+        let filepath = self.directory.join(context.name.clone() + ".jsonl");
+
         // Create and append to the file
+        // NOTE: the whole batch is serialized before anything is written, so a record that
+        // can't be serialized doesn't leave a partial batch in the file.
+        let lines = msg_batch
+            .iter()
+            .map(|record| serde_json::to_string::<T>(record).map(|json| json + "\n"))
+            .collect::<Result<String, serde_json::Error>>()
+            .map_err(|e| PublishError::fatal(BACKEND, e))?;
+
         let mut file = OpenOptions::new()
             .create(true)
-            .truncate(true)
-            .write(true)
+            .append(true)
             .open(filepath)
-            .expect("Failed to open file");
-
-        let json = serde_json::to_string::<T>(&msg).unwrap();
-        writeln!(file, "{}", json).expect("storage is writable");
+            .map_err(|e| PublishError::io(BACKEND, e))?;
+        file.write_all(lines.as_bytes())
+            .map_err(|e| PublishError::io(BACKEND, e))
     }
-}
 
-impl StreamPublisherConnection {
-    /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish_batch<T: Serialize + Message>(&self, filename: &str, msg_batch: Vec<T>) {
-        self.client.publish_batch(filename, msg_batch).await;
+    /// Files are written as records are published, so there is nothing to flush.
+    async fn flush(&self) -> Result<(), PublishError> {
+        Ok(())
     }
 
-    /// Publish a prost message to the JSON file
-    #[inline]
-    pub async fn publish<T: Serialize + Message>(&self, filename: &str, msg: T) {
-        self.client.publish(filename, msg).await;
+    /// There is no connection to close for local files.
+    async fn disconnect(self) -> Result<(), PublishError> {
+        Ok(())
     }
 }
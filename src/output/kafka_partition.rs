@@ -0,0 +1,212 @@
+//! This module decides the key and partition of the records published to Apache Kafka.
+//!
+//! Each record is keyed by `KAFKA_PARTITION_KEY`: the height of its block, its transaction
+//! hash or its account, taken from the record's fields (`KAFKA_KEY_FIELDS`).  A `Partitioner`
+//! then routes the record to one of the topic's partitions.  The default `murmur2`
+//! partitioner hashes the key like Kafka's own clients do, so records with the same key always
+//! go to the same partition, in the order they were published, and consumers written with
+//! other clients agree on where a key lives.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::error::PublishError;
+use super::publish::RecordContext;
+use crate::gaps::DEFAULT_HEIGHT_FIELDS;
+
+/// The fields holding the transaction hash of a record, tried in order.
+const TRANSACTION_HASH_FIELDS: &[&str] = &["transaction_hash", "tx_hash", "signature", "hash"];
+
+/// The fields holding the account of a record, tried in order.
+const ACCOUNT_FIELDS: &[&str] = &["account", "address", "sender", "signer", "owner"];
+
+/// Routes records to the partitions of a topic.
+pub trait Partitioner: Send + Sync {
+    /// Returns the index of the partition (out of `partitions`, at least 1) that a record with
+    /// `key` is published to.
+    fn partition(&self, key: Option<&[u8]>, partitions: usize) -> usize;
+}
+
+/// Routes keyed records by the murmur2 hash of their key, like the default partitioner of
+/// Kafka's clients, and records without key round-robin.
+#[derive(Default)]
+pub struct Murmur2Partitioner {
+    /// The partition of the next record without key.
+    unkeyed: RoundRobinPartitioner,
+}
+
+impl Partitioner for Murmur2Partitioner {
+    fn partition(&self, key: Option<&[u8]>, partitions: usize) -> usize {
+        match key {
+            Some(key) => (murmur2(key) & 0x7fff_ffff) as usize % partitions.max(1),
+            None => self.unkeyed.partition(None, partitions),
+        }
+    }
+}
+
+/// Routes records to each partition in turn, ignoring their key.
+///
+/// NOTE: records with the same key can then be consumed out of order.
+#[derive(Default)]
+pub struct RoundRobinPartitioner {
+    /// The partition of the next record.
+    next: AtomicUsize,
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&self, _key: Option<&[u8]>, partitions: usize) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % partitions.max(1)
+    }
+}
+
+/// Returns the partitioner named `name`, one of `config::KAFKA_PARTITIONERS`.
+pub fn partitioner(name: &str) -> Result<Arc<dyn Partitioner>, PublishError> {
+    match name {
+        "murmur2" => Ok(Arc::new(Murmur2Partitioner::default())),
+        "round_robin" => Ok(Arc::new(RoundRobinPartitioner::default())),
+        _ => Err(PublishError::configuration(
+            "apache_kafka",
+            format!("unknown partitioner: {}", name),
+        )),
+    }
+}
+
+/// The murmur2 hash of Kafka's clients, as a signed integer like theirs.
+pub fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (index, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u32) << (8 * index);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+/// What the records are keyed by.
+pub struct PartitionKey {
+    /// Whether the height of the records' block is used when the context holds it.
+    block_number: bool,
+    /// The fields of the records holding the key, tried in order.
+    fields: Vec<String>,
+}
+
+impl PartitionKey {
+    /// Returns the key named `key` (one of `config::KAFKA_PARTITION_KEYS`), taken from
+    /// `fields` or, if there are none, from the usual fields for that key.  Returns `None` if
+    /// records aren't keyed.
+    pub fn new(key: &str, fields: &[String]) -> Result<Option<PartitionKey>, PublishError> {
+        let default_fields = match key {
+            "none" => return Ok(None),
+            "block_number" => DEFAULT_HEIGHT_FIELDS,
+            "transaction_hash" => TRANSACTION_HASH_FIELDS,
+            "account" => ACCOUNT_FIELDS,
+            _ => {
+                return Err(PublishError::configuration(
+                    "apache_kafka",
+                    format!("unknown partition key: {}", key),
+                ))
+            }
+        };
+        let fields = if fields.is_empty() {
+            default_fields
+                .iter()
+                .map(|field| field.to_string())
+                .collect()
+        } else {
+            fields.to_vec()
+        };
+        Ok(Some(PartitionKey {
+            block_number: key == "block_number",
+            fields,
+        }))
+    }
+
+    /// Returns the key of a record, or `None` if it holds none of the fields.
+    ///
+    /// NOTE: keys other than the height of the block serialize the record to find its
+    /// fields, which costs about as much as writing it as JSON.
+    pub fn key<T: Serialize>(&self, context: &RecordContext, record: &T) -> Option<Vec<u8>> {
+        if let (true, Some(block_number)) = (self.block_number, context.block_number) {
+            return Some(block_number.to_string().into_bytes());
+        }
+        let record = serde_json::to_value(record).ok()?;
+        self.fields
+            .iter()
+            .find_map(|field| match record.get(field)? {
+                Value::String(key) => Some(key.clone()),
+                Value::Number(key) => Some(key.to_string()),
+                _ => None,
+            })
+            .map(String::into_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur2_matches_kafka() {
+        // NOTE: the values of Kafka's own tests of its murmur2 implementation.
+        assert_eq!(murmur2(b"21"), -973932308);
+        assert_eq!(murmur2(b"foobar"), -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+        assert_eq!(murmur2(b"abc"), 479470107);
+    }
+
+    #[test]
+    fn test_keys_and_partitions() {
+        #[derive(Serialize)]
+        struct Transfer {
+            slot: u64,
+            signature: String,
+        }
+        let transfer = Transfer {
+            slot: 7,
+            signature: String::from("5sig"),
+        };
+        let context = RecordContext::new("7").with_block_number(8);
+
+        let height = PartitionKey::new("block_number", &[]).unwrap().unwrap();
+        assert_eq!(height.key(&context, &transfer), Some(b"8".to_vec()));
+        assert_eq!(
+            height.key(&RecordContext::new("7"), &transfer),
+            Some(b"7".to_vec())
+        );
+        let hash = PartitionKey::new("transaction_hash", &[]).unwrap().unwrap();
+        assert_eq!(hash.key(&context, &transfer), Some(b"5sig".to_vec()));
+        let account = PartitionKey::new("account", &[]).unwrap().unwrap();
+        assert_eq!(account.key(&context, &transfer), None);
+        assert!(PartitionKey::new("none", &[]).unwrap().is_none());
+
+        let partitioner = Murmur2Partitioner::default();
+        let partition = partitioner.partition(Some(b"5sig"), 6);
+        assert!(partition < 6);
+        assert_eq!(partitioner.partition(Some(b"5sig"), 6), partition);
+        assert_ne!(
+            partitioner.partition(None, 6),
+            partitioner.partition(None, 6)
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! This module publishes the records of each block, across every table, in a single Kafka
+//! transaction, when `KAFKA_TRANSACTIONAL` is set.
+//!
+//! Each worker has a `KafkaTransaction`: a transactional producer, shared by the Kafka
+//! connections of every table of the worker (see `StreamPublisherConnection::with_transaction`).
+//! A block is published between `begin()` and `commit()`, which publishes the block's progress
+//! marker to `KAFKA_PROGRESS_TOPIC` (read by the `kafka` checkpoint store) and commits the
+//! transaction.  Consumers reading committed records (`isolation.level=read_committed`) see
+//! every record of a block at once, or none of them, and a block is only recorded in the
+//! checkpoints once its records are committed.  A block that fails is aborted when the next
+//! transaction begins, so its retry doesn't duplicate the records that were already published.
+//!
+//! The transactional ids are `KAFKA_TRANSACTIONAL_ID` followed by the slot of the worker (see
+//! `BlockchainConfig::worker_publisher`), so a restarted indexer fences the producers of the
+//! previous run and aborts their transactions.  `KAFKA_TRANSACTIONAL_ID` must be unique to each
+//! indexer instance sharing the brokers, or their producers fence each other.
+
+use rdkafka::{
+    config::ClientConfig,
+    error::{KafkaError, KafkaResult},
+    producer::{FutureProducer, FutureRecord, Producer},
+    util::Timeout,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::apache_kafka::{self, classify_error, BACKEND};
+use super::error::PublishError;
+use crate::checkpoint::kafka::marker;
+use crate::config::{self, KafkaConfig};
+
+/// How long initializing, committing or aborting a transaction may take.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The transaction that a worker publishes its blocks in.  Its clones share the producer.
+#[derive(Clone)]
+pub struct KafkaTransaction {
+    /// The transactional producer, shared by the tables of the worker.
+    producer: FutureProducer,
+    /// The topic the progress markers are published to.
+    progress_topic: String,
+    /// The name the checkpoints are recorded under.
+    output: String,
+    /// Whether a transaction was begun and not committed or aborted yet.
+    open: Arc<AtomicBool>,
+}
+
+/// Classifies an error of a transaction.  Fatal errors (e.g. a producer fenced by another one
+/// with the same transactional id) are configuration errors, since the producer can't be used
+/// anymore, others are classified like the errors of publishing.
+fn transaction_error(error: &KafkaError) -> PublishError {
+    match error {
+        KafkaError::Transaction(rdkafka_error) if rdkafka_error.is_fatal() => {
+            PublishError::configuration(BACKEND, error)
+        }
+        KafkaError::Transaction(_) => PublishError::retryable(BACKEND, error),
+        _ => classify_error(error),
+    }
+}
+
+/// Returns the transactional id of the producer of the worker in slot `worker`.
+fn transactional_id(kafka: &KafkaConfig, worker: usize) -> String {
+    format!("{}-{}", kafka.transactional_id, worker)
+}
+
+impl KafkaTransaction {
+    /// Creates the transactional producer of the worker in slot `worker`, with the configured
+    /// brokers and batching, and the transactional id `KAFKA_TRANSACTIONAL_ID-<worker>`.
+    pub async fn for_worker(worker: usize) -> Result<KafkaTransaction, PublishError> {
+        let config = config::get();
+        let kafka = &config.kafka;
+        let progress_topic = kafka.progress_topic.clone().ok_or_else(|| {
+            PublishError::configuration(BACKEND, "kafka.progress_topic should be configured")
+        })?;
+        let mut client_config = apache_kafka::producer_config(kafka, &kafka.compression)?;
+        client_config.set("transactional.id", transactional_id(kafka, worker));
+        KafkaTransaction::new(
+            client_config,
+            progress_topic,
+            config.checkpoint.output(&config.output),
+        )
+        .await
+    }
+
+    /// Creates a transactional producer with `client_config` (which should set a
+    /// `transactional.id`), and initializes its transactions.  Its progress markers are
+    /// published to `progress_topic`, for `output`.
+    pub async fn new(
+        client_config: ClientConfig,
+        progress_topic: String,
+        output: String,
+    ) -> Result<KafkaTransaction, PublishError> {
+        let transaction = KafkaTransaction {
+            producer: client_config.create().map_err(|e| classify_error(&e))?,
+            progress_topic,
+            output,
+            open: Arc::default(),
+        };
+        transaction
+            .blocking(|producer| producer.init_transactions(TRANSACTION_TIMEOUT))
+            .await?;
+        Ok(transaction)
+    }
+
+    /// Returns the transactional producer, for the publishers of the tables.
+    pub fn producer(&self) -> &FutureProducer {
+        &self.producer
+    }
+
+    /// Calls a blocking method of the producer on the blocking thread pool.
+    async fn blocking<F>(&self, call: F) -> Result<(), PublishError>
+    where
+        F: FnOnce(&FutureProducer) -> KafkaResult<()> + Send + 'static,
+    {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || call(&producer))
+            .await
+            .map_err(|e| PublishError::retryable(BACKEND, e))?
+            .map_err(|e| transaction_error(&e))
+    }
+
+    /// Begins the transaction of a block, aborting the transaction of the previous block if
+    /// it wasn't committed (e.g. because the block failed).
+    pub async fn begin(&self) -> Result<(), PublishError> {
+        self.abort().await?;
+        self.blocking(|producer| producer.begin_transaction())
+            .await?;
+        self.open.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Publishes the progress marker of the block at `height` and commits the transaction.
+    /// The records of the tables should be flushed first, so their failures are known.
+    pub async fn commit(&self, height: u64) -> Result<(), PublishError> {
+        let (key, payload) = marker(&self.output, height, height + 1);
+        self.producer
+            .send(
+                FutureRecord::to(&self.progress_topic)
+                    .key(&key)
+                    .payload(&payload),
+                Timeout::Never,
+            )
+            .await
+            .map_err(|(e, _)| classify_error(&e))?;
+        self.blocking(|producer| producer.commit_transaction(TRANSACTION_TIMEOUT))
+            .await?;
+        self.open.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Aborts the open transaction, if any, so none of its records are committed.
+    pub async fn abort(&self) -> Result<(), PublishError> {
+        if !self.open.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.blocking(|producer| producer.abort_transaction(TRANSACTION_TIMEOUT))
+            .await?;
+        self.open.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::{kafka::KafkaCheckpointStore, CheckpointStore};
+    use crate::output::apache_kafka::mock_publisher;
+    use crate::output::publish::{Publisher, RecordContext};
+    use crate::reorg::Retraction;
+    use rdkafka::mocking::MockCluster;
+
+    #[tokio::test]
+    async fn test_blocks_are_committed_with_their_marker() {
+        crate::testing::init_config();
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("blocks", 1, 1).unwrap();
+        cluster.create_topic("progress", 1, 1).unwrap();
+        let bootstrap_servers = cluster.bootstrap_servers();
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &bootstrap_servers)
+            .set("transactional.id", "test-0");
+        let transaction = KafkaTransaction::new(
+            client_config,
+            String::from("progress"),
+            String::from("apache_kafka"),
+        )
+        .await
+        .unwrap();
+        let publisher = mock_publisher(&bootstrap_servers, "blocks").with_transaction(&transaction);
+
+        // NOTE: the first block is committed, the second fails and is aborted when the third
+        // begins, which would fail if the second were still open.  The mock cluster doesn't
+        // hide aborted records from consumers, so only the markers are checked.
+        for height in 1..=3 {
+            transaction.begin().await.unwrap();
+            let context = RecordContext::new("1").with_block_number(height);
+            publisher
+                .publish(&context, Retraction::default())
+                .await
+                .unwrap();
+            publisher.flush().await.unwrap();
+            if height != 2 {
+                transaction.commit(height).await.unwrap();
+            }
+        }
+
+        assert!(!transaction.open.load(Ordering::SeqCst));
+        let mut client_config = ClientConfig::new();
+        client_config.set("bootstrap.servers", &bootstrap_servers);
+        let checkpoints = KafkaCheckpointStore::new(client_config, String::from("progress"))
+            .unwrap()
+            .completed("apache_kafka")
+            .unwrap();
+        assert_eq!(checkpoints.ranges().collect::<Vec<_>>(), [(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn test_transactional_ids_are_stable() {
+        let kafka = KafkaConfig {
+            transactional_id: String::from("indexer-a"),
+            ..KafkaConfig::default()
+        };
+        let ids = |workers: usize| {
+            (0..workers)
+                .map(|worker| transactional_id(&kafka, worker))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(ids(3), ["indexer-a-0", "indexer-a-1", "indexer-a-2"]);
+        assert_eq!(ids(3), ids(3));
+    }
+}
@@ -1,5 +1,10 @@
 #![doc = include_str!("README.md")]
+pub mod dead_letter;
+pub mod error;
+pub mod fanout;
+pub mod ordered;
 pub mod publish;
+pub mod routing;
 
 #[cfg(feature = "SINGLE_PUBLISHER")]
 pub mod single_stream_publisher;
@@ -9,6 +14,10 @@ pub mod google_pubsub;
 
 #[cfg(feature = "APACHE_KAFKA")]
 pub mod apache_kafka;
+#[cfg(feature = "APACHE_KAFKA")]
+pub mod kafka_partition;
+#[cfg(feature = "APACHE_KAFKA")]
+pub mod kafka_transaction;
 
 #[cfg(feature = "RABBITMQ_CLASSIC")]
 pub mod rabbitmq_classic;
@@ -31,7 +40,12 @@ pub mod environment;
 mod tests {
     #[tokio::test]
     async fn test_publisher_connection() {
-        let _ = crate::output::publish::StreamPublisher::new().await;
+        crate::testing::init_config();
+        let _ = crate::output::publish::connect(
+            "QUEUE_NAME",
+            &crate::output::publish::ChainInfo::new("test"),
+        )
+        .await;
         //publisher.disconnect().await;
     }
 }
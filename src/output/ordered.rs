@@ -0,0 +1,154 @@
+//! This module contains the reorder buffer, which releases the records of blocks completed out
+//! of order (e.g. by several workers) strictly in height order, for outputs whose consumers
+//! expect monotonic heights.  It is enabled with `OUTPUT_ORDERED`.
+//!
+//! The buffer knows the heights that will be completed, so heights that were already indexed
+//! aren't waited for.  A worker reserves a height before extracting it, and waits while the
+//! height is `OUTPUT_ORDERED_BUFFER` heights or more ahead of the release point, which bounds
+//! the blocks held in memory.  Completing the next height releases it, along with the heights
+//! after it that were already completed.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard, Notify};
+
+use crate::checkpoint::HeightSet;
+use crate::metrics;
+
+/// The heights waited for and the blocks completed ahead of them.
+struct State<T> {
+    /// The heights that haven't been released yet.
+    expected: HeightSet,
+    /// The next height to release, or `None` once every height was released.
+    next: Option<u64>,
+    /// The blocks completed ahead of `next`, or `None` for heights without records.
+    completed: BTreeMap<u64, Option<T>>,
+    /// The highest height reserved so far.
+    highest_reserved: u64,
+}
+
+impl<T> State<T> {
+    /// Exports how far ahead of the release point the workers are.
+    fn record_ahead(&self) {
+        let ahead = self
+            .next
+            .map(|next| self.highest_reserved.saturating_sub(next))
+            .unwrap_or_default();
+        metrics::record_reorder_ahead(ahead);
+    }
+}
+
+/// Releases the blocks completed out of order in height order.
+pub struct ReorderBuffer<T> {
+    /// How far ahead of the release point a height can be reserved.
+    capacity: u64,
+    /// The heights waited for and the blocks completed ahead of them.
+    state: Mutex<State<T>>,
+    /// Wakes up the reservations waiting for the release point to move.
+    released: Notify,
+    /// Held while released blocks are published, so releases don't overtake each other.
+    publishing: AsyncMutex<()>,
+}
+
+/// Blocks released in height order.  The next release waits until this one is dropped, so the
+/// blocks should be published before dropping it.
+pub struct Released<'a, T> {
+    /// The released blocks, in height order.
+    pub blocks: Vec<(u64, T)>,
+    /// Keeps the next release waiting.
+    _publishing: MutexGuard<'a, ()>,
+}
+
+impl<T> ReorderBuffer<T> {
+    /// Creates a buffer releasing the blocks at `heights`, reserving at most `capacity` heights
+    /// ahead of the release point.
+    pub fn new(heights: HeightSet, capacity: u64) -> ReorderBuffer<T> {
+        let next = heights.next_from(0);
+        ReorderBuffer {
+            capacity: capacity.max(1),
+            state: Mutex::new(State {
+                expected: heights,
+                next,
+                completed: BTreeMap::new(),
+                highest_reserved: 0,
+            }),
+            released: Notify::new(),
+            publishing: AsyncMutex::new(()),
+        }
+    }
+
+    /// Waits until `height` is less than `capacity` heights ahead of the release point.
+    pub async fn reserve(&self, height: u64) {
+        loop {
+            // NOTE: the notification is registered before checking, so a release between the
+            // check and the wait isn't missed.
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().expect("reorder buffer lock poisoned");
+                let ready = state
+                    .next
+                    .is_none_or(|next| height < next.saturating_add(self.capacity));
+                if ready {
+                    state.highest_reserved = state.highest_reserved.max(height);
+                    state.record_ahead();
+                    return;
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Completes a height, with its block or `None` if it has no records to publish (e.g. it
+    /// had no block or failed), and returns the blocks it releases.
+    pub async fn complete(&self, height: u64, block: Option<T>) -> Released<'_, T> {
+        let publishing = self.publishing.lock().await;
+        let mut blocks = Vec::new();
+        {
+            let mut state = self.state.lock().expect("reorder buffer lock poisoned");
+            state.completed.insert(height, block);
+            while let Some(next) = state.next {
+                let Some(block) = state.completed.remove(&next) else {
+                    break;
+                };
+                blocks.extend(block.map(|block| (next, block)));
+                state.next = next
+                    .checked_add(1)
+                    .and_then(|after| state.expected.next_from(after));
+            }
+            state.record_ahead();
+        }
+        self.released.notify_waiters();
+        Released {
+            blocks,
+            _publishing: publishing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_release_in_order() {
+        let mut heights = HeightSet::new();
+        heights.insert(1, 3);
+        heights.insert(5, 7);
+        let buffer = ReorderBuffer::new(heights, 2);
+
+        assert!(buffer.complete(5, Some("e")).await.blocks.is_empty());
+        assert!(buffer.complete(2, Some("b")).await.blocks.is_empty());
+        let released = buffer.complete(1, Some("a")).await;
+        assert_eq!(released.blocks, [(1, "a"), (2, "b"), (5, "e")]);
+        drop(released);
+
+        // NOTE: 8 is more than 2 heights ahead of the release point (6) until 6 is released.
+        let reserve = tokio::time::timeout(std::time::Duration::from_millis(50), async {
+            buffer.reserve(8).await
+        })
+        .await;
+        assert!(reserve.is_err());
+        assert!(buffer.complete(6, None).await.blocks.is_empty());
+        buffer.reserve(8).await;
+    }
+}
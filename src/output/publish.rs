@@ -2,88 +2,519 @@
 //! depending on the compilation features enabled.  The objective is to allow
 //! the structs and functions provided in this module regardless of the comilation
 //! features enabled.
+//!
+//! Every output backend implements the [`Publisher`] trait, and the backend used
+//! at runtime is selected with the `OUTPUT_BACKEND` environment variable (or per
+//! table, see `routing.rs`), from the backends that were compiled in with their features.
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
+use prost::{Message, Name};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::dead_letter;
+use super::environment::{get_output_skip_timeout_ms, get_output_skippable_backends};
+pub use super::error::PublishError;
+use super::fanout::{FanoutPublisher, FanoutSink, SinkMode};
+use super::routing::{DiscardPublisher, OutputRoute};
+
+// NOTE: with `SEPARATE_PUBLISHERS`, the StreamPublisher is defined by each blockchain config (as
+// `BlockchainConfig::StreamPublisher`) due to using the names of the tables to identify each publisher.
 #[cfg(feature = "SINGLE_PUBLISHER")]
 pub use super::single_stream_publisher::StreamPublisher;
 
-// This needs to be defined by the blockchain config due to using the names of the tables to identify each publisher.
-#[cfg(feature = "SEPARATE_PUBLISHERS")]
-pub use crate::blockchain_config::streampublisher::StreamPublisher;
-
-// Get the appropriate connect
-#[cfg(feature = "APACHE_KAFKA")]
-pub use super::apache_kafka::connect;
-#[cfg(feature = "GOOGLE_PUBSUB")]
-pub use super::google_pubsub::connect;
-#[cfg(feature = "JSON")]
-pub use super::json::connect;
-#[cfg(feature = "JSONL")]
-pub use super::jsonl::connect;
-#[cfg(feature = "RABBITMQ_CLASSIC")]
-pub use super::rabbitmq_classic::connect;
-#[cfg(feature = "RABBITMQ_STREAM")]
-pub use super::rabbitmq_stream::connect;
-
-/// An enum that represents a connection to an output.  Will only contain one item
-/// dependent on the enabled features.
+/// Describes the records being published, so that each output can decide where and how to
+/// write them.  Outputs ignore the fields that are irrelevant to them (for example, a
+/// message queue does not use the file `name`).
+#[derive(Clone, Debug, Default)]
+pub struct RecordContext {
+    /// The name used by file-based outputs (JSON, JSONL, GCS) for the file being written.
+    pub name: String,
+    /// The timestamps of the records, used by outputs that partition files by time (GCS).
+    /// When publishing a batch, there should be one timestamp per record.
+    pub timestamps: Vec<DateTime<Utc>>,
+    /// The height of the block the records were extracted from, kept with dead letters.
+    pub block_number: Option<u64>,
+    /// The protobuf full name of the records, overriding their `prost::Name` (e.g. for raw
+    /// records replayed from dead letters).
+    pub message_type: Option<String>,
+}
+
+impl RecordContext {
+    /// Creates a context with the given file name and no timestamps.
+    pub fn new(name: impl Into<String>) -> RecordContext {
+        RecordContext {
+            name: name.into(),
+            timestamps: Vec::new(),
+            block_number: None,
+            message_type: None,
+        }
+    }
+
+    /// Returns the context with the timestamps of the records attached.
+    pub fn with_timestamps(self, timestamps: Vec<DateTime<Utc>>) -> RecordContext {
+        RecordContext { timestamps, ..self }
+    }
+
+    /// Returns the context with the height of the records' block attached.
+    pub fn with_block_number(self, block_number: u64) -> RecordContext {
+        RecordContext {
+            block_number: Some(block_number),
+            ..self
+        }
+    }
+
+    /// Returns the context with the protobuf full name of the records attached.
+    pub fn with_message_type(self, message_type: impl Into<String>) -> RecordContext {
+        RecordContext {
+            message_type: Some(message_type.into()),
+            ..self
+        }
+    }
+
+    /// Returns the protobuf full name of the records, `T`'s unless the context overrides it.
+    pub fn message_type<T: Name>(&self) -> String {
+        self.message_type.clone().unwrap_or_else(T::full_name)
+    }
+}
+
+/// What the outputs need to know about the blockchain being indexed, passed to `connect()` by
+/// the blockchain config (see `BlockchainConfig::chain_info`).
+#[derive(Clone, Copy, Debug)]
+pub struct ChainInfo {
+    /// The name of the blockchain, used as the `chain_id` header of Kafka records unless
+    /// `KAFKA_CHAIN_ID` is set.
+    pub name: &'static str,
+    /// Maps the queue env key of a table to its Apache Avro schema, used when publishing Avro
+    /// messages to Pub/Sub.
+    #[cfg(feature = "APACHE_AVRO")]
+    pub avro_schema: fn(&str) -> Option<&'static str>,
+}
+
+impl ChainInfo {
+    /// Creates the info of the blockchain named `name`, without Avro schemas.
+    pub fn new(name: &'static str) -> ChainInfo {
+        ChainInfo {
+            name,
+            #[cfg(feature = "APACHE_AVRO")]
+            avro_schema: |_| None,
+        }
+    }
+}
+
+/// The common interface implemented by every output backend.
+///
+/// NOTE: records must implement both `prost::Message` and `serde::Serialize`, since the
+/// message queues publish the protobuf encoding while the file outputs write JSON.  They
+/// must also be `Clone` (as prost messages are), so a record can be sent to several outputs.
+///
+/// Failures are returned as a `PublishError` rather than panicking, so the caller can decide
+/// whether to retry, skip the record or stop indexing.
+#[async_trait]
+pub trait Publisher: Send + Sync + Sized {
+    /// Publishes a single record.
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static;
+
+    /// Publishes a batch of records.
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static;
+
+    /// Waits until every record handed to the publisher has been delivered.
+    async fn flush(&self) -> Result<(), PublishError>;
+
+    /// Flushes and closes the connection.  Should be called before terminating the program.
+    async fn disconnect(self) -> Result<(), PublishError>;
+}
+
+/// The output backends that can be selected at runtime.  Only the backends whose
+/// features are enabled are available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputBackend {
+    #[cfg(feature = "GOOGLE_PUBSUB")]
+    GooglePubSub,
+    #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+    GoogleCloudStorage,
+    #[cfg(feature = "APACHE_KAFKA")]
+    ApacheKafka,
+    #[cfg(feature = "RABBITMQ_CLASSIC")]
+    RabbitMQClassic,
+    #[cfg(feature = "RABBITMQ_STREAM")]
+    RabbitMQStream,
+    #[cfg(feature = "JSONL")]
+    JsonL,
+    #[cfg(feature = "JSON")]
+    Json,
+}
+
+impl OutputBackend {
+    /// Returns every backend compiled into this binary.
+    pub fn available() -> Vec<OutputBackend> {
+        vec![
+            #[cfg(feature = "GOOGLE_PUBSUB")]
+            OutputBackend::GooglePubSub,
+            #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+            OutputBackend::GoogleCloudStorage,
+            #[cfg(feature = "APACHE_KAFKA")]
+            OutputBackend::ApacheKafka,
+            #[cfg(feature = "RABBITMQ_CLASSIC")]
+            OutputBackend::RabbitMQClassic,
+            #[cfg(feature = "RABBITMQ_STREAM")]
+            OutputBackend::RabbitMQStream,
+            #[cfg(feature = "JSONL")]
+            OutputBackend::JsonL,
+            #[cfg(feature = "JSON")]
+            OutputBackend::Json,
+        ]
+    }
+
+    /// The name used to select the backend in the configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "GOOGLE_PUBSUB")]
+            OutputBackend::GooglePubSub => "google_pubsub",
+            #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+            OutputBackend::GoogleCloudStorage => "google_cloud_storage",
+            #[cfg(feature = "APACHE_KAFKA")]
+            OutputBackend::ApacheKafka => "apache_kafka",
+            #[cfg(feature = "RABBITMQ_CLASSIC")]
+            OutputBackend::RabbitMQClassic => "rabbitmq_classic",
+            #[cfg(feature = "RABBITMQ_STREAM")]
+            OutputBackend::RabbitMQStream => "rabbitmq_stream",
+            #[cfg(feature = "JSONL")]
+            OutputBackend::JsonL => "jsonl",
+            #[cfg(feature = "JSON")]
+            OutputBackend::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for OutputBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for OutputBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        OutputBackend::available()
+            .into_iter()
+            .find(|backend| backend.name() == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown or not compiled in output backend `{}`, expected one of: {}",
+                    s,
+                    OutputBackend::available()
+                        .iter()
+                        .map(|backend| backend.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// An enum that represents a connection to an output.  Contains one variant per
+/// backend compiled into the binary, and one for publishing to several of them at once.
 #[derive(Clone)]
 pub enum StreamPublisherConnectionClient {
     #[cfg(feature = "GOOGLE_PUBSUB")]
-    GcpPubSub(google_cloud_pubsub::publisher::Publisher),
+    GcpPubSub(super::google_pubsub::GooglePubSubPublisher),
     #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
-    GcsBucket(google_cloud_storage::client::Client),
+    GcsBucket(super::gcs::GcsPublisher),
     #[cfg(feature = "APACHE_KAFKA")]
-    ApacheKafka(
-        std::sync::Arc<rskafka::client::partition::PartitionClient>, // /*rskafka::client::producer::BatchProducer<rskafka::client::producer::aggregator::RecordAggregator,>,
-    ),
+    ApacheKafka(super::apache_kafka::ApacheKafkaPublisher),
     #[cfg(feature = "RABBITMQ_CLASSIC")]
-    RabbitMQClassic(amqprs::connection::Connection),
+    RabbitMQClassic(super::rabbitmq_classic::RabbitMQClassicPublisher),
     #[cfg(feature = "RABBITMQ_STREAM")]
-    RabbitMQStream(rabbitmq_stream_client::Producer<rabbitmq_stream_client::NoDedup>),
+    RabbitMQStream(super::rabbitmq_stream::RabbitMQStreamPublisher),
     #[cfg(feature = "JSONL")]
-    JsonL(std::path::PathBuf),
+    JsonL(super::jsonl::JsonlPublisher),
     #[cfg(feature = "JSON")]
-    Json(std::path::PathBuf),
+    Json(super::json::JsonPublisher),
+    /// Publishes to several backends at once
+    Fanout(super::fanout::FanoutPublisher),
+    /// Drops the records of a table that isn't needed
+    Discard(super::routing::DiscardPublisher),
+    /// Dead-letters the records that another connection fails to publish
+    DeadLetter(super::dead_letter::DeadLetterPublisher),
+}
+
+/// Calls the same expression on whichever backend the client holds.
+macro_rules! dispatch {
+    ($client:expr, $inner:ident => $call:expr) => {
+        match $client {
+            #[cfg(feature = "GOOGLE_PUBSUB")]
+            StreamPublisherConnectionClient::GcpPubSub($inner) => $call,
+            #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+            StreamPublisherConnectionClient::GcsBucket($inner) => $call,
+            #[cfg(feature = "APACHE_KAFKA")]
+            StreamPublisherConnectionClient::ApacheKafka($inner) => $call,
+            #[cfg(feature = "RABBITMQ_CLASSIC")]
+            StreamPublisherConnectionClient::RabbitMQClassic($inner) => $call,
+            #[cfg(feature = "RABBITMQ_STREAM")]
+            StreamPublisherConnectionClient::RabbitMQStream($inner) => $call,
+            #[cfg(feature = "JSONL")]
+            StreamPublisherConnectionClient::JsonL($inner) => $call,
+            #[cfg(feature = "JSON")]
+            StreamPublisherConnectionClient::Json($inner) => $call,
+            StreamPublisherConnectionClient::Fanout($inner) => $call,
+            StreamPublisherConnectionClient::Discard($inner) => $call,
+            StreamPublisherConnectionClient::DeadLetter($inner) => $call,
+        }
+    };
 }
 
 /// A struct that contains the client used to connect to the publisher and the queue_name
-//#[derive(Clone)]
+#[derive(Clone)]
 pub struct StreamPublisherConnection {
-    /// The `client` is an Enum with a singular item depending on the enabled features that
-    /// contain the functionality of publishing
+    /// The `client` is an Enum holding the backend selected at runtime, which contains
+    /// the functionality of publishing
     pub client: StreamPublisherConnectionClient,
     /// The `queue_name` is a string to represent the output stream.  This would be things like
     /// the google pubsub topic, the rabbitmq queue or stream name, etc.
     pub queue_name: String,
+}
 
-    /// Used to serialize the record when publishing.
-    #[cfg(feature = "APACHE_AVRO")]
-    pub schema: apache_avro::Schema,
+/// Connects to the output backend(s) that the table is routed to, either by its own
+/// `OUTPUT_BACKEND_<TABLE>` key or by `OUTPUT_BACKEND`.  When several backends are selected,
+/// the connection publishes to all of them through a `FanoutPublisher`.  When the table is
+/// routed to `none`, its records are dropped.  When a dead-letter directory or backend is
+/// configured, records that can't be published are dead-lettered (see `dead_letter.rs`).
+///
+/// NOTE: `queue_env` is not the name of the queue itself, rather the name of the
+/// parameter in the .env file that holds the queue name (e.g. `QUEUE_NAME_BLOCKS`).
+pub async fn connect(
+    queue_env: &str,
+    chain: &ChainInfo,
+) -> Result<StreamPublisherConnection, PublishError> {
+    let connection = connect_without_dead_letters(queue_env, chain).await?;
+    dead_letter::wrap(connection, queue_env, chain).await
+}
 
-    /// Not thread-safe. Needs to be constructed within the thread that is using it.
-    #[cfg(feature = "RABBITMQ_CLASSIC")]
-    pub channel: Option<amqprs::channel::Channel>,
+/// Connects to the output backend(s) that the table is routed to, like `connect()`, but
+/// returns the errors of records that can't be published instead of dead-lettering them.
+pub async fn connect_without_dead_letters(
+    queue_env: &str,
+    chain: &ChainInfo,
+) -> Result<StreamPublisherConnection, PublishError> {
+    let backends = match OutputRoute::for_table(queue_env)? {
+        OutputRoute::Drop => {
+            info!("Dropping the records of {}", queue_env);
+            return Ok(StreamPublisherConnection {
+                client: StreamPublisherConnectionClient::Discard(DiscardPublisher::default()),
+                queue_name: String::new(),
+            });
+        }
+        OutputRoute::Backends(backends) => backends,
+    };
+    match backends.as_slice() {
+        [backend] => connect_backend(*backend, queue_env, chain).await,
+        _ => {
+            let skippable = get_output_skippable_backends();
+            let timeout = Duration::from_millis(*get_output_skip_timeout_ms());
+            let mut sinks = Vec::with_capacity(backends.len());
+            for backend in backends.into_iter() {
+                let mode = if skippable.iter().any(|name| name == backend.name()) {
+                    SinkMode::Skip(timeout)
+                } else {
+                    SinkMode::Block
+                };
+                let connection = connect_backend(backend, queue_env, chain).await?;
+                sinks.push(FanoutSink::new(backend.to_string(), connection, mode));
+            }
+            let queue_name = sinks
+                .iter()
+                .map(|sink| sink.connection.queue_name.clone())
+                .collect::<Vec<String>>()
+                .join(",");
+            Ok(StreamPublisherConnection {
+                client: StreamPublisherConnectionClient::Fanout(FanoutPublisher { sinks }),
+                queue_name,
+            })
+        }
+    }
+}
 
-    /// Not thread-safe. Needs to be constructed within the thread that is using it.
-    #[cfg(feature = "APACHE_KAFKA")]
-    pub producer: Option<
-        rskafka::client::producer::BatchProducer<
-            rskafka::client::producer::aggregator::RecordAggregator,
-        >,
-    >,
+/// Connects to a single output backend.
+#[cfg_attr(
+    not(any(feature = "GOOGLE_PUBSUB", feature = "APACHE_KAFKA")),
+    allow(unused_variables)
+)]
+pub async fn connect_backend(
+    backend: OutputBackend,
+    queue_env: &str,
+    chain: &ChainInfo,
+) -> Result<StreamPublisherConnection, PublishError> {
+    info!("Connecting to {} for {}...", backend, queue_env);
+    let (client, queue_name) = match backend {
+        #[cfg(feature = "GOOGLE_PUBSUB")]
+        OutputBackend::GooglePubSub => {
+            let inner = super::google_pubsub::connect(queue_env, chain).await?;
+            let queue_name = inner.topic_name.clone();
+            (
+                StreamPublisherConnectionClient::GcpPubSub(inner),
+                queue_name,
+            )
+        }
+        #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+        OutputBackend::GoogleCloudStorage => {
+            let inner = super::gcs::connect(queue_env).await?;
+            let queue_name = inner.bucket_name.clone();
+            (
+                StreamPublisherConnectionClient::GcsBucket(inner),
+                queue_name,
+            )
+        }
+        #[cfg(feature = "APACHE_KAFKA")]
+        OutputBackend::ApacheKafka => {
+            let inner = super::apache_kafka::connect(queue_env, chain).await?;
+            let queue_name = inner.topic_name.clone();
+            (
+                StreamPublisherConnectionClient::ApacheKafka(inner),
+                queue_name,
+            )
+        }
+        #[cfg(feature = "RABBITMQ_CLASSIC")]
+        OutputBackend::RabbitMQClassic => {
+            let inner = super::rabbitmq_classic::connect(queue_env).await?;
+            let queue_name = inner.queue_name.clone();
+            (
+                StreamPublisherConnectionClient::RabbitMQClassic(inner),
+                queue_name,
+            )
+        }
+        #[cfg(feature = "RABBITMQ_STREAM")]
+        OutputBackend::RabbitMQStream => {
+            let inner = super::rabbitmq_stream::connect(queue_env).await?;
+            let queue_name = inner.stream_name.clone();
+            (
+                StreamPublisherConnectionClient::RabbitMQStream(inner),
+                queue_name,
+            )
+        }
+        #[cfg(feature = "JSONL")]
+        OutputBackend::JsonL => {
+            let inner = super::jsonl::connect(queue_env).await?;
+            let queue_name = inner.subdirectory.clone();
+            (StreamPublisherConnectionClient::JsonL(inner), queue_name)
+        }
+        #[cfg(feature = "JSON")]
+        OutputBackend::Json => {
+            let inner = super::json::connect(queue_env).await?;
+            let queue_name = inner.subdirectory.clone();
+            (StreamPublisherConnectionClient::Json(inner), queue_name)
+        }
+    };
+
+    Ok(StreamPublisherConnection { client, queue_name })
 }
 
-impl Clone for StreamPublisherConnection {
-    fn clone(&self) -> StreamPublisherConnection {
+impl StreamPublisherConnection {
+    /// Returns the connection prepared for a worker of its own: with a RabbitMQ channel, when
+    /// connected to RabbitMQ Classic.  Other backends are returned unchanged.
+    pub async fn for_worker(self) -> Result<StreamPublisherConnection, PublishError> {
+        #[allow(unused_mut)]
+        let mut connection = self;
+        #[cfg(feature = "RABBITMQ_CLASSIC")]
+        {
+            connection = connection.with_channel().await?;
+        }
+        Ok(connection)
+    }
+
+    /// Returns a new StreamPublisherConnection with a RabbitMQ channel, when connected to
+    /// RabbitMQ Classic.  Other backends are returned unchanged.
+    ///
+    /// NOTE: the channel cannot move threads, so this should be called once you are in the
+    /// thread you intend to use the publisher.
+    #[cfg(feature = "RABBITMQ_CLASSIC")]
+    pub async fn with_channel(self) -> Result<StreamPublisherConnection, PublishError> {
+        let client = match self.client {
+            StreamPublisherConnectionClient::RabbitMQClassic(inner) => {
+                StreamPublisherConnectionClient::RabbitMQClassic(inner.with_channel().await?)
+            }
+            StreamPublisherConnectionClient::Fanout(inner) => {
+                StreamPublisherConnectionClient::Fanout(inner.with_channel().await?)
+            }
+            StreamPublisherConnectionClient::DeadLetter(inner) => {
+                StreamPublisherConnectionClient::DeadLetter(inner.with_channel().await?)
+            }
+            #[allow(unreachable_patterns)]
+            client => client,
+        };
+        Ok(StreamPublisherConnection {
+            client,
+            queue_name: self.queue_name,
+        })
+    }
+
+    /// Returns the connection publishing to Kafka with the producer of a transaction, so its
+    /// records are committed along with the other tables of the block (see
+    /// `kafka_transaction`).  Other backends are returned unchanged.
+    #[cfg(feature = "APACHE_KAFKA")]
+    pub fn with_transaction(
+        self,
+        transaction: &super::kafka_transaction::KafkaTransaction,
+    ) -> StreamPublisherConnection {
+        let client = match self.client {
+            StreamPublisherConnectionClient::ApacheKafka(inner) => {
+                StreamPublisherConnectionClient::ApacheKafka(inner.with_transaction(transaction))
+            }
+            StreamPublisherConnectionClient::Fanout(inner) => {
+                StreamPublisherConnectionClient::Fanout(inner.with_transaction(transaction))
+            }
+            StreamPublisherConnectionClient::DeadLetter(inner) => {
+                StreamPublisherConnectionClient::DeadLetter(inner.with_transaction(transaction))
+            }
+            #[allow(unreachable_patterns)]
+            client => client,
+        };
         StreamPublisherConnection {
-            client: self.client.clone(),
-            queue_name: self.queue_name.clone(),
-            #[cfg(feature = "APACHE_AVRO")]
-            schema: self.schema.clone(),
-            #[cfg(feature = "RABBITMQ_CLASSIC")]
-            channel: None,
-            #[cfg(feature = "APACHE_KAFKA")]
-            producer: None,
+            client,
+            queue_name: self.queue_name,
         }
     }
 }
+
+#[async_trait]
+impl Publisher for StreamPublisherConnection {
+    async fn publish<T>(&self, context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        dispatch!(&self.client, inner => inner.publish(context, msg).await)
+    }
+
+    async fn publish_batch<T>(
+        &self,
+        context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        dispatch!(&self.client, inner => inner.publish_batch(context, msg_batch).await)
+    }
+
+    async fn flush(&self) -> Result<(), PublishError> {
+        dispatch!(&self.client, inner => inner.flush().await)
+    }
+
+    async fn disconnect(self) -> Result<(), PublishError> {
+        dispatch!(self.client, inner => inner.disconnect().await)
+    }
+}
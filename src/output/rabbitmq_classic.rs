@@ -1,122 +1,197 @@
 //! This module contains implementation details for
-//! StreamPublisherConnection when the `RABBITMQ_CLASSIC`
-//! feature is enabled.  This allows StreamPublisherConnection
+//! the RabbitMQ Classic output backend, enabled with the `RABBITMQ_CLASSIC`
+//! feature.  This allows StreamPublisherConnection
 //! to connect and publish to the RabbitMQ Classic (not to be
 //! confused with RabbitMQ Stream)
 
 use super::environment::*;
-use super::publish::{StreamPublisherConnection, StreamPublisherConnectionClient};
+use super::error::PublishError;
+use super::publish::{Publisher, RecordContext};
+use crate::config;
+use async_trait::async_trait;
 use log::info;
-use prost::Message;
+use prost::{Message, Name};
+use serde::Serialize;
 
-//use amqprs::channel::Channel;
+/// The name of this backend, used in errors.
+const BACKEND: &str = "rabbitmq_classic";
+
+/// A connection to a RabbitMQ Classic queue.
+pub struct RabbitMQClassicPublisher {
+    /// The connection to the RabbitMQ server.
+    pub connection: amqprs::connection::Connection,
+    /// The name of the RabbitMQ queue.
+    pub queue_name: String,
+    /// Not thread-safe. Needs to be constructed within the thread that is using it.
+    pub channel: Option<amqprs::channel::Channel>,
+}
+
+impl Clone for RabbitMQClassicPublisher {
+    fn clone(&self) -> RabbitMQClassicPublisher {
+        RabbitMQClassicPublisher {
+            connection: self.connection.clone(),
+            queue_name: self.queue_name.clone(),
+            channel: None,
+        }
+    }
+}
 
 /// Connects to the RabbitMQ Classic queue system.
-/// Expects the following parameters to be stored in the .env file:
+/// Expects the following parameters to be configured (in the configuration file or the .env file):
 /// - `RABBITMQ_ADDRESS`
 /// - `RABBITMQ_PORT`
 /// - `RABBITMQ_USER`
 /// - `RABBITMQ_PASSWORD`
-pub async fn connect(queue_name: &str) -> StreamPublisherConnection {
-    // Extract necessary information from the .env from the queue
-    let address = get_rabbitmq_addr();
-    let port = get_rabbitmq_port();
-    let user = get_rabbitmq_username();
-    let password = get_rabbitmq_password();
+pub async fn connect(queue_name: &str) -> Result<RabbitMQClassicPublisher, PublishError> {
+    // Extract necessary information from the configuration for the queue
+    let address = get_rabbitmq_addr()?;
+    let port = get_rabbitmq_port()?;
+    let user = get_rabbitmq_username()?;
+    let password = get_rabbitmq_password()?;
 
     info!("Creating rabbitmq environment...");
     let connection = amqprs::connection::Connection::open(
         &amqprs::connection::OpenConnectionArguments::new(address, *port, user, password),
     )
     .await
-    .expect("rabbitmq server has been setup");
+    .map_err(retryable)?;
 
     connection
         .register_callback(amqprs::callbacks::DefaultConnectionCallback)
         .await
-        .unwrap();
+        .map_err(retryable)?;
 
-    let rabbitmq_queue_name = dotenvy::var(queue_name)
-        .unwrap_or_else(|_| panic!("{} should exist in .env file", queue_name))
-        .parse::<String>()
-        .unwrap();
+    let rabbitmq_queue_name = get_queue_name(queue_name)?.clone();
 
-    StreamPublisherConnection {
-        client: StreamPublisherConnectionClient::RabbitMQClassic(connection),
+    Ok(RabbitMQClassicPublisher {
+        connection,
         queue_name: rabbitmq_queue_name,
         channel: None,
-    }
+    })
+}
+
+/// Wraps an error returned by the RabbitMQ client.  These are connection, channel and network
+/// errors, which may succeed once the connection is re-established, so they are retryable.
+fn retryable(error: amqprs::error::Error) -> PublishError {
+    PublishError::retryable(BACKEND, error)
 }
 
-impl StreamPublisherConnectionClient {
+impl RabbitMQClassicPublisher {
     /// Establishes a connection to the RabbitMQ Server
-    pub async fn establish_connection(&self, queue_name: &str) -> amqprs::channel::Channel {
-        let StreamPublisherConnectionClient::RabbitMQClassic(connection) = self;
-        let channel = connection.open_channel(None).await.unwrap();
+    pub async fn establish_connection(&self) -> Result<amqprs::channel::Channel, PublishError> {
+        let channel = self
+            .connection
+            .open_channel(None)
+            .await
+            .map_err(retryable)?;
 
         channel
             .register_callback(amqprs::callbacks::DefaultChannelCallback)
             .await
-            .unwrap();
+            .map_err(retryable)?;
 
-        let _ = channel
-            .queue_declare(amqprs::channel::QueueDeclareArguments::durable_client_named(queue_name))
-            .await
-            .unwrap()
-            .unwrap();
         channel
+            .queue_declare(
+                amqprs::channel::QueueDeclareArguments::durable_client_named(&self.queue_name),
+            )
+            .await
+            .map_err(retryable)?
+            .ok_or_else(|| {
+                PublishError::configuration(
+                    BACKEND,
+                    format!("queue {} could not be declared", self.queue_name),
+                )
+            })?;
+        Ok(channel)
     }
 
-    /// Disconnects from the RabbitMQ server
-    pub async fn disconnect(self) {
-        let StreamPublisherConnectionClient::RabbitMQClassic(connection) = self;
-        let _ = connection.close().await;
-    }
-}
-
-impl StreamPublisherConnection {
-    /// Returns a new StreamPublisherConnection with a channel.  This instance cannot be moved between
+    /// Returns a new RabbitMQClassicPublisher with a channel.  This instance cannot be moved between
     /// threads safely.
     ///
-    /// NOTE: You cannot use this function and send the resulting StreamPublisherConnection
+    /// NOTE: You cannot use this function and send the resulting publisher
     /// to another thread, as the channel cannot move threads.  Instead, you should
     /// call this function once you are in the thread you intend to use the publisher.
-    pub async fn with_channel(self) -> StreamPublisherConnection {
+    pub async fn with_channel(self) -> Result<RabbitMQClassicPublisher, PublishError> {
         // Create a channel with the current client
-        let channel = Some(self.client.establish_connection(&self.queue_name).await);
-        // Create a new StreamPublisherConnection
-        StreamPublisherConnection {
-            client: self.client,
-            queue_name: self.queue_name,
-            channel,
-        }
+        let channel = Some(self.establish_connection().await?);
+        Ok(RabbitMQClassicPublisher { channel, ..self })
     }
 
+    /// Returns the channel, which must have been created with `with_channel()`.
+    fn channel(&self) -> Result<&amqprs::channel::Channel, PublishError> {
+        self.channel.as_ref().ok_or_else(|| {
+            PublishError::configuration(
+                BACKEND,
+                "channel should have been created with StreamPublisherConnection.with_channel()",
+            )
+        })
+    }
+
+    /// Publishes a message on the channel, retrying retryable failures according to the
+    /// configured retry policy.
+    async fn send(
+        &self,
+        channel: &amqprs::channel::Channel,
+        payload: Vec<u8>,
+    ) -> Result<(), PublishError> {
+        let args = amqprs::channel::BasicPublishArguments::new("", &self.queue_name);
+        config::get()
+            .retry
+            .run("rabbitmq_classic publish", || async {
+                channel
+                    .basic_publish(
+                        amqprs::BasicProperties::default(),
+                        payload.clone(),
+                        args.clone(),
+                    )
+                    .await
+                    .map_err(retryable)
+            })
+            .await
+            .map_err(PublishError::from)
+    }
+}
+
+#[async_trait]
+impl Publisher for RabbitMQClassicPublisher {
     /// Sends the message to the RabbitMQ classic queue.
     ///
-    /// NOTE: Will panic if channel is not yet created.  The `RABBITMQ_CLASSIC` feature
+    /// NOTE: Will fail if channel is not yet created.  The `RABBITMQ_CLASSIC` feature
     /// creates a connection without a channel to allow the StreamPublisherConnection to move
     /// between threads safely.  Once in the thread you intend to publish in, you can call
     /// `with_channel` to return a StreamPublisherConnection with the same `client` and
     /// `queue_name`, but also with a channel that will only be functional in the current
     /// thread.
-    #[inline]
-    pub async fn publish<T: Message>(&self, msg: T) {
-        let args = amqprs::channel::BasicPublishArguments::new("", &self.queue_name);
-        self.channel
-            .as_ref()
-            .unwrap()
-            .basic_publish(
-                amqprs::BasicProperties::default(),
-                msg.encode_to_vec(),
-                args.clone(),
-            )
-            .await
-            .unwrap();
+    async fn publish<T>(&self, _context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        self.send(self.channel()?, msg.encode_to_vec()).await
+    }
+
+    /// Sends each message of the batch to the RabbitMQ classic queue.
+    async fn publish_batch<T>(
+        &self,
+        _context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        let channel = self.channel()?;
+        for msg in msg_batch.into_iter() {
+            self.send(channel, msg.encode_to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Messages are handed to the broker as they are published, so there is nothing to flush.
+    async fn flush(&self) -> Result<(), PublishError> {
+        Ok(())
     }
 
-    /// Disconnects the client.  Should be called before terminating the program.
-    pub async fn disconnect(self) {
-        self.client.disconnect().await;
+    /// Disconnects from the RabbitMQ server
+    async fn disconnect(self) -> Result<(), PublishError> {
+        self.connection.close().await.map_err(retryable)
     }
 }
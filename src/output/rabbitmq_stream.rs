@@ -1,6 +1,6 @@
 //! This module contains implementation details for
-//! StreamPublisherConnection when the `RABBITMQ_STREAM`
-//! feature is enabled.  This allows StreamPublisherConnection
+//! the RabbitMQ Stream output backend, enabled with the `RABBITMQ_STREAM`
+//! feature.  This allows StreamPublisherConnection
 //! to connect and publish to the RabbitMQ Stream (not to be
 //! confused with RabbitMQ Classic Queue)
 
@@ -8,35 +8,31 @@
 use log::info;
 
 // 3rd party imports
-use rabbitmq_stream_client::types::Message;
+use async_trait::async_trait;
+use rabbitmq_stream_client::types::Message as StreamMessage;
+use rabbitmq_stream_client::{NoDedup, Producer};
+use serde::Serialize;
 
 // local imports
 use super::environment::*;
-use super::publish::{StreamPublisherConnection, StreamPublisherConnectionClient};
+use super::error::PublishError;
+use super::publish::{Publisher, RecordContext};
+use crate::config;
 
-impl StreamPublisherConnectionClient {
-    /// Sends a message to the RabbitMQ Stream server.
-    #[inline]
-    pub async fn publish(&self, msg: Vec<u8>) {
-        let StreamPublisherConnectionClient::RabbitMQStream(rabbitmq_publisher) = self;
-        rabbitmq_publisher
-            .send_with_confirm(Message::builder().body(msg).build())
-            .await
-            .expect("FATAL: could not send the rabbitmq message to the stream queue");
-    }
+/// The name of this backend, used in errors.
+const BACKEND: &str = "rabbitmq_stream";
 
-    /// Disconnects from the RabbitMQ server stream
-    pub async fn disconnect(self) {
-        let StreamPublisherConnectionClient::RabbitMQStream(rabbitmq_producer) = self;
-        rabbitmq_producer
-            .close()
-            .await
-            .expect("FATAL: could not close the producer");
-    }
+/// A connection to a RabbitMQ Stream.
+#[derive(Clone)]
+pub struct RabbitMQStreamPublisher {
+    /// The producer publishing to the stream.
+    pub producer: Producer<NoDedup>,
+    /// The name of the RabbitMQ stream.
+    pub stream_name: String,
 }
 
 /// Connects to the RabbitMQ Classic queue system.
-/// Expects the following parameters to be stored in the .env file:
+/// Expects the following parameters to be configured (in the configuration file or the .env file):
 /// - `RABBITMQ_ADDRESS`
 /// - `RABBITMQ_PORT`
 /// - `environment`
@@ -47,47 +43,103 @@ impl StreamPublisherConnectionClient {
 /// This means you do not pass the queue name for `queue_name`, rather
 /// the name of the parameter in the .env file that reflects the name
 /// for the queue.
-pub async fn connect(queue_name: &str) -> StreamPublisherConnection {
-    // Extract values from the .env
-    let rabbitmq_address = get_rabbitmq_addr();
-    let rabbitmq_port = get_rabbitmq_port();
+pub async fn connect(queue_name: &str) -> Result<RabbitMQStreamPublisher, PublishError> {
+    // Extract values from the configuration
+    let rabbitmq_address = get_rabbitmq_addr()?;
+    let rabbitmq_port = get_rabbitmq_port()?;
     let rabbitmq_environment = rabbitmq_stream_client::Environment::builder()
-        .host(&rabbitmq_address)
+        .host(rabbitmq_address)
         .port(*rabbitmq_port)
         .build()
         .await
-        .expect("FATAL: could not create rabbitmq environment");
-    let rabbitmq_queue_name = dotenvy::var(queue_name)
-        .unwrap_or_else(|_| panic!("{} should exist in .env file", queue_name))
-        .parse::<String>()
-        .unwrap();
+        .map_err(|e| {
+            PublishError::retryable(BACKEND, format!("could not create environment: {}", e))
+        })?;
+    let rabbitmq_queue_name = get_queue_name(queue_name)?.clone();
 
     info!("Successfully created the rabbitmq environment");
 
     // this will cause a panic if the stream HAS been created:
     //environment.stream_creator().create(constants::STREAM_NAME).await.expect("FATAL: stream already exists");
 
-    // this will cause a panic if the stream has NOT been created:
+    // this will fail if the stream has NOT been created:
     let producer = rabbitmq_environment
         .producer()
         .build(&rabbitmq_queue_name)
         .await
-        .expect("FATAL: stream has not yet been created");
+        .map_err(|e| {
+            PublishError::configuration(
+                BACKEND,
+                format!(
+                    "stream {} has not yet been created: {}",
+                    rabbitmq_queue_name, e
+                ),
+            )
+        })?;
+
+    Ok(RabbitMQStreamPublisher {
+        producer,
+        stream_name: rabbitmq_queue_name,
+    })
+}
 
-    StreamPublisherConnection {
-        client: StreamPublisherConnectionClient::RabbitMQStream(producer),
-        queue_name: rabbitmq_queue_name,
+impl RabbitMQStreamPublisher {
+    /// Sends a message to the RabbitMQ Stream server, retrying retryable failures according to
+    /// the configured retry policy.
+    async fn send(&self, msg: Vec<u8>) -> Result<(), PublishError> {
+        config::get()
+            .retry
+            .run("rabbitmq_stream publish", || async {
+                self.producer
+                    .send_with_confirm(StreamMessage::builder().body(msg.clone()).build())
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| {
+                        PublishError::retryable(
+                            BACKEND,
+                            format!("could not send the message to the stream: {}", e),
+                        )
+                    })
+            })
+            .await
+            .map_err(PublishError::from)
     }
 }
 
-impl StreamPublisherConnection {
+#[async_trait]
+impl Publisher for RabbitMQStreamPublisher {
     /// Sends the message to the client
-    #[inline]
-    pub async fn publish<T: prost::Message>(&self, msg: T) {
-        self.client.publish(msg.encode_to_vec()).await;
+    async fn publish<T>(&self, _context: &RecordContext, msg: T) -> Result<(), PublishError>
+    where
+        T: prost::Message + prost::Name + Serialize + Clone + 'static,
+    {
+        self.send(msg.encode_to_vec()).await
     }
-    /// Sends the message to the client
-    pub async fn disconnect(self) {
-        self.client.disconnect().await;
+
+    /// Sends each message of the batch to the client
+    async fn publish_batch<T>(
+        &self,
+        _context: &RecordContext,
+        msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: prost::Message + prost::Name + Serialize + Clone + 'static,
+    {
+        for msg in msg_batch.into_iter() {
+            self.send(msg.encode_to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Every message is confirmed as it is sent, so there is nothing to flush.
+    async fn flush(&self) -> Result<(), PublishError> {
+        Ok(())
+    }
+
+    /// Disconnects from the RabbitMQ server stream
+    async fn disconnect(self) -> Result<(), PublishError> {
+        self.producer.close().await.map_err(|e| {
+            PublishError::retryable(BACKEND, format!("could not close the producer: {}", e))
+        })
     }
 }
@@ -0,0 +1,109 @@
+//! This module decides which output backend(s) each table is published to.
+//!
+//! By default every table uses the backends listed in `OUTPUT_BACKEND`.  A table can be routed
+//! elsewhere with an `OUTPUT_BACKEND_<TABLE>` key, where `<TABLE>` is the suffix of the table's
+//! `QUEUE_NAME_<TABLE>` key, e.g. `OUTPUT_BACKEND_TRACES=google_cloud_storage`, or in the
+//! `[output.routes]` section of the configuration file.  Routing a table to `none` drops its
+//! records.
+
+use async_trait::async_trait;
+use prost::{Message, Name};
+use serde::Serialize;
+
+use super::error::PublishError;
+use super::publish::{OutputBackend, Publisher, RecordContext};
+use crate::config;
+
+/// The backend name that drops the records of a table.
+pub const DROP_ROUTE: &str = "none";
+
+/// Where the records of a table are published.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputRoute {
+    /// Publish to every one of these backends.
+    Backends(Vec<OutputBackend>),
+    /// Drop the records.
+    Drop,
+}
+
+impl OutputRoute {
+    /// Parses a comma-separated list of backend names, or `none`.
+    pub fn parse(names: &str) -> Result<OutputRoute, String> {
+        if names.trim().eq_ignore_ascii_case(DROP_ROUTE) {
+            return Ok(OutputRoute::Drop);
+        }
+        let backends = names
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| name.parse::<OutputBackend>())
+            .collect::<Result<Vec<OutputBackend>, String>>()?;
+        if backends.is_empty() {
+            return Err(String::from("at least one backend should be listed"));
+        }
+        Ok(OutputRoute::Backends(backends))
+    }
+
+    /// Returns the route of the table published with `queue_env` (e.g. `QUEUE_NAME_BLOCKS`):
+    /// its `OUTPUT_BACKEND_<TABLE>` override if there is one, otherwise `OUTPUT_BACKEND`.  If
+    /// neither is set and only one backend is compiled in, that backend is used.
+    ///
+    /// NOTE: the routes are validated when the configuration is loaded, so this only fails if
+    /// the configuration was bypassed.
+    pub fn for_table(queue_env: &str) -> Result<OutputRoute, PublishError> {
+        config::get().route(queue_env).map_err(|e| {
+            PublishError::configuration("output", format!("route for {}: {}", queue_env, e))
+        })
+    }
+}
+
+/// A publisher that drops every record, used for tables routed to `none`.
+#[derive(Clone, Debug, Default)]
+pub struct DiscardPublisher {}
+
+#[async_trait]
+impl Publisher for DiscardPublisher {
+    async fn publish<T>(&self, _context: &RecordContext, _msg: T) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        Ok(())
+    }
+
+    async fn publish_batch<T>(
+        &self,
+        _context: &RecordContext,
+        _msg_batch: Vec<T>,
+    ) -> Result<(), PublishError>
+    where
+        T: Message + Name + Serialize + Clone + 'static,
+    {
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), PublishError> {
+        Ok(())
+    }
+
+    async fn disconnect(self) -> Result<(), PublishError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(OutputRoute::parse("none"), Ok(OutputRoute::Drop));
+        assert_eq!(OutputRoute::parse(" NONE "), Ok(OutputRoute::Drop));
+        assert!(OutputRoute::parse("").is_err());
+        assert!(OutputRoute::parse("not_a_backend").is_err());
+        for backend in OutputBackend::available() {
+            assert_eq!(
+                OutputRoute::parse(backend.name()),
+                Ok(OutputRoute::Backends(vec![backend]))
+            );
+        }
+    }
+}
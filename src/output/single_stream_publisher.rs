@@ -1,31 +1,84 @@
 //! This module defines the single StreamPublisher.  Since all outputs are managed through a singular stream,
 //! it is not blockchain specific.  Note, StreamPublishers supporting seperate publishers should be implemented within
 //! the blockchain.
-use super::publish::StreamPublisherConnection;
+use super::publish::{ChainInfo, PublishError, Publisher, StreamPublisherConnection};
 use log::info;
 
 // Get the appropriate connect
 use super::publish::connect;
 
+#[cfg(feature = "APACHE_KAFKA")]
+use super::kafka_transaction::KafkaTransaction;
+
 /// StreamPublisher struct (single-publisher version) that contains a singular Stream for
 /// all output.
 #[cfg(feature = "SINGLE_PUBLISHER")]
 #[derive(Clone)]
 pub struct StreamPublisher {
     pub all: StreamPublisherConnection,
+    /// The Kafka transaction the blocks of a worker are published in, with
+    /// `KAFKA_TRANSACTIONAL` (see `for_worker`).
+    #[cfg(feature = "APACHE_KAFKA")]
+    pub transaction: Option<KafkaTransaction>,
 }
 
 #[cfg(feature = "SINGLE_PUBLISHER")]
 impl StreamPublisher {
-    pub async fn new() -> StreamPublisher {
+    pub async fn new(chain: &ChainInfo) -> Result<StreamPublisher, PublishError> {
         info!("Connecting to the publisher...");
-        StreamPublisher {
-            all: connect("QUEUE_NAME").await,
+        Ok(StreamPublisher {
+            all: connect("QUEUE_NAME", chain).await?,
+            #[cfg(feature = "APACHE_KAFKA")]
+            transaction: None,
+        })
+    }
+
+    /// Returns the publisher prepared for the worker in slot `worker` (see
+    /// `StreamPublisherConnection::for_worker`), publishing in a Kafka transaction of its own
+    /// when `KAFKA_TRANSACTIONAL` is set.  Meant for `BlockchainConfig::worker_publisher`.
+    #[cfg_attr(not(feature = "APACHE_KAFKA"), allow(unused_variables))]
+    pub async fn for_worker(&self, worker: usize) -> Result<StreamPublisher, PublishError> {
+        let all = self.all.clone().for_worker().await?;
+        #[cfg(feature = "APACHE_KAFKA")]
+        if crate::config::get().kafka.transactional {
+            let transaction = KafkaTransaction::for_worker(worker).await?;
+            return Ok(StreamPublisher {
+                all: all.with_transaction(&transaction),
+                transaction: Some(transaction),
+            });
+        }
+        Ok(StreamPublisher {
+            all,
+            #[cfg(feature = "APACHE_KAFKA")]
+            transaction: None,
+        })
+    }
+
+    /// Begins the transaction of a block, if the blocks are published in transactions.  Meant
+    /// for `BlockchainConfig::begin_block`.
+    pub async fn begin_block(&self) -> Result<(), PublishError> {
+        #[cfg(feature = "APACHE_KAFKA")]
+        if let Some(transaction) = &self.transaction {
+            transaction.begin().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the records of the block at `height` are delivered, and commits its
+    /// transaction, if the blocks are published in transactions.  Meant for
+    /// `BlockchainConfig::finish_block`.
+    #[cfg_attr(not(feature = "APACHE_KAFKA"), allow(unused_variables))]
+    pub async fn finish_block(&self, height: u64) -> Result<(), PublishError> {
+        self.all.flush().await?;
+        #[cfg(feature = "APACHE_KAFKA")]
+        if let Some(transaction) = &self.transaction {
+            transaction.commit(height).await?;
         }
+        Ok(())
     }
 
-    pub async fn disconnect(self) {
+    pub async fn disconnect(self) -> Result<(), PublishError> {
         info!("Disconnecting from publisher...");
-        self.all.disconnect().await;
+        self.all.disconnect().await
     }
 }
@@ -0,0 +1,164 @@
+//! This module provides the queues and stages of the extract → transform → publish pipeline
+//! (see `indexer::index_pipeline`).
+//!
+//! Each stage runs its own number of workers, and hands its output to the next stage through a
+//! `StageQueue`, which is bounded both in items and in bytes.  A full queue makes the stage
+//! before it wait (backpressure), so a slow output holds up extraction only once the queues
+//! are full, rather than on every block.  The depth of each queue is exported as the
+//! `pipeline_queue_depth` and `pipeline_queue_bytes` metrics, labelled by the stage that
+//! consumes it, so the slowest stage is the one whose queue stays full.
+
+use async_channel::{Receiver, Sender};
+use futures::future::try_join_all;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics;
+
+/// An item in a queue, with the bytes it holds in the queue's byte budget.
+struct Queued<T> {
+    /// The item.
+    item: T,
+    /// The bytes of the item, released once it is taken off the queue.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// A queue between two stages, bounded in items and in bytes.
+pub struct StageQueue<T> {
+    /// The name of the stage consuming the queue, used as the metrics label.
+    stage: &'static str,
+    /// Sends items to the queue.
+    sender: Sender<Queued<T>>,
+    /// Receives items from the queue.
+    receiver: Receiver<Queued<T>>,
+    /// The bytes left in the queue's budget, if it is bounded in bytes.
+    bytes: Option<Arc<Semaphore>>,
+    /// The byte budget of the queue (0 if it isn't bounded in bytes).
+    max_bytes: u32,
+}
+
+impl<T> StageQueue<T> {
+    /// Creates a queue consumed by `stage`, holding at most `capacity` items and (unless it is
+    /// 0) `max_bytes` bytes.
+    pub fn new(stage: &'static str, capacity: usize, max_bytes: u64) -> StageQueue<T> {
+        let (sender, receiver) = async_channel::bounded(capacity.max(1));
+        let max_bytes = u32::try_from(max_bytes).unwrap_or(u32::MAX);
+        StageQueue {
+            stage,
+            sender,
+            receiver,
+            bytes: (max_bytes > 0).then(|| Arc::new(Semaphore::new(max_bytes as usize))),
+            max_bytes,
+        }
+    }
+
+    /// Adds an item of `size` bytes to the queue, waiting while the queue is full.  Returns
+    /// `false` if the queue was closed.
+    ///
+    /// NOTE: an item larger than the whole byte budget waits for the queue to be empty, rather
+    /// than forever.
+    pub async fn send(&self, item: T, size: usize) -> bool {
+        let permit = match &self.bytes {
+            Some(bytes) => {
+                let size = u32::try_from(size).unwrap_or(u32::MAX).min(self.max_bytes);
+                match bytes.clone().acquire_many_owned(size).await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => return false,
+                }
+            }
+            None => None,
+        };
+        let sent = self
+            .sender
+            .send(Queued {
+                item,
+                _permit: permit,
+            })
+            .await
+            .is_ok();
+        self.record_depth();
+        sent
+    }
+
+    /// Takes the next item off the queue, waiting for one.  Returns `None` once the queue is
+    /// closed and empty.
+    pub async fn recv(&self) -> Option<T> {
+        let queued = self.receiver.recv().await.ok()?;
+        let item = queued.item;
+        self.record_depth();
+        Some(item)
+    }
+
+    /// Closes the queue once the stage before it is done, so the consuming stage stops when
+    /// it is empty.
+    pub fn close(&self) {
+        self.sender.close();
+    }
+
+    /// Exports the items and bytes in the queue.
+    fn record_depth(&self) {
+        let bytes = self
+            .bytes
+            .as_ref()
+            .map(|bytes| self.max_bytes as usize - bytes.available_permits())
+            .unwrap_or_default();
+        metrics::record_queue_depth(self.stage, self.receiver.len(), bytes);
+    }
+}
+
+/// Runs a stage of `workers` workers, each called with its index.  Returns once every worker
+/// is done, or with the first error of a worker.  `done` is called in either case, to close the
+/// queue the stage sends to.
+pub async fn stage<W, Fut, E>(workers: usize, worker: W, done: impl FnOnce()) -> Result<(), E>
+where
+    W: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let result = try_join_all((0..workers.max(1)).map(worker)).await;
+    done();
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_bounds() {
+        let queue = StageQueue::new("test", 10, 100);
+        assert!(queue.send(1, 60).await);
+        // NOTE: the second item only fits once the first one is taken off the queue.
+        let blocked = tokio::time::timeout(std::time::Duration::from_millis(50), async {
+            queue.send(2, 60).await
+        })
+        .await;
+        assert!(blocked.is_err());
+        assert_eq!(queue.recv().await, Some(1));
+        assert!(queue.send(3, 1000).await);
+        queue.close();
+        assert_eq!(queue.recv().await, Some(3));
+        assert_eq!(queue.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_stage_closes_its_queue() {
+        let queue = StageQueue::new("test", 4, 0);
+        stage(
+            2,
+            |worker| {
+                let queue = &queue;
+                async move {
+                    queue.send(worker, 0).await;
+                    Ok::<(), ()>(())
+                }
+            },
+            || queue.close(),
+        )
+        .await
+        .unwrap();
+        let mut items = vec![queue.recv().await, queue.recv().await, queue.recv().await];
+        items.sort();
+        assert_eq!(items, [None, Some(0), Some(1)]);
+    }
+}
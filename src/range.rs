@@ -0,0 +1,99 @@
+//! This module describes the ranges indexed by `index-range`.
+//!
+//! A range is indexed forward from `start` until `end` (exclusive) or without end, or in
+//! reverse from `end` (exclusive) down to `start`, or from `start` down to the genesis block if
+//! there is no `end`.  The heights that were indexed are recorded in the checkpoints (see
+//! `checkpoint`), so a run that is started again skips the blocks that were already published.
+
+use std::fmt;
+
+/// The heights indexed by a run of `index-range`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexRange {
+    /// The first height of a forward range, or the lowest height of a reverse range.
+    pub start: u64,
+    /// The height the range stops at (exclusive), if any.
+    pub end: Option<u64>,
+    /// Whether the range is indexed backwards, towards the genesis block.
+    pub reverse: bool,
+}
+
+impl fmt::Display for IndexRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.end, self.reverse) {
+            (Some(end), false) => write!(f, "[{}, {})", self.start, end),
+            (None, false) => write!(f, "[{}, ...)", self.start),
+            (Some(end), true) => write!(f, "[{}, {}) in reverse", self.start, end),
+            (None, true) => write!(f, "[0, {}] in reverse", self.start),
+        }
+    }
+}
+
+impl IndexRange {
+    /// Creates a range, checking that it isn't empty.
+    pub fn new(start: u64, end: Option<u64>, reverse: bool) -> Result<IndexRange, String> {
+        if end.is_some_and(|end| end <= start) {
+            return Err(format!(
+                "the end of the range ({}) should be greater than its start ({})",
+                end.unwrap_or_default(),
+                start
+            ));
+        }
+        Ok(IndexRange {
+            start,
+            end,
+            reverse,
+        })
+    }
+
+    /// Returns the first height indexed.
+    pub fn first(&self) -> u64 {
+        match (self.end, self.reverse) {
+            (Some(end), true) => end - 1,
+            _ => self.start,
+        }
+    }
+
+    /// Returns the last height indexed, if the range has an end.
+    pub fn last(&self) -> Option<u64> {
+        match (self.end, self.reverse) {
+            (Some(_), true) => Some(self.start),
+            (None, true) => Some(0),
+            (end, false) => end.map(|end| end - 1),
+        }
+    }
+
+    /// Returns the heights of the range, in the order they are indexed.
+    pub fn heights(&self) -> Box<dyn Iterator<Item = u64> + Send> {
+        let first = self.first();
+        match (self.reverse, self.last()) {
+            (false, Some(last)) => Box::new(first..=last),
+            (false, None) => Box::new(first..),
+            (true, last) => Box::new((last.unwrap_or(0)..=first).rev()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heights() {
+        let forward = IndexRange::new(5, Some(8), false).unwrap();
+        assert_eq!(forward.heights().collect::<Vec<u64>>(), [5, 6, 7]);
+        assert_eq!(forward.last(), Some(7));
+
+        let reverse = IndexRange::new(5, Some(8), true).unwrap();
+        assert_eq!(reverse.heights().collect::<Vec<u64>>(), [7, 6, 5]);
+
+        let to_genesis = IndexRange::new(2, None, true).unwrap();
+        assert_eq!(to_genesis.heights().collect::<Vec<u64>>(), [2, 1, 0]);
+
+        let open = IndexRange::new(3, None, false).unwrap();
+        assert_eq!(open.heights().take(2).collect::<Vec<u64>>(), [3, 4]);
+        assert_eq!(open.last(), None);
+
+        assert!(IndexRange::new(5, Some(5), false).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! This module detects chain reorganizations while following the chain head, and describes
+//! the records that retract the blocks they replaced.
+//!
+//! The hash and parent hash of the most recent blocks (up to `follow.max_reorg_depth`) are kept
+//! by a `ReorgTracker`.  When a new block's parent hash isn't the hash of the last block that
+//! was published below it, the chain was reorganized: the indexer walks back to the last block
+//! that is still on the chain, publishes a `Retraction` (with `reorged` set) to every table for
+//! each block that was replaced, and indexes the new blocks at those heights.
+
+use chrono::Utc;
+use prost::{Message, Name};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::output::error::PublishError;
+use crate::output::publish::{Publisher, RecordContext, StreamPublisherConnection};
+
+/// The hash of a block and of its parent, which chain it to the block before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockLink {
+    /// The height of the block.
+    pub height: u64,
+    /// The hash of the block.
+    pub hash: String,
+    /// The hash of the block before it.
+    pub parent_hash: String,
+}
+
+/// A record retracting the records of a block that was replaced by a reorganization.
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub struct Retraction {
+    /// The height of the block that was replaced.
+    #[prost(uint64, tag = "1")]
+    pub block_number: u64,
+    /// The hash of the block that was replaced.
+    #[prost(string, tag = "2")]
+    pub block_hash: String,
+    /// The queue of the table whose records are retracted.
+    #[prost(string, tag = "3")]
+    pub table: String,
+    /// Always set, so retractions can be told apart from the table's own records.
+    #[prost(bool, tag = "4")]
+    pub reorged: bool,
+    /// When the reorganization was detected, as RFC 3339.
+    #[prost(string, tag = "5")]
+    pub detected_at: String,
+}
+
+impl Name for Retraction {
+    const NAME: &'static str = "Retraction";
+    const PACKAGE: &'static str = "blockchain_etl";
+}
+
+/// Publishes the retraction of a replaced block to a table.  Blockchains call this for each
+/// of their tables in `BlockchainConfig::publish_retractions`.
+pub async fn publish_retraction(
+    publisher: &StreamPublisherConnection,
+    link: &BlockLink,
+) -> Result<(), PublishError> {
+    let retraction = Retraction {
+        block_number: link.height,
+        block_hash: link.hash.clone(),
+        table: publisher.queue_name.clone(),
+        reorged: true,
+        detected_at: Utc::now().to_rfc3339(),
+    };
+    let context =
+        RecordContext::new(format!("{}_reorged", link.height)).with_block_number(link.height);
+    publisher.publish(&context, retraction).await
+}
+
+/// The links of the blocks published most recently, used to detect reorganizations.
+pub struct ReorgTracker {
+    /// How many blocks back a reorganization is followed.
+    max_depth: u64,
+    /// The links of the last `max_depth` blocks published, by height.
+    links: Mutex<BTreeMap<u64, BlockLink>>,
+}
+
+impl ReorgTracker {
+    /// Creates a tracker following reorganizations up to `max_depth` blocks deep.
+    pub fn new(max_depth: u64) -> ReorgTracker {
+        ReorgTracker {
+            max_depth,
+            links: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns how many blocks back a reorganization is followed.
+    pub fn max_depth(&self) -> u64 {
+        self.max_depth
+    }
+
+    /// Returns whether the block doesn't chain to the last block published below it (if any),
+    /// i.e. the chain may have been reorganized.
+    pub fn conflicts(&self, link: &BlockLink) -> bool {
+        let links = self.links.lock().expect("reorg tracker lock poisoned");
+        links
+            .range(..link.height)
+            .next_back()
+            .is_some_and(|(_, parent)| parent.hash != link.parent_hash)
+    }
+
+    /// Returns the link of the block published at `height`, if it is still tracked.
+    pub fn get(&self, height: u64) -> Option<BlockLink> {
+        let links = self.links.lock().expect("reorg tracker lock poisoned");
+        links.get(&height).cloned()
+    }
+
+    /// Returns the height of the oldest block that is still tracked, if any.
+    pub fn oldest(&self) -> Option<u64> {
+        let links = self.links.lock().expect("reorg tracker lock poisoned");
+        links.keys().next().copied()
+    }
+
+    /// Forgets the block published at `height`, once it was retracted.
+    pub fn forget(&self, height: u64) {
+        let mut links = self.links.lock().expect("reorg tracker lock poisoned");
+        links.remove(&height);
+    }
+
+    /// Records the link of a published block, forgetting the blocks that are now more than
+    /// `max_depth` below it.
+    pub fn record(&self, link: BlockLink) {
+        let mut links = self.links.lock().expect("reorg tracker lock poisoned");
+        let oldest = link.height.saturating_sub(self.max_depth);
+        links.insert(link.height, link);
+        *links = links.split_off(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the link of a test block.
+    fn link(height: u64, hash: &str, parent_hash: &str) -> BlockLink {
+        BlockLink {
+            height,
+            hash: hash.to_string(),
+            parent_hash: parent_hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_conflicts_and_pruning() {
+        let tracker = ReorgTracker::new(2);
+        tracker.record(link(10, "a", "z"));
+        assert!(!tracker.conflicts(&link(11, "b", "a")));
+        tracker.record(link(11, "b", "a"));
+        assert!(tracker.conflicts(&link(12, "c", "b2")));
+        assert!(!tracker.conflicts(&link(13, "d", "b")));
+
+        tracker.record(link(12, "c", "b"));
+        tracker.record(link(13, "d", "c"));
+        assert_eq!(tracker.get(10), None);
+        assert_eq!(tracker.get(11), Some(link(11, "b", "a")));
+    }
+}
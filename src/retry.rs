@@ -0,0 +1,340 @@
+//! This module contains the retry policy shared by every publisher and request to the node.
+//!
+//! Failed operations are retried with exponential backoff: the delay starts at
+//! `initial_delay_ms`, is multiplied by `multiplier` after every attempt up to `max_delay_ms`,
+//! and is randomly spread by `jitter` so that many workers don't retry in lockstep.  The
+//! operation is given up on once `max_attempts` or `max_elapsed_ms` is reached, whichever
+//! comes first, and every retry is counted in the `retry_count` metric.  An error may ask for a
+//! longer delay (see `Retryable::retry_after`), e.g. when the node throttles requests.
+
+use log::{error, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::metrics;
+
+/// Implemented by errors that the retry policy can retry.
+pub trait Retryable {
+    /// Returns whether the operation may succeed if it is retried.
+    fn is_retryable(&self) -> bool;
+
+    /// Returns how long the other side asked to wait before the next attempt (e.g. the
+    /// `Retry-After` of a throttled request), if it did.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The error returned when an operation couldn't be completed.
+#[derive(Debug, Error)]
+pub enum RetryError<E: fmt::Display + fmt::Debug> {
+    /// The operation failed with an error that isn't retryable.
+    #[error("{0}")]
+    Permanent(E),
+    /// The operation kept failing with retryable errors until the retry budget was exhausted.
+    #[error("{operation} failed {attempts} times in {elapsed:?}, giving up: {last_error}")]
+    Exhausted {
+        operation: String,
+        attempts: u32,
+        elapsed: Duration,
+        last_error: E,
+    },
+}
+
+/// How failed operations are retried.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// The delay before the first retry, in milliseconds.  (env: `RETRY_INITIAL_DELAY_MS`)
+    pub initial_delay_ms: u64,
+    /// The factor the delay is multiplied by after every retry.  (env: `RETRY_MULTIPLIER`)
+    pub multiplier: f64,
+    /// The longest delay between two attempts, in milliseconds.  (env: `RETRY_MAX_DELAY_MS`)
+    pub max_delay_ms: u64,
+    /// How much each delay is randomly spread, as a fraction of the delay (e.g. `0.2` for
+    /// +/- 20%).  (env: `RETRY_JITTER`)
+    pub jitter: f64,
+    /// The number of attempts (including the first one) before giving up.
+    /// (env: `RETRY_MAX_ATTEMPTS`)
+    pub max_attempts: Option<u32>,
+    /// How long to keep retrying before giving up, in milliseconds.
+    /// (env: `RETRY_MAX_ELAPSED_MS`)
+    pub max_elapsed_ms: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 2.0,
+            max_delay_ms: 30_000,
+            jitter: 0.2,
+            max_attempts: Some(10),
+            max_elapsed_ms: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the problems with the policy, if any.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            problems.push(String::from("multiplier should be at least 1"));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            problems.push(String::from("jitter should be between 0 and 1"));
+        }
+        if self.max_delay_ms < self.initial_delay_ms {
+            problems.push(String::from(
+                "max_delay_ms should be at least initial_delay_ms",
+            ));
+        }
+        if self.max_attempts == Some(0) {
+            problems.push(String::from("max_attempts should be at least 1"));
+        }
+        if self.max_attempts.is_none() && self.max_elapsed_ms.is_none() {
+            problems.push(String::from(
+                "max_attempts or max_elapsed_ms should be set, otherwise failures are retried forever",
+            ));
+        }
+        problems
+    }
+
+    /// Returns the delay before the retry following the given (1-based) failed attempt, before
+    /// jitter is applied.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay_ms = (self.initial_delay_ms as f64) * self.multiplier.powi(exponent);
+        Duration::from_millis(delay_ms.min(self.max_delay_ms as f64) as u64)
+    }
+
+    /// Returns the delay before the retry following the given (1-based) failed attempt, with
+    /// jitter applied.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay(attempt);
+        if self.jitter <= 0.0 {
+            return base;
+        }
+        let spread = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        base.mul_f64((1.0 + spread).max(0.0))
+    }
+
+    /// Runs the operation until it succeeds, fails with an error that isn't retryable, or
+    /// exhausts the retry budget.  `operation` names the operation in logs and in the
+    /// `retry_count` metric, so it should not contain unbounded values like block heights.
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        operation: &str,
+        mut attempt: F,
+    ) -> Result<T, RetryError<E>>
+    where
+        E: Retryable + fmt::Display + fmt::Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let started = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(err) if err.is_retryable() => err,
+                Err(err) => return Err(RetryError::Permanent(err)),
+            };
+
+            // NOTE: the delay asked for by the other side is honoured even when it is longer
+            // than `max_delay_ms`.
+            let delay = match err.retry_after() {
+                Some(retry_after) => retry_after.max(self.delay(attempts)),
+                None => self.delay(attempts),
+            };
+            let elapsed = started.elapsed();
+            let out_of_attempts = self.max_attempts.is_some_and(|max| attempts >= max);
+            let out_of_time = self
+                .max_elapsed_ms
+                .is_some_and(|max| elapsed + delay > Duration::from_millis(max));
+            if out_of_attempts || out_of_time {
+                metrics::record_retries_exhausted(operation);
+                let exhausted = RetryError::Exhausted {
+                    operation: operation.to_string(),
+                    attempts,
+                    elapsed,
+                    last_error: err,
+                };
+                error!("{}", exhausted);
+                return Err(exhausted);
+            }
+
+            metrics::record_retry(operation);
+            warn!(
+                "{} failed (attempt {}): {}, retrying in {:?}...",
+                operation, attempts, err, delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::error::SourceError;
+
+    /// A policy retrying after `delay_ms`, without jitter.
+    fn policy(
+        delay_ms: u64,
+        max_attempts: Option<u32>,
+        max_elapsed_ms: Option<u64>,
+    ) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay_ms: delay_ms,
+            multiplier: 1.0,
+            max_delay_ms: delay_ms,
+            jitter: 0.0,
+            max_attempts,
+            max_elapsed_ms,
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 2.0,
+            max_delay_ms: 1000,
+            jitter: 0.0,
+            max_attempts: Some(10),
+            max_elapsed_ms: None,
+        };
+        assert_eq!(policy.base_delay(1), Duration::from_millis(100));
+        assert_eq!(policy.base_delay(2), Duration::from_millis(200));
+        assert_eq!(policy.base_delay(4), Duration::from_millis(800));
+        assert_eq!(policy.base_delay(5), Duration::from_millis(1000));
+        assert_eq!(policy.delay(50), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RetryPolicy::default().validate().is_empty());
+        let policy = RetryPolicy {
+            multiplier: 0.5,
+            jitter: 2.0,
+            max_attempts: None,
+            max_elapsed_ms: None,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.validate().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let mut calls = 0;
+        let result: Result<(), _> = policy(1, Some(3), None)
+            .run("test", || {
+                calls += 1;
+                async { Err(SourceError::retryable("test", "timed out")) }
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(RetryError::Exhausted { attempts: 3, .. })
+        ));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = policy(1, Some(3), None)
+            .run("test", || {
+                calls += 1;
+                let attempt = calls;
+                async move {
+                    if attempt < 2 {
+                        Err(SourceError::retryable("test", "timed out"))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: Result<(), _> = policy(1, Some(3), None)
+            .run("test", || {
+                calls += 1;
+                async { Err(SourceError::fatal("test", "bad request")) }
+            })
+            .await;
+        assert!(matches!(result, Err(RetryError::Permanent(_))));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_elapsed() {
+        let started = Instant::now();
+        let mut calls = 0;
+        let result: Result<(), _> = policy(10, None, Some(50))
+            .run("test", || {
+                calls += 1;
+                async { Err(SourceError::retryable("test", "timed out")) }
+            })
+            .await;
+        let Err(RetryError::Exhausted {
+            attempts, elapsed, ..
+        }) = result
+        else {
+            panic!("should give up once max_elapsed_ms is reached");
+        };
+        assert_eq!(attempts, calls);
+        assert!((2..=5).contains(&attempts));
+        // NOTE: no retry is started that would end after `max_elapsed_ms`, so it gives up
+        // about then (allowing for slow timers).
+        assert!(elapsed <= started.elapsed());
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_waits_retry_after() {
+        let throttled = || SourceError::Throttled {
+            client: "test",
+            retry_after: Duration::from_millis(30),
+            reason: String::from("429 Too Many Requests"),
+        };
+
+        let started = Instant::now();
+        let mut calls = 0;
+        let result = policy(1, Some(3), None)
+            .run("test", || {
+                calls += 1;
+                let attempt = calls;
+                async move {
+                    match attempt {
+                        1 => Err(throttled()),
+                        _ => Ok(attempt),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        // NOTE: waiting as long as the node asks would exceed the budget, so it gives up at
+        // once.
+        let mut calls = 0;
+        let result: Result<(), _> = policy(1, Some(3), Some(10))
+            .run("test", || {
+                calls += 1;
+                async { Err(throttled()) }
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(RetryError::Exhausted { attempts: 1, .. })
+        ));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,162 @@
+//! This module splits the ranges indexed by several workers into work units, and runs the
+//! workers over a queue of those units.
+//!
+//! A range is split into units of `workers.unit_size` heights, leaving out the heights that the
+//! checkpoints already hold.  The units are queued on an `async-channel`, and each of the
+//! `NUM_EXTRACTOR_THREADS` workers takes the next unit from the queue once it is done with the
+//! previous one, so slow blocks only hold up the worker indexing them.  Each worker runs in a
+//! task of its own, so the workers run in parallel on the runtime's threads.
+
+use async_channel::Receiver;
+use std::future::Future;
+use tokio::task::{JoinError, JoinSet};
+
+use crate::checkpoint::HeightSet;
+
+/// A run of consecutive heights indexed by a single worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkUnit {
+    /// The first height of the unit.
+    pub start: u64,
+    /// The height after the last height of the unit.
+    pub end: u64,
+}
+
+/// Splits `[start, end)` into units of at most `unit_size` heights, leaving out the heights
+/// that were completed.
+pub fn work_units(start: u64, end: u64, unit_size: u64, completed: &HeightSet) -> Vec<WorkUnit> {
+    let unit_size = unit_size.max(1);
+    completed
+        .gaps(start, end)
+        .into_iter()
+        .flat_map(|(gap_start, gap_end)| {
+            (gap_start..gap_end)
+                .step_by(unit_size as usize)
+                .map(move |unit_start| WorkUnit {
+                    start: unit_start,
+                    end: gap_end.min(unit_start.saturating_add(unit_size)),
+                })
+        })
+        .collect()
+}
+
+/// Queues the units and runs `workers` workers over the queue, each spawned as a task with
+/// its index and the queue.  A worker should take units until the queue is closed and empty.
+/// Returns once every worker is done, or with the first error of a worker (the other workers
+/// are then aborted).  A worker that panics makes this panic too, and a worker that was
+/// cancelled (e.g. as the runtime shuts down) is returned as an error.
+///
+/// NOTE: the workers are also aborted if the returned future is dropped before it completes.
+pub async fn run<W, Fut, E>(units: Vec<WorkUnit>, workers: usize, worker: W) -> Result<(), E>
+where
+    W: Fn(usize, Receiver<WorkUnit>) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: From<JoinError> + Send + 'static,
+{
+    let (sender, receiver) = async_channel::unbounded();
+    for unit in units {
+        sender
+            .try_send(unit)
+            .expect("the queue is unbounded and open");
+    }
+    // NOTE: closing the queue lets the workers stop once it is empty.
+    sender.close();
+    let mut tasks = JoinSet::new();
+    for index in 0..workers.max(1) {
+        tasks.spawn(worker(index, receiver.clone()));
+    }
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok(result) => result?,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// The error of the test workers.
+    #[derive(Debug, PartialEq)]
+    enum TestError {
+        Failed,
+        Cancelled,
+    }
+
+    impl From<JoinError> for TestError {
+        fn from(_: JoinError) -> TestError {
+            TestError::Cancelled
+        }
+    }
+
+    /// Sets its flag when it is dropped, i.e. when the task holding it is done or aborted.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_units_and_workers() {
+        let mut completed = HeightSet::new();
+        completed.insert(3, 5);
+        let units = work_units(0, 10, 2, &completed);
+        assert_eq!(
+            units
+                .iter()
+                .map(|unit| (unit.start, unit.end))
+                .collect::<Vec<_>>(),
+            [(0, 2), (2, 3), (5, 7), (7, 9), (9, 10)]
+        );
+
+        let indexed = Arc::new(Mutex::new(HeightSet::new()));
+        run(units, 3, |_, queue| {
+            let indexed = indexed.clone();
+            async move {
+                while let Ok(unit) = queue.recv().await {
+                    indexed.lock().unwrap().insert(unit.start, unit.end);
+                }
+                Ok::<(), TestError>(())
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            indexed.lock().unwrap().ranges().collect::<Vec<_>>(),
+            [(0, 3), (5, 10)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_aborts_the_other_workers() {
+        let aborted = Arc::new(AtomicBool::new(false));
+        let result = run(Vec::new(), 2, |worker, _| {
+            // NOTE: only the worker that doesn't fail holds the flag.
+            let flag = (worker == 1).then(|| DropFlag(aborted.clone()));
+            async move {
+                let Some(_flag) = flag else {
+                    return Err(TestError::Failed);
+                };
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(())
+            }
+        })
+        .await;
+        assert_eq!(result, Err(TestError::Failed));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !aborted.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the other worker should be aborted");
+    }
+}
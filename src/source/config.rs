@@ -0,0 +1,181 @@
+//! This module contains `RequestConfig`, which describes where and how the requests to the node
+//! are sent: the endpoints, the timeouts, the retry policy and the failover from the primary
+//! endpoint (`ENDPOINT`) to the fallback endpoint (`FALLBACK_ENDPOINT`).
+
+use log::warn;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder};
+use std::time::Duration;
+
+use super::error::SourceError;
+use crate::config::{self, SourceConfig};
+use crate::metrics::{self, Metrics};
+
+/// The name of this client, used in errors.
+const CLIENT: &str = "http";
+
+/// How the requests to the node are sent.
+///
+/// NOTE: a request config is created once and cloned for each request, so the size of the
+/// `ReqBldr` variant doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum RequestConfig {
+    /// A prepared request to a single endpoint, whose body is set for every call.  There is
+    /// no failover.
+    ReqBldr(RequestBuilder),
+    /// Requests to the configured endpoints, failing over to the fallback endpoint.
+    Endpoints(Endpoints),
+}
+
+/// The endpoints of the node, sharing a single HTTP client.
+///
+/// NOTE: the reqwest docs suggest reusing a single client rather than creating one for every
+/// request, and cloning it is cheap.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    /// The HTTP client.
+    pub client: Client,
+    /// The address requests are sent to first.
+    pub primary: String,
+    /// The address requests are sent to when the primary endpoint fails.
+    pub fallback: Option<String>,
+}
+
+impl RequestConfig {
+    /// Creates the request config of the configured endpoints.
+    pub fn from_config(source: &SourceConfig) -> Result<RequestConfig, SourceError> {
+        let primary = source.endpoint.clone().ok_or_else(|| {
+            SourceError::configuration(CLIENT, "source.endpoint (ENDPOINT) should be set")
+        })?;
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(source.connect_timeout_secs))
+            .build()
+            .map_err(|e| SourceError::configuration(CLIENT, e))?;
+        Ok(RequestConfig::Endpoints(Endpoints {
+            client,
+            primary,
+            fallback: source.fallback_endpoint.clone(),
+        }))
+    }
+
+    /// Returns a copy of the request config, or `None` if its request builder can't be cloned.
+    pub fn try_clone(&self) -> Option<RequestConfig> {
+        match self {
+            RequestConfig::ReqBldr(builder) => builder.try_clone().map(RequestConfig::ReqBldr),
+            RequestConfig::Endpoints(endpoints) => {
+                Some(RequestConfig::Endpoints(endpoints.clone()))
+            }
+        }
+    }
+
+    /// Posts a JSON body and returns the body of the response.  Failed requests are retried
+    /// according to the configured retry policy, each attempt failing over to the fallback
+    /// endpoint if the primary endpoint fails.  `method` selects the timeout of the request
+    /// (see `SourceConfig::method_timeout`) and names it in logs and metrics.
+    pub async fn post(
+        &self,
+        method: &str,
+        body: Vec<u8>,
+        metrics: Option<&Metrics>,
+    ) -> Result<Vec<u8>, SourceError> {
+        let config = config::get();
+        let timeout = config.source.method_timeout(method);
+        config
+            .retry
+            .run(&format!("{} {}", CLIENT, method), || async {
+                match self {
+                    RequestConfig::ReqBldr(builder) => {
+                        let builder = builder.try_clone().ok_or_else(|| {
+                            SourceError::configuration(CLIENT, "the request can't be cloned")
+                        })?;
+                        send(builder, timeout, body.clone(), metrics).await
+                    }
+                    RequestConfig::Endpoints(endpoints) => {
+                        endpoints.post(timeout, &body, metrics).await
+                    }
+                }
+            })
+            .await
+            .map_err(SourceError::from)
+    }
+}
+
+impl Endpoints {
+    /// Posts the body to the primary endpoint, then to the fallback endpoint if the primary
+    /// endpoint failed with a retryable error.
+    async fn post(
+        &self,
+        timeout: Duration,
+        body: &[u8],
+        metrics: Option<&Metrics>,
+    ) -> Result<Vec<u8>, SourceError> {
+        let primary = send(
+            self.client.post(&self.primary),
+            timeout,
+            body.to_vec(),
+            metrics,
+        )
+        .await;
+        match (primary, &self.fallback) {
+            (Err(e), Some(fallback)) if e.is_retryable() => {
+                warn!(
+                    "Request to the primary endpoint failed: {}, failing over to the fallback endpoint...",
+                    e
+                );
+                send(self.client.post(fallback), timeout, body.to_vec(), metrics).await
+            }
+            (result, _) => result,
+        }
+    }
+}
+
+/// Sends a single request and returns the body of the response, counting it in the metrics.
+async fn send(
+    builder: RequestBuilder,
+    timeout: Duration,
+    body: Vec<u8>,
+    metrics: Option<&Metrics>,
+) -> Result<Vec<u8>, SourceError> {
+    let metrics = metrics.or(metrics::global());
+    if let Some(metrics) = metrics {
+        metrics.request_count.inc();
+    }
+    let result = async {
+        let response = builder
+            .header(CONTENT_TYPE, "application/json")
+            .timeout(timeout)
+            .body(body)
+            .send()
+            .await
+            .map_err(classify_error)?;
+        let status = response.status();
+        let bytes = response.bytes().await.map_err(classify_error)?;
+        if !status.is_success() {
+            let reason = format!(
+                "the node responded with {}: {}",
+                status,
+                String::from_utf8_lossy(&bytes[..bytes.len().min(256)])
+            );
+            return Err(SourceError::from_status(CLIENT, status.as_u16(), reason));
+        }
+        Ok(bytes.to_vec())
+    }
+    .await;
+    if let (Err(_), Some(metrics)) = (&result, metrics) {
+        metrics.failed_request_count.inc();
+    }
+    result
+}
+
+/// Classifies an error sending a request or reading its response.  Requests that couldn't be
+/// built are configuration errors, anything else (timeouts, connection errors) may be transient.
+pub fn classify_error(error: reqwest::Error) -> SourceError {
+    if error.is_builder() {
+        return SourceError::configuration(CLIENT, error);
+    }
+    match error.status() {
+        Some(status) => SourceError::from_status(CLIENT, status.as_u16(), error),
+        None => SourceError::retryable(CLIENT, error),
+    }
+}
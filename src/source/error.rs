@@ -0,0 +1,141 @@
+//! This module defines `SourceError`, the error returned by every source client when a request
+//! to the node fails.  Like `PublishError`, its variant tells the caller what to do about it:
+//! retry the request, skip the block, or stop indexing.
+
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::retry::{RetryError, Retryable};
+
+/// An error returned when requesting data from the node.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum SourceError {
+    /// A transient failure (e.g. a timeout, a connection error or an overloaded node).  The
+    /// request may succeed if it is retried.
+    #[error("{client}: {reason}")]
+    Retryable {
+        client: &'static str,
+        reason: String,
+    },
+    /// The node throttled the request (e.g. a 429 response), asking to wait `retry_after`
+    /// before sending it again.
+    #[error("{client}: {reason} (retry after {retry_after:?})")]
+    Throttled {
+        client: &'static str,
+        retry_after: Duration,
+        reason: String,
+    },
+    /// The request can never succeed (e.g. it is malformed, or the response can't be decoded).
+    #[error("{client}: {reason} (not retryable)")]
+    Fatal {
+        client: &'static str,
+        reason: String,
+    },
+    /// The source is misconfigured (e.g. a missing endpoint or invalid credentials), so no
+    /// request to it can succeed.  Indexing should stop.
+    #[error("{client} is misconfigured: {reason}")]
+    Configuration {
+        client: &'static str,
+        reason: String,
+    },
+    /// The request kept failing with retryable errors until the retry policy gave up.
+    #[error("{client}: gave up after {attempts} attempts: {reason}")]
+    Exhausted {
+        client: &'static str,
+        attempts: u32,
+        reason: String,
+    },
+}
+
+impl SourceError {
+    /// Creates a `SourceError::Retryable`.
+    pub fn retryable(client: &'static str, reason: impl fmt::Display) -> SourceError {
+        SourceError::Retryable {
+            client,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Creates a `SourceError::Fatal`.
+    pub fn fatal(client: &'static str, reason: impl fmt::Display) -> SourceError {
+        SourceError::Fatal {
+            client,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Creates a `SourceError::Configuration`.
+    pub fn configuration(client: &'static str, reason: impl fmt::Display) -> SourceError {
+        SourceError::Configuration {
+            client,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Classifies the HTTP status of a failed response.  Throttling, timeouts and server
+    /// errors may be transient, authentication errors and unknown paths are configuration
+    /// errors, and other client errors mean the request itself is wrong.
+    pub fn from_status(
+        client: &'static str,
+        status: u16,
+        reason: impl fmt::Display,
+    ) -> SourceError {
+        match status {
+            408 | 429 | 500..=599 => SourceError::retryable(client, reason),
+            401 | 403 | 404 => SourceError::configuration(client, reason),
+            _ => SourceError::fatal(client, reason),
+        }
+    }
+
+    /// Returns whether the request may succeed if it is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            SourceError::Retryable { .. } | SourceError::Throttled { .. }
+        )
+    }
+
+    /// Returns the name of the client that failed.
+    pub fn client(&self) -> &'static str {
+        match self {
+            SourceError::Retryable { client, .. }
+            | SourceError::Throttled { client, .. }
+            | SourceError::Fatal { client, .. }
+            | SourceError::Configuration { client, .. }
+            | SourceError::Exhausted { client, .. } => client,
+        }
+    }
+}
+
+impl Retryable for SourceError {
+    fn is_retryable(&self) -> bool {
+        SourceError::is_retryable(self)
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SourceError::Throttled { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+impl From<RetryError<SourceError>> for SourceError {
+    /// Returns an error that isn't retryable as is, or a `SourceError::Exhausted` describing
+    /// the last failure if the retry policy gave up.
+    fn from(error: RetryError<SourceError>) -> SourceError {
+        match error {
+            RetryError::Permanent(error) => error,
+            RetryError::Exhausted {
+                attempts,
+                last_error,
+                ..
+            } => SourceError::Exhausted {
+                client: last_error.client(),
+                attempts,
+                reason: last_error.to_string(),
+            },
+        }
+    }
+}
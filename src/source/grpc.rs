@@ -0,0 +1,494 @@
+//! This module contains the gRPC client of the `GRPC` feature.
+//!
+//! `connect()` builds a tonic channel from the `[source.grpc]` settings: the endpoints (tried
+//! in order until one connects), the TLS roots, the metadata sent with every request (e.g. an
+//! auth token), the compression, the HTTP/2 keepalive and the largest message size.  The
+//! returned `GrpcClient` calls the node's methods with the codec of the chain's generated
+//! messages (e.g. `tonic::codec::ProstCodec`), so a blockchain config doesn't need to set up
+//! its own channel.
+//!
+//! Blocks that the node streams (server-streaming methods) are read with a `BlockStream`, which
+//! resumes the stream from the block after the last one received whenever it fails or ends
+//! early.
+//!
+//! Like the other source clients, failed calls are retried according to the configured retry
+//! policy, and every call is counted in the request metrics.
+
+use log::warn;
+use std::time::Duration;
+use tonic::codec::{Codec, CompressionEncoding, Streaming};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, Request, Response, Status};
+
+use super::error::SourceError;
+use crate::config::{self, GrpcConfig, SourceConfig};
+use crate::metrics::{self, Metrics};
+
+/// The name of this client, used in errors, logs and metrics.
+const CLIENT: &str = "grpc";
+
+/// The service requests are sent through: the channel, with the configured metadata added to
+/// every request.  Generated clients can be created on it with `GrpcClient::service()`.
+pub type GrpcService = InterceptedService<Channel, MetadataInterceptor>;
+
+/// Adds the configured metadata (`auth_token` and `metadata`) to every request.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataInterceptor {
+    metadata: Vec<(AsciiMetadataKey, AsciiMetadataValue)>,
+}
+
+impl MetadataInterceptor {
+    /// Creates the interceptor of the configured metadata.
+    pub fn from_config(grpc: &GrpcConfig) -> Result<MetadataInterceptor, SourceError> {
+        let auth = grpc
+            .auth_token
+            .as_ref()
+            .map(|token| (String::from("authorization"), format!("Bearer {}", token)));
+        let metadata = grpc
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .chain(auth)
+            .map(|(key, value)| {
+                let key = AsciiMetadataKey::from_bytes(key.as_bytes()).map_err(|e| {
+                    SourceError::configuration(
+                        CLIENT,
+                        format!("invalid metadata key {}: {}", key, e),
+                    )
+                })?;
+                let mut value = value.parse::<AsciiMetadataValue>().map_err(|e| {
+                    SourceError::configuration(CLIENT, format!("invalid value of {}: {}", key, e))
+                })?;
+                value.set_sensitive(true);
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>, SourceError>>()?;
+        Ok(MetadataInterceptor { metadata })
+    }
+}
+
+impl Interceptor for MetadataInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        for (key, value) in self.metadata.iter() {
+            request.metadata_mut().insert(key.clone(), value.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Returns the configured compression, if any.
+fn compression(grpc: &GrpcConfig) -> Result<Option<CompressionEncoding>, SourceError> {
+    match grpc.compression.as_deref() {
+        None => Ok(None),
+        Some("gzip") => Ok(Some(CompressionEncoding::Gzip)),
+        Some("zstd") => Ok(Some(CompressionEncoding::Zstd)),
+        Some(other) => Err(SourceError::configuration(
+            CLIENT,
+            format!("unknown compression {}", other),
+        )),
+    }
+}
+
+/// Returns the TLS settings of `https` endpoints: the configured CA certificates, or the
+/// system's roots if there are none.
+fn tls_config(grpc: &GrpcConfig) -> Result<ClientTlsConfig, SourceError> {
+    let tls = match &grpc.ca_cert_path {
+        Some(path) => {
+            let pem = std::fs::read(path).map_err(|e| {
+                SourceError::configuration(CLIENT, format!("can't read {}: {}", path, e))
+            })?;
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(pem))
+        }
+        None => ClientTlsConfig::new().with_native_roots(),
+    };
+    Ok(match &grpc.tls_domain {
+        Some(domain) => tls.domain_name(domain),
+        None => tls,
+    })
+}
+
+/// Creates the endpoint of an address, with the configured timeouts, keepalive and TLS.
+fn endpoint(address: &str, source: &SourceConfig) -> Result<Endpoint, SourceError> {
+    let grpc = &source.grpc;
+    let mut endpoint = Endpoint::from_shared(address.to_string())
+        .map_err(|e| {
+            SourceError::configuration(CLIENT, format!("invalid endpoint {}: {}", address, e))
+        })?
+        .connect_timeout(Duration::from_secs(source.connect_timeout_secs))
+        .keep_alive_timeout(Duration::from_secs(grpc.keepalive_timeout_secs));
+    if let Some(interval) = grpc.keepalive_interval_secs {
+        endpoint = endpoint
+            .http2_keep_alive_interval(Duration::from_secs(interval))
+            .keep_alive_while_idle(true);
+    }
+    if endpoint.uri().scheme_str() == Some("https") {
+        endpoint = endpoint
+            .tls_config(tls_config(grpc)?)
+            .map_err(|e| SourceError::configuration(CLIENT, e))?;
+    }
+    Ok(endpoint)
+}
+
+/// Connects to the first endpoint that accepts the connection.
+async fn connect_any(endpoints: &[Endpoint]) -> Result<Channel, SourceError> {
+    let mut last_error = SourceError::configuration(CLIENT, "no endpoint is configured");
+    for endpoint in endpoints {
+        match endpoint.connect().await {
+            Ok(channel) => return Ok(channel),
+            Err(e) => {
+                warn!(
+                    "Couldn't connect to {}: {}, trying the next endpoint...",
+                    endpoint.uri(),
+                    e
+                );
+                last_error = SourceError::retryable(
+                    CLIENT,
+                    format!("couldn't connect to {}: {}", endpoint.uri(), e),
+                );
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Connects to the configured endpoints (`source.grpc.endpoints`, or `source.endpoint`),
+/// retrying according to the configured retry policy until one of them accepts the connection.
+pub async fn connect(source: &SourceConfig) -> Result<GrpcClient, SourceError> {
+    let grpc = &source.grpc;
+    let endpoints = grpc
+        .endpoints(source)
+        .iter()
+        .map(|address| endpoint(address, source))
+        .collect::<Result<Vec<Endpoint>, SourceError>>()?;
+    let interceptor = MetadataInterceptor::from_config(grpc)?;
+    let channel = config::get()
+        .retry
+        .run(&format!("{} connect", CLIENT), || connect_any(&endpoints))
+        .await
+        .map_err(SourceError::from)?;
+    GrpcClient::new(channel, interceptor, grpc)
+}
+
+/// Classifies the status of a failed call.  Unavailable or overloaded nodes, timeouts and
+/// aborted calls may be transient, authentication errors and unknown methods are configuration
+/// errors, and anything else means the request itself is wrong.
+pub fn classify_status(status: &Status) -> SourceError {
+    let reason = format!("{:?}: {}", status.code(), status.message());
+    match status.code() {
+        Code::Unavailable
+        | Code::DeadlineExceeded
+        | Code::ResourceExhausted
+        | Code::Aborted
+        | Code::Cancelled
+        | Code::Unknown
+        | Code::Internal => SourceError::retryable(CLIENT, reason),
+        Code::Unauthenticated | Code::PermissionDenied | Code::Unimplemented => {
+            SourceError::configuration(CLIENT, reason)
+        }
+        _ => SourceError::fatal(CLIENT, reason),
+    }
+}
+
+/// Returns the method name of a path (e.g. `GetBlock` for `/node.v1.Node/GetBlock`), which
+/// selects the timeout of unary calls.
+fn method_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// A client of the node's gRPC API, which calls its methods with any codec.  Cloning it is
+/// cheap, and the clones share the same connection.
+#[derive(Clone)]
+pub struct GrpcClient {
+    /// The service requests are sent through.
+    service: GrpcService,
+    /// The gRPC client on the service, with the configured compression and message sizes.
+    grpc: tonic::client::Grpc<GrpcService>,
+    /// The metrics calls are counted in, if not the global ones.
+    metrics: Option<Metrics>,
+}
+
+impl GrpcClient {
+    /// Creates the client of a channel.
+    pub fn new(
+        channel: Channel,
+        interceptor: MetadataInterceptor,
+        grpc: &GrpcConfig,
+    ) -> Result<GrpcClient, SourceError> {
+        let service = InterceptedService::new(channel, interceptor);
+        let mut client = tonic::client::Grpc::new(service.clone())
+            .max_decoding_message_size(grpc.max_message_size)
+            .max_encoding_message_size(grpc.max_message_size);
+        if let Some(encoding) = compression(grpc)? {
+            client = client.send_compressed(encoding).accept_compressed(encoding);
+        }
+        Ok(GrpcClient {
+            service,
+            grpc: client,
+            metrics: None,
+        })
+    }
+
+    /// Counts the calls in `metrics` rather than in the global metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> GrpcClient {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns the service requests are sent through, for creating the generated client of a
+    /// service (e.g. `NodeClient::new(client.service())`).
+    ///
+    /// NOTE: generated clients don't share the compression and message sizes of this client,
+    /// so they should be set on the generated client too.
+    pub fn service(&self) -> GrpcService {
+        self.service.clone()
+    }
+
+    /// Counts a call, and whether it failed, in the metrics.
+    fn record<T>(&self, result: &Result<T, SourceError>) {
+        if let Some(metrics) = self.metrics.as_ref().or(metrics::global()) {
+            metrics.request_count.inc();
+            if result.is_err() {
+                metrics.failed_request_count.inc();
+            }
+        }
+    }
+
+    /// Calls a unary method, e.g. `/node.v1.Node/GetBlock`, with the codec of its messages.
+    /// The call is given the timeout of its method (see `SourceConfig::method_timeout`).
+    pub async fn unary<C>(
+        &self,
+        path: &'static str,
+        message: C::Encode,
+    ) -> Result<C::Decode, SourceError>
+    where
+        C: Codec + Default,
+        C::Encode: Clone + Send + Sync + 'static,
+        C::Decode: Send + Sync + 'static,
+    {
+        let config = config::get();
+        let timeout = config.source.method_timeout(method_name(path));
+        config
+            .retry
+            .run(&format!("{} {}", CLIENT, path), || {
+                let mut grpc = self.grpc.clone();
+                let message = message.clone();
+                async move {
+                    let result = async {
+                        grpc.ready()
+                            .await
+                            .map_err(|e| SourceError::retryable(CLIENT, e))?;
+                        let mut request = Request::new(message);
+                        request.set_timeout(timeout);
+                        grpc.unary(request, PathAndQuery::from_static(path), C::default())
+                            .await
+                            .map(Response::into_inner)
+                            .map_err(|status| classify_status(&status))
+                    }
+                    .await;
+                    self.record(&result);
+                    result
+                }
+            })
+            .await
+            .map_err(SourceError::from)
+    }
+
+    /// Calls a server-streaming method with the codec of its messages, and returns the stream
+    /// of responses.  Only opening the stream is retried; see `BlockStream` for a stream that
+    /// resumes when it fails.
+    pub async fn server_streaming<C>(
+        &self,
+        path: &'static str,
+        message: C::Encode,
+    ) -> Result<Streaming<C::Decode>, SourceError>
+    where
+        C: Codec + Default,
+        C::Encode: Clone + Send + Sync + 'static,
+        C::Decode: Send + Sync + 'static,
+    {
+        config::get()
+            .retry
+            .run(&format!("{} {}", CLIENT, path), || {
+                let mut grpc = self.grpc.clone();
+                let message = message.clone();
+                async move {
+                    let result = async {
+                        grpc.ready()
+                            .await
+                            .map_err(|e| SourceError::retryable(CLIENT, e))?;
+                        grpc.server_streaming(
+                            Request::new(message),
+                            PathAndQuery::from_static(path),
+                            C::default(),
+                        )
+                        .await
+                        .map(Response::into_inner)
+                        .map_err(|status| classify_status(&status))
+                    }
+                    .await;
+                    self.record(&result);
+                    result
+                }
+            })
+            .await
+            .map_err(SourceError::from)
+    }
+}
+
+/// A server-streaming method of the node that streams blocks, implemented by each blockchain
+/// config that reads blocks from a stream.
+pub trait BlockFeed: Send + Sync {
+    /// The request that opens the stream.
+    type Request: Clone + Send + Sync + 'static;
+    /// The messages of the stream.  A message may hold several blocks (e.g. a batch of
+    /// transactions), in which case its height is the height of the last one.
+    type Block: Send + Sync + 'static;
+    /// The codec of the request and the messages.
+    type Codec: Codec<Encode = Self::Request, Decode = Self::Block> + Default;
+    /// The path of the method, e.g. `/node.v1.Node/StreamBlocks`.
+    const PATH: &'static str;
+
+    /// Creates the request for the blocks from `start` until `end` (exclusive), or without
+    /// end if `end` is `None`.
+    fn request(&self, start: u64, end: Option<u64>) -> Self::Request;
+
+    /// Returns the height of a message.
+    fn height(block: &Self::Block) -> u64;
+}
+
+/// A stream of blocks, which is reopened from the block after the last one received whenever
+/// it fails with a retryable error or ends before `end`.  Blocks the node sends again after
+/// the stream is reopened are dropped, so every block is returned once, in order.
+///
+/// NOTE: a stream that keeps failing is given up on after the configured number of retry
+/// attempts in a row, with the retry policy's delay before each reopening.
+pub struct BlockStream<F: BlockFeed> {
+    /// The client the stream is opened with.
+    client: GrpcClient,
+    /// The method that streams the blocks.
+    feed: F,
+    /// The height of the next block to return.
+    next_height: u64,
+    /// The height the stream ends at (exclusive), if any.
+    end: Option<u64>,
+    /// The open stream, if any.
+    stream: Option<Streaming<F::Block>>,
+    /// How many times the stream was reopened since the last block was received.
+    failures: u32,
+}
+
+impl<F: BlockFeed> BlockStream<F> {
+    /// Creates the stream of the blocks from `start` until `end` (exclusive), or without end if
+    /// `end` is `None`.  The stream is opened on the first call to `next()`.
+    pub fn new(client: GrpcClient, feed: F, start: u64, end: Option<u64>) -> BlockStream<F> {
+        BlockStream {
+            client,
+            feed,
+            next_height: start,
+            end,
+            stream: None,
+            failures: 0,
+        }
+    }
+
+    /// Returns the height of the next block, which the stream would resume from.
+    pub fn next_height(&self) -> u64 {
+        self.next_height
+    }
+
+    /// Returns the next block, `None` once the stream reached `end`, or an error if the stream
+    /// failed with an error that isn't retryable or kept failing.
+    pub async fn next(&mut self) -> Option<Result<F::Block, SourceError>> {
+        loop {
+            if self.end.is_some_and(|end| self.next_height >= end) {
+                return None;
+            }
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => {
+                    let request = self.feed.request(self.next_height, self.end);
+                    match self
+                        .client
+                        .server_streaming::<F::Codec>(F::PATH, request)
+                        .await
+                    {
+                        Ok(stream) => self.stream.insert(stream),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            };
+            let error = match stream.message().await {
+                Ok(Some(block)) => {
+                    let height = F::height(&block);
+                    if height < self.next_height {
+                        // sent again after the stream was reopened
+                        continue;
+                    }
+                    self.next_height = height + 1;
+                    self.failures = 0;
+                    return Some(Ok(block));
+                }
+                Ok(None) => SourceError::retryable(CLIENT, "the stream ended early"),
+                Err(status) => classify_status(&status),
+            };
+            self.stream = None;
+            if let Err(e) = self.resume(error).await {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// Waits before the stream is reopened, or returns the error if it isn't retryable or the
+    /// stream kept failing.
+    async fn resume(&mut self, error: SourceError) -> Result<(), SourceError> {
+        if !error.is_retryable() {
+            return Err(error);
+        }
+        let operation = format!("{} {}", CLIENT, F::PATH);
+        let policy = &config::get().retry;
+        self.failures += 1;
+        if policy
+            .max_attempts
+            .is_some_and(|max_attempts| self.failures >= max_attempts)
+        {
+            metrics::record_retries_exhausted(&operation);
+            return Err(SourceError::Exhausted {
+                client: CLIENT,
+                attempts: self.failures,
+                reason: error.to_string(),
+            });
+        }
+        warn!(
+            "The {} stream failed at height {}: {}, resuming...",
+            F::PATH,
+            self.next_height,
+            error
+        );
+        metrics::record_retry(&operation);
+        tokio::time::sleep(policy.delay(self.failures)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_status() {
+        assert!(classify_status(&Status::unavailable("down")).is_retryable());
+        assert!(matches!(
+            classify_status(&Status::unauthenticated("bad token")),
+            SourceError::Configuration { .. }
+        ));
+        assert!(matches!(
+            classify_status(&Status::invalid_argument("bad height")),
+            SourceError::Fatal { .. }
+        ));
+        assert_eq!(method_name("/node.v1.Node/GetBlock"), "GetBlock");
+    }
+}
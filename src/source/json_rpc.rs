@@ -0,0 +1,272 @@
+//! This module contains the JSON-RPC client of the `RPC` feature.
+//!
+//! A blockchain config describes the methods it calls with structs implementing `RpcRequest`,
+//! whose serialization is the `params` of the request and whose `Result` is the type of the
+//! `result` of the response.  The requests for a block and for the current block height
+//! implement `BlockRequest` and `BlockHeightRequest`, so they can be made with
+//! `call_getBlock()` and `call_getBlockHeight()`.  Several requests can be sent in a single
+//! JSON-RPC batch with `call_batch()`.
+//!
+//! Every request is retried according to the configured retry policy, and fails over to the
+//! fallback endpoint when the primary endpoint fails (see `source::config`).
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::config::RequestConfig;
+use super::error::SourceError;
+use crate::config;
+use crate::metrics::Metrics;
+
+/// The name of this client, used in errors.
+const CLIENT: &str = "json_rpc";
+
+/// The JSON-RPC version of every request.
+const JSONRPC_VERSION: &str = "2.0";
+
+/// A JSON-RPC method call.  The request is serialized as the `params` of the call, so it
+/// should serialize to an array or an object (e.g. a tuple struct for positional params).
+pub trait RpcRequest: Serialize + Send + Sync {
+    /// The name of the method (e.g. `getBlock`).  Also selects the timeout of the request.
+    const METHOD: &'static str;
+    /// The type of the `result` of the response.
+    type Result: DeserializeOwned + Send;
+}
+
+/// The request for the block at a given height, implemented by each blockchain config.
+pub trait BlockRequest: RpcRequest {
+    /// Creates the request for the block at `height`.
+    fn new(height: u64) -> Self;
+}
+
+/// The request for the current block height, implemented by each blockchain config.
+pub trait BlockHeightRequest: RpcRequest {
+    /// Creates the request.
+    fn new() -> Self;
+}
+
+/// The envelope of a JSON-RPC request.
+#[derive(Serialize)]
+struct JsonRpcEnvelope<'a, P> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'static str,
+    params: &'a P,
+}
+
+impl<'a, P> JsonRpcEnvelope<'a, P> {
+    /// Wraps the params of a call to `method`.
+    fn new(id: u64, method: &'static str, params: &'a P) -> JsonRpcEnvelope<'a, P> {
+        JsonRpcEnvelope {
+            jsonrpc: JSONRPC_VERSION,
+            id,
+            method,
+            params,
+        }
+    }
+}
+
+/// The error object of a JSON-RPC response.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct JsonRpcError {
+    /// The error code, e.g. `-32601` for an unknown method.
+    pub code: i64,
+    /// The description of the error.
+    pub message: String,
+    /// Additional information, specific to the node.
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+}
+
+/// A JSON-RPC response, holding either the `result` of the call or its `error`.
+///
+/// NOTE: the error object is returned as is rather than as a `SourceError`, since what the
+/// codes mean (e.g. a skipped block) is specific to each node.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct JsonRpcResponse<T> {
+    /// The id of the request the response answers.
+    pub id: Option<u64>,
+    /// The result of the call, if it succeeded.
+    pub result: Option<T>,
+    /// The error of the call, if it failed.
+    pub error: Option<JsonRpcError>,
+}
+
+impl<T> JsonRpcResponse<T> {
+    /// Returns the result of the call, or a `SourceError::Fatal` describing its error.
+    pub fn into_result(self, method: &str) -> Result<T, SourceError> {
+        match (self.result, self.error) {
+            (Some(result), _) => Ok(result),
+            (None, Some(error)) => Err(SourceError::fatal(
+                CLIENT,
+                format!(
+                    "{} returned error {}: {}",
+                    method, error.code, error.message
+                ),
+            )),
+            (None, None) => Err(SourceError::fatal(
+                CLIENT,
+                format!("{} returned neither a result nor an error", method),
+            )),
+        }
+    }
+}
+
+/// Decodes the body of a response.
+fn decode<T: DeserializeOwned>(method: &str, body: &[u8]) -> Result<T, SourceError> {
+    serde_json::from_slice(body)
+        .map_err(|e| SourceError::fatal(CLIENT, format!("invalid {} response: {}", method, e)))
+}
+
+/// Calls the method of the request.
+pub async fn call<Req: RpcRequest>(
+    request_config: RequestConfig,
+    request: &Req,
+    metrics: Option<Metrics>,
+) -> Result<JsonRpcResponse<Req::Result>, SourceError> {
+    let body = serde_json::to_vec(&JsonRpcEnvelope::new(0, Req::METHOD, request))
+        .map_err(|e| SourceError::fatal(CLIENT, e))?;
+    let response = request_config
+        .post(Req::METHOD, body, metrics.as_ref())
+        .await?;
+    decode(Req::METHOD, &response)
+}
+
+/// Calls the method of every request, sending them in batches of at most `max_batch_size`
+/// (`RPC_MAX_BATCH_SIZE`).  Returns the responses in the order of the requests.
+pub async fn call_batch<Req: RpcRequest>(
+    request_config: RequestConfig,
+    requests: &[Req],
+    metrics: Option<Metrics>,
+) -> Result<Vec<JsonRpcResponse<Req::Result>>, SourceError> {
+    let max_batch_size = config::get().source.max_batch_size.max(1);
+    let mut responses = Vec::with_capacity(requests.len());
+    for batch in requests.chunks(max_batch_size) {
+        let envelopes = batch
+            .iter()
+            .enumerate()
+            .map(|(id, request)| JsonRpcEnvelope::new(id as u64, Req::METHOD, request))
+            .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&envelopes).map_err(|e| SourceError::fatal(CLIENT, e))?;
+        let response = request_config
+            .post(Req::METHOD, body, metrics.as_ref())
+            .await?;
+
+        // NOTE: a node that rejects the whole batch (e.g. because it is too large) responds
+        // with a single error object rather than an array.
+        let mut batch_responses =
+            match decode::<Vec<JsonRpcResponse<Req::Result>>>(Req::METHOD, &response) {
+                Ok(batch_responses) => batch_responses,
+                Err(e) => {
+                    let rejected =
+                        decode::<JsonRpcResponse<serde_json::Value>>(Req::METHOD, &response)
+                            .ok()
+                            .and_then(|response| response.error);
+                    return Err(match rejected {
+                        Some(error) => SourceError::fatal(
+                            CLIENT,
+                            format!(
+                                "the {} batch was rejected with error {}: {}",
+                                Req::METHOD,
+                                error.code,
+                                error.message
+                            ),
+                        ),
+                        None => e,
+                    });
+                }
+            };
+
+        // The responses of a batch may be in any order.
+        batch_responses.sort_by_key(|response| response.id);
+        let ids_match = batch_responses.len() == batch.len()
+            && batch_responses
+                .iter()
+                .enumerate()
+                .all(|(id, response)| response.id == Some(id as u64));
+        if !ids_match {
+            return Err(SourceError::fatal(
+                CLIENT,
+                format!(
+                    "the {} batch response doesn't answer each of the {} requests",
+                    Req::METHOD,
+                    batch.len()
+                ),
+            ));
+        }
+        responses.extend(batch_responses);
+    }
+    Ok(responses)
+}
+
+/// Requests the block at the given height.
+#[allow(non_snake_case)]
+pub async fn call_getBlock<Req: BlockRequest>(
+    request_config: RequestConfig,
+    height: u64,
+    metrics: Option<Metrics>,
+) -> Result<JsonRpcResponse<Req::Result>, SourceError> {
+    call(request_config, &Req::new(height), metrics).await
+}
+
+/// Requests the blocks at the given heights, in batches.  Returns the responses in the order
+/// of the heights.
+#[allow(non_snake_case)]
+pub async fn call_getBlocks<Req: BlockRequest>(
+    request_config: RequestConfig,
+    heights: &[u64],
+    metrics: Option<Metrics>,
+) -> Result<Vec<JsonRpcResponse<Req::Result>>, SourceError> {
+    let requests = heights
+        .iter()
+        .map(|height| Req::new(*height))
+        .collect::<Vec<Req>>();
+    call_batch(request_config, &requests, metrics).await
+}
+
+/// Requests the current block height.
+#[allow(non_snake_case)]
+pub async fn call_getBlockHeight<Req: BlockHeightRequest>(
+    request_config: RequestConfig,
+    metrics: Option<Metrics>,
+) -> Result<Req::Result, SourceError> {
+    call(request_config, &Req::new(), metrics)
+        .await?
+        .into_result(Req::METHOD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct GetBlock {
+        height: u64,
+    }
+
+    impl RpcRequest for GetBlock {
+        const METHOD: &'static str = "getBlock";
+        type Result = serde_json::Value;
+    }
+
+    #[test]
+    fn test_envelope_and_response() {
+        let envelope = JsonRpcEnvelope::new(3, GetBlock::METHOD, &GetBlock { height: 42 });
+        assert_eq!(
+            serde_json::to_string(&envelope).unwrap(),
+            r#"{"jsonrpc":"2.0","id":3,"method":"getBlock","params":{"height":42}}"#
+        );
+
+        let response: JsonRpcResponse<u64> =
+            decode("getBlockHeight", br#"{"jsonrpc":"2.0","id":0,"result":7}"#).unwrap();
+        assert_eq!(response.into_result("getBlockHeight"), Ok(7));
+
+        let response: JsonRpcResponse<u64> = decode(
+            "getBlock",
+            br#"{"jsonrpc":"2.0","id":0,"error":{"code":-32007,"message":"skipped"}}"#,
+        )
+        .unwrap();
+        assert_eq!(response.error.as_ref().map(|e| e.code), Some(-32007));
+        assert!(!response.into_result("getBlock").unwrap_err().is_retryable());
+    }
+}
@@ -0,0 +1,18 @@
+//! This module contains the clients that request blocks from the node, shared by the
+//! blockchain configs.  Each client is compiled in with its feature (e.g. `RPC` for
+//! `json_rpc`, `GRPC` for `grpc` and `REST` for `rest`), and every one of them retries failed
+//! requests with the shared retry policy, returning a `SourceError` once it gives up.
+
+pub mod error;
+
+#[cfg(any(feature = "RPC", feature = "REST"))]
+pub mod config;
+
+#[cfg(feature = "RPC")]
+pub mod json_rpc;
+
+#[cfg(feature = "GRPC")]
+pub mod grpc;
+
+#[cfg(feature = "REST")]
+pub mod rest;
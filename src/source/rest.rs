@@ -0,0 +1,465 @@
+//! This module contains the REST client of the `REST` feature.
+//!
+//! A `RestClient` sends `GET` requests to paths relative to the configured base URL
+//! (`[source.rest]`), with the configured auth token and headers.  Lists that the node returns
+//! a page at a time are fetched with `get_pages_by_offset()` (e.g. `?start=100&limit=100`) or
+//! `get_pages_by_cursor()` (a cursor returned in the body or a header of each page).  Resources
+//! that are polled (e.g. the chain head) can be fetched with `get_json_cached()`, which sends
+//! the last `ETag` so that the node can answer with a bodyless `304 Not Modified`.
+//!
+//! Like the other source clients, failed requests are retried according to the configured
+//! retry policy, and every request is counted in the request metrics.  A throttled request
+//! (`429 Too Many Requests`) is retried no sooner than its `Retry-After`.
+
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, ETAG, IF_NONE_MATCH, RETRY_AFTER,
+};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::config::classify_error;
+use super::error::SourceError;
+use crate::config::{self, SourceConfig};
+use crate::metrics::{self, Metrics};
+
+/// The name of this client, used in errors, logs and metrics.
+const CLIENT: &str = "rest";
+
+/// The query parameters of a request.
+pub type Query<'a> = [(&'a str, String)];
+
+/// The `ETag` and body of the last response of the most recently used URLs.
+type EtagCache = Arc<Mutex<LruCache<String, (HeaderValue, Vec<u8>)>>>;
+
+/// A successful response.
+#[derive(Clone, Debug)]
+pub struct RestResponse {
+    /// The headers of the response.
+    pub headers: HeaderMap,
+    /// The body of the response.
+    pub body: Vec<u8>,
+}
+
+impl RestResponse {
+    /// Returns the value of a header, if it is set and is valid text.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    /// Decodes the JSON body of the response.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, SourceError> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| SourceError::fatal(CLIENT, format!("invalid response: {}", e)))
+    }
+}
+
+/// The names of the parameters and headers of a paginated API.
+#[derive(Clone, Copy, Debug)]
+pub struct Pagination {
+    /// The query parameter of the offset of a page.
+    pub offset_param: &'static str,
+    /// The query parameter of the number of items in a page.
+    pub limit_param: &'static str,
+    /// The query parameter of the cursor of a page.
+    pub cursor_param: &'static str,
+    /// The header holding the cursor of the next page, if the API returns it in a header
+    /// rather than in the body.
+    pub cursor_header: Option<&'static str>,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Pagination {
+            offset_param: "start",
+            limit_param: "limit",
+            cursor_param: "cursor",
+            cursor_header: None,
+        }
+    }
+}
+
+/// A page of a cursor-paginated API, implemented by each blockchain config that reads one.
+pub trait CursorPage: DeserializeOwned {
+    /// The items of the page.
+    type Item;
+
+    /// Returns the items of the page.
+    fn into_items(self) -> Vec<Self::Item>;
+
+    /// Returns the cursor of the next page, or `None` if this is the last page.  Not needed if
+    /// the API returns the cursor in a header (see `Pagination::cursor_header`).
+    fn next_cursor(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<T: DeserializeOwned> CursorPage for Vec<T> {
+    type Item = T;
+
+    fn into_items(self) -> Vec<T> {
+        self
+    }
+}
+
+/// Parses a `Retry-After` header: either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Joins the base URL and a path with a single slash.
+fn join_url(base_url: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// A client of the node's REST API.  Cloning it is cheap, and the clones share the same
+/// connections and `ETag` cache.
+///
+/// NOTE: the reqwest docs suggest reusing a single client rather than creating one for every
+/// request.
+#[derive(Clone)]
+pub struct RestClient {
+    /// The HTTP client, which sends the configured headers with every request.
+    client: Client,
+    /// The base URL that request paths are relative to.
+    base_url: String,
+    /// The number of items requested per page.
+    page_size: u64,
+    /// The longest `Retry-After` that is honoured.
+    max_retry_after: Duration,
+    /// The `ETag` and body of the last response of the URLs fetched with `get_json_cached()`,
+    /// up to `source.rest.etag_cache_size` of them.
+    ///
+    /// NOTE: the cache is keyed by the query too, so it is bounded in case a caller polls
+    /// with ever-changing queries (e.g. a height).
+    etags: EtagCache,
+    /// The metrics requests are counted in, if not the global ones.
+    metrics: Option<Metrics>,
+}
+
+impl RestClient {
+    /// Creates the client of the configured REST API.
+    pub fn from_config(source: &SourceConfig) -> Result<RestClient, SourceError> {
+        let rest = &source.rest;
+        let base_url = rest.base_url(source).cloned().ok_or_else(|| {
+            SourceError::configuration(
+                CLIENT,
+                "source.rest.base_url (REST_BASE_URL) or source.endpoint should be set",
+            )
+        })?;
+        let auth = rest
+            .auth_token
+            .as_ref()
+            .map(|token| (AUTHORIZATION.to_string(), format!("Bearer {}", token)));
+        let mut headers = HeaderMap::new();
+        for (name, value) in rest
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .chain(auth)
+        {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+                SourceError::configuration(CLIENT, format!("invalid header {}: {}", name, e))
+            })?;
+            let mut value = HeaderValue::from_str(&value).map_err(|e| {
+                SourceError::configuration(CLIENT, format!("invalid value of {}: {}", name, e))
+            })?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(source.connect_timeout_secs))
+            .default_headers(headers)
+            .build()
+            .map_err(|e| SourceError::configuration(CLIENT, e))?;
+        let etag_cache_size = NonZeroUsize::new(rest.etag_cache_size).ok_or_else(|| {
+            SourceError::configuration(CLIENT, "source.rest.etag_cache_size should be at least 1")
+        })?;
+        Ok(RestClient {
+            client,
+            base_url,
+            page_size: rest.page_size.max(1),
+            max_retry_after: Duration::from_secs(rest.max_retry_after_secs),
+            etags: Arc::new(Mutex::new(LruCache::new(etag_cache_size))),
+            metrics: None,
+        })
+    }
+
+    /// Counts the requests in `metrics` rather than in the global metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> RestClient {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns the number of items requested per page.
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Sends a `GET` request to a path relative to the base URL (e.g. `/v1/blocks/by_height/1`)
+    /// and returns the response.  Failed requests are retried according to the configured
+    /// retry policy.  The request is given the timeout of its path, without the query (see
+    /// `SourceConfig::method_timeout`).
+    pub async fn get(&self, path: &str, query: &Query<'_>) -> Result<RestResponse, SourceError> {
+        self.get_with_retry(path, query, false).await
+    }
+
+    /// Like `get()`, decoding the JSON body of the response.
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &Query<'_>,
+    ) -> Result<T, SourceError> {
+        self.get(path, query).await?.json()
+    }
+
+    /// Like `get_json()`, sending the `ETag` of the last response so that the node can answer
+    /// with `304 Not Modified` if the resource didn't change, in which case the last response
+    /// is returned again.
+    pub async fn get_json_cached<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &Query<'_>,
+    ) -> Result<T, SourceError> {
+        self.get_with_retry(path, query, true).await?.json()
+    }
+
+    /// Fetches the items of an offset-paginated API from `start`, a page at a time.  Stops
+    /// after `count` items, or at the first page shorter than requested if `count` is `None`.
+    pub async fn get_pages_by_offset<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &Query<'_>,
+        pagination: &Pagination,
+        start: u64,
+        count: Option<u64>,
+    ) -> Result<Vec<T>, SourceError> {
+        let mut items = Vec::new();
+        let mut offset = start;
+        loop {
+            let limit = match count {
+                Some(count) => count.saturating_sub(items.len() as u64).min(self.page_size),
+                None => self.page_size,
+            };
+            if limit == 0 {
+                break;
+            }
+            let mut page_query = query.to_vec();
+            page_query.push((pagination.offset_param, offset.to_string()));
+            page_query.push((pagination.limit_param, limit.to_string()));
+            let page: Vec<T> = self.get_json(path, &page_query).await?;
+
+            // NOTE: APIs may cap the page size below the limit, so a short page only ends an
+            // unbounded list.
+            let len = page.len() as u64;
+            items.extend(page);
+            offset += len;
+            if len == 0 || (count.is_none() && len < limit) {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Fetches the items of a cursor-paginated API, a page at a time, until the last page or
+    /// until `max_items` items were fetched.
+    pub async fn get_pages_by_cursor<P: CursorPage>(
+        &self,
+        path: &str,
+        query: &Query<'_>,
+        pagination: &Pagination,
+        max_items: Option<usize>,
+    ) -> Result<Vec<P::Item>, SourceError> {
+        let mut items = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut page_query = query.to_vec();
+            page_query.push((pagination.limit_param, self.page_size.to_string()));
+            if let Some(cursor) = cursor.take() {
+                page_query.push((pagination.cursor_param, cursor));
+            }
+            let response = self.get(path, &page_query).await?;
+            let page: P = response.json()?;
+            cursor = match pagination.cursor_header {
+                Some(header) => response.header(header).map(String::from),
+                None => page.next_cursor(),
+            };
+            items.extend(page.into_items());
+            if let Some(max_items) = max_items {
+                if items.len() >= max_items {
+                    items.truncate(max_items);
+                    break;
+                }
+            }
+            if cursor.is_none() {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Sends a `GET` request with the configured retry policy.
+    async fn get_with_retry(
+        &self,
+        path: &str,
+        query: &Query<'_>,
+        use_etag: bool,
+    ) -> Result<RestResponse, SourceError> {
+        let config = config::get();
+        let timeout = config.source.method_timeout(path);
+        config
+            .retry
+            .run(&format!("{} GET", CLIENT), || async move {
+                let result = self.send(path, query, timeout, use_etag).await;
+                if let Some(metrics) = self.metrics.as_ref().or(metrics::global()) {
+                    metrics.request_count.inc();
+                    if result.is_err() {
+                        metrics.failed_request_count.inc();
+                    }
+                }
+                result
+            })
+            .await
+            .map_err(SourceError::from)
+    }
+
+    /// Sends a single `GET` request.
+    async fn send(
+        &self,
+        path: &str,
+        query: &Query<'_>,
+        timeout: Duration,
+        use_etag: bool,
+    ) -> Result<RestResponse, SourceError> {
+        let url = join_url(&self.base_url, path);
+        let mut request = self.client.get(&url).query(query).timeout(timeout);
+
+        // NOTE: the cache is keyed by the whole URL, including the query.
+        let cache_key = format!("{}?{:?}", url, query);
+        let cached = if use_etag {
+            self.etags
+                .lock()
+                .expect("etag cache lock")
+                .get(&cache_key)
+                .cloned()
+        } else {
+            None
+        };
+        if let Some((etag, _)) = &cached {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+
+        let response = request.send().await.map_err(classify_error)?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        if status == StatusCode::NOT_MODIFIED {
+            if let Some((_, body)) = cached {
+                return Ok(RestResponse { headers, body });
+            }
+        }
+        let body = response.bytes().await.map_err(classify_error)?.to_vec();
+        if !status.is_success() {
+            let reason = format!(
+                "GET {} responded with {}: {}",
+                path,
+                status,
+                String::from_utf8_lossy(&body[..body.len().min(256)])
+            );
+            let retry_after = headers
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, Utc::now()));
+            return Err(match retry_after {
+                Some(retry_after)
+                    if status == StatusCode::TOO_MANY_REQUESTS
+                        || status == StatusCode::SERVICE_UNAVAILABLE =>
+                {
+                    SourceError::Throttled {
+                        client: CLIENT,
+                        retry_after: retry_after.min(self.max_retry_after),
+                        reason,
+                    }
+                }
+                _ => SourceError::from_status(CLIENT, status.as_u16(), reason),
+            });
+        }
+
+        if use_etag {
+            if let Some(etag) = headers.get(ETAG) {
+                self.etags
+                    .lock()
+                    .expect("etag cache lock")
+                    .put(cache_key, (etag.clone(), body.clone()));
+            }
+        }
+        Ok(RestResponse { headers, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after_and_urls() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        assert_eq!(
+            join_url("https://node/v1/", "/blocks/by_height/1"),
+            "https://node/v1/blocks/by_height/1"
+        );
+    }
+
+    #[test]
+    fn test_etag_cache_is_bounded() {
+        let mut source = SourceConfig {
+            endpoint: Some(String::from("https://node/v1")),
+            ..SourceConfig::default()
+        };
+        source.rest.etag_cache_size = 2;
+        let client = RestClient::from_config(&source).unwrap();
+        for height in 0..10 {
+            client.etags.lock().unwrap().put(
+                format!("https://node/v1/blocks/by_height/{}", height),
+                (HeaderValue::from_static("\"etag\""), Vec::new()),
+            );
+        }
+        let etags = client.etags.lock().unwrap();
+        assert_eq!(etags.len(), 2);
+        assert!(etags.contains("https://node/v1/blocks/by_height/9"));
+
+        source.rest.etag_cache_size = 0;
+        assert!(RestClient::from_config(&source).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! This module contains the mock blockchain and checkpoint store that the tests of the indexing
+//! loops run against, so they don't need a node or an output, and the configuration the tests
+//! run with, so they don't need environment variables.
+
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::blockchain_config::{BlockchainConfig, ExtractionError};
+use crate::checkpoint::{CheckpointError, CheckpointStore, Checkpoints, HeightSet};
+use crate::config::{self, Config};
+use crate::metrics::Metrics;
+use crate::output::error::PublishError;
+use crate::output::publish::OutputBackend;
+use crate::reorg::BlockLink;
+
+/// Returns the configuration the tests run with: the defaults, publishing to JSONL files (or
+/// the first backend compiled in) in the temporary directory, with a metrics port.
+pub fn test_config() -> Config {
+    let mut config = Config::default();
+    #[cfg(feature = "JSONL")]
+    let backend = OutputBackend::JsonL;
+    #[cfg(not(feature = "JSONL"))]
+    let backend = OutputBackend::available()[0];
+    config.output.backends = vec![backend.to_string()];
+    config.file.output_dir = Some(
+        std::env::temp_dir()
+            .join("blockchain-etl-tests")
+            .to_string_lossy()
+            .into_owned(),
+    );
+    config.metrics.port = Some(9090);
+    config
+}
+
+/// Stores `test_config()` as the configuration returned by `config::get()`, unless it was
+/// already stored.  Meant to be called first by the tests that read the configuration.
+pub fn init_config() {
+    config::init(test_config());
+}
+
+/// Keeps the checkpoints in memory.
+#[derive(Default)]
+pub struct MemoryCheckpointStore {
+    /// The heights completed for each output.
+    completed: Mutex<BTreeMap<String, HeightSet>>,
+}
+
+impl CheckpointStore for MemoryCheckpointStore {
+    fn completed(&self, output: &str) -> Result<HeightSet, CheckpointError> {
+        let completed = self.completed.lock().unwrap();
+        Ok(completed.get(output).cloned().unwrap_or_default())
+    }
+
+    fn record(&self, output: &str, start: u64, end: u64) -> Result<(), CheckpointError> {
+        let mut completed = self.completed.lock().unwrap();
+        completed
+            .entry(output.to_string())
+            .or_default()
+            .insert(start, end);
+        Ok(())
+    }
+
+    fn outputs(&self) -> Result<Vec<String>, CheckpointError> {
+        Ok(self.completed.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Returns empty checkpoints, kept in memory.
+pub fn checkpoints() -> Checkpoints {
+    Checkpoints::new(
+        Box::<MemoryCheckpointStore>::default(),
+        String::from("mock"),
+    )
+}
+
+/// A publisher that, like a RabbitMQ channel, can only publish once it was prepared by
+/// `worker_publisher`.  Its clones share the heights published and retracted.
+#[derive(Clone, Default)]
+pub struct MockPublisher {
+    /// Whether the publisher was prepared by `worker_publisher`.
+    prepared: bool,
+    /// The heights published, in order.
+    published: Arc<Mutex<Vec<u64>>>,
+    /// The heights retracted, in order.
+    retracted: Arc<Mutex<Vec<u64>>>,
+    /// The heights begun, in order.
+    begun: Arc<Mutex<Vec<u64>>>,
+    /// The heights finished, in order.
+    finished: Arc<Mutex<Vec<u64>>>,
+    /// The slots of the workers the publisher was prepared for, in order.
+    workers: Arc<Mutex<Vec<usize>>>,
+}
+
+impl MockPublisher {
+    /// Returns a publisher that was already prepared.
+    pub fn prepared() -> MockPublisher {
+        MockPublisher {
+            prepared: true,
+            ..MockPublisher::default()
+        }
+    }
+
+    /// Returns the heights published, in order.
+    pub fn published(&self) -> Vec<u64> {
+        self.published.lock().unwrap().clone()
+    }
+
+    /// Returns the heights retracted, in order.
+    pub fn retracted(&self) -> Vec<u64> {
+        self.retracted.lock().unwrap().clone()
+    }
+
+    /// Returns the heights begun, in order.
+    pub fn begun(&self) -> Vec<u64> {
+        self.begun.lock().unwrap().clone()
+    }
+
+    /// Returns the heights finished, in order.
+    pub fn finished(&self) -> Vec<u64> {
+        self.finished.lock().unwrap().clone()
+    }
+
+    /// Returns the slots of the workers the publisher was prepared for, in order.
+    pub fn workers(&self) -> Vec<usize> {
+        self.workers.lock().unwrap().clone()
+    }
+}
+
+/// A blockchain whose block at each height is the height itself, hashed as the height (e.g.
+/// `"12"`), or with a `b` suffix from the height it was forked at (e.g. `"12b"`).
+#[derive(Default)]
+pub struct MockChain {
+    /// The chain head, if the blockchain can tell.
+    pub head: Option<u64>,
+    /// The height the chain was reorganized from, if it was.
+    pub fork: Option<u64>,
+    /// How long extracting a block waits, like a request to the node.
+    pub extract_delay: Duration,
+    /// How long transforming a block blocks its thread, like a heavy transformation.
+    pub transform_delay: Duration,
+    /// The number of blocks being transformed.
+    pub transforming: AtomicUsize,
+    /// The number of blocks extracted while another block was being transformed.
+    pub overlaps: AtomicUsize,
+}
+
+impl MockChain {
+    /// Returns the link of the block at `height` on the chain before it was forked.
+    pub fn original_link(height: u64) -> BlockLink {
+        BlockLink {
+            height,
+            hash: height.to_string(),
+            parent_hash: height.saturating_sub(1).to_string(),
+        }
+    }
+
+    /// Returns the hash of the block at `height` on the current chain.
+    fn hash(&self, height: u64) -> String {
+        match self.fork {
+            Some(fork) if height >= fork => format!("{}b", height),
+            _ => height.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockchainConfig for MockChain {
+    const NAME: &'static str = "mock";
+    type RawBlock = u64;
+    type Records = u64;
+    type StreamPublisher = MockPublisher;
+
+    async fn new() -> Result<Self, ExtractionError> {
+        Ok(MockChain::default())
+    }
+
+    async fn connect_publisher(&self) -> Result<MockPublisher, ExtractionError> {
+        Ok(MockPublisher::default())
+    }
+
+    async fn disconnect_publisher(&self, _: MockPublisher) -> Result<(), ExtractionError> {
+        Ok(())
+    }
+
+    async fn extract_block(
+        &self,
+        height: u64,
+        _metrics: Option<Metrics>,
+    ) -> Result<Option<u64>, ExtractionError> {
+        if !self.extract_delay.is_zero() {
+            tokio::time::sleep(self.extract_delay).await;
+        }
+        if self.transforming.load(Ordering::SeqCst) > 0 {
+            self.overlaps.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(Some(height))
+    }
+
+    fn transform_block(&self, _height: u64, block: u64) -> Result<u64, ExtractionError> {
+        self.transforming.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(self.transform_delay);
+        self.transforming.fetch_sub(1, Ordering::SeqCst);
+        Ok(block)
+    }
+
+    async fn publish_records(
+        &self,
+        publisher: &MockPublisher,
+        records: u64,
+    ) -> Result<(), ExtractionError> {
+        if !publisher.prepared {
+            return Err(PublishError::configuration("mock", "publisher wasn't prepared").into());
+        }
+        publisher.published.lock().unwrap().push(records);
+        Ok(())
+    }
+
+    async fn begin_block(
+        &self,
+        publisher: &MockPublisher,
+        height: u64,
+    ) -> Result<(), ExtractionError> {
+        publisher.begun.lock().unwrap().push(height);
+        Ok(())
+    }
+
+    async fn finish_block(
+        &self,
+        publisher: &MockPublisher,
+        height: u64,
+    ) -> Result<(), ExtractionError> {
+        publisher.finished.lock().unwrap().push(height);
+        Ok(())
+    }
+
+    async fn chain_head(&self, _metrics: Option<Metrics>) -> Result<Option<u64>, ExtractionError> {
+        Ok(self.head)
+    }
+
+    fn block_link(&self, height: u64, _block: &u64) -> Option<BlockLink> {
+        Some(BlockLink {
+            height,
+            hash: self.hash(height),
+            parent_hash: self.hash(height.saturating_sub(1)),
+        })
+    }
+
+    async fn publish_retractions(
+        &self,
+        publisher: &MockPublisher,
+        link: &BlockLink,
+    ) -> Result<(), ExtractionError> {
+        publisher.retracted.lock().unwrap().push(link.height);
+        Ok(())
+    }
+
+    async fn worker_publisher(
+        &self,
+        publisher: &MockPublisher,
+        worker: usize,
+    ) -> Result<MockPublisher, ExtractionError> {
+        publisher.workers.lock().unwrap().push(worker);
+        Ok(MockPublisher {
+            prepared: true,
+            ..publisher.clone()
+        })
+    }
+
+    async fn extract_txs(
+        &self,
+        _start: u64,
+        _end: u64,
+        _outdir: Option<PathBuf>,
+    ) -> Result<Vec<u64>, ExtractionError> {
+        Ok(Vec::new())
+    }
+
+    async fn create_test_data(
+        &self,
+        _start: u64,
+        _end: u64,
+        _dir: &Path,
+        _metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError> {
+        Ok(())
+    }
+
+    #[cfg(feature = "ORCHESTRATED")]
+    async fn subscribe_and_extract(
+        &self,
+        _subscription: google_cloud_pubsub::subscription::Subscription,
+        _publisher: MockPublisher,
+        _metrics: Option<Metrics>,
+    ) -> Result<(), ExtractionError> {
+        Ok(())
+    }
+
+    #[cfg(feature = "APACHE_AVRO")]
+    fn avro_schema(_queue_env: &str) -> Option<&'static str> {
+        None
+    }
+}